@@ -0,0 +1,235 @@
+//! Derive macros for the `socket` crate, enabled with its `macros` feature.
+//!
+//! `#[derive(Commands)]` turns an enum with one variant per command into
+//! the registration and client-side dispatch code for all of them:
+//!
+//! ```ignore
+//! #[derive(Commands)]
+//! pub enum ProcessCommand {
+//!     /// Start a named process
+//!     #[command(name = "start", response = String)]
+//!     Start(StartRequest),
+//!     #[command(response = HashMap<String, String>)]
+//!     List,
+//! }
+//! ```
+//!
+//! A variant holds its request data, or sends `()` if it has none. Its
+//! command name defaults to the variant name in snake case, and its
+//! response data to `()`. The derive generates:
+//!
+//! - `ProcessCommandResponse`, an enum with the response data of each variant
+//! - `ProcessCommandHandler`, a trait with one method per variant, named like
+//!   its default command name, which the server implements
+//! - `ProcessCommand::register(&server, handler)`, registering every command
+//!   on a `SocketServer<serde_json::Value, serde_json::Value>`
+//! - `ProcessCommand::send(self, &client)`, sending the command and returning
+//!   its response data
+//! - `ProcessCommand::COMMAND_NAMES` and `command_name()`
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, Ident, LitStr, Type};
+
+/// Generate typed registration and dispatch for an enum of commands; see the
+/// [crate] docs
+#[proc_macro_derive(Commands, attributes(command))]
+pub fn derive_commands(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// One variant of the enum
+struct Variant {
+    ident: Ident,
+    docs: Vec<Attribute>,
+    /// Command name on the wire
+    name: LitStr,
+    /// Handler method name
+    method: Ident,
+    request: Type,
+    response: Type,
+    /// Whether the variant holds its request data
+    has_data: bool,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "Commands can only be derived for enums"));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "command enums can't be generic"));
+    }
+    if data.variants.is_empty() {
+        return Err(syn::Error::new_spanned(&input.ident, "a command enum needs at least one command"));
+    }
+    let variants = data.variants.iter().map(parse_variant).collect::<syn::Result<Vec<_>>>()?;
+
+    let vis = &input.vis;
+    let ident = &input.ident;
+    let response_enum = format_ident!("{}Response", ident);
+    let handler_trait = format_ident!("{}Handler", ident);
+    let markers: Vec<Ident> = variants.iter().map(|v| format_ident!("__{}{}", ident, v.ident)).collect();
+
+    let variant_idents: Vec<&Ident> = variants.iter().map(|v| &v.ident).collect();
+    let docs: Vec<&Vec<Attribute>> = variants.iter().map(|v| &v.docs).collect();
+    let names: Vec<&LitStr> = variants.iter().map(|v| &v.name).collect();
+    let methods: Vec<&Ident> = variants.iter().map(|v| &v.method).collect();
+    let requests: Vec<&Type> = variants.iter().map(|v| &v.request).collect();
+    let responses: Vec<&Type> = variants.iter().map(|v| &v.response).collect();
+    let patterns: Vec<TokenStream> = variants
+        .iter()
+        .map(|v| {
+            let variant = &v.ident;
+            if v.has_data {
+                quote!(Self::#variant(request))
+            } else {
+                quote!(Self::#variant)
+            }
+        })
+        .collect();
+    let request_exprs: Vec<TokenStream> = variants
+        .iter()
+        .map(|v| if v.has_data { quote!(request) } else { quote!(()) })
+        .collect();
+    let ignored: Vec<TokenStream> = variants
+        .iter()
+        .map(|v| {
+            let variant = &v.ident;
+            if v.has_data {
+                quote!(Self::#variant(_))
+            } else {
+                quote!(Self::#variant)
+            }
+        })
+        .collect();
+
+    let response_doc = format!("Response data of a [`{ident}`] command");
+    let handler_doc = format!("Serves every [`{ident}`] command, registered with [`{ident}::register`]");
+
+    Ok(quote! {
+        #[doc = #response_doc]
+        #[derive(Debug)]
+        #vis enum #response_enum {
+            #( #(#docs)* #variant_idents(#responses), )*
+        }
+
+        #[doc = #handler_doc]
+        #vis trait #handler_trait: Send + Sync + 'static {
+            #( #(#docs)* fn #methods(&self, request: #requests) -> ::circle_socket::SocketResult<#responses>; )*
+        }
+
+        const _: () = {
+            #(
+                struct #markers;
+
+                impl ::circle_socket::Command for #markers {
+                    const NAME: &'static str = #names;
+                    type Request = #requests;
+                    type Response = #responses;
+                }
+            )*
+
+            impl #ident {
+                /// Names of every command, in declaration order
+                pub const COMMAND_NAMES: &'static [&'static str] = &[#(#names),*];
+
+                /// Name of this command on the wire
+                pub fn command_name(&self) -> &'static str {
+                    match self {
+                        #( #ignored => #names, )*
+                    }
+                }
+
+                /// Send this command and return its response data. An error
+                /// response becomes `SocketError::ServerError`.
+                pub async fn send(
+                    self,
+                    client: &::circle_socket::SocketClient,
+                ) -> ::circle_socket::SocketResult<#response_enum> {
+                    match self {
+                        #(
+                            #patterns => client
+                                .call::<#markers>(#request_exprs)
+                                .await
+                                .map(#response_enum::#variant_idents),
+                        )*
+                    }
+                }
+
+                /// Register `handler` for every command on `server`
+                pub async fn register<H: #handler_trait>(
+                    server: &::circle_socket::SocketServer<
+                        ::circle_socket::__private::Value,
+                        ::circle_socket::__private::Value,
+                    >,
+                    handler: H,
+                ) {
+                    let handler = ::std::sync::Arc::new(handler);
+                    #(
+                        let serving = ::std::sync::Arc::clone(&handler);
+                        server
+                            .register_command::<#markers, _>(move |request| serving.#methods(request))
+                            .await;
+                    )*
+                }
+            }
+        };
+    })
+}
+
+fn parse_variant(variant: &syn::Variant) -> syn::Result<Variant> {
+    let (request, has_data) = match &variant.fields {
+        Fields::Unit => (syn::parse_quote!(()), false),
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => (fields.unnamed[0].ty.clone(), true),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "a command variant holds its request data in a single unnamed field, or nothing",
+            ))
+        }
+    };
+
+    let snake = snake_case(&variant.ident.to_string());
+    let mut name = LitStr::new(&snake, variant.ident.span());
+    let mut response: Type = syn::parse_quote!(());
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("command")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = meta.value()?.parse()?;
+                Ok(())
+            } else if meta.path.is_ident("response") {
+                response = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("expected `name` or `response`"))
+            }
+        })?;
+    }
+
+    Ok(Variant {
+        ident: variant.ident.clone(),
+        docs: variant.attrs.iter().filter(|attr| attr.path().is_ident("doc")).cloned().collect(),
+        name,
+        method: Ident::new(&snake, Span::call_site()),
+        request,
+        response,
+        has_data,
+    })
+}
+
+/// `ListProcesses` to `list_processes`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
@@ -0,0 +1,117 @@
+//! The process manager from `socket_example.rs`, with each operation as a typed command.
+//! `define_command!` names the request and response types once for both daemon and client.
+
+use circle_socket::{define_command, SocketClient, SocketConfig, SocketError, SocketResult, SocketServer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StartRequest {
+    pub name: String,
+    pub command: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StopRequest {
+    pub name: String,
+}
+
+define_command! {
+    /// Start a named process
+    StartProcess = "start": StartRequest => String;
+    /// Stop a named process
+    StopProcess = "stop": StopRequest => String;
+    /// List running processes, name -> command
+    ListProcesses = "list": () => HashMap<String, String>;
+}
+
+// Run daemon in background
+async fn run_daemon(socket_path: &PathBuf) -> SocketResult<()> {
+    println!("Starting daemon at {:?}", socket_path);
+
+    let processes = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+    let server = SocketServer::new(SocketConfig::from(socket_path));
+
+    let store = Arc::clone(&processes);
+    server
+        .register_command::<StartProcess, _>(move |req| {
+            let mut processes = store.lock().unwrap();
+            if processes.contains_key(&req.name) {
+                return Err(SocketError::ServerError(format!("Process '{}' already running", req.name)));
+            }
+            println!("[Daemon] Started process: {} -> {}", req.name, req.command);
+            processes.insert(req.name.clone(), req.command);
+            Ok(format!("Process '{}' started", req.name))
+        })
+        .await;
+
+    let store = Arc::clone(&processes);
+    server
+        .register_command::<StopProcess, _>(move |req| match store.lock().unwrap().remove(&req.name) {
+            Some(_) => {
+                println!("[Daemon] Stopped process: {}", req.name);
+                Ok(format!("Process '{}' stopped", req.name))
+            }
+            None => Err(SocketError::ServerError(format!("Process '{}' not found", req.name))),
+        })
+        .await;
+
+    let store = Arc::clone(&processes);
+    server
+        .register_command::<ListProcesses, _>(move |()| Ok(store.lock().unwrap().clone()))
+        .await;
+
+    println!("Daemon ready. Use another terminal to send commands.");
+    server.run().await
+}
+
+fn report(result: SocketResult<String>) {
+    match result {
+        Ok(message) => println!("✓ {}", message),
+        Err(e) => println!("✗ {}", e),
+    }
+}
+
+#[tokio::main]
+async fn main() -> SocketResult<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let socket_path = PathBuf::from("/tmp/circle_commands_example.sock");
+    let client = SocketClient::new(SocketConfig::from(&socket_path));
+
+    match args.first().map(String::as_str) {
+        Some("daemon") => run_daemon(&socket_path).await,
+        Some("start") if args.len() >= 3 => {
+            let req = StartRequest {
+                name: args[1].clone(),
+                command: args[2].clone(),
+            };
+            report(client.call::<StartProcess>(req).await);
+            Ok(())
+        }
+        Some("stop") if args.len() >= 2 => {
+            let req = StopRequest { name: args[1].clone() };
+            report(client.call::<StopProcess>(req).await);
+            Ok(())
+        }
+        Some("list") => {
+            let processes = client.call::<ListProcesses>(()).await?;
+            if processes.is_empty() {
+                println!("  No running processes");
+            }
+            for (name, cmd) in processes {
+                println!("    - {}: {}", name, cmd);
+            }
+            Ok(())
+        }
+        _ => {
+            println!("Usage:");
+            println!("  cargo run --example commands_example --features macros -- daemon");
+            println!("  cargo run --example commands_example --features macros -- start <name> <command>");
+            println!("  cargo run --example commands_example --features macros -- stop <name>");
+            println!("  cargo run --example commands_example --features macros -- list");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! A key-value store whose commands are the variants of one enum.
+//! `#[derive(Commands)]` generates the handler trait the daemon implements and
+//! the dispatch the client sends through, so both agree on every command.
+
+use circle_socket::{Commands, SocketClient, SocketConfig, SocketError, SocketResult, SocketServer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SetRequest {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Commands)]
+enum StoreCommand {
+    /// Store a value under a key, returning the previous one
+    #[command(response = Option<String>)]
+    Set(SetRequest),
+    /// Look up the value stored under a key
+    #[command(name = "get", response = String)]
+    Get(String),
+}
+
+#[derive(Default)]
+struct Store(Mutex<HashMap<String, String>>);
+
+impl StoreCommandHandler for Store {
+    fn set(&self, request: SetRequest) -> SocketResult<Option<String>> {
+        println!("[Daemon] {} = {}", request.key, request.value);
+        Ok(self.0.lock().unwrap().insert(request.key, request.value))
+    }
+
+    fn get(&self, key: String) -> SocketResult<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| SocketError::ServerError(format!("No value for '{}'", key)))
+    }
+}
+
+// Run daemon in background
+async fn run_daemon(socket_path: &PathBuf) -> SocketResult<()> {
+    println!("Starting daemon at {:?}", socket_path);
+
+    let server = SocketServer::new(SocketConfig::from(socket_path));
+    StoreCommand::register(&server, Store::default()).await;
+
+    println!("Daemon ready. Serving: {}", StoreCommand::COMMAND_NAMES.join(", "));
+    server.run().await
+}
+
+#[tokio::main]
+async fn main() -> SocketResult<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let socket_path = PathBuf::from("/tmp/circle_derive_commands_example.sock");
+    let client = SocketClient::new(SocketConfig::from(&socket_path));
+
+    let command = match args.first().map(String::as_str) {
+        Some("daemon") => return run_daemon(&socket_path).await,
+        Some("set") if args.len() >= 3 => StoreCommand::Set(SetRequest {
+            key: args[1].clone(),
+            value: args[2].clone(),
+        }),
+        Some("get") if args.len() >= 2 => StoreCommand::Get(args[1].clone()),
+        _ => {
+            println!("Usage:");
+            println!("  cargo run --example derive_commands_example --features macros -- daemon");
+            println!("  cargo run --example derive_commands_example --features macros -- set <key> <value>");
+            println!("  cargo run --example derive_commands_example --features macros -- get <key>");
+            return Ok(());
+        }
+    };
+
+    match command.send(&client).await {
+        Ok(StoreCommandResponse::Set(Some(previous))) => println!("✓ Replaced '{}'", previous),
+        Ok(StoreCommandResponse::Set(None)) => println!("✓ Stored"),
+        Ok(StoreCommandResponse::Get(value)) => println!("✓ {}", value),
+        Err(e) => println!("✗ {}", e),
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 //! Simple example demonstrating circle-socket for CLI background process management
 //! Shows the start/stop pattern for managing long-running commands
 
-use circle_socket::{SocketClient, SocketConfig, SocketPayload, SocketResponse, SocketResult};
+use circle_socket::{JsonEnvelope, SocketClient, SocketConfig, SocketResult};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -96,20 +96,16 @@ async fn run_daemon(socket_path: &PathBuf) -> SocketResult<()> {
     let store = Arc::new(ProcessStore::new());
     let config = SocketConfig::from(socket_path);
 
-    // Use string payloads for simplicity
-    let server = circle_socket::SocketServer::<String, String>::new(config.clone());
+    // Requests and responses travel as JSON strings; JsonEnvelope does the nested encoding
+    let server = circle_socket::SocketServer::<JsonEnvelope<ProcessRequest>, JsonEnvelope<ProcessResponse>>::new(
+        config.clone(),
+    );
 
     // Register handler for all requests
     let store_clone = Arc::clone(&store);
-    server.register_handler("request", move |payload| {
-        if let Ok(req) = serde_json::from_str::<ProcessRequest>(&payload.data) {
-            let response = store_clone.handle_request(req);
-            let response_str = serde_json::to_string(&response).unwrap();
-            Ok(SocketResponse::success(payload.request_id, response_str))
-        } else {
-            Ok(SocketResponse::error(payload.request_id, "Invalid request format"))
-        }
-    }).await;
+    server
+        .register_envelope_handler("request", move |req| Ok(store_clone.handle_request(req)))
+        .await;
 
     println!("Daemon ready. Use another terminal to send commands.");
     server.run().await
@@ -125,29 +121,22 @@ async fn send_command(socket_path: &PathBuf, command: &str, name: &str, payload:
         payload: payload.to_string(),
     };
 
-    let payload = SocketPayload::new("request", serde_json::to_string(&req)?);
-    let response = client.send_request::<String, String>(payload).await?;
-
-    if response.success {
-        if let Ok(resp) = serde_json::from_str::<ProcessResponse>(&response.data.unwrap()) {
-            if resp.success {
-                println!("✓ {}", resp.message);
-                if let Some(processes) = resp.processes {
-                    if processes.is_empty() {
-                        println!("  No running processes");
-                    } else {
-                        println!("  Running processes:");
-                        for (name, cmd) in processes {
-                            println!("    - {}: {}", name, cmd);
-                        }
+    match client.send_envelope::<_, ProcessResponse>("request", req).await {
+        Ok(resp) if resp.success => {
+            println!("✓ {}", resp.message);
+            if let Some(processes) = resp.processes {
+                if processes.is_empty() {
+                    println!("  No running processes");
+                } else {
+                    println!("  Running processes:");
+                    for (name, cmd) in processes {
+                        println!("    - {}: {}", name, cmd);
                     }
                 }
-            } else {
-                println!("✗ {}", resp.message);
             }
         }
-    } else {
-        println!("✗ Error: {}", response.error.unwrap());
+        Ok(resp) => println!("✗ {}", resp.message),
+        Err(e) => println!("✗ Error: {}", e),
     }
 
     Ok(())
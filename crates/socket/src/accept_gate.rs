@@ -0,0 +1,89 @@
+//! Pausing and resuming the accept loops, and capping open connections.
+//!
+//! While paused the listeners stay bound but nothing is accepted, so new
+//! clients wait in the listen backlog and are served in turn on resume.
+//! With `max_connections` set, a connection holds a slot until its task
+//! finishes; when none is free, the server either stops accepting until
+//! one is, leaving clients in the backlog the same way, or accepts and
+//! refuses the connection, as [`ConnectionLimitPolicy`] says.
+
+use crate::transport::Listener;
+use crate::{ConnectionLimitPolicy, SocketConfig, TransportStream};
+use std::sync::Arc;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+/// Whether the server accepts connections, shared with [`ServerHandle`](crate::ServerHandle)s
+#[derive(Clone)]
+pub(crate) struct AcceptGate {
+    paused: Arc<watch::Sender<bool>>,
+    /// One permit per connection `max_connections` allows
+    slots: Option<Arc<Semaphore>>,
+    policy: ConnectionLimitPolicy,
+}
+
+/// What becomes of a connection just accepted
+pub(crate) enum Admission {
+    /// Serve it, holding its slot, if connections are capped, until dropped
+    Admitted(Option<OwnedSemaphorePermit>),
+    /// Every slot is taken and the policy is to refuse
+    OverLimit,
+}
+
+impl AcceptGate {
+    pub(crate) fn new(config: &SocketConfig) -> Self {
+        Self {
+            paused: Arc::new(watch::Sender::new(false)),
+            slots: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            policy: config.connection_limit_policy,
+        }
+    }
+
+    /// Stop accepting, including in an `accept` already waiting
+    pub(crate) fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("Paused accepting connections");
+        }
+    }
+
+    /// Accept connections again, starting with those that queued meanwhile
+    pub(crate) fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("Resumed accepting connections");
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Accept the next connection on `listener` while not paused and, under
+    /// [`ConnectionLimitPolicy::Wait`], once a slot is free
+    pub(crate) async fn accept(&self, listener: &mut Listener) -> std::io::Result<(TransportStream, Admission)> {
+        let mut paused = self.paused.subscribe();
+        loop {
+            // The sender lives as long as `self`, so waiting can't fail
+            let _ = paused.wait_for(|paused| !paused).await;
+            let slot = match &self.slots {
+                Some(slots) if self.policy == ConnectionLimitPolicy::Wait => {
+                    Some(Arc::clone(slots).acquire_owned().await.expect("the semaphore is never closed"))
+                }
+                _ => None,
+            };
+            tokio::select! {
+                accepted = listener.accept() => return Ok((accepted?, self.admit(slot))),
+                _ = paused.wait_for(|paused| *paused) => {}
+            }
+        }
+    }
+
+    fn admit(&self, slot: Option<OwnedSemaphorePermit>) -> Admission {
+        match (&self.slots, slot) {
+            (Some(slots), None) => match Arc::clone(slots).try_acquire_owned() {
+                Ok(slot) => Admission::Admitted(Some(slot)),
+                Err(_) => Admission::OverLimit,
+            },
+            (_, slot) => Admission::Admitted(slot),
+        }
+    }
+}
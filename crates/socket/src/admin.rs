@@ -0,0 +1,170 @@
+//! Built-in administrative commands answered by the server itself.
+//!
+//! Admin commands are recognised from the request header alone, before the
+//! request data is parsed as the server's payload type, so they work with
+//! any `T`. Their response data is plain JSON rather than the server's `R`.
+
+use crate::codec::{self, DecodeError};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// Lists requests whose handlers are currently executing
+pub const INFLIGHT_COMMAND: &str = "__inflight";
+
+/// Returns a [`ServerSnapshot`](crate::ServerSnapshot) of the whole server
+pub const SNAPSHOT_COMMAND: &str = "__snapshot";
+
+/// Returns the server's statistics as a string in the Prometheus text
+/// exposition format
+pub const METRICS_COMMAND: &str = "__metrics";
+
+/// Answers `"pong"`, even while the server is warming up
+pub const PING_COMMAND: &str = "__ping";
+
+/// Answers `{"ready": bool}`: whether the server's warm-up period is over
+pub const READY_COMMAND: &str = "__ready";
+
+/// Answers `{"uptime_secs": u64, "commands": usize}`, the seconds since the
+/// server started and how many commands have a handler, unless
+/// [`SocketConfig::health_command`](crate::SocketConfig::health_command) is off
+pub const HEALTH_COMMAND: &str = "__health";
+
+/// Subscribes the connection to events from
+/// [`SocketServer::broadcast`](crate::SocketServer::broadcast), or with a
+/// topic, to what is published to it; see
+/// [`SocketClient::subscribe`](crate::SocketClient::subscribe)
+pub const SUBSCRIBE_COMMAND: &str = "__subscribe";
+
+/// Stops a subscribed connection receiving events published to a topic
+pub const UNSUBSCRIBE_COMMAND: &str = "__unsubscribe";
+
+/// The routing fields of a request, readable without knowing its data type
+pub(crate) struct RequestHeader {
+    pub(crate) request_id: String,
+    /// Empty when the command was longer than the limit it was read with
+    pub(crate) command: String,
+    /// Length of the command, when it was longer than the limit
+    pub(crate) command_too_long: Option<usize>,
+    pub(crate) close_after: bool,
+    pub(crate) accept_codec: Option<String>,
+    pub(crate) ack: bool,
+}
+
+impl RequestHeader {
+    /// Read the header of a request. A command longer than `max_command_len`
+    /// bytes is measured but never copied out of the message.
+    pub(crate) fn decode(message: &[u8], max_command_len: usize) -> Result<Self, DecodeError> {
+        codec::decode_seed(message, HeaderSeed { max_command_len })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum HeaderField {
+    RequestId,
+    Command,
+    CloseAfter,
+    AcceptCodec,
+    Ack,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Clone, Copy)]
+struct HeaderSeed {
+    max_command_len: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for HeaderSeed {
+    type Value = RequestHeader;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<RequestHeader, D::Error> {
+        const FIELDS: &[&str] = &["request_id", "command", "close_after", "accept_codec", "ack"];
+        deserializer.deserialize_struct("RequestHeader", FIELDS, self)
+    }
+}
+
+impl<'de> Visitor<'de> for HeaderSeed {
+    type Value = RequestHeader;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a request")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RequestHeader, A::Error> {
+        let mut request_id = None;
+        let mut command = None;
+        let mut close_after = None;
+        let mut accept_codec = None;
+        let mut ack = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                HeaderField::RequestId if request_id.is_some() => return Err(de::Error::duplicate_field("request_id")),
+                HeaderField::RequestId => request_id = Some(map.next_value()?),
+                HeaderField::Command if command.is_some() => return Err(de::Error::duplicate_field("command")),
+                HeaderField::Command => command = Some(map.next_value_seed(BoundedCommand(self.max_command_len))?),
+                HeaderField::CloseAfter => close_after = Some(map.next_value()?),
+                HeaderField::AcceptCodec => accept_codec = map.next_value()?,
+                HeaderField::Ack => ack = Some(map.next_value()?),
+                HeaderField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let command = command.ok_or_else(|| de::Error::missing_field("command"))?;
+        Ok(RequestHeader {
+            request_id: request_id.ok_or_else(|| de::Error::missing_field("request_id"))?,
+            command_too_long: command.as_ref().err().copied(),
+            command: command.unwrap_or_default(),
+            close_after: close_after.unwrap_or_default(),
+            accept_codec,
+            ack: ack.unwrap_or_default(),
+        })
+    }
+}
+
+/// A command, or its length when it is longer than the limit
+struct BoundedCommand(usize);
+
+impl<'de> DeserializeSeed<'de> for BoundedCommand {
+    type Value = Result<String, usize>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl Visitor<'_> for BoundedCommand {
+    type Value = Result<String, usize>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a command name")
+    }
+
+    fn visit_str<E: de::Error>(self, command: &str) -> Result<Self::Value, E> {
+        if command.len() > self.0 {
+            return Ok(Err(command.len()));
+        }
+        Ok(Ok(command.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_stops_at_long_command() {
+        let message = br#"{"request_id":"1","command":"build","data":{"nested":[1,2]},"ack":true}"#;
+        let header = RequestHeader::decode(message, 16).unwrap();
+        assert_eq!((header.request_id.as_str(), header.command.as_str()), ("1", "build"));
+        assert!(header.ack && !header.close_after && header.command_too_long.is_none());
+
+        let header = RequestHeader::decode(message, 4).unwrap();
+        assert_eq!((header.command.as_str(), header.command_too_long), ("", Some(5)));
+
+        assert!(RequestHeader::decode(br#"{"request_id":"1"}"#, 16).is_err());
+        assert!(RequestHeader::decode(br#"{"request_id":"1","command":"a","command":"b"}"#, 16).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Configuring a server and registering its handlers in one expression.
+//!
+//! [`SocketServer::new`] followed by `register_handler` awaits suits
+//! servers whose commands change at runtime. Servers with a fixed set of
+//! commands can instead chain everything onto a [`SocketServerBuilder`],
+//! whose `build` is synchronous, so no runtime is needed until `run`.
+
+use crate::{
+    AsyncHandler, CommandHandler, Middleware, SocketConfig, SocketPayload, SocketResponse, SocketResult, SocketServer,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Builds a [`SocketServer`] from chained settings and handlers, starting
+/// from [`SocketServer::builder`]
+pub struct SocketServerBuilder<T, R> {
+    config: SocketConfig,
+    handlers: Vec<(String, CommandHandler<T, R>)>,
+    middleware: Vec<Middleware<T, R>>,
+}
+
+impl<T, R> SocketServerBuilder<T, R>
+where
+    T: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    R: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+{
+    /// Start from the default configuration
+    pub fn new() -> Self {
+        Self::with_config(SocketConfig::default())
+    }
+
+    /// Start from `config`, for settings without a method of their own
+    pub fn with_config(config: SocketConfig) -> Self {
+        Self {
+            config,
+            handlers: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Listen at `path`
+    pub fn socket_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.socket_path = path.into();
+        self
+    }
+
+    /// Timeout for connections, in seconds
+    pub fn timeout(mut self, seconds: u64) -> Self {
+        self.config.timeout = seconds;
+        self
+    }
+
+    /// Permission bits for the socket file; see [`SocketConfig::socket_mode`]
+    pub fn socket_mode(mut self, mode: u32) -> Self {
+        self.config.socket_mode = Some(mode);
+        self
+    }
+
+    /// Handle `command` with `handler`, as with [`SocketServer::register_handler`]
+    pub fn handler<F>(mut self, command: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        let handler = CommandHandler::Inline(Arc::new(move |payload, _| handler(payload)));
+        self.handlers.push((command.into(), handler));
+        self
+    }
+
+    /// Handle `command` on the blocking thread pool, as with
+    /// [`SocketServer::register_blocking_handler`]
+    pub fn blocking_handler<F>(mut self, command: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        let handler = CommandHandler::Blocking(Arc::new(move |payload, _| handler(payload)));
+        self.handlers.push((command.into(), handler));
+        self
+    }
+
+    /// Handle `command` with a future, as with [`SocketServer::register_async_handler`]
+    pub fn async_handler<F, Fut>(mut self, command: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(SocketPayload<T, R>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = SocketResult<SocketResponse<R>>> + Send + 'static,
+    {
+        let handler: AsyncHandler<T, R> = Arc::new(move |payload, _| Box::pin(handler(payload)));
+        self.handlers.push((command.into(), CommandHandler::Async(handler)));
+        self
+    }
+
+    /// Run `middleware` before every handler, as with [`SocketServer::add_middleware`]
+    pub fn middleware<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&SocketPayload<T, R>) -> SocketResult<()> + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(move |payload, _| middleware(payload)));
+        self
+    }
+
+    /// Create the server with everything registered. A handler for a
+    /// command registered twice replaces the earlier one.
+    pub fn build(self) -> SocketServer<T, R> {
+        let mut server = SocketServer::new(self.config);
+        let state = Arc::get_mut(&mut server.state).expect("a new server's state is not shared yet");
+        state.handlers.get_mut().extend(self.handlers);
+        state.middleware.get_mut().extend(self.middleware);
+        server
+    }
+}
+
+impl<T, R> Default for SocketServerBuilder<T, R>
+where
+    T: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    R: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,278 @@
+//! Wire encodings for messages.
+//!
+//! `SocketConfig::codec` picks the encoding a client writes its requests in
+//! and a server answers in by default. A request can also name the codec it
+//! wants its response in with `SocketPayload::accept_codec`, much like an
+//! HTTP `Accept` header; names the server doesn't support fall back to its
+//! default, so asking is always safe. Each codec's output is recognisable
+//! from its first bytes, the same way compressed bodies are, so readers
+//! accept either whatever they are configured with.
+//!
+//! Messages are deserialized straight from the codec they arrived in with
+//! [`decode`], so MessagePack is never transcoded through JSON on the way.
+
+use crate::{SocketError, SocketResult};
+use serde::de::{DeserializeOwned, DeserializeSeed};
+use serde::Serialize;
+use tracing::debug;
+
+/// Wire encoding of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// A JSON document
+    #[default]
+    Json,
+    /// A MessagePack map with named fields
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    /// The codec a request names with `accept_codec`, if supported
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// The name requests use for this codec
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "msgpack",
+        }
+    }
+
+    /// Codec for the response to a request that accepts `accept`, given the
+    /// server's configured codec
+    pub(crate) fn negotiate(accept: Option<&str>, configured: Self) -> Self {
+        let Some(name) = accept else {
+            return configured;
+        };
+        Self::from_name(name).unwrap_or_else(|| {
+            debug!("Codec {:?} is not supported, answering in {}", name, configured.name());
+            configured
+        })
+    }
+
+    /// Serialize `value` in this codec
+    pub(crate) fn encode<S: Serialize>(self, value: &S) -> SocketResult<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(invalid_data),
+        }
+    }
+
+    /// Re-encode a message serialized as JSON, i.e. a signed request
+    #[cfg(feature = "signing")]
+    pub(crate) fn reencode_json(self, json: Vec<u8>) -> SocketResult<Vec<u8>> {
+        match self {
+            Self::Json => Ok(json),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => self.encode(&serde_json::from_slice::<serde_json::Value>(&json)?),
+        }
+    }
+}
+
+/// Why a message couldn't be deserialized, in the codec it arrived in
+#[derive(Debug)]
+pub(crate) enum DecodeError {
+    Json(serde_json::Error),
+    #[cfg(feature = "msgpack")]
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json(e) => e.fmt(f),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<DecodeError> for SocketError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Json(e) => e.into(),
+            #[cfg(feature = "msgpack")]
+            DecodeError::MessagePack(e) => invalid_data(e),
+        }
+    }
+}
+
+/// Deserialize a message in whichever supported codec it was encoded in
+pub(crate) fn decode<D: DeserializeOwned>(message: &[u8]) -> Result<D, DecodeError> {
+    #[cfg(feature = "msgpack")]
+    if is_msgpack(message) {
+        return rmp_serde::from_slice(message).map_err(DecodeError::MessagePack);
+    }
+    serde_json::from_slice(message).map_err(DecodeError::Json)
+}
+
+/// Deserialize a message with `seed`, in whichever supported codec it was
+/// encoded in
+pub(crate) fn decode_seed<'de, S: DeserializeSeed<'de>>(message: &'de [u8], seed: S) -> Result<S::Value, DecodeError> {
+    #[cfg(feature = "msgpack")]
+    if is_msgpack(message) {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(message);
+        return seed.deserialize(&mut deserializer).map_err(DecodeError::MessagePack);
+    }
+    let mut deserializer = serde_json::Deserializer::from_slice(message);
+    let value = seed.deserialize(&mut deserializer).map_err(DecodeError::Json)?;
+    deserializer.end().map_err(DecodeError::Json)?;
+    Ok(value)
+}
+
+/// Whether `message` is a JSON or MessagePack array, i.e. a batch of requests
+pub(crate) fn is_array(message: &[u8]) -> bool {
+    #[cfg(feature = "msgpack")]
+    if matches!(message.first(), Some(0x90..=0x9f | 0xdc | 0xdd)) {
+        return true;
+    }
+    message.trim_ascii_start().starts_with(b"[")
+}
+
+/// Whether `bytes` starts a MessagePack map or array, which no JSON document
+/// does: their markers are all above ASCII
+#[cfg(feature = "msgpack")]
+pub(crate) fn is_msgpack(bytes: &[u8]) -> bool {
+    matches!(bytes.first(), Some(0x80..=0x9f | 0xdc..=0xdf))
+}
+
+/// Length of the first complete MessagePack message in `buf`, if it has all arrived
+#[cfg(feature = "msgpack")]
+pub(crate) fn msgpack_len(buf: &[u8]) -> SocketResult<Option<usize>> {
+    use rmp_serde::decode::Error;
+    use serde::Deserialize;
+
+    let mut rest = buf;
+    match serde::de::IgnoredAny::deserialize(&mut rmp_serde::Deserializer::new(&mut rest)) {
+        Ok(_) => Ok(Some(buf.len() - rest.len())),
+        Err(Error::InvalidMarkerRead(e) | Error::InvalidDataRead(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Ok(None)
+        }
+        Err(e) => Err(invalid_data(e)),
+    }
+}
+
+/// How far the MessagePack message at the start of a buffer has been
+/// walked, so each read only looks at the bytes it added
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default)]
+pub(crate) struct MsgpackScan {
+    /// Bytes of the buffer already walked
+    pos: usize,
+    /// Values still to come before the message is complete, once started
+    pending: Option<u64>,
+}
+
+#[cfg(feature = "msgpack")]
+impl MsgpackScan {
+    /// Length of the message at the start of `buf`, once all of it has
+    /// arrived. `buf` must be the same buffer as on earlier calls, with
+    /// bytes only added to its end.
+    pub(crate) fn advance(&mut self, buf: &[u8]) -> SocketResult<Option<usize>> {
+        let pending = self.pending.get_or_insert(1);
+        while *pending > 0 {
+            let Some((len, nested)) = value_header(&buf[self.pos..])? else {
+                return Ok(None);
+            };
+            if ((buf.len() - self.pos) as u64) < len {
+                return Ok(None);
+            }
+            self.pos += len as usize;
+            *pending += nested;
+            *pending -= 1;
+        }
+        Ok(Some(self.pos))
+    }
+}
+
+/// Size of the MessagePack value at the start of `bytes`, leaving out the
+/// values nested in it, and how many values are nested in it. `None` until
+/// its size has arrived.
+#[cfg(feature = "msgpack")]
+fn value_header(bytes: &[u8]) -> SocketResult<Option<(u64, u64)>> {
+    let Some(&marker) = bytes.first() else {
+        return Ok(None);
+    };
+    // The big-endian size field of `n` bytes after the marker
+    let size = |n: usize| bytes.get(1..1 + n).map(|field| field.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)));
+    let header = match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => Some((1, 0)),
+        0x80..=0x8f => Some((1, 2 * u64::from(marker & 0x0f))),
+        0x90..=0x9f => Some((1, u64::from(marker & 0x0f))),
+        0xa0..=0xbf => Some((1 + u64::from(marker & 0x1f), 0)),
+        0xc4 | 0xd9 => size(1).map(|len| (2 + len, 0)),
+        0xc5 | 0xda => size(2).map(|len| (3 + len, 0)),
+        0xc6 | 0xdb => size(4).map(|len| (5 + len, 0)),
+        0xc7 => size(1).map(|len| (3 + len, 0)),
+        0xc8 => size(2).map(|len| (4 + len, 0)),
+        0xc9 => size(4).map(|len| (6 + len, 0)),
+        0xcc | 0xd0 => Some((2, 0)),
+        0xcd | 0xd1 => Some((3, 0)),
+        0xca | 0xce | 0xd2 => Some((5, 0)),
+        0xcb | 0xcf | 0xd3 => Some((9, 0)),
+        0xd4 => Some((3, 0)),
+        0xd5 => Some((4, 0)),
+        0xd6 => Some((6, 0)),
+        0xd7 => Some((10, 0)),
+        0xd8 => Some((18, 0)),
+        0xdc => size(2).map(|count| (3, count)),
+        0xdd => size(4).map(|count| (5, count)),
+        0xde => size(2).map(|count| (3, 2 * count)),
+        0xdf => size(4).map(|count| (5, 2 * count)),
+        0xc1 => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid MessagePack marker 0xc1").into())
+        }
+    };
+    Ok(header)
+}
+
+#[cfg(feature = "msgpack")]
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> crate::SocketError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_falls_back_to_default() {
+        assert_eq!(Codec::negotiate(None, Codec::Json), Codec::Json);
+        assert_eq!(Codec::negotiate(Some("json"), Codec::Json), Codec::Json);
+        assert_eq!(Codec::negotiate(Some("bincode"), Codec::Json), Codec::Json);
+        assert_eq!(Codec::from_name(Codec::Json.name()), Some(Codec::Json));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_reads_as_json() {
+        let value = serde_json::json!({"request_id": "1", "command": "status", "data": [1, 2.5, null]});
+        let packed = Codec::MessagePack.encode(&value).unwrap();
+        assert!(is_msgpack(&packed));
+        assert!(!is_msgpack(&serde_json::to_vec(&value).unwrap()));
+        assert_eq!(decode::<serde_json::Value>(&packed).unwrap(), value);
+        assert!(!is_array(&packed));
+        assert!(is_array(&Codec::MessagePack.encode(&[&value]).unwrap()));
+
+        assert_eq!(msgpack_len(&packed).unwrap(), Some(packed.len()));
+        assert_eq!(msgpack_len(&packed[..packed.len() - 1]).unwrap(), None);
+        let mut scan = MsgpackScan::default();
+        for end in 0..packed.len() {
+            assert_eq!(scan.advance(&packed[..end]).unwrap(), None);
+        }
+        assert_eq!(scan.advance(&packed).unwrap(), Some(packed.len()));
+        assert_eq!(Codec::negotiate(None, Codec::MessagePack), Codec::MessagePack);
+        assert_eq!(Codec::negotiate(Some("json"), Codec::MessagePack), Codec::Json);
+    }
+}
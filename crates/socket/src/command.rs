@@ -0,0 +1,49 @@
+//! Typed commands pairing a command name with its request and response types.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A command with fixed request and response types.
+///
+/// Implemented on a marker type, usually with [`define_command!`](crate::define_command),
+/// so the client and server name the command and its types in one place.
+/// Send it with [`SocketClient::call`](crate::SocketClient::call) and serve it
+/// with [`SocketServer::register_command`](crate::SocketServer::register_command).
+pub trait Command {
+    /// The command name on the wire
+    const NAME: &'static str;
+    /// Data sent with the request
+    type Request: Serialize + DeserializeOwned + Send + 'static;
+    /// Data returned on success
+    type Response: Serialize + DeserializeOwned + std::fmt::Debug + Send + 'static;
+}
+
+/// Define [`Command`] marker types from a name, request type and response type.
+///
+/// ```ignore
+/// define_command! {
+///     /// Start a named process
+///     pub StartProcess = "start": StartRequest => StartResponse;
+///     pub ListProcesses = "list": () => Vec<ProcessInfo>;
+/// }
+///
+/// server.register_command::<StartProcess, _>(|request| start(request)).await;
+/// let started = client.call::<StartProcess>(request).await?;
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! define_command {
+    ($($(#[$meta:meta])* $vis:vis $name:ident = $command:literal : $request:ty => $response:ty;)*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, Copy)]
+            $vis struct $name;
+
+            impl $crate::Command for $name {
+                const NAME: &'static str = $command;
+                type Request = $request;
+                type Response = $response;
+            }
+        )*
+    };
+}
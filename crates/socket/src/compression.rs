@@ -0,0 +1,286 @@
+//! Payload compression helpers.
+//!
+//! Compressed bodies are recognised by their magic bytes, which can never
+//! start a JSON document, so compressed and plain bodies can share a stream.
+//! The magic bytes serve as the flag saying whether a body is compressed.
+//! Clients decompress whatever response arrives compressed, while servers
+//! only accept compressed requests if they configure compression
+//! themselves. Either way a body is inflated no further than
+//! `max_message_size`, so a small compressed body can't exhaust memory.
+
+use crate::framing::check_size;
+use crate::{SocketConfig, SocketError, SocketResult};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// Magic bytes at the start of every gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic bytes at the start of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How a peer compresses the messages it sends, set with
+/// [`SocketConfig::compression`](crate::SocketConfig::compression).
+///
+/// Bodies are compressed after serialization and before framing. With
+/// [`Framing::Json`](crate::Framing::Json) a compressed body runs to the end
+/// of the stream, so only a connection's last message is compressed: the
+/// request of a one-shot exchange, which the client follows by half-closing,
+/// and the response the server closes the connection after. Length-prefixed
+/// framing delimits compressed bodies like any other, so every message is
+/// compressed. Lines of [`Framing::NdJson`](crate::Framing::NdJson) are
+/// text, so none are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum Compression {
+    /// Send bodies as they are
+    #[default]
+    None,
+    /// Gzip bodies
+    Gzip,
+    /// Zstd-compress bodies, without a dictionary
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Compress `data`, or return `None` if this is [`Compression::None`]
+    pub(crate) fn compress(self, data: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Gzip => gzip(data).map(Some),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(data, 0).map(Some),
+        }
+    }
+}
+
+/// A zstd dictionary trained offline on representative messages.
+///
+/// Small messages with a lot of shared structure compress far better
+/// against a dictionary than on their own. Client and server must be
+/// configured with the same dictionary; they compare IDs in the handshake.
+#[cfg(feature = "zstd")]
+#[derive(Clone)]
+pub struct CompressionDictionary {
+    bytes: std::sync::Arc<Vec<u8>>,
+    id: String,
+}
+
+#[cfg(feature = "zstd")]
+impl CompressionDictionary {
+    /// Wrap the raw bytes of a trained dictionary, e.g. from `zstd --train`
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        let bytes = bytes.into();
+        let id = format!("{:016x}", fnv1a(&bytes));
+        Self {
+            bytes: std::sync::Arc::new(bytes),
+            id,
+        }
+    }
+
+    /// Fingerprint of the dictionary contents, exchanged in the handshake
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl std::fmt::Debug for CompressionDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompressionDictionary").field("id", &self.id).finish()
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds so peers agree on dictionary IDs
+#[cfg(feature = "zstd")]
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// ID of the compression dictionary in `config`, if one is configured
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn dictionary_id(config: &SocketConfig) -> Option<&str> {
+    #[cfg(feature = "zstd")]
+    return config.compression_dictionary.as_ref().map(CompressionDictionary::id);
+    #[cfg(not(feature = "zstd"))]
+    None
+}
+
+/// Gzip `data` with the default compression level
+pub(crate) fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Zstd-compress `data` against `dictionary`
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_compress(data: &[u8], dictionary: &CompressionDictionary) -> std::io::Result<Vec<u8>> {
+    zstd::bulk::Compressor::with_dictionary(0, &dictionary.bytes)?.compress(data)
+}
+
+/// Whether `data` starts a gzip or zstd body
+pub(crate) fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC) || data.starts_with(&ZSTD_MAGIC)
+}
+
+/// Decompresses bodies as they are read, with the dictionary a peer is
+/// configured with
+#[derive(Clone)]
+pub(crate) struct Decompressor {
+    /// Whether compressed bodies are accepted at all
+    accept: bool,
+    /// Size a body may inflate to before it is refused
+    max_message_size: usize,
+    #[cfg(feature = "zstd")]
+    dictionary: Option<CompressionDictionary>,
+}
+
+impl Decompressor {
+    /// Decompress responses, accepting any the server sends compressed
+    pub(crate) fn new(config: &SocketConfig) -> Self {
+        Self::with_accept(config, true)
+    }
+
+    /// Decompress requests, accepting compressed ones only if `config` sets
+    /// a compression or a dictionary
+    pub(crate) fn for_requests(config: &SocketConfig) -> Self {
+        let accept = config.compression != Compression::None || dictionary_id(config).is_some();
+        Self::with_accept(config, accept)
+    }
+
+    fn with_accept(config: &SocketConfig, accept: bool) -> Self {
+        Self {
+            accept,
+            max_message_size: config.max_message_size,
+            #[cfg(feature = "zstd")]
+            dictionary: config.compression_dictionary.clone(),
+        }
+    }
+
+    /// Decompress `data` if it is gzip or zstd, otherwise return it untouched.
+    ///
+    /// Zstd bodies are decoded with the configured dictionary, if any.
+    /// Decoding stops as soon as the body grows past `max_message_size`,
+    /// returning [`SocketError::MessageTooLarge`].
+    pub(crate) fn decompress<'a>(&self, data: &'a [u8]) -> SocketResult<Cow<'a, [u8]>> {
+        if !is_compressed(data) {
+            return Ok(Cow::Borrowed(data));
+        }
+        if !self.accept {
+            return Err(SocketError::InvalidRequest(
+                "compressed body, but compression is not enabled on this server".into(),
+            ));
+        }
+
+        // Read one byte past the limit, to tell a body at the limit from one over it
+        let limit = self.max_message_size as u64 + 1;
+        let mut decoded = Vec::new();
+        if data.starts_with(&GZIP_MAGIC) {
+            flate2::read::GzDecoder::new(data).take(limit).read_to_end(&mut decoded)?;
+        } else {
+            self.decompress_zstd(data, limit, &mut decoded)?;
+        }
+        check_size(decoded.len(), self.max_message_size)?;
+        Ok(Cow::Owned(decoded))
+    }
+
+    #[cfg(feature = "zstd")]
+    fn decompress_zstd(&self, data: &[u8], limit: u64, decoded: &mut Vec<u8>) -> std::io::Result<()> {
+        match &self.dictionary {
+            Some(dictionary) => zstd::stream::read::Decoder::with_dictionary(data, &dictionary.bytes)?
+                .take(limit)
+                .read_to_end(decoded)?,
+            None => zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(decoded)?,
+        };
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn decompress_zstd(&self, _data: &[u8], _limit: u64, _decoded: &mut Vec<u8>) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "received a zstd-compressed body but the zstd feature is not enabled",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let body = br#"{"request_id":"1","success":true}"#.repeat(50);
+        let compressed = gzip(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        let decompressor = Decompressor::new(&SocketConfig::default());
+        assert_eq!(decompressor.decompress(&compressed).unwrap().as_ref(), body.as_slice());
+    }
+
+    #[test]
+    fn test_decompression_stops_at_limit() {
+        let config = SocketConfig {
+            compression: Compression::Gzip,
+            max_message_size: 1024,
+            ..SocketConfig::default()
+        };
+        let bomb = gzip(&vec![b' '; 64 * 1024 * 1024]).unwrap();
+        let result = Decompressor::for_requests(&config).decompress(&bomb);
+        assert!(matches!(result, Err(SocketError::MessageTooLarge { size: 1025, limit: 1024 })));
+    }
+
+    #[test]
+    fn test_compressed_requests_need_compression_configured() {
+        let compressed = gzip(br#"{"request_id":"1"}"#).unwrap();
+        let result = Decompressor::for_requests(&SocketConfig::default()).decompress(&compressed);
+        assert!(matches!(result, Err(SocketError::InvalidRequest(_))));
+        let config = SocketConfig {
+            compression: Compression::Gzip,
+            ..SocketConfig::default()
+        };
+        assert!(Decompressor::for_requests(&config).decompress(&compressed).is_ok());
+    }
+
+    #[test]
+    fn test_plain_body_passes_through() {
+        let body = br#"{"request_id":"1"}"#;
+        let decompressor = Decompressor::for_requests(&SocketConfig::default());
+        assert!(matches!(decompressor.decompress(body).unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_dictionary_round_trip() {
+        let dictionary = CompressionDictionary::new(
+            br#"{"request_id":"","success":true,"data":{"processes":[{"name":"","pid":0,"status":"running"}]},"error":null}"#
+                .to_vec(),
+        );
+        let body = br#"{"request_id":"42","success":true,"data":{"processes":[{"name":"web","pid":7,"status":"running"}]},"error":null}"#;
+        let compressed = zstd_compress(body, &dictionary).unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+
+        let config = SocketConfig {
+            compression_dictionary: Some(dictionary),
+            ..SocketConfig::default()
+        };
+        assert_eq!(Decompressor::new(&config).decompress(&compressed).unwrap().as_ref(), body.as_slice());
+        assert!(Decompressor::new(&SocketConfig::default()).decompress(&compressed).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression_round_trip() {
+        let body = br#"{"name":"web","pid":7,"status":"running"}"#.repeat(100);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&body).unwrap().unwrap();
+            assert!(is_compressed(&compressed));
+            assert!(compressed.len() < body.len() / 10);
+            let decompressor = Decompressor::new(&SocketConfig::default());
+            assert_eq!(decompressor.decompress(&compressed).unwrap().as_ref(), body.as_slice());
+        }
+        assert!(Compression::None.compress(&body).unwrap().is_none());
+    }
+}
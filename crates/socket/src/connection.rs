@@ -0,0 +1,153 @@
+//! A client connection that carries any number of requests.
+//!
+//! The connection is opened with a multiplexing handshake, so the server
+//! runs each request's handler on its own task and answers them as they
+//! complete. A reader task matches responses to waiting senders by
+//! `request_id`.
+
+use crate::framing::FrameReader;
+use crate::{codec, SocketClient, SocketError, SocketPayload, SocketResponse, SocketResult, TransportStream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Senders waiting for a response, by request ID
+#[derive(Default)]
+struct Pending {
+    waiting: HashMap<String, oneshot::Sender<Vec<u8>>>,
+    /// Set once the server has closed the connection
+    closed: bool,
+}
+
+/// A persistent connection from [`SocketClient::connect`].
+///
+/// `send` can be called concurrently; every request shares the one socket.
+/// Dropping the connection closes it.
+pub struct Connection {
+    client: SocketClient,
+    writer: tokio::sync::Mutex<WriteHalf<TransportStream>>,
+    pending: Arc<Mutex<Pending>>,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Connection {
+    pub(crate) fn new(client: SocketClient, stream: TransportStream) -> Self {
+        let (read_half, writer) = tokio::io::split(stream);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let reader = tokio::spawn(read_responses(
+            read_half,
+            FrameReader::new(&client.config),
+            Arc::clone(&pending),
+        ));
+        Self {
+            client,
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            reader,
+        }
+    }
+
+    /// Send a request on this connection and wait for its response
+    pub async fn send<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<SocketResponse<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        let request = self.client.encode_request(&payload, false)?;
+        let request_id = payload.request_id;
+        let (sender, response) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(closed());
+            }
+            pending.waiting.insert(request_id.clone(), sender);
+        }
+
+        let written = self.writer.lock().await.write_all(&request).await;
+        if let Err(e) = written {
+            self.pending.lock().unwrap().waiting.remove(&request_id);
+            return Err(e.into());
+        }
+
+        let timeout = Duration::from_secs(self.client.config.timeout);
+        let frame = match tokio::time::timeout(timeout, response).await {
+            Ok(Ok(frame)) => frame,
+            // The reader dropped the sender when the connection closed
+            Ok(Err(_)) => return Err(closed()),
+            Err(_) => {
+                self.pending.lock().unwrap().waiting.remove(&request_id);
+                return Err(SocketError::ConnectionTimeout);
+            }
+        };
+
+        let response: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received response on connection: {:?}", response);
+        if let Some(validator) = self.client.validator::<R>() {
+            validator(&response).map_err(SocketError::InvalidResponse)?;
+        }
+        Ok(response)
+    }
+
+    /// Whether the server has closed the connection, so `send` would fail
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    /// Tell the server no more requests follow and wait for it to close the
+    /// connection
+    pub async fn close(mut self) -> SocketResult<()> {
+        self.writer.get_mut().shutdown().await?;
+        (&mut self.reader).await.map_err(|e| SocketError::Io(std::io::Error::other(e)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Hand each response to the sender waiting for it until the server closes
+/// the connection, then fail whoever is still waiting
+async fn read_responses(mut stream: ReadHalf<TransportStream>, mut reader: FrameReader, pending: Arc<Mutex<Pending>>) {
+    #[derive(serde::Deserialize)]
+    struct ResponseId {
+        request_id: String,
+    }
+
+    loop {
+        let frame = match reader.next_frame(&mut stream).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Connection failed: {}", e);
+                break;
+            }
+        };
+        let Ok(ResponseId { request_id }) = codec::decode(&frame) else {
+            debug!("Ignoring a message without a request ID");
+            continue;
+        };
+        match pending.lock().unwrap().waiting.remove(&request_id) {
+            Some(sender) => {
+                let _ = sender.send(frame);
+            }
+            None => debug!("Ignoring response for unknown request ID {}", request_id),
+        }
+    }
+
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.waiting.clear();
+}
+
+fn closed() -> SocketError {
+    SocketError::Io(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "connection closed before the response arrived",
+    ))
+}
@@ -0,0 +1,156 @@
+//! Bookkeeping for connections currently open on a server.
+
+use crate::{CancellationToken, ConnectionHook};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A connection currently being served
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ConnectionInfo {
+    /// Server-assigned connection identifier
+    pub id: u64,
+    /// Name the client supplied in its handshake, if any
+    pub client_name: Option<String>,
+    /// When the connection was accepted
+    pub connected_at: SystemTime,
+    /// Total time spent in handlers for this connection so far
+    pub handler_time: Duration,
+    /// Who is on the other end, for Unix domain socket connections
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+/// The process on the other end of a Unix domain socket, as the kernel
+/// reports it (`SO_PEERCRED` on Linux, `getpeereid` on BSDs and macOS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PeerCredentials {
+    /// Effective user ID of the peer process
+    pub uid: u32,
+    /// Effective group ID of the peer process
+    pub gid: u32,
+    /// Process ID of the peer, where the OS reports it
+    pub pid: Option<i32>,
+}
+
+/// Registry of open connections shared between the server and its handles
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionRegistry {
+    next_id: Arc<AtomicU64>,
+    connections: Arc<Mutex<HashMap<u64, ConnectionInfo>>>,
+}
+
+impl ConnectionRegistry {
+    /// Record a newly accepted connection. It stays registered until the
+    /// returned guard is dropped.
+    pub(crate) fn register(&self, peer_credentials: Option<PeerCredentials>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = ConnectionInfo {
+            id,
+            client_name: None,
+            connected_at: SystemTime::now(),
+            handler_time: Duration::ZERO,
+            peer_credentials,
+        };
+        self.connections.lock().unwrap().insert(id, info);
+        ConnectionGuard {
+            id,
+            registry: self.clone(),
+            disconnect_hooks: Vec::new(),
+            authenticated: false,
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Number of connections accepted since the server was created
+    pub(crate) fn total_accepted(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of all open connections, oldest first
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+}
+
+/// Keeps a connection registered for as long as it is alive
+pub(crate) struct ConnectionGuard {
+    id: u64,
+    registry: ConnectionRegistry,
+    /// Called once the connection is unregistered, however it ended
+    disconnect_hooks: Vec<ConnectionHook>,
+    /// Whether the client's handshake carried the server's auth token
+    authenticated: bool,
+    /// Cancelled once the client goes away, for every request on the connection
+    cancellation: CancellationToken,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The connection's details as of now
+    pub(crate) fn info(&self) -> Option<ConnectionInfo> {
+        self.registry.connections.lock().unwrap().get(&self.id).cloned()
+    }
+
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    pub(crate) fn set_authenticated(&mut self) {
+        self.authenticated = true;
+    }
+
+    pub(crate) fn set_disconnect_hooks(&mut self, hooks: Vec<ConnectionHook>) {
+        self.disconnect_hooks = hooks;
+    }
+
+    pub(crate) fn client_name(&self) -> Option<String> {
+        let connections = self.registry.connections.lock().unwrap();
+        connections.get(&self.id).and_then(|info| info.client_name.clone())
+    }
+
+    pub(crate) fn peer_credentials(&self) -> Option<PeerCredentials> {
+        let connections = self.registry.connections.lock().unwrap();
+        connections.get(&self.id).and_then(|info| info.peer_credentials)
+    }
+
+    pub(crate) fn set_client_name(&self, name: Option<String>) {
+        if let Some(info) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            info.client_name = name;
+        }
+    }
+
+    pub(crate) fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Total handler time charged to this connection
+    pub(crate) fn handler_time(&self) -> Duration {
+        let connections = self.registry.connections.lock().unwrap();
+        connections.get(&self.id).map_or(Duration::ZERO, |info| info.handler_time)
+    }
+
+    /// Charge handler time to this connection
+    pub(crate) fn add_handler_time(&self, elapsed: Duration) {
+        if let Some(info) = self.registry.connections.lock().unwrap().get_mut(&self.id) {
+            info.handler_time += elapsed;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // Unlock before the hooks run, so they can list connections
+        let info = self.registry.connections.lock().unwrap().remove(&self.id);
+        if let Some(info) = info {
+            for hook in &self.disconnect_hooks {
+                hook(&info);
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Per-request context shared between middleware and handlers.
+
+use crate::PeerCredentials;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// A type map of values attached to a single request.
+///
+/// Middleware inserts values, such as an authenticated user, and handlers
+/// read them back by type. Each type holds at most one value.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty type map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value, returning the previous value of the same type
+    pub fn insert<V: Send + Sync + 'static>(&mut self, value: V) -> Option<V> {
+        self.map
+            .insert(TypeId::of::<V>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|previous| *previous))
+    }
+
+    /// Get the value of type `V`, if present
+    pub fn get<V: Send + Sync + 'static>(&self) -> Option<&V> {
+        self.map.get(&TypeId::of::<V>())?.downcast_ref()
+    }
+
+    /// Get a mutable reference to the value of type `V`, if present
+    pub fn get_mut<V: Send + Sync + 'static>(&mut self) -> Option<&mut V> {
+        self.map.get_mut(&TypeId::of::<V>())?.downcast_mut()
+    }
+
+    /// Remove and return the value of type `V`, if present
+    pub fn remove<V: Send + Sync + 'static>(&mut self) -> Option<V> {
+        self.map
+            .remove(&TypeId::of::<V>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    /// Number of values stored
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether no values are stored
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.len()).finish()
+    }
+}
+
+/// Tells a handler its client has gone away, so the work is no longer wanted.
+///
+/// Async handlers are dropped at their next `.await` once the token is
+/// cancelled; blocking and inline handlers keep running unless they check
+/// [`is_cancelled`](Self::is_cancelled) themselves.
+#[derive(Debug, Clone)]
+pub struct CancellationToken(Arc<watch::Sender<bool>>);
+
+impl CancellationToken {
+    /// A token that is not cancelled yet
+    pub fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+
+    /// Cancel the token and every clone of it
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Whether the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the token is cancelled
+    pub async fn cancelled(&self) {
+        // The sender lives as long as `self`, so waiting can't fail
+        let _ = self.0.subscribe().wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the server knows about a request beyond its payload
+#[derive(Debug)]
+pub struct RequestContext {
+    /// ID of the request being handled
+    pub request_id: String,
+    /// Command being handled
+    pub command: String,
+    /// Connection the request arrived on
+    pub connection_id: u64,
+    /// Name the client gave in its handshake, if any
+    pub client_name: Option<String>,
+    /// The connecting process's uid, gid and pid, on Unix domain sockets
+    pub peer_credentials: Option<PeerCredentials>,
+    /// Cancelled once the client disconnects without waiting for the response
+    pub cancellation: CancellationToken,
+    /// Values attached by middleware
+    pub extensions: Extensions,
+}
+
+impl RequestContext {
+    /// A copy of everything but the extensions, which go to the handler
+    pub(crate) fn without_extensions(&self) -> Self {
+        Self {
+            request_id: self.request_id.clone(),
+            command: self.command.clone(),
+            connection_id: self.connection_id,
+            client_name: self.client_name.clone(),
+            peer_credentials: self.peer_credentials,
+            cancellation: self.cancellation.clone(),
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct UserId(u32);
+
+    #[test]
+    fn test_extensions_by_type() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.is_empty());
+
+        assert_eq!(extensions.insert(UserId(1)), None);
+        assert_eq!(extensions.insert("admin"), None);
+        assert_eq!(extensions.insert(UserId(2)), Some(UserId(1)));
+        assert_eq!(extensions.len(), 2);
+
+        extensions.get_mut::<UserId>().unwrap().0 += 1;
+        assert_eq!(extensions.get::<UserId>(), Some(&UserId(3)));
+        assert_eq!(extensions.get::<&str>(), Some(&"admin"));
+        assert_eq!(extensions.remove::<UserId>(), Some(UserId(3)));
+        assert_eq!(extensions.get::<UserId>(), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Running a request's handler at most once per idempotency key.
+//!
+//! A request carrying `SocketPayload::idempotency_key` claims the key in the
+//! server's [`DedupStore`] before its handler runs, and the response is
+//! stored under the key afterwards. A retry with the same key gets the
+//! stored response without the handler running again, however often the
+//! client retries and whichever connection the retry arrives on.
+//!
+//! # Guarantees and failure modes
+//!
+//! - The handler runs at most once per key for as long as the store
+//!   remembers it. The in-memory store forgets keys when the server
+//!   restarts and after its retention period; a durable store can keep them
+//!   across restarts.
+//! - While the first request with a key is running, duplicates get a
+//!   `duplicate_in_progress` error instead of waiting. Clients can retry
+//!   after a backoff and then receive the stored response.
+//! - If the store fails to claim a key, the request is refused with a
+//!   `dedup_unavailable` error and the handler doesn't run.
+//! - If the handler panics, the server crashes between claiming and
+//!   completing, or storing the response fails, the key stays claimed and
+//!   every retry gets `duplicate_in_progress`. Whether the side effects
+//!   happened is then unknown; the key is never run a second time, so
+//!   resolving it is left to the operator. The handler's response is still
+//!   returned when only storing it fails.
+//! - If the handler doesn't finish, because it was cancelled when its client
+//!   disconnected or abandoned with a `handler_timeout`, the claim is
+//!   released instead of completed, so a retry runs the handler again rather
+//!   than replaying the failure. An abandoned blocking handler may still be
+//!   running when the retry starts.
+//! - Exactly-once effects need the handler's side effects and the store's
+//!   update to commit together, e.g. in one database transaction. This
+//!   module provides at-most-once execution plus replay of the response.
+
+use crate::SocketResult;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What a [`DedupStore`] knows about an idempotency key
+#[derive(Debug, Clone, PartialEq)]
+pub enum DedupEntry {
+    /// The key was unknown and is now claimed; the caller runs the handler
+    Claimed,
+    /// Another request claimed the key and hasn't completed yet
+    InProgress,
+    /// A request with the key completed with this response
+    Completed(serde_json::Value),
+}
+
+/// Storage of idempotency keys and the responses to their requests.
+///
+/// Implementations must make `claim` atomic: of several concurrent claims of
+/// one key, exactly one may return [`DedupEntry::Claimed`]. Methods are
+/// called inline on the connection's task, so a store backed by a file or a
+/// database should keep them quick.
+pub trait DedupStore: Send + Sync {
+    /// Claim `key` for a new execution unless it is already known
+    fn claim(&self, key: &str) -> SocketResult<DedupEntry>;
+
+    /// Record the response of the execution that claimed `key`
+    fn complete(&self, key: &str, response: serde_json::Value) -> SocketResult<()>;
+
+    /// Forget the claim on `key` of an execution that didn't finish, so the
+    /// next request with the key claims it again
+    fn release(&self, key: &str) -> SocketResult<()>;
+}
+
+/// The default [`DedupStore`], keeping keys in memory for a retention period
+pub struct MemoryDedupStore {
+    retention: Duration,
+    entries: Mutex<HashMap<String, (Instant, Option<serde_json::Value>)>>,
+}
+
+impl MemoryDedupStore {
+    /// Remember keys for a day
+    pub fn new() -> Self {
+        Self::with_retention(Duration::from_secs(24 * 60 * 60))
+    }
+
+    /// Remember keys for `retention` after they were claimed
+    pub fn with_retention(retention: Duration) -> Self {
+        Self {
+            retention,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryDedupStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DedupStore for MemoryDedupStore {
+    fn claim(&self, key: &str) -> SocketResult<DedupEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (claimed, _)| claimed.elapsed() < self.retention);
+        Ok(match entries.get(key) {
+            Some((_, Some(response))) => DedupEntry::Completed(response.clone()),
+            Some((_, None)) => DedupEntry::InProgress,
+            None => {
+                entries.insert(key.to_string(), (Instant::now(), None));
+                DedupEntry::Claimed
+            }
+        })
+    }
+
+    fn complete(&self, key: &str, response: serde_json::Value) -> SocketResult<()> {
+        if let Some((_, stored)) = self.entries.lock().unwrap().get_mut(key) {
+            *stored = Some(response);
+        }
+        Ok(())
+    }
+
+    fn release(&self, key: &str) -> SocketResult<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if matches!(entries.get(key), Some((_, None))) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_claims_once() {
+        let store = MemoryDedupStore::new();
+        assert_eq!(store.claim("a").unwrap(), DedupEntry::Claimed);
+        assert_eq!(store.claim("a").unwrap(), DedupEntry::InProgress);
+        store.complete("a", serde_json::json!({"ok": true})).unwrap();
+        assert_eq!(store.claim("a").unwrap(), DedupEntry::Completed(serde_json::json!({"ok": true})));
+        assert_eq!(store.claim("b").unwrap(), DedupEntry::Claimed);
+        store.release("b").unwrap();
+        assert_eq!(store.claim("b").unwrap(), DedupEntry::Claimed);
+        store.release("a").unwrap();
+        assert!(matches!(store.claim("a").unwrap(), DedupEntry::Completed(_)));
+
+        let store = MemoryDedupStore::with_retention(Duration::ZERO);
+        assert_eq!(store.claim("a").unwrap(), DedupEntry::Claimed);
+        assert_eq!(store.claim("a").unwrap(), DedupEntry::Claimed);
+    }
+}
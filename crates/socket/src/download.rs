@@ -0,0 +1,161 @@
+//! Bulk binary transfer from a handler to the client.
+//!
+//! A download starts with a download response, followed by raw chunks, each
+//! a 4-byte big-endian length and that many bytes. A zero-length chunk ends
+//! the data and is followed by a trailer response saying whether the handler
+//! finished successfully.
+
+use crate::{codec, SocketError, SocketResponse, SocketResult};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Largest chunk written to the connection
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Chunks a handler may queue ahead of the connection before it has to wait
+const QUEUED_CHUNKS: usize = 8;
+
+/// Where a download handler writes the bytes it sends to the client
+pub struct DownloadSink {
+    chunks: mpsc::Sender<Vec<u8>>,
+}
+
+impl DownloadSink {
+    /// Send `bytes` to the client, waiting if the client is reading slower
+    /// than the handler produces data. Fails once the client has gone away.
+    pub async fn write_all(&mut self, bytes: &[u8]) -> SocketResult<()> {
+        for chunk in bytes.chunks(MAX_CHUNK) {
+            self.chunks.send(chunk.to_vec()).await.map_err(|_| closed())?;
+        }
+        Ok(())
+    }
+
+    /// Send everything `reader` produces, such as a file or a process's
+    /// stdout, returning the number of bytes sent
+    pub async fn copy_from<Rd>(&mut self, reader: &mut Rd) -> SocketResult<u64>
+    where
+        Rd: AsyncRead + Unpin,
+    {
+        let mut total = 0;
+        loop {
+            let mut chunk = vec![0u8; MAX_CHUNK];
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            chunk.truncate(n);
+            self.chunks.send(chunk).await.map_err(|_| closed())?;
+            total += n as u64;
+        }
+    }
+}
+
+fn closed() -> SocketError {
+    SocketError::Io(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+}
+
+/// Run a download handler, writing what it sends to `stream` as chunks
+/// followed by the trailer
+pub(crate) async fn serve<W, F, Fut>(stream: &mut W, request_id: &str, handler: F) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+    F: FnOnce(DownloadSink) -> Fut,
+    Fut: std::future::Future<Output = SocketResult<()>>,
+{
+    let (chunks, mut outgoing) = mpsc::channel::<Vec<u8>>(QUEUED_CHUNKS);
+    let handler = handler(DownloadSink { chunks });
+    let forward = async {
+        while let Some(chunk) = outgoing.recv().await {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(&chunk).await?;
+        }
+        Ok::<_, SocketError>(())
+    };
+
+    // The sink is dropped when the handler finishes, which ends forwarding
+    let (result, forwarded) = tokio::join!(handler, forward);
+    forwarded?;
+
+    let trailer = match result {
+        Ok(()) => SocketResponse::<()>::success(request_id, ()),
+        Err(e) => SocketResponse::error(request_id, e.to_string()),
+    };
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.write_all(&serde_json::to_vec(&trailer)?).await?;
+    Ok(())
+}
+
+/// Copy a download's chunks from `stream` into `writer`, returning the
+/// number of bytes received once the trailer confirms success
+pub(crate) async fn receive<Rd, W>(stream: &mut Rd, writer: &mut W) -> SocketResult<u64>
+where
+    Rd: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+    let mut chunk = Vec::new();
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await.map_err(interrupted)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            break;
+        }
+        chunk.resize(len, 0);
+        stream.read_exact(&mut chunk).await.map_err(interrupted)?;
+        writer.write_all(&chunk).await?;
+        total += len as u64;
+    }
+    writer.flush().await?;
+
+    let mut trailer = Vec::new();
+    stream.read_to_end(&mut trailer).await?;
+    let trailer: SocketResponse<()> = codec::decode(&trailer)?;
+    match trailer.error {
+        Some(error) if !trailer.success => Err(SocketError::ServerError(error)),
+        _ => Ok(total),
+    }
+}
+
+fn interrupted(e: std::io::Error) -> SocketError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        return SocketError::InvalidResponse("download ended before it was complete".to_string());
+    }
+    e.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        let expected = data.clone();
+        serve(&mut wire, "1", |mut sink| async move { sink.copy_from(&mut data.as_slice()).await.map(|_| ()) })
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        let n = receive(&mut wire.as_slice(), &mut received).await.unwrap();
+        assert_eq!(n, expected.len() as u64);
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_reaches_client() {
+        let mut wire = Vec::new();
+        serve(&mut wire, "1", |mut sink| async move {
+            sink.write_all(b"partial").await?;
+            Err(SocketError::ServerError("disk on fire".to_string()))
+        })
+        .await
+        .unwrap();
+
+        let mut received = Vec::new();
+        let result = receive(&mut wire.as_slice(), &mut received).await;
+        assert!(matches!(result, Err(SocketError::ServerError(msg)) if msg.contains("disk on fire")));
+        assert_eq!(received, b"partial");
+    }
+}
@@ -0,0 +1,112 @@
+//! Tracking connection tasks, so a server can be drained before it exits.
+//!
+//! Every accepted connection is served on a task in a shared [`JoinSet`].
+//! Draining waits for the set to empty, then aborts whatever is left. With a
+//! worker pool, connections first wait in its queue, and join the set once a
+//! worker picks them up.
+
+use crate::worker_pool::{ConnectionQueue, WorkerPool, Workers};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// The tasks serving open connections, shared with [`ServerHandle`](crate::ServerHandle)s
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionTasks {
+    tasks: Arc<Mutex<JoinSet<()>>>,
+    queue: Option<ConnectionQueue>,
+}
+
+impl ConnectionTasks {
+    pub(crate) fn new(pool: Option<WorkerPool>) -> Self {
+        Self {
+            tasks: Arc::default(),
+            queue: pool.map(ConnectionQueue::new),
+        }
+    }
+
+    /// Serve a connection with `task`: straight away, or once a worker is
+    /// free with a worker pool, waiting while its queue is full
+    pub(crate) async fn serve(&self, task: impl Future<Output = ()> + Send + 'static) {
+        match &self.queue {
+            Some(queue) => queue.push(Box::pin(task)).await,
+            None => self.spawn(task),
+        }
+    }
+
+    /// Serve a connection with `task` straight away
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        // Reap finished tasks, so the set doesn't grow with every connection served
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    /// Connections waiting for a worker
+    pub(crate) fn queued(&self) -> usize {
+        self.queue.as_ref().map_or(0, ConnectionQueue::depth)
+    }
+
+    /// Start the worker pool, if there is one
+    pub(crate) fn start_workers(&self) -> Workers {
+        let Some(queue) = &self.queue else {
+            return Workers(Vec::new());
+        };
+        let workers = (0..queue.workers())
+            .map(|_| tokio::spawn(self.clone().work(queue.clone())))
+            .collect();
+        Workers(workers)
+    }
+
+    /// Serve queued connections one at a time
+    async fn work(self, queue: ConnectionQueue) {
+        while let Some(connection) = queue.next().await {
+            // Each connection still gets a task of its own, so draining sees
+            // it and a panicking handler doesn't take the worker down with it
+            let (done, served) = oneshot::channel::<()>();
+            self.spawn(async move {
+                connection.await;
+                let _ = done.send(());
+            });
+            let _ = served.await;
+        }
+    }
+
+    fn take(&self) -> JoinSet<()> {
+        std::mem::take(&mut *self.tasks.lock().unwrap())
+    }
+
+    /// Wait up to `timeout` for every task to finish, then abort the rest,
+    /// returning how many were aborted
+    pub(crate) async fn drain(&self, timeout: Duration) -> usize {
+        let mut tasks = self.take();
+        info!("Draining {} connections", tasks.len() + self.queued());
+        let finished = tokio::time::timeout(timeout, async {
+            loop {
+                while tasks.join_next().await.is_some() {}
+                tasks = self.take();
+                if tasks.is_empty() {
+                    if self.queued() == 0 {
+                        break;
+                    }
+                    // A worker is about to start the next queued connection
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        })
+        .await;
+        if finished.is_ok() {
+            return 0;
+        }
+        let unserved = self.queue.as_ref().map_or(0, ConnectionQueue::clear);
+        let mut started = self.take();
+        let cancelled = tasks.len() + started.len() + unserved;
+        warn!("Cancelling {} connections still open after draining for {:?}", cancelled, timeout);
+        tasks.shutdown().await;
+        started.shutdown().await;
+        cancelled
+    }
+}
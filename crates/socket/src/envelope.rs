@@ -0,0 +1,100 @@
+//! Typed payloads carried as JSON strings.
+//!
+//! Servers and clients that exchange `String` payloads holding JSON (as the
+//! `socket_example` daemon did) can switch to [`JsonEnvelope`] without
+//! changing the wire format: the inner value is still sent as a string of
+//! JSON, but neither side calls `serde_json::to_string`/`from_str` by hand.
+
+use crate::{SocketClient, SocketResponse, SocketResult, SocketServer};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A value serialized as a JSON string containing its own JSON encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JsonEnvelope<Inner>(pub Inner);
+
+impl<Inner> JsonEnvelope<Inner> {
+    /// Wrap `inner` for sending
+    pub fn new(inner: Inner) -> Self {
+        Self(inner)
+    }
+
+    /// Unwrap the received value
+    pub fn into_inner(self) -> Inner {
+        self.0
+    }
+}
+
+impl<Inner> std::ops::Deref for JsonEnvelope<Inner> {
+    type Target = Inner;
+
+    fn deref(&self) -> &Inner {
+        &self.0
+    }
+}
+
+impl<Inner: Serialize> Serialize for JsonEnvelope<Inner> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let json = serde_json::to_string(&self.0).map_err(S::Error::custom)?;
+        serializer.serialize_str(&json)
+    }
+}
+
+impl<'de, Inner: DeserializeOwned> Deserialize<'de> for JsonEnvelope<Inner> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = String::deserialize(deserializer)?;
+        serde_json::from_str(&json).map(Self).map_err(D::Error::custom)
+    }
+}
+
+impl<T, R> SocketServer<JsonEnvelope<T>, JsonEnvelope<R>>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+    R: Serialize + DeserializeOwned + Send + Sync + std::fmt::Debug + 'static,
+{
+    /// Register a handler that takes the unwrapped request and returns the
+    /// unwrapped response data, sent back as a success response
+    pub async fn register_envelope_handler<F>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(T) -> SocketResult<R> + Send + Sync + 'static,
+    {
+        self.register_handler(command, move |payload| {
+            let response = handler(payload.data.into_inner())?;
+            Ok(SocketResponse::success(payload.request_id, JsonEnvelope(response)))
+        })
+        .await;
+    }
+}
+
+impl SocketClient {
+    /// Send `request` wrapped in a [`JsonEnvelope`] and return the unwrapped
+    /// response data.
+    ///
+    /// An error response becomes [`crate::SocketError::ServerError`].
+    pub async fn send_envelope<T, R>(&self, command: impl Into<String>, request: T) -> SocketResult<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned + std::fmt::Debug + 'static,
+    {
+        let payload = self.payload::<_, JsonEnvelope<R>>(command, JsonEnvelope(request));
+        Ok(self.send_request(payload).await?.into_result()?.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_matches_hand_rolled_encoding() {
+        let inner = HashMap::from([("web".to_string(), "python -m http.server".to_string())]);
+        let by_hand = serde_json::to_string(&serde_json::to_string(&inner).unwrap()).unwrap();
+        assert_eq!(serde_json::to_string(&JsonEnvelope(&inner)).unwrap(), by_hand);
+
+        let decoded: JsonEnvelope<HashMap<String, String>> = serde_json::from_str(&by_hand).unwrap();
+        assert_eq!(decoded.into_inner(), inner);
+        assert!(serde_json::from_str::<JsonEnvelope<HashMap<String, String>>>("\"not json\"").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Events the server pushes to subscribed clients.
+//!
+//! A client subscribes by sending [`SUBSCRIBE_COMMAND`](crate::admin::SUBSCRIBE_COMMAND)
+//! and keeping the connection open. The server answers with a
+//! [`ResponseKind::Subscribed`](crate::ResponseKind::Subscribed) response,
+//! then writes every event broadcast from then on. On that connection the
+//! client can send further `__subscribe` and
+//! [`__unsubscribe`](crate::admin::UNSUBSCRIBE_COMMAND) requests naming a
+//! topic, to also receive what is published to it. Events are encoded once,
+//! when sent, and the same bytes go to every subscriber.
+
+use crate::framing::{self, FrameReader};
+use crate::log_throttle::LogThrottle;
+use crate::{
+    admin, codec, Codec, Framing, ResponseKind, SocketClient, SocketConfig, SocketError, SocketResponse,
+    SocketResult, TransportStream,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+/// Events a subscriber may fall behind by before it is dropped
+const EVENT_BACKLOG: usize = 256;
+
+/// An encoded event and the topic it was published to, if any
+#[derive(Clone)]
+struct Event {
+    topic: Option<Arc<str>>,
+    bytes: Arc<Vec<u8>>,
+}
+
+/// Fans events out to subscribed connections and tracks the topics each
+/// one has subscribed to
+#[derive(Clone)]
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<Event>,
+    topics: Arc<Mutex<HashMap<u64, HashSet<String>>>>,
+    codec: Codec,
+    framing: Framing,
+}
+
+impl EventBus {
+    pub(crate) fn new(config: &SocketConfig) -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BACKLOG).0,
+            topics: Arc::default(),
+            codec: config.codec,
+            framing: config.framing,
+        }
+    }
+
+    /// Send `event` to every current subscriber, returning how many there are
+    pub(crate) fn broadcast<E: serde::Serialize>(&self, event: &SocketResponse<E>) -> SocketResult<usize> {
+        let bytes = Arc::new(self.encode(event)?);
+        // Sending only fails when nobody is subscribed
+        Ok(self.sender.send(Event { topic: None, bytes }).unwrap_or(0))
+    }
+
+    /// Send `data` to the connections subscribed to `topic`, returning how many there are
+    pub(crate) fn publish<E: serde::Serialize>(&self, topic: &str, data: E) -> SocketResult<usize> {
+        let subscribers = self.topics.lock().unwrap().values().filter(|topics| topics.contains(topic)).count();
+        if subscribers == 0 {
+            return Ok(0);
+        }
+        let bytes = Arc::new(self.encode(&SocketResponse::success(topic, data))?);
+        let _ = self.sender.send(Event {
+            topic: Some(topic.into()),
+            bytes,
+        });
+        Ok(subscribers)
+    }
+
+    fn encode<E: serde::Serialize>(&self, message: &SocketResponse<E>) -> SocketResult<Vec<u8>> {
+        framing::encode(self.framing, self.codec.encode(message)?)
+    }
+}
+
+/// The optional data of a subscription request
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TopicChange {
+    topic: String,
+}
+
+/// A subscription request as the server reads it
+#[derive(serde::Deserialize)]
+struct SubscriptionRequest {
+    request_id: String,
+    command: String,
+    #[serde(default)]
+    data: Option<TopicChange>,
+}
+
+/// One connection's subscription, which ends when dropped
+pub(crate) struct Subscriber {
+    bus: EventBus,
+    connection_id: u64,
+    events: broadcast::Receiver<Event>,
+}
+
+impl Subscriber {
+    /// Receive everything broadcast from now on, on connection `connection_id`
+    pub(crate) fn new(bus: &EventBus, connection_id: u64) -> Self {
+        bus.topics.lock().unwrap().insert(connection_id, HashSet::new());
+        Self {
+            bus: bus.clone(),
+            connection_id,
+            events: bus.sender.subscribe(),
+        }
+    }
+
+    /// Apply a `__subscribe` or `__unsubscribe` request, returning the
+    /// encoded response to it
+    pub(crate) fn handle_request(&mut self, frame: &[u8]) -> SocketResult<Vec<u8>> {
+        let request: SubscriptionRequest = match codec::decode(frame) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = SocketError::InvalidRequest(e.to_string()).to_string();
+                return self.bus.encode(&SocketResponse::<()>::error("", error));
+            }
+        };
+        let mut topics = self.bus.topics.lock().unwrap();
+        let subscribed = topics.entry(self.connection_id).or_default();
+        let response = match (request.command.as_str(), request.data) {
+            (admin::SUBSCRIBE_COMMAND, Some(change)) => {
+                subscribed.insert(change.topic);
+                SocketResponse::subscribed(request.request_id)
+            }
+            (admin::SUBSCRIBE_COMMAND, None) => SocketResponse::subscribed(request.request_id),
+            (admin::UNSUBSCRIBE_COMMAND, Some(change)) => {
+                subscribed.remove(&change.topic);
+                SocketResponse::subscribed(request.request_id)
+            }
+            (admin::UNSUBSCRIBE_COMMAND, None) => {
+                SocketResponse::error(request.request_id, "__unsubscribe needs a topic")
+            }
+            (command, _) => SocketResponse::<()>::error(
+                request.request_id,
+                format!("subscribed connections only accept __subscribe and __unsubscribe, not {}", command),
+            ),
+        };
+        drop(topics);
+        self.bus.encode(&response)
+    }
+
+    /// Wait for the next event this subscriber should see. Returns `None`
+    /// once it has fallen more than [`EVENT_BACKLOG`] events behind, and
+    /// should be dropped rather than allowed to hold up the others.
+    pub(crate) async fn next_event(&mut self, log_throttle: &LogThrottle) -> Option<Arc<Vec<u8>>> {
+        loop {
+            match self.events.recv().await {
+                Ok(Event { topic: None, bytes }) => return Some(bytes),
+                Ok(Event { topic: Some(topic), bytes }) => {
+                    let topics = self.bus.topics.lock().unwrap();
+                    if topics.get(&self.connection_id).is_some_and(|topics| topics.contains(&*topic)) {
+                        return Some(bytes);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log_throttle.warn(format!("Dropping subscriber that fell {} events behind", missed));
+                    return None;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.bus.topics.lock().unwrap().remove(&self.connection_id);
+    }
+}
+
+/// Events pushed by the server, from [`SocketClient::subscribe`].
+///
+/// Events published to a topic carry the topic's name as their
+/// `request_id`. Dropping the subscription closes its connection.
+pub struct Subscription<R> {
+    client: SocketClient,
+    stream: TransportStream,
+    reader: FrameReader,
+    /// Events that arrived while waiting for a topic change to be confirmed
+    pending: VecDeque<SocketResponse<R>>,
+}
+
+impl<R> Subscription<R>
+where
+    R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+{
+    pub(crate) fn new(client: SocketClient, stream: TransportStream, reader: FrameReader) -> Self {
+        Self {
+            client,
+            stream,
+            reader,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Wait for the next event, however long that takes. Returns `None` once
+    /// the server closes the subscription, e.g. after dropping a subscriber
+    /// that fell behind.
+    pub async fn next(&mut self) -> Option<SocketResult<SocketResponse<R>>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(Ok(event));
+        }
+        self.read().await.transpose()
+    }
+
+    /// Also receive events published to `topic`. Returns once the server
+    /// has confirmed, so everything published to it afterwards arrives.
+    pub async fn subscribe(&mut self, topic: impl Into<String>) -> SocketResult<()> {
+        self.change_topic(admin::SUBSCRIBE_COMMAND, topic.into()).await
+    }
+
+    /// Stop receiving events published to `topic`. Events published before
+    /// the server confirmed may still arrive.
+    pub async fn unsubscribe(&mut self, topic: impl Into<String>) -> SocketResult<()> {
+        self.change_topic(admin::UNSUBSCRIBE_COMMAND, topic.into()).await
+    }
+
+    async fn change_topic(&mut self, command: &str, topic: String) -> SocketResult<()> {
+        let payload = self.client.payload::<TopicChange, R>(command, TopicChange { topic });
+        let request = self.client.encode_request(&payload, false)?;
+        self.stream.write_all(&request).await?;
+
+        let closed = || SocketError::InvalidRequest("the server closed the subscription".into());
+        loop {
+            let response = tokio::time::timeout(self.client.timeout(), self.read())
+                .await
+                .map_err(|_| SocketError::ConnectionTimeout)??
+                .ok_or_else(closed)?;
+            if response.request_id != payload.request_id {
+                self.pending.push_back(response);
+            } else if response.kind == Some(ResponseKind::Subscribed) {
+                return Ok(());
+            } else {
+                return response.into_result().map(|_| ());
+            }
+        }
+    }
+
+    async fn read(&mut self) -> SocketResult<Option<SocketResponse<R>>> {
+        let Some(frame) = self.reader.next_frame(&mut self.stream).await? else {
+            return Ok(None);
+        };
+        let event: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received event: {:?}", event);
+        Ok(Some(event))
+    }
+}
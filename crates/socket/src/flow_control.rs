@@ -0,0 +1,204 @@
+//! Per-request flow control for multiplexed connections.
+//!
+//! A client that sets `flow_control` in its handshake receives each response
+//! as data frames: a `{"data": DataHeader}` message followed by `len` raw
+//! bytes of the response JSON, the last piece marked `end`. The server sends
+//! at most the request's window of bytes before waiting for the client to
+//! grant more with a `{"window_update": WindowUpdate}` message, and it keeps
+//! writing other responses in the meantime. A response the client reads
+//! slowly therefore can't hold up the rest of the connection.
+
+use crate::{codec, framing, SocketResult};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+
+/// Largest piece of a response sent in one data frame
+const MAX_DATA_FRAME: usize = 16 * 1024;
+
+/// Precedes each piece of a response on a flow-controlled connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataHeader {
+    /// Request the response belongs to
+    pub request_id: String,
+    /// Number of raw response bytes following this header
+    pub len: usize,
+    /// Whether this is the last piece of the response
+    pub end: bool,
+}
+
+/// Sent by a client to let the server send more of a response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowUpdate {
+    /// Request whose response the credit applies to
+    pub request_id: String,
+    /// Additional bytes the server may send
+    pub increment: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DataFrame {
+    data: DataHeader,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct WindowUpdateFrame {
+    window_update: WindowUpdate,
+}
+
+impl WindowUpdateFrame {
+    /// Parse `frame` as a window update; anything else is left to request handling
+    pub(crate) fn parse(frame: &[u8]) -> Option<WindowUpdate> {
+        if codec::is_array(frame) {
+            return None;
+        }
+        codec::decode::<Self>(frame).ok().map(|frame| frame.window_update)
+    }
+}
+
+/// What the multiplexed read loop and request tasks tell the writer
+pub(crate) enum WriterEvent {
+    /// A complete serialized response
+    Response { request_id: String, bytes: Vec<u8> },
+    /// The client granted more credit to a response
+    WindowUpdate(WindowUpdate),
+    /// The client half-closed and can't send window updates any more
+    ClientClosed,
+}
+
+struct PendingResponse {
+    request_id: String,
+    bytes: Vec<u8>,
+    sent: usize,
+    window: u64,
+}
+
+/// Write responses to `stream` as they arrive on `events` until every sender
+/// is gone. Without flow control each response is written whole; with it,
+/// responses are interleaved in data frames within their windows.
+pub(crate) async fn write_responses<W>(
+    stream: &mut W,
+    mut events: mpsc::Receiver<WriterEvent>,
+    flow_control: Option<u32>,
+    write_timeout: Option<Duration>,
+) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(initial_window) = flow_control else {
+        while let Some(event) = events.recv().await {
+            if let WriterEvent::Response { bytes, .. } = event {
+                framing::write_all_within(stream, &bytes, write_timeout).await?;
+            }
+        }
+        return Ok(());
+    };
+
+    let mut pending: VecDeque<PendingResponse> = VecDeque::new();
+    let mut unlimited = false;
+    loop {
+        while let Ok(event) = events.try_recv() {
+            apply(event, &mut pending, &mut unlimited, initial_window);
+        }
+
+        // Send one frame from the first response with credit, then rotate it
+        // to the back so responses share the connection
+        let ready = pending.iter().position(|r| r.window > 0 || unlimited);
+        let Some(position) = ready else {
+            match events.recv().await {
+                Some(event) => apply(event, &mut pending, &mut unlimited, initial_window),
+                None if pending.is_empty() => return Ok(()),
+                // Nobody is left to grant credit, so finish what's queued
+                None => unlimited = true,
+            }
+            continue;
+        };
+        let mut response = pending.remove(position).expect("position is in range");
+
+        let remaining = response.bytes.len() - response.sent;
+        let window = if unlimited { u64::MAX } else { response.window };
+        let len = remaining.min(MAX_DATA_FRAME).min(usize::try_from(window).unwrap_or(usize::MAX));
+        let end = len == remaining;
+        let header = DataFrame {
+            data: DataHeader { request_id: response.request_id.clone(), len, end },
+        };
+        let mut frame = serde_json::to_vec(&header)?;
+        frame.extend_from_slice(&response.bytes[response.sent..response.sent + len]);
+        framing::write_all_within(stream, &frame, write_timeout).await?;
+
+        response.sent += len;
+        response.window = response.window.saturating_sub(len as u64);
+        if !end {
+            pending.push_back(response);
+        }
+    }
+}
+
+fn apply(event: WriterEvent, pending: &mut VecDeque<PendingResponse>, unlimited: &mut bool, initial_window: u32) {
+    match event {
+        WriterEvent::Response { request_id, bytes } => pending.push_back(PendingResponse {
+            request_id,
+            bytes,
+            sent: 0,
+            window: u64::from(initial_window),
+        }),
+        WriterEvent::WindowUpdate(update) => {
+            // Updates for responses already sent in full are stale and ignored
+            if let Some(response) = pending.iter_mut().find(|r| r.request_id == update.request_id) {
+                response.window = response.window.saturating_add(update.increment);
+            }
+        }
+        WriterEvent::ClientClosed => *unlimited = true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split written data frames back into (request_id, piece, end) triples
+    fn frames(mut wire: &[u8]) -> Vec<(String, Vec<u8>, bool)> {
+        let mut frames = Vec::new();
+        while !wire.is_empty() {
+            let mut headers = serde_json::Deserializer::from_slice(wire).into_iter::<DataFrame>();
+            let header = headers.next().unwrap().unwrap().data;
+            let start = headers.byte_offset();
+            frames.push((header.request_id, wire[start..start + header.len].to_vec(), header.end));
+            wire = &wire[start + header.len..];
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_blocked_response_does_not_hold_up_others() {
+        let (events, receiver) = mpsc::channel(8);
+        events
+            .send(WriterEvent::Response { request_id: "big".to_string(), bytes: vec![b'x'; 1000] })
+            .await
+            .unwrap();
+        events
+            .send(WriterEvent::Response { request_id: "small".to_string(), bytes: b"{}".to_vec() })
+            .await
+            .unwrap();
+
+        let mut wire = Vec::new();
+        let writer = write_responses(&mut wire, receiver, Some(100), None);
+        let grant = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let update = WindowUpdate { request_id: "big".to_string(), increment: 900 };
+            events.send(WriterEvent::WindowUpdate(update)).await.unwrap();
+            drop(events);
+        };
+        let (written, ()) = tokio::join!(writer, grant);
+        written.unwrap();
+
+        let frames = frames(&wire);
+        assert_eq!(frames[0], ("big".to_string(), vec![b'x'; 100], false));
+        assert_eq!(frames[1], ("small".to_string(), b"{}".to_vec(), true));
+        let rest: usize = frames[2..].iter().map(|(_, piece, _)| piece.len()).sum();
+        assert_eq!(rest, 900);
+        assert!(frames.last().unwrap().2);
+    }
+}
@@ -0,0 +1,602 @@
+//! Splitting a byte stream into individual messages.
+
+use crate::compression::{self, Decompressor};
+#[cfg(feature = "msgpack")]
+use crate::codec;
+use crate::{SocketConfig, SocketError, SocketResult};
+use serde::de::IgnoredAny;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+/// Size of the length prefix in [`Framing::LengthPrefixed`] messages
+const PREFIX_LEN: usize = 4;
+
+/// Largest message read by default, and by [`read_framed`]
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// How messages are delimited on a connection. Both peers must agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum Framing {
+    /// Each message is a JSON document, delimited by its own structure
+    #[default]
+    Json,
+    /// Each message is a 4-byte big-endian length followed by that many
+    /// bytes, so messages of any size and content are read exactly
+    LengthPrefixed,
+    /// Each message is one line of JSON ending in `\n`, as produced and
+    /// consumed by line-oriented tools such as `socat` and `jq`. Blank lines
+    /// are skipped. Lines are text, so messages are never compressed, not even
+    /// oversized responses under `LargeResponsePolicy::Compress`.
+    NdJson,
+}
+
+impl Framing {
+    /// Whether a compressed body can be told apart from what follows it:
+    /// always with length prefixes, with JSON framing only when it runs to
+    /// the end of the stream (`last`), and never on newline-delimited lines
+    pub(crate) fn delimits_compressed(self, last: bool) -> bool {
+        match self {
+            Framing::Json => last,
+            Framing::LengthPrefixed => true,
+            Framing::NdJson => false,
+        }
+    }
+}
+
+/// What a server does with bytes a client sends after a complete request on
+/// a connection that isn't multiplexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum TrailingBytes {
+    /// Answer the request and discard whatever follows it
+    #[default]
+    Ignore,
+    /// Refuse the request with a `trailing_bytes` error if anything but
+    /// whitespace follows it. The server waits for the client to half-close
+    /// before handling the request, so clients must shut down their write side.
+    Reject,
+    /// Read what follows as the next request and answer it on the same
+    /// connection, until the client half-closes. Bytes that aren't a valid
+    /// request are answered with an `InvalidRequest` error and close the
+    /// connection, like any malformed request.
+    NextFrame,
+}
+
+/// Reads complete messages from a stream one at a time.
+///
+/// A message is handed out as soon as it has fully arrived, so peers don't
+/// need to half-close before the message is processed. Bytes that arrive
+/// after the end of a message are kept for the next call. Compressed
+/// messages are decompressed; otherwise messages are handed out in the
+/// [`Codec`](crate::Codec) they arrived in.
+pub(crate) struct FrameReader {
+    buf: Vec<u8>,
+    /// Size of each read from the underlying stream
+    read_size: usize,
+    max_message_size: usize,
+    framing: Framing,
+    decompressor: Decompressor,
+    /// How far the JSON framing has looked into `buf` for the end of a message
+    scan: DocumentScan,
+}
+
+impl FrameReader {
+    /// Read responses in the framing and with the dictionary `config` sets
+    pub(crate) fn new(config: &SocketConfig) -> Self {
+        Self::with_decompressor(config, Decompressor::new(config))
+    }
+
+    /// Read requests in the framing `config` sets, accepting compressed ones
+    /// only if it configures compression
+    pub(crate) fn for_requests(config: &SocketConfig) -> Self {
+        Self::with_decompressor(config, Decompressor::for_requests(config))
+    }
+
+    fn with_decompressor(config: &SocketConfig, decompressor: Decompressor) -> Self {
+        Self {
+            buf: Vec::new(),
+            // A zero-length read would look like the peer closing
+            read_size: config.read_buffer_size.max(1),
+            max_message_size: config.max_message_size,
+            framing: config.framing,
+            decompressor,
+            scan: DocumentScan::default(),
+        }
+    }
+
+    /// Give up on framing and return whatever has been read past the last message
+    pub(crate) fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Read the next message. Returns `None` if the peer closed the
+    /// connection without sending anything further.
+    pub(crate) async fn next_frame<S>(&mut self, stream: &mut S) -> SocketResult<Option<Vec<u8>>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut chunk = vec![0u8; self.read_size];
+        loop {
+            match self.framing {
+                Framing::Json => {
+                    if let Some(end) = self.scan.advance(&self.buf)? {
+                        self.scan = DocumentScan::default();
+                        let message = self.buf.drain(..end).collect();
+                        return Ok(Some(self.decode(message)?));
+                    }
+                    check_size(self.buf.len(), self.max_message_size)?;
+                }
+                Framing::NdJson => {
+                    // Skip blank lines, e.g. a bare `\n` typed at a terminal
+                    while let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = self.buf.drain(..=end).collect();
+                        if !line.iter().all(u8::is_ascii_whitespace) {
+                            check_size(line.len(), self.max_message_size)?;
+                            return Ok(Some(line));
+                        }
+                    }
+                    check_size(self.buf.len(), self.max_message_size)?;
+                }
+                Framing::LengthPrefixed => {
+                    // Refuse on the declared length, before the body is buffered
+                    if let Some(len) = declared_len(&self.buf) {
+                        check_size(len, self.max_message_size)?;
+                    }
+                    if let Some(len) = complete_message_len(&self.buf) {
+                        let message = self.buf[PREFIX_LEN..PREFIX_LEN + len].to_vec();
+                        self.buf.drain(..PREFIX_LEN + len);
+                        return Ok(Some(self.decode(message)?));
+                    }
+                }
+            }
+
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                self.scan = DocumentScan::default();
+                return match self.framing {
+                    Framing::Json if self.buf.iter().all(u8::is_ascii_whitespace) => Ok(None),
+                    // A compressed body runs to the end of the stream
+                    Framing::Json if compression::is_compressed(&self.buf) => {
+                        let message = std::mem::take(&mut self.buf);
+                        Ok(Some(self.decode(message)?))
+                    }
+                    // Peer closed mid-document; let the parser report what's wrong
+                    Framing::Json => Ok(Some(std::mem::take(&mut self.buf))),
+                    Framing::NdJson if self.buf.iter().all(u8::is_ascii_whitespace) => Ok(None),
+                    // A last line without its newline
+                    Framing::NdJson => Ok(Some(std::mem::take(&mut self.buf))),
+                    Framing::LengthPrefixed if self.buf.is_empty() => Ok(None),
+                    Framing::LengthPrefixed => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                };
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Decompress a message as it arrived, if it is compressed
+    fn decode(&self, message: Vec<u8>) -> SocketResult<Vec<u8>> {
+        let message = match self.decompressor.decompress(&message)? {
+            std::borrow::Cow::Owned(decompressed) => decompressed,
+            std::borrow::Cow::Borrowed(_) => message,
+        };
+        check_size(message.len(), self.max_message_size)?;
+        Ok(message)
+    }
+}
+
+/// Prepare `message` for the wire, adding the length prefix or newline if
+/// `framing` uses one
+pub(crate) fn encode(framing: Framing, mut message: Vec<u8>) -> SocketResult<Vec<u8>> {
+    match framing {
+        Framing::Json => Ok(message),
+        Framing::NdJson => {
+            message.push(b'\n');
+            Ok(message)
+        }
+        Framing::LengthPrefixed => {
+            let mut framed = Vec::with_capacity(PREFIX_LEN + message.len());
+            framed.extend_from_slice(&prefix(message.len())?);
+            framed.extend_from_slice(&message);
+            Ok(framed)
+        }
+    }
+}
+
+fn prefix(len: usize) -> SocketResult<[u8; PREFIX_LEN]> {
+    let len = u32::try_from(len).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "message too large for a length prefix")
+    })?;
+    Ok(len.to_be_bytes())
+}
+
+/// Write `message` within `timeout`, preceded by its length or followed by a
+/// newline if `framing` uses one
+pub(crate) async fn write_message<W>(
+    stream: &mut W,
+    framing: Framing,
+    message: &[u8],
+    timeout: Option<Duration>,
+) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_prefix(stream, framing, message.len(), timeout).await?;
+    write_all_within(stream, message, timeout).await?;
+    write_terminator(stream, framing, timeout).await
+}
+
+/// Write the length prefix for a `len`-byte message whose body the caller
+/// writes itself, e.g. in chunks. Writes nothing if `framing` has no prefix.
+pub(crate) async fn write_prefix<W>(
+    stream: &mut W,
+    framing: Framing,
+    len: usize,
+    timeout: Option<Duration>,
+) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if framing == Framing::LengthPrefixed {
+        write_all_within(stream, &prefix(len)?, timeout).await?;
+    }
+    Ok(())
+}
+
+/// Write the newline ending a message whose body the caller wrote itself.
+/// Writes nothing if `framing` doesn't end messages with one.
+pub(crate) async fn write_terminator<W>(stream: &mut W, framing: Framing, timeout: Option<Duration>) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if framing == Framing::NdJson {
+        write_all_within(stream, b"\n", timeout).await?;
+    }
+    Ok(())
+}
+
+/// Read one length-prefixed message of at most 16 MiB from `stream`.
+///
+/// Fails with an `UnexpectedEof` I/O error if the stream ends first, and
+/// with [`SocketError::MessageTooLarge`] if the message is longer.
+pub async fn read_framed<S>(stream: &mut S) -> SocketResult<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_framed_with_limit(stream, DEFAULT_MAX_MESSAGE_SIZE).await
+}
+
+/// Read one length-prefixed message from `stream`, failing with
+/// [`SocketError::MessageTooLarge`] before reading its body if it is longer
+/// than `max_message_size`
+pub async fn read_framed_with_limit<S>(stream: &mut S, max_message_size: usize) -> SocketResult<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; PREFIX_LEN];
+    stream.read_exact(&mut prefix).await?;
+    let len = u32::from_be_bytes(prefix) as usize;
+    check_size(len, max_message_size)?;
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Fail with [`SocketError::MessageTooLarge`] if `size` is over `limit`
+pub(crate) fn check_size(size: usize, limit: usize) -> SocketResult<()> {
+    if size > limit {
+        return Err(SocketError::MessageTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+/// Write `message` to `stream` as one length-prefixed message
+pub async fn write_framed<W>(stream: &mut W, message: &[u8]) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let framed = encode(Framing::LengthPrefixed, message.to_vec())?;
+    stream.write_all(&framed).await?;
+    Ok(())
+}
+
+/// Write all of `bytes`, giving up once `timeout` has elapsed.
+///
+/// A timeout leaves the peer holding part of a message, so the caller must
+/// close the connection instead of writing anything further to it. How much
+/// was written is logged.
+pub(crate) async fn write_all_within<W>(stream: &mut W, bytes: &[u8], timeout: Option<Duration>) -> SocketResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(timeout) = timeout else {
+        stream.write_all(bytes).await?;
+        return Ok(());
+    };
+
+    let mut written = 0;
+    let write = async {
+        while written < bytes.len() {
+            match stream.write(&bytes[written..]).await? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                n => written += n,
+            }
+        }
+        Ok(())
+    };
+    match tokio::time::timeout(timeout, write).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            warn!(
+                "Write timed out after {} of {} bytes, closing connection",
+                written,
+                bytes.len()
+            );
+            Err(SocketError::ConnectionTimeout)
+        }
+    }
+}
+
+/// Body length the first length-prefixed message in `buf` declares, once its prefix has arrived
+fn declared_len(buf: &[u8]) -> Option<usize> {
+    let prefix: [u8; PREFIX_LEN] = buf.get(..PREFIX_LEN)?.try_into().ok()?;
+    Some(u32::from_be_bytes(prefix) as usize)
+}
+
+/// Body length of the first length-prefixed message in `buf`, if all of it has arrived
+fn complete_message_len(buf: &[u8]) -> Option<usize> {
+    let len = declared_len(buf)?;
+    (buf.len() >= PREFIX_LEN + len).then_some(len)
+}
+
+/// How far the message at the start of a [`FrameReader`]'s buffer has been
+/// scanned for its end.
+///
+/// Each read only scans the bytes it added, tracking just enough of the
+/// JSON structure to see where the document ends: how deeply objects and
+/// arrays are nested, and whether the scan is inside a string. The finished
+/// document is then parsed once, to report it if it is malformed.
+#[derive(Debug, Default)]
+struct DocumentScan {
+    /// Bytes of the buffer already scanned
+    pos: usize,
+    /// First byte of the document, once past any leading whitespace
+    first: Option<u8>,
+    /// Objects and arrays open at `pos`
+    depth: usize,
+    in_string: bool,
+    /// Whether the byte before `pos` is a backslash escaping the next one
+    escaped: bool,
+    #[cfg(feature = "msgpack")]
+    msgpack: codec::MsgpackScan,
+}
+
+impl DocumentScan {
+    /// Length of the first complete JSON document in `buf`, or MessagePack
+    /// message, if one has arrived. A compressed body is never complete
+    /// before the end of the stream. `buf` must be the same buffer as on
+    /// earlier calls, with bytes only added to its end.
+    fn advance(&mut self, buf: &[u8]) -> SocketResult<Option<usize>> {
+        // The first byte of gzip and zstd magic, which no JSON document starts with
+        if matches!(buf.first(), Some(0x1f | 0x28)) {
+            return Ok(None);
+        }
+        #[cfg(feature = "msgpack")]
+        if codec::is_msgpack(buf) {
+            return match self.msgpack.advance(buf)? {
+                Some(end) => Ok(Some(codec::msgpack_len(&buf[..end])?.unwrap_or(end))),
+                None => Ok(None),
+            };
+        }
+        match self.scan_json(buf) {
+            // Anything the scan took for a document the parser doesn't is handed out for it to report
+            Some(end) => Ok(Some(complete_json_len(&buf[..end])?.unwrap_or(end))),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the JSON document at the start of `buf` ends, if it has arrived
+    fn scan_json(&mut self, buf: &[u8]) -> Option<usize> {
+        while let Some(&byte) = buf.get(self.pos) {
+            self.pos += 1;
+            let Some(first) = self.first else {
+                match byte {
+                    b'{' | b'[' => self.depth = 1,
+                    b'"' => self.in_string = true,
+                    b'-' | b'0'..=b'9' | b't' | b'f' | b'n' => {}
+                    _ if byte.is_ascii_whitespace() => continue,
+                    // Not the start of any value; let the parser say so straight away
+                    _ => return Some(self.pos),
+                }
+                self.first = Some(byte);
+                continue;
+            };
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if first == b'"' {
+                        return Some(self.pos);
+                    }
+                }
+                continue;
+            }
+            match (first, byte) {
+                (b'{' | b'[', b'"') => self.in_string = true,
+                (b'{' | b'[', b'{' | b'[') => self.depth += 1,
+                (b'{' | b'[', b'}' | b']') => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(self.pos);
+                    }
+                }
+                (b'{' | b'[', _) => {}
+                // A number or literal runs until a byte that can't be part of one
+                _ if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'+' | b'.') => {}
+                _ => return Some(self.pos - 1),
+            }
+        }
+        None
+    }
+}
+
+/// Length of the first JSON document in `buf`, parsing it in full
+fn complete_json_len(buf: &[u8]) -> SocketResult<Option<usize>> {
+    let mut documents = serde_json::Deserializer::from_slice(buf).into_iter::<IgnoredAny>();
+    match documents.next() {
+        Some(Ok(_)) => Ok(Some(documents.byte_offset())),
+        Some(Err(e)) if e.is_eof() => Ok(None),
+        Some(Err(e)) => Err(e.into()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_splits_back_to_back_documents() {
+        let mut input: &[u8] = br#"{"a":1} {"b":"}"}"#;
+        let mut reader = FrameReader::new(&SocketConfig::default());
+        assert_eq!(reader.next_frame(&mut input).await.unwrap().unwrap(), br#"{"a":1}"#);
+        assert_eq!(reader.next_frame(&mut input).await.unwrap().unwrap(), br#" {"b":"}"}"#);
+        assert!(reader.next_frame(&mut input).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_documents_split_across_reads() {
+        let wire = br#"{"a":"}\"]","b":[1,{"c":null}]} "x\"" 42 ["#;
+        let (mut writer, mut stream) = tokio::io::duplex(1);
+        let feed = async move {
+            writer.write_all(wire).await.unwrap();
+        };
+
+        let mut reader = FrameReader::new(&SocketConfig::default());
+        let read = async {
+            let mut frames = Vec::new();
+            for _ in 0..3 {
+                frames.push(reader.next_frame(&mut stream).await.unwrap().unwrap());
+            }
+            frames
+        };
+        let ((), frames) = tokio::join!(feed, read);
+        assert_eq!(frames[0], br#"{"a":"}\"]","b":[1,{"c":null}]}"#);
+        assert_eq!(frames[1], br#" "x\"""#);
+        assert_eq!(frames[2], b" 42");
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_messages_split_across_reads() {
+        let mut wire = encode(Framing::LengthPrefixed, vec![b'x'; 20_000]).unwrap();
+        wire.extend(encode(Framing::LengthPrefixed, b"not json".to_vec()).unwrap());
+        let (mut writer, mut stream) = tokio::io::duplex(1000);
+        let feed = async move {
+            for piece in wire.chunks(777) {
+                writer.write_all(piece).await.unwrap();
+            }
+        };
+
+        let mut reader = FrameReader::new(&SocketConfig {
+            framing: Framing::LengthPrefixed,
+            ..SocketConfig::default()
+        });
+        let read = async {
+            let first = reader.next_frame(&mut stream).await.unwrap().unwrap();
+            let second = reader.next_frame(&mut stream).await.unwrap().unwrap();
+            let end = reader.next_frame(&mut stream).await.unwrap();
+            (first, second, end)
+        };
+        let ((), (first, second, end)) = tokio::join!(feed, read);
+        assert_eq!(first, vec![b'x'; 20_000]);
+        assert_eq!(second, b"not json");
+        assert!(end.is_none());
+
+        let mut truncated: &[u8] = &[0, 0, 0, 9, b'{'];
+        assert!(read_framed(&mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_lines_split_across_reads() {
+        let (mut writer, mut stream) = tokio::io::duplex(1000);
+        let feed = async move {
+            for piece in [&b"{\"a\":"[..], b"1}\n\n{\"b\"", b":2}\n  \n{\"c\":3}"] {
+                writer.write_all(piece).await.unwrap();
+            }
+        };
+
+        let mut reader = FrameReader::new(&SocketConfig {
+            framing: Framing::NdJson,
+            ..SocketConfig::default()
+        });
+        let read = async {
+            let mut frames = Vec::new();
+            while let Some(frame) = reader.next_frame(&mut stream).await.unwrap() {
+                frames.push(frame);
+            }
+            frames
+        };
+        let ((), frames) = tokio::join!(feed, read);
+        // Blank lines are skipped and the last line needn't end in a newline
+        assert_eq!(frames, [&b"{\"a\":1}\n"[..], b"{\"b\":2}\n", b"{\"c\":3}"]);
+        assert_eq!(encode(Framing::NdJson, b"{}".to_vec()).unwrap(), b"{}\n");
+    }
+
+    #[tokio::test]
+    async fn test_small_read_buffer_reassembles_message() {
+        let message = format!(r#"{{"value":"{}"}}"#, "y".repeat(1000));
+        let mut input = message.as_bytes();
+        let mut reader = FrameReader::new(&SocketConfig {
+            read_buffer_size: 16,
+            ..SocketConfig::default()
+        });
+        assert_eq!(reader.next_frame(&mut input).await.unwrap().unwrap(), message.as_bytes());
+        assert!(reader.next_frame(&mut input).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_declared_length_is_refused() {
+        let config = SocketConfig {
+            framing: Framing::LengthPrefixed,
+            max_message_size: 1024,
+            ..SocketConfig::default()
+        };
+        // Only the prefix is sent, so anything but an immediate refusal would
+        // wait for (and buffer) the 4 GiB body or fail on the missing bytes
+        let mut input: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        let result = FrameReader::new(&config).next_frame(&mut input).await;
+        assert!(matches!(
+            result,
+            Err(SocketError::MessageTooLarge { size: 0xffff_ffff, limit: 1024 })
+        ));
+
+        let mut input: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(read_framed(&mut input).await, Err(SocketError::MessageTooLarge { .. })));
+
+        let mut input: &[u8] = &[0, 0, 4, 1];
+        let result = read_framed_with_limit(&mut input, 1024).await;
+        assert!(matches!(result, Err(SocketError::MessageTooLarge { size: 1025, limit: 1024 })));
+
+        let document = format!(r#"{{"value":"{}"}}"#, "z".repeat(2000));
+        let mut input = document.as_bytes();
+        let result = FrameReader::new(&SocketConfig {
+            max_message_size: 1024,
+            ..SocketConfig::default()
+        })
+        .next_frame(&mut input)
+        .await;
+        assert!(matches!(result, Err(SocketError::MessageTooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout_on_stalled_reader() {
+        let (mut writer, _reader) = tokio::io::duplex(64);
+        let result = write_all_within(&mut writer, &[0u8; 1024], Some(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(SocketError::ConnectionTimeout)));
+
+        let (mut writer, _reader) = tokio::io::duplex(64);
+        write_all_within(&mut writer, &[0u8; 64], Some(Duration::from_millis(50))).await.unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+//! Handlers implemented as types rather than closures.
+//!
+//! A type implementing [`Handler`] can hold whatever state it needs in its
+//! fields, and its [`handle`](Handler::handle) method can be called directly
+//! in unit tests, without a server or socket.
+
+use crate::{SocketPayload, SocketResponse, SocketResult};
+use async_trait::async_trait;
+
+/// Answers the requests for a command, registered with
+/// [`SocketServer::register_handler_trait`](crate::SocketServer::register_handler_trait).
+///
+/// ```ignore
+/// struct Counter {
+///     count: AtomicU64,
+/// }
+///
+/// #[async_trait]
+/// impl Handler<Increment, u64> for Counter {
+///     async fn handle(&self, payload: SocketPayload<Increment, u64>) -> SocketResult<SocketResponse<u64>> {
+///         let count = self.count.fetch_add(payload.data.by, Ordering::SeqCst) + payload.data.by;
+///         Ok(SocketResponse::success(payload.request_id, count))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Handler<T: Send + 'static, R: Send + 'static>: Send + Sync + 'static {
+    /// Answer `payload`. Runs on the connection's task like an
+    /// [`AsyncHandler`](crate::AsyncHandler), so it may await other I/O.
+    async fn handle(&self, payload: SocketPayload<T, R>) -> SocketResult<SocketResponse<R>>;
+}
@@ -0,0 +1,209 @@
+//! Optional connection handshake.
+//!
+//! A client may open a connection with a handshake message before sending
+//! its request; the server replies with a [`ServerInfo`]. Clients that skip
+//! the handshake are served exactly as before.
+
+use crate::codec;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Version of this crate, exchanged in the handshake
+pub(crate) const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Version of the wire protocol, exchanged in the handshake. It goes up
+/// whenever a change to framing, codecs or message layout would leave peers
+/// on different versions misreading each other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this crate still speaks
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Sent by a client as the first message on a connection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Self-reported client name, shown in logs and `active_connections()`
+    pub client_name: Option<String>,
+    /// Keep the connection open for any number of requests, handled
+    /// concurrently and answered as each completes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub multiplex: bool,
+    /// On a multiplexed connection, send responses in data frames within
+    /// per-request windows the client replenishes with window updates
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub flow_control: bool,
+    /// Version of the crate the client was built with
+    #[serde(default)]
+    pub crate_version: Option<String>,
+    /// ID of the compression dictionary the client expects responses to use
+    #[serde(default)]
+    pub dictionary_id: Option<String>,
+    /// Fingerprint of the request and response types the client was built with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub type_fingerprint: Option<String>,
+    /// Shared secret from `SocketConfig::auth_token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+    /// Newest [`PROTOCOL_VERSION`] the client speaks; `None` for clients
+    /// that predate versioning, which are served as version 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+}
+
+/// The server's reply to a [`Handshake`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Identifier the server assigned to this connection
+    pub connection_id: u64,
+    /// Version of the crate the server was built with; `None` for servers
+    /// that predate it
+    #[serde(default)]
+    pub crate_version: Option<String>,
+    /// ID of the server's compression dictionary, if it has one
+    #[serde(default)]
+    pub dictionary_id: Option<String>,
+    /// Initial per-request window, when flow control was agreed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_window_size: Option<u32>,
+    /// Fingerprint of the request and response types the server was built with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub type_fingerprint: Option<String>,
+    /// Protocol version the connection uses, when the client sent one. If
+    /// the server can't speak the client's version, this is the server's
+    /// newest and the connection is closed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+}
+
+/// Wire envelope distinguishing handshake messages from request payloads
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HandshakeFrame<H> {
+    pub(crate) handshake: H,
+}
+
+impl HandshakeFrame<Handshake> {
+    /// Parse `frame` as a client handshake. A batch never qualifies: serde
+    /// would otherwise accept a one-element batch array as the struct.
+    pub(crate) fn parse(frame: &[u8]) -> Option<Handshake> {
+        if codec::is_array(frame) {
+            return None;
+        }
+        codec::decode::<Self>(frame).ok().map(|frame| frame.handshake)
+    }
+}
+
+/// Log a warning if a peer runs a semver-incompatible version of this crate
+pub(crate) fn check_peer_version(peer: &str, peer_version: Option<&str>) {
+    let Some(peer_version) = peer_version else {
+        return;
+    };
+    if !semver_compatible(CRATE_VERSION, peer_version) {
+        warn!(
+            "{} runs circle-socket {}, incompatible with local version {}",
+            peer, peer_version, CRATE_VERSION
+        );
+    }
+}
+
+/// Protocol version to use with a peer whose newest is `peer`: the newer
+/// side downgrades to the older one's. `None` if that is older than this
+/// crate still speaks.
+pub(crate) fn negotiate_version(peer: u32) -> Option<u32> {
+    let version = peer.min(PROTOCOL_VERSION);
+    (version >= MIN_PROTOCOL_VERSION).then_some(version)
+}
+
+/// Fingerprint of the request type `T` and response type `R`, for
+/// `SocketConfig::type_fingerprint`. It is a hash of both types' JSON
+/// schemas, so it changes whenever a field is added, removed, renamed or
+/// changes type.
+#[cfg(feature = "schema")]
+pub fn type_fingerprint<T: schemars::JsonSchema, R: schemars::JsonSchema>() -> String {
+    let schemas = serde_json::json!([schemars::schema_for!(T), schemars::schema_for!(R)]);
+    format!("{:016x}", fnv1a(schemas.to_string().as_bytes()))
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is the same in every build
+#[cfg(feature = "schema")]
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Whether a client's token equals the configured one, taking the same time
+/// wherever the first difference is so the comparison leaks nothing about it
+pub(crate) fn token_matches(expected: &str, given: Option<&str>) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Whether two versions share a major version (the minor version too, below 1.0)
+fn semver_compatible(a: &str, b: &str) -> bool {
+    let significant = |version: &str| {
+        let mut parts = version.split('.');
+        let major = parts.next().unwrap_or_default().to_string();
+        match major.as_str() {
+            "0" => format!("0.{}", parts.next().unwrap_or_default()),
+            _ => major,
+        }
+    };
+    significant(a) == significant(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_semver_compatible() {
+        assert!(semver_compatible("1.2.3", "1.9.0"));
+        assert!(!semver_compatible("1.2.3", "2.0.0"));
+        assert!(semver_compatible("0.3.1", "0.3.7"));
+        assert!(!semver_compatible("0.3.1", "0.4.0"));
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("s3cret", Some("s3cret")));
+        assert!(!token_matches("s3cret", Some("s3cres")));
+        assert!(!token_matches("s3cret", Some("s3cret2")));
+        assert!(!token_matches("s3cret", None));
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_type_fingerprint_tracks_fields() {
+        #[allow(dead_code)]
+        mod v1 {
+            #[derive(schemars::JsonSchema)]
+            pub struct Request {
+                pub name: String,
+            }
+        }
+        #[allow(dead_code)]
+        mod v2 {
+            #[derive(schemars::JsonSchema)]
+            pub struct Request {
+                pub name: String,
+                pub force: bool,
+            }
+        }
+
+        let fingerprint = type_fingerprint::<v1::Request, String>();
+        assert_eq!(fingerprint, type_fingerprint::<v1::Request, String>());
+        assert_eq!(fingerprint.len(), 16);
+        assert_ne!(fingerprint, type_fingerprint::<v2::Request, String>());
+        assert_ne!(fingerprint, type_fingerprint::<v1::Request, u64>());
+    }
+}
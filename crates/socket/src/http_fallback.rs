@@ -0,0 +1,100 @@
+//! Forwarding commands without a handler to an HTTP backend.
+//!
+//! A server configured with an [`HttpFallback`] answers a command it has no
+//! handler for by POSTing the payload's data as JSON to the URL mapped to
+//! that command. A 2xx response body becomes the data of a success
+//! response; any other status becomes an error response carrying the status
+//! and body.
+
+use crate::{SocketError, SocketResponse, SocketResult};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Maps commands without a registered handler to HTTP endpoints
+#[derive(Debug, Clone, Default)]
+pub struct HttpFallback {
+    base_url: Option<String>,
+    routes: HashMap<String, String>,
+    client: reqwest::Client,
+}
+
+impl HttpFallback {
+    /// Forward every unhandled command to `{base_url}/{command}`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: Some(base_url.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Forward `command` to `url`, taking precedence over the base URL
+    pub fn route(mut self, command: impl Into<String>, url: impl Into<String>) -> Self {
+        self.routes.insert(command.into(), url.into());
+        self
+    }
+
+    /// URL `command` is forwarded to, if any
+    pub fn url_for(&self, command: &str) -> Option<String> {
+        if let Some(url) = self.routes.get(command) {
+            return Some(url.clone());
+        }
+        let base_url = self.base_url.as_deref()?;
+        Some(format!("{}/{}", base_url.trim_end_matches('/'), command))
+    }
+
+    /// POST `data` to `url` and turn the HTTP response into a socket response
+    pub(crate) async fn forward<T, R>(
+        &self,
+        url: &str,
+        request_id: &str,
+        data: &T,
+        timeout: Duration,
+    ) -> SocketResult<SocketResponse<R>>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let response = self
+            .client
+            .post(url)
+            .timeout(timeout)
+            .json(data)
+            .send()
+            .await
+            .map_err(http_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(http_error)?;
+        if !status.is_success() {
+            let body = String::from_utf8_lossy(&body);
+            return Ok(SocketResponse::error(
+                request_id,
+                format!("http_fallback: {} returned {}: {}", url, status, body.trim()),
+            ));
+        }
+        Ok(SocketResponse::success(request_id, serde_json::from_slice(&body)?))
+    }
+}
+
+fn http_error(e: reqwest::Error) -> SocketError {
+    if e.is_timeout() {
+        return SocketError::ConnectionTimeout;
+    }
+    SocketError::Io(std::io::Error::other(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_for() {
+        let fallback = HttpFallback::new("http://127.0.0.1:8080/rpc/").route("deploy", "http://deployer/run");
+        assert_eq!(fallback.url_for("status").as_deref(), Some("http://127.0.0.1:8080/rpc/status"));
+        assert_eq!(fallback.url_for("deploy").as_deref(), Some("http://deployer/run"));
+
+        let routes_only = HttpFallback::default().route("deploy", "http://deployer/run");
+        assert_eq!(routes_only.url_for("status"), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Tracking of requests whose handlers are currently running.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A request whose handler is currently executing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InflightRequest {
+    /// Command being handled
+    pub command: String,
+    /// ID of the request being handled
+    pub request_id: String,
+    /// Connection the request arrived on
+    pub connection_id: u64,
+    /// How long the handler has been running, in milliseconds
+    pub age_ms: u64,
+}
+
+struct Entry {
+    command: String,
+    request_id: String,
+    connection_id: u64,
+    started: Instant,
+}
+
+/// Registry of executing requests shared between the server and its handles
+#[derive(Clone, Default)]
+pub(crate) struct InflightRegistry {
+    next_id: Arc<AtomicU64>,
+    entries: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl InflightRegistry {
+    /// Record a request as executing until the returned guard is dropped
+    pub(crate) fn start(&self, command: &str, request_id: &str, connection_id: u64) -> InflightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            command: command.to_string(),
+            request_id: request_id.to_string(),
+            connection_id,
+            started: Instant::now(),
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        InflightGuard {
+            id,
+            registry: self.clone(),
+        }
+    }
+
+    /// Snapshot of executing requests, longest-running first
+    pub(crate) fn list(&self) -> Vec<InflightRequest> {
+        let entries = self.entries.lock().unwrap();
+        let mut requests: Vec<_> = entries
+            .values()
+            .map(|entry| InflightRequest {
+                command: entry.command.clone(),
+                request_id: entry.request_id.clone(),
+                connection_id: entry.connection_id,
+                age_ms: entry.started.elapsed().as_millis() as u64,
+            })
+            .collect();
+        requests.sort_by_key(|request| std::cmp::Reverse(request.age_ms));
+        requests
+    }
+}
+
+/// Keeps a request listed as in-flight while its handler runs
+pub(crate) struct InflightGuard {
+    id: u64,
+    registry: InflightRegistry,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.registry.entries.lock().unwrap().remove(&self.id);
+    }
+}
@@ -1,12 +1,124 @@
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::RwLock;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
+mod accept_gate;
+pub mod admin;
+mod builder;
+mod codec;
+mod command;
+mod compression;
+mod connection;
+mod connections;
+mod context;
+mod dedup;
+mod download;
+mod drain;
+mod envelope;
+mod events;
+mod flow_control;
+mod framing;
+mod handler;
+mod handshake;
+#[cfg(feature = "http-fallback")]
+mod http_fallback;
+mod inflight;
+mod listeners;
+mod log_level;
+mod log_throttle;
+mod metrics;
+mod pool;
+mod prometheus;
+mod rate_limit;
+mod readiness;
+mod retry;
+mod response_stream;
+mod self_test;
+#[cfg(feature = "signing")]
+mod signing;
+mod snapshot;
+#[cfg(unix)]
+mod socket_file;
+mod streaming;
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+mod upgrade;
+mod worker_pool;
+
+pub use async_trait::async_trait;
+pub use builder::SocketServerBuilder;
+pub use codec::Codec;
+pub use compression::Compression;
+pub use command::Command;
+pub use connection::Connection;
+pub use connections::{ConnectionInfo, PeerCredentials};
+pub use context::{CancellationToken, Extensions, RequestContext};
+pub use dedup::{DedupEntry, DedupStore, MemoryDedupStore};
+pub use envelope::JsonEnvelope;
+pub use events::Subscription;
+pub use flow_control::{DataHeader, WindowUpdate};
+pub use framing::{read_framed, read_framed_with_limit, write_framed, Framing, TrailingBytes};
+pub use handler::Handler;
+pub use handshake::{Handshake, ServerInfo, PROTOCOL_VERSION};
+pub use inflight::InflightRequest;
+pub use log_level::LogLevel;
+pub use metrics::MetricsSink;
+pub use pool::{PooledConnection, SocketClientPool};
+pub use prometheus::{CommandMetrics, LatencyHistogram, SocketMetrics};
+pub use response_stream::ResponseStream;
+pub use rate_limit::RateLimit;
+pub use retry::RetryConfig;
+pub use self_test::{CheckOutcome, CommandCheck, SelfTestReport};
+#[cfg(feature = "zstd")]
+pub use compression::CompressionDictionary;
+#[cfg(feature = "signing")]
+pub use signing::SigningConfig;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+#[cfg(feature = "http-fallback")]
+pub use http_fallback::HttpFallback;
+#[cfg(feature = "schema")]
+pub use handshake::type_fingerprint;
+pub use snapshot::ServerSnapshot;
+pub use transport::{Transport, TransportStream};
+pub use upgrade::UpgradedStream;
+pub use download::DownloadSink;
+pub use streaming::ResponseSink;
+#[cfg(feature = "macros")]
+pub use circle_socket_macros::Commands;
+
+/// Paths the code generated by `#[derive(Commands)]` relies on
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json::Value;
+}
+pub use worker_pool::WorkerPool;
+
+use connections::{ConnectionGuard, ConnectionRegistry};
+use admin::RequestHeader;
+use flow_control::{WindowUpdateFrame, WriterEvent};
+use framing::FrameReader;
+use handshake::HandshakeFrame;
+use inflight::InflightRegistry;
+use listeners::{ListenerChange, ListenerControl};
+use log_level::command_log;
+use log_throttle::LogThrottle;
+use rate_limit::RateLimiter;
+use retry::{Failed, Phase};
+use readiness::Readiness;
+use accept_gate::{AcceptGate, Admission};
+use drain::ConnectionTasks;
+use transport::Hangup;
+use events::{EventBus, Subscriber};
+
 /// Errors that can occur during socket operations
 #[derive(Error, Debug)]
 pub enum SocketError {
@@ -16,12 +128,26 @@ pub enum SocketError {
     Serialization(#[from] serde_json::Error),
     #[error("Socket already exists at path: {0}")]
     AlreadyExists(PathBuf),
+    #[error("No space left on the filesystem to create the socket at {0}")]
+    DiskFull(PathBuf),
     #[error("Connection timed out")]
     ConnectionTimeout,
     #[error("Request handler not found for command: {0}")]
     HandlerNotFound(String),
-    #[error("Invalid request format")]
-    InvalidRequest,
+    #[error("Invalid request format: {0}")]
+    InvalidRequest(String),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("Server returned an error: {0}")]
+    ServerError(String),
+    #[error("Message of {size} bytes exceeds the limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+    #[error("Protocol version mismatch: client speaks up to {client}, server chose {server}")]
+    VersionMismatch { client: u32, server: u32 },
+    #[error("Type fingerprint mismatch: client has {client}, server has {server}; rebuild both against the same types")]
+    TypeMismatch { client: String, server: String },
+    #[error("Response is for request {got}, expected {expected}")]
+    ResponseMismatch { expected: String, got: String },
 }
 
 /// Result type for socket operations
@@ -36,6 +162,21 @@ pub struct SocketPayload<T, R> {
     pub command: String,
     /// The actual payload data
     pub data: T,
+    /// Synthetic request from a self-test; handlers should skip side effects
+    pub dry_run: bool,
+    /// Last request on a persistent connection: the server closes the
+    /// connection once this request's response is written
+    pub close_after: bool,
+    /// Name of the [`Codec`] the response should be encoded in. The server
+    /// answers in its default codec if it doesn't support this one.
+    pub accept_codec: Option<String>,
+    /// Run the handler at most once for requests with this key; retries get
+    /// the first response again. See [`DedupStore`].
+    pub idempotency_key: Option<String>,
+    /// Fire and forget with an acknowledgement: the server answers with an
+    /// [`ResponseKind::Ack`] response as soon as the request parses, then
+    /// runs the handler without sending its response
+    pub ack: bool,
     /// Expected response type marker
     _phantom: std::marker::PhantomData<R>,
 }
@@ -49,10 +190,39 @@ where
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("SocketPayload", 3)?;
+        let len = 3
+            + usize::from(self.dry_run)
+            + usize::from(self.close_after)
+            + usize::from(self.accept_codec.is_some())
+            + usize::from(self.idempotency_key.is_some())
+            + usize::from(self.ack);
+        let mut state = serializer.serialize_struct("SocketPayload", len)?;
         state.serialize_field("request_id", &self.request_id)?;
         state.serialize_field("command", &self.command)?;
         state.serialize_field("data", &self.data)?;
+        if self.dry_run {
+            state.serialize_field("dry_run", &self.dry_run)?;
+        } else {
+            state.skip_field("dry_run")?;
+        }
+        if self.close_after {
+            state.serialize_field("close_after", &self.close_after)?;
+        } else {
+            state.skip_field("close_after")?;
+        }
+        match &self.accept_codec {
+            Some(codec) => state.serialize_field("accept_codec", codec)?,
+            None => state.skip_field("accept_codec")?,
+        }
+        match &self.idempotency_key {
+            Some(key) => state.serialize_field("idempotency_key", key)?,
+            None => state.skip_field("idempotency_key")?,
+        }
+        if self.ack {
+            state.serialize_field("ack", &self.ack)?;
+        } else {
+            state.skip_field("ack")?;
+        }
         state.end()
     }
 }
@@ -70,6 +240,16 @@ where
             request_id: String,
             command: String,
             data: T,
+            #[serde(default)]
+            dry_run: bool,
+            #[serde(default)]
+            close_after: bool,
+            #[serde(default)]
+            accept_codec: Option<String>,
+            #[serde(default)]
+            idempotency_key: Option<String>,
+            #[serde(default)]
+            ack: bool,
         }
 
         let data = SocketPayloadData::<T>::deserialize(deserializer)?;
@@ -77,6 +257,11 @@ where
             request_id: data.request_id,
             command: data.command,
             data: data.data,
+            dry_run: data.dry_run,
+            close_after: data.close_after,
+            accept_codec: data.accept_codec,
+            idempotency_key: data.idempotency_key,
+            ack: data.ack,
             _phantom: std::marker::PhantomData,
         })
     }
@@ -85,13 +270,30 @@ where
 impl<T, R> SocketPayload<T, R> {
     /// Create a new socket payload
     pub fn new(command: impl Into<String>, data: T) -> Self {
+        Self::with_request_id(command, Uuid::new_v4().to_string(), data)
+    }
+
+    /// Create a payload with the given request ID instead of a random one,
+    /// e.g. for reproducible tests or to reuse an external trace ID
+    pub fn with_request_id(command: impl Into<String>, request_id: impl Into<String>, data: T) -> Self {
         Self {
-            request_id: Uuid::new_v4().to_string(),
+            request_id: request_id.into(),
             command: command.into(),
             data,
+            dry_run: false,
+            close_after: false,
+            accept_codec: None,
+            idempotency_key: None,
+            ack: false,
             _phantom: std::marker::PhantomData,
         }
     }
+
+    /// Have the server run the handler at most once for `key`
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
 }
 
 /// Response sent back through the socket
@@ -105,6 +307,102 @@ pub struct SocketResponse<R> {
     pub data: Option<R>,
     /// Error message if any
     pub error: Option<String>,
+    /// Machine-readable error code, such as `not_found`, for clients to
+    /// match on instead of the message
+    pub code: Option<String>,
+    /// Marks a response that changes what happens to the connection next
+    pub kind: Option<ResponseKind>,
+}
+
+/// Special meanings a response can carry beyond success or failure
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseKind {
+    /// The connection is now a raw byte pipe; see [`UpgradedStream`]
+    Upgrade,
+    /// Binary chunks written to a [`DownloadSink`] follow this response
+    Download,
+    /// The connection now carries broadcast events; see [`Subscription`]
+    Subscribed,
+    /// Chunks sent through a [`ResponseSink`] follow this response, each a
+    /// success response of its own
+    Stream,
+    /// Ends the chunks of a streaming response: the handler finished successfully
+    StreamEnd,
+    /// A request sent with [`SocketPayload::ack`] was received and parsed;
+    /// its handler runs without the client waiting for the response
+    Ack,
+    /// The request should be sent to another server instead
+    Redirect {
+        /// Where the server that handles the request listens
+        target: RedirectTarget,
+    },
+}
+
+/// Where a [`ResponseKind::Redirect`] sends the client
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectTarget {
+    /// A Unix socket path, or a named pipe on Windows
+    Path(PathBuf),
+    /// A TCP address, reached over TLS if the client is configured for it
+    Addr(std::net::SocketAddr),
+}
+
+impl RedirectTarget {
+    /// `config` pointed at this target instead, keeping its other settings
+    fn apply(&self, config: &SocketConfig) -> SocketConfig {
+        match self {
+            Self::Path(path) => SocketConfig {
+                socket_path: path.clone(),
+                transport: Transport::default(),
+                ..config.clone()
+            },
+            Self::Addr(addr) => SocketConfig {
+                transport: Transport::Tcp(*addr),
+                ..config.clone()
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for RedirectTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => path.display().fmt(f),
+            Self::Addr(addr) => addr.fmt(f),
+        }
+    }
+}
+
+impl From<PathBuf> for RedirectTarget {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<&PathBuf> for RedirectTarget {
+    fn from(path: &PathBuf) -> Self {
+        Self::Path(path.clone())
+    }
+}
+
+impl From<&Path> for RedirectTarget {
+    fn from(path: &Path) -> Self {
+        Self::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for RedirectTarget {
+    fn from(path: &str) -> Self {
+        Self::Path(PathBuf::from(path))
+    }
+}
+
+impl From<std::net::SocketAddr> for RedirectTarget {
+    fn from(addr: std::net::SocketAddr) -> Self {
+        Self::Addr(addr)
+    }
 }
 
 impl<R> serde::Serialize for SocketResponse<R>
@@ -116,11 +414,20 @@ where
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("SocketResponse", 4)?;
+        let len = 4 + usize::from(self.code.is_some()) + usize::from(self.kind.is_some());
+        let mut state = serializer.serialize_struct("SocketResponse", len)?;
         state.serialize_field("request_id", &self.request_id)?;
         state.serialize_field("success", &self.success)?;
         state.serialize_field("data", &self.data)?;
         state.serialize_field("error", &self.error)?;
+        match &self.code {
+            Some(code) => state.serialize_field("code", code)?,
+            None => state.skip_field("code")?,
+        }
+        match &self.kind {
+            Some(kind) => state.serialize_field("kind", kind)?,
+            None => state.skip_field("kind")?,
+        }
         state.end()
     }
 }
@@ -139,6 +446,10 @@ where
             success: bool,
             data: Option<R>,
             error: Option<String>,
+            #[serde(default)]
+            code: Option<String>,
+            #[serde(default)]
+            kind: Option<ResponseKind>,
         }
 
         let data = SocketResponseData::<R>::deserialize(deserializer)?;
@@ -147,6 +458,8 @@ where
             success: data.success,
             data: data.data,
             error: data.error,
+            code: data.code,
+            kind: data.kind,
         })
     }
 }
@@ -159,6 +472,94 @@ impl<R> SocketResponse<R> {
             success: true,
             data: Some(data),
             error: None,
+            code: None,
+            kind: None,
+        }
+    }
+
+    /// Create a response telling the client the connection has been upgraded
+    pub fn upgrade(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::Upgrade),
+        }
+    }
+
+    /// Create a response announcing that a download's chunks follow
+    pub fn download(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::Download),
+        }
+    }
+
+    /// Create a response announcing that a streaming response's chunks follow
+    pub fn stream(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::Stream),
+        }
+    }
+
+    /// Create a response ending a streaming response's chunks
+    pub fn stream_end(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::StreamEnd),
+        }
+    }
+
+    /// Create a response acknowledging a fire-and-forget request
+    pub fn ack(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::Ack),
+        }
+    }
+
+    /// Create a response confirming a subscription to broadcast events
+    pub fn subscribed(request_id: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: Some(ResponseKind::Subscribed),
+        }
+    }
+
+    /// Create a response sending the client to the server at `target`, a
+    /// socket path or a TCP address.
+    ///
+    /// Clients configured with `with_max_redirects` re-send the request
+    /// there automatically. Others get it back as an error with the code
+    /// `redirect`, naming the target.
+    pub fn redirect(request_id: impl Into<String>, target: impl Into<RedirectTarget>) -> Self {
+        let target = target.into();
+        Self {
+            kind: Some(ResponseKind::Redirect { target: target.clone() }),
+            ..Self::error_with_code(request_id, "redirect", format!("redirect: send the request to {}", target))
         }
     }
 
@@ -169,25 +570,353 @@ impl<R> SocketResponse<R> {
             success: false,
             data: None,
             error: Some(error.into()),
+            code: None,
+            kind: None,
+        }
+    }
+
+    /// Create a success response from `Ok` data, or an error response
+    /// carrying the message of the `Err`
+    pub fn from_result<E: std::fmt::Display>(request_id: impl Into<String>, result: Result<R, E>) -> Self {
+        match result {
+            Ok(data) => Self::success(request_id, data),
+            Err(e) => Self::error(request_id, e.to_string()),
+        }
+    }
+
+    /// Create an error response with a machine-readable `code` alongside
+    /// the message
+    pub fn error_with_code(request_id: impl Into<String>, code: impl Into<String>, error: impl Into<String>) -> Self {
+        Self {
+            code: Some(code.into()),
+            ..Self::error(request_id, error)
+        }
+    }
+
+    /// Whether this claims success but carries no data.
+    ///
+    /// `success` only builds responses with data, but a hand-built response
+    /// can still be successful with `data: None`. Servers with
+    /// `strict_responses` enabled turn these into errors before sending.
+    pub fn is_success_without_data(&self) -> bool {
+        self.success && self.data.is_none() && self.kind.is_none()
+    }
+
+    /// Convert into the response data, or an error describing why there is none.
+    ///
+    /// Error responses become [`SocketError::ServerError`]; a successful
+    /// response without data becomes [`SocketError::InvalidResponse`] rather
+    /// than something callers have to `unwrap`.
+    pub fn into_result(self) -> SocketResult<R> {
+        if !self.success {
+            return Err(SocketError::ServerError(
+                self.error.unwrap_or_else(|| "unknown error".to_string()),
+            ));
         }
+        self.data.ok_or_else(|| {
+            SocketError::InvalidResponse(format!(
+                "successful response for request ID {} has no data",
+                self.request_id
+            ))
+        })
     }
 }
 
+/// What the server does with a response whose serialized size exceeds
+/// [`SocketConfig::large_response_threshold`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum LargeResponsePolicy {
+    /// Send the response as-is
+    #[default]
+    Allow,
+    /// Write the response in threshold-sized chunks, flushing between them so
+    /// a slow reader pushes back on the server instead of one huge write.
+    /// The response is still serialized in full first; handlers whose
+    /// responses shouldn't be held in memory at once belong with
+    /// [`SocketServer::register_streaming_handler`] or
+    /// [`SocketServer::register_download_handler`].
+    Stream,
+    /// Gzip the response body; clients decompress it transparently. Where the
+    /// framing can't delimit a compressed body, i.e. with
+    /// [`Framing::NdJson`], or with `Framing::Json`
+    /// on a connection carrying several responses, the response is sent
+    /// uncompressed and a warning is logged.
+    Compress,
+    /// Replace the response with a `response_too_large` error
+    Error,
+}
+
+/// What `SocketServer::run` does when a file already exists at the socket path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum ExistingSocketPolicy {
+    /// Remove whatever is there and bind anyway
+    Overwrite,
+    /// Fail with [`SocketError::AlreadyExists`]
+    FailIfExists,
+    /// Remove the file only if it is a socket no server is listening on;
+    /// otherwise fail with [`SocketError::AlreadyExists`]
+    #[default]
+    ReplaceIfStale,
+}
+
+/// What the server does with a new connection once
+/// [`SocketConfig::max_connections`] are open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub enum ConnectionLimitPolicy {
+    /// Stop accepting until a connection closes, leaving new clients waiting
+    /// in the listen backlog
+    #[default]
+    Wait,
+    /// Accept the connection and answer its first request with a
+    /// `too_many_connections` error
+    Reject,
+}
+
 /// Configuration for socket connections
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct SocketConfig {
-    /// Path to the Unix socket file
+    /// Path to the Unix socket file, or the name of the named pipe on Windows.
+    /// On Linux, `@name` names an abstract socket instead of a file.
     pub socket_path: PathBuf,
+    /// How the server listens and clients connect at `socket_path`
+    pub transport: Transport,
+    /// How the server treats a file already at `socket_path`
+    pub existing_socket_policy: ExistingSocketPolicy,
+    /// Remove any file at `socket_path` before binding, even a socket another
+    /// server is listening on: shorthand for setting `existing_socket_policy`
+    /// to [`ExistingSocketPolicy::Overwrite`], which it takes precedence over.
+    /// Off by default.
+    pub overwrite_existing: bool,
+    /// Permission bits to give the socket file after binding, e.g. `0o660`
+    /// to admit only the owner and group. `None` leaves whatever the process
+    /// umask produced. Only applies to Unix domain sockets.
+    pub socket_mode: Option<u32>,
     /// Timeout for connections in seconds
     pub timeout: u64,
+    /// Most connections the server has open at once, across all its
+    /// listeners. `None` leaves them unbounded.
+    pub max_connections: Option<usize>,
+    /// What happens to connections beyond `max_connections`
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    /// Serve connections on a fixed number of workers; see [`WorkerPool`].
+    /// `None` serves each connection on a task of its own as it is accepted.
+    pub worker_pool: Option<WorkerPool>,
+    /// How many requests each client may send; see [`RateLimit`]. Requests
+    /// over the limit get a `rate_limited` error. `None` leaves them unlimited.
+    pub rate_limit: Option<RateLimit>,
+    /// How responses larger than `large_response_threshold` are handled
+    pub large_response_policy: LargeResponsePolicy,
+    /// Serialized response size in bytes above which `large_response_policy` applies
+    pub large_response_threshold: usize,
+    /// Compress the messages this side sends. Clients decompress any
+    /// compressed response whatever their own setting, but a server refuses
+    /// compressed requests unless this (or a dictionary) is set; see
+    /// [`Compression`].
+    pub compression: Compression,
+    /// Run every synchronous handler on tokio's blocking thread pool, as if
+    /// registered with [`SocketServer::register_blocking_handler`], so none
+    /// can stall the runtime. Off by default, since moving quick handlers to
+    /// another thread costs more than running them inline.
+    pub blocking_handlers: bool,
+    /// Treat a handler's successful response without data as a protocol
+    /// error and send an error response instead
+    pub strict_responses: bool,
+    /// Cumulative handler time a single connection may use. Once spent, the
+    /// next request gets a `cpu_budget_exceeded` error and the connection is
+    /// closed.
+    pub handler_time_budget: Option<std::time::Duration>,
+    /// Shared secret clients send in their handshake and servers require
+    /// before running any handler. `None` or an empty token disables the
+    /// check.
+    #[serde(skip)]
+    pub auth_token: Option<String>,
+    /// Encrypt TCP connections: a certificate for servers to present, or the
+    /// authority clients verify it against. Unix sockets and named pipes
+    /// are not affected.
+    #[cfg(feature = "tls")]
+    #[serde(skip)]
+    pub tls: Option<TlsConfig>,
+    /// Sign requests (client) and require valid signatures (server)
+    #[cfg(feature = "signing")]
+    #[serde(skip)]
+    pub signing: Option<SigningConfig>,
+    /// Zstd dictionary for compressing responses. Both peers need the same
+    /// dictionary; the client checks this during the handshake.
+    #[cfg(feature = "zstd")]
+    #[serde(skip)]
+    pub compression_dictionary: Option<CompressionDictionary>,
+    /// Forward commands without a registered handler to an HTTP backend
+    #[cfg(feature = "http-fallback")]
+    #[serde(skip)]
+    pub http_fallback: Option<HttpFallback>,
+    /// Answer the built-in diagnostic commands in [`admin`], such as `__inflight`
+    pub admin_commands: bool,
+    /// Answer [`admin::HEALTH_COMMAND`] without any handler registered. On
+    /// by default; turn it off for setups that must expose only their own commands.
+    pub health_command: bool,
+    /// How long writing a response may take before the connection is closed
+    /// mid-response. `None` waits for slow readers indefinitely.
+    pub write_timeout: Option<std::time::Duration>,
+    /// Longest `command` accepted, in bytes. Requests naming a longer command
+    /// get a `command_too_long` error without being dispatched.
+    pub max_command_len: usize,
+    /// Log each distinct connection or parse error at most once per window,
+    /// summarizing how often it repeated. `None` logs every occurrence.
+    pub log_throttle_window: Option<std::time::Duration>,
+    /// Level for the server's per-request log lines, by command. Commands
+    /// not listed log at [`LogLevel::Debug`].
+    pub command_log_levels: std::collections::HashMap<String, LogLevel>,
+    /// How long a new connection may take to send its first message, the
+    /// handshake or the request itself, before it is dropped. `None` waits
+    /// indefinitely, which eagerly opened client connections rely on.
+    pub handshake_timeout: Option<std::time::Duration>,
+    /// How long a persistent connection, multiplexed or reading requests
+    /// one after another with [`TrailingBytes::NextFrame`], may go without
+    /// sending a message before the server closes it. Responses still owed
+    /// are written first. `None` keeps idle connections open.
+    pub idle_timeout: Option<std::time::Duration>,
+    /// Bytes of each response the server sends on a flow-controlled
+    /// multiplexed connection before waiting for a window update
+    pub initial_window_size: u32,
+    /// Period after `run` starts during which requests other than `__ping`,
+    /// `__ready` and admin commands get a `warming_up` error. It ends early
+    /// on [`SocketServer::mark_ready`]; `Duration::MAX` waits for that alone.
+    pub warm_up: Option<std::time::Duration>,
+    /// How messages are delimited on the wire; clients and servers sharing a
+    /// socket must use the same framing
+    pub framing: Framing,
+    /// Bytes requested from the socket per read. Messages of any size are
+    /// still read whole; this only trades memory per read against the
+    /// number of reads a large message takes.
+    pub read_buffer_size: usize,
+    /// Largest message either side reads, in bytes, whether requests on the
+    /// server or responses on the client. Longer messages fail with
+    /// [`SocketError::MessageTooLarge`]; a length-prefixed one is refused on
+    /// its declared length, before its body is read.
+    pub max_message_size: usize,
+    /// Encoding clients write requests in and servers answer in, unless a
+    /// request asks for another with `accept_codec`. Either side reads
+    /// messages in any supported codec.
+    pub codec: Codec,
+    /// What the server does with bytes that follow a complete request
+    pub trailing_bytes: TrailingBytes,
+    /// Most connections a [`SocketClientPool`] keeps open
+    pub pool_size: usize,
+    /// Identifies the request and response types this side was built with,
+    /// such as the hash of their schemas from `type_fingerprint` (with the
+    /// `schema` feature). When both peers
+    /// set one, the handshake fails with [`SocketError::TypeMismatch`] unless
+    /// they are equal.
+    pub type_fingerprint: Option<String>,
 }
 
 impl Default for SocketConfig {
     fn default() -> Self {
         Self {
+            #[cfg(unix)]
             socket_path: PathBuf::from("/tmp/circle.sock"),
+            #[cfg(windows)]
+            socket_path: PathBuf::from(r"\\.\pipe\circle"),
+            transport: Transport::default(),
+            existing_socket_policy: ExistingSocketPolicy::ReplaceIfStale,
+            overwrite_existing: false,
+            socket_mode: None,
             timeout: 30,
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::Wait,
+            worker_pool: None,
+            rate_limit: None,
+            large_response_policy: LargeResponsePolicy::Allow,
+            large_response_threshold: 1024 * 1024,
+            compression: Compression::None,
+            blocking_handlers: false,
+            strict_responses: false,
+            handler_time_budget: None,
+            auth_token: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "signing")]
+            signing: None,
+            #[cfg(feature = "zstd")]
+            compression_dictionary: None,
+            #[cfg(feature = "http-fallback")]
+            http_fallback: None,
+            admin_commands: false,
+            health_command: true,
+            write_timeout: None,
+            max_command_len: 256,
+            log_throttle_window: Some(std::time::Duration::from_secs(10)),
+            command_log_levels: std::collections::HashMap::new(),
+            handshake_timeout: None,
+            idle_timeout: None,
+            initial_window_size: 64 * 1024,
+            warm_up: None,
+            framing: Framing::Json,
+            read_buffer_size: 8192,
+            max_message_size: framing::DEFAULT_MAX_MESSAGE_SIZE,
+            codec: Codec::Json,
+            trailing_bytes: TrailingBytes::Ignore,
+            pool_size: 4,
+            type_fingerprint: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    /// Level at which per-request lines for `command` are logged
+    pub fn command_log_level(&self, command: &str) -> LogLevel {
+        self.command_log_levels.get(command).copied().unwrap_or_default()
+    }
+
+    /// How the server treats a file already at `socket_path`, with
+    /// `overwrite_existing` applied
+    #[cfg(unix)]
+    pub(crate) fn socket_policy(&self) -> ExistingSocketPolicy {
+        if self.overwrite_existing {
+            return ExistingSocketPolicy::Overwrite;
+        }
+        self.existing_socket_policy
+    }
+
+    /// Config for a server listening, or a client connecting, over TCP at `addr`
+    pub fn tcp(addr: impl Into<std::net::SocketAddr>) -> Self {
+        Self {
+            transport: Transport::Tcp(addr.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Config for a socket named `name` in the conventional runtime directory.
+    ///
+    /// The directory is `$CIRCLE_RUNTIME_DIR` if set, otherwise
+    /// `$XDG_RUNTIME_DIR`, otherwise `/tmp`. It is created with `0700`
+    /// permissions if it doesn't exist yet. A `.sock` extension is added
+    /// when `name` has none, so tools can find each other by name alone.
+    pub fn in_runtime_dir(name: impl AsRef<Path>) -> SocketResult<Self> {
+        let base = ["CIRCLE_RUNTIME_DIR", "XDG_RUNTIME_DIR"]
+            .iter()
+            .filter_map(std::env::var_os)
+            .find(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/tmp"));
+        Self::in_dir(&base, name.as_ref())
+    }
+
+    /// Config for a socket named `name` in `base`, created if missing, as
+    /// with [`in_runtime_dir`](Self::in_runtime_dir)
+    fn in_dir(base: &Path, name: &Path) -> SocketResult<Self> {
+        if !base.exists() {
+            let mut builder = std::fs::DirBuilder::new();
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder.recursive(true).create(base)?;
+        }
+
+        let mut socket_path = base.join(name);
+        if socket_path.extension().is_none() {
+            socket_path.set_extension("sock");
         }
+        Ok(Self::from(socket_path))
     }
 }
 
@@ -195,7 +924,7 @@ impl<P> From<P> for SocketConfig where P: AsRef<Path> {
     fn from(path: P) -> Self {
         Self {
             socket_path: path.as_ref().to_path_buf(),
-            timeout: 30,
+            ..Default::default()
         }
     }
 }
@@ -203,205 +932,2862 @@ impl<P> From<P> for SocketConfig where P: AsRef<Path> {
 /// A handler function for processing socket requests
 pub type RequestHandler<T, R> = Arc<dyn Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync>;
 
-/// Unix socket server for handling incoming requests
-pub struct SocketServer<T, R> {
-    config: SocketConfig,
-    handlers: Arc<RwLock<std::collections::HashMap<String, RequestHandler<T, R>>>>,
-}
+/// A handler that also receives the request's [`RequestContext`]
+pub type ContextHandler<T, R> =
+    Arc<dyn Fn(SocketPayload<T, R>, RequestContext) -> SocketResult<SocketResponse<R>> + Send + Sync>;
 
-impl<T, R> SocketServer<T, R>
-where
-    T: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de>,
-    R: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de> + std::fmt::Debug,
-{
-    /// Create a new socket server
-    pub fn new(config: SocketConfig) -> Self {
-        Self {
-            config,
-            handlers: Arc::new(RwLock::new(std::collections::HashMap::new())),
-        }
-    }
+/// Logic run before every handler; returning `Err` answers the request with an error response
+pub type Middleware<T, R> = Arc<dyn Fn(&SocketPayload<T, R>, &mut RequestContext) -> SocketResult<()> + Send + Sync>;
 
-    /// Register a handler for a specific command
-    pub async fn register_handler<F>(&self, command: impl Into<String>, handler: F)
-    where
-        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
-    {
-        let mut handlers = self.handlers.write().await;
-        handlers.insert(command.into(), Arc::new(handler));
-    }
+/// Logic run after every handler with the response it produced
+pub type PostHook<R> = Arc<dyn Fn(&RequestContext, &SocketResponse<R>) + Send + Sync>;
 
-    /// Start the socket server
-    pub async fn run(self) -> SocketResult<()> {
-        let socket_path = &self.config.socket_path;
+/// Decides whether a request may run the command it names
+pub type Authorizer = Arc<dyn Fn(&RequestContext, &str) -> bool + Send + Sync>;
 
-        // Remove existing socket file if it exists
-        if socket_path.exists() {
-            std::fs::remove_file(socket_path)?;
-        }
+/// Logic run when a connection opens or closes, with its details
+pub type ConnectionHook = Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
 
-        let listener = UnixListener::bind(socket_path)?;
-        info!("Socket server listening on: {:?}", socket_path);
+/// A check on a request's raw bytes, run before any deserialization
+pub type RawFilter = Arc<dyn Fn(&[u8]) -> SocketResult<()> + Send + Sync>;
 
-        loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let handlers = Arc::clone(&self.handlers);
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, handlers).await {
-                            error!("Error handling connection: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);
-                }
-            }
-        }
-    }
+/// A client-side check run on each response before it is returned
+pub type ResponseValidator<R> = Arc<dyn Fn(&SocketResponse<R>) -> Result<(), String> + Send + Sync>;
 
-    async fn handle_connection(
-        mut stream: UnixStream,
-        handlers: Arc<RwLock<std::collections::HashMap<String, RequestHandler<T, R>>>>,
-    ) -> SocketResult<()> {
-        // Read the request
-        let mut buffer = vec![0u8; 8192];
-        let n = stream.read(&mut buffer).await?;
-        if n == 0 {
-            warn!("Empty connection received");
-            return Ok(());
-        }
+/// Produces the request IDs of the payloads a client creates
+pub type RequestIdGenerator = Arc<dyn Fn() -> String + Send + Sync>;
 
-        let request_str = String::from_utf8_lossy(&buffer[..n]);
-        debug!("Received request: {}", request_str);
+/// A boxed future returned by asynchronous handlers
+pub type BoxFuture<O> = std::pin::Pin<Box<dyn std::future::Future<Output = O> + Send>>;
 
-        // Parse the payload
-        let payload: SocketPayload<T, R> = serde_json::from_str(&request_str)
-            .map_err(|_| SocketError::InvalidRequest)?;
+/// A request handler that does its work asynchronously
+pub type AsyncHandler<T, R> =
+    Arc<dyn Fn(SocketPayload<T, R>, RequestContext) -> BoxFuture<SocketResult<SocketResponse<R>>> + Send + Sync>;
 
-        // Store request_id before moving payload
-        let request_id = payload.request_id.clone();
-        let command = payload.command.clone();
+/// A handler that takes over a connection after upgrading it to a raw byte pipe
+pub type UpgradeHandler<T, R> = Arc<dyn Fn(SocketPayload<T, R>, UpgradedStream) -> BoxFuture<SocketResult<()>> + Send + Sync>;
 
-        // Find and execute the handler
-        let handlers = handlers.read().await;
-        if let Some(handler) = handlers.get(&payload.command) {
-            match handler(payload) {
-                Ok(response) => {
-                    let response_json = serde_json::to_string(&response)?;
-                    stream.write_all(response_json.as_bytes()).await?;
-                    debug!("Sent response for request ID: {}", response.request_id);
-                }
-                Err(e) => {
-                    let error_response = SocketResponse::<R>::error(&request_id, e.to_string());
-                    let response_json = serde_json::to_string(&error_response)?;
-                    stream.write_all(response_json.as_bytes()).await?;
-                    warn!("Error handling request: {}", e);
-                }
-            }
-        } else {
-            let error_response = SocketResponse::<R>::error(
-                &request_id,
-                format!("No handler for command: {}", command),
-            );
-            let response_json = serde_json::to_string(&error_response)?;
-            stream.write_all(response_json.as_bytes()).await?;
+/// A handler that streams binary data to the client through a [`DownloadSink`]
+pub type DownloadHandler<T, R> = Arc<dyn Fn(SocketPayload<T, R>, DownloadSink) -> BoxFuture<SocketResult<()>> + Send + Sync>;
+
+/// A handler that sends its response in parts through a [`ResponseSink`]
+pub type StreamingHandler<T, R> =
+    Arc<dyn Fn(SocketPayload<T, R>, ResponseSink<R>) -> BoxFuture<SocketResult<()>> + Send + Sync>;
+
+/// A registered request handler and where it runs
+enum CommandHandler<T, R> {
+    /// Called directly on the connection's task
+    Inline(ContextHandler<T, R>),
+    /// Called on tokio's blocking thread pool
+    Blocking(ContextHandler<T, R>),
+    /// Awaited on the connection's task
+    Async(AsyncHandler<T, R>),
+}
+
+impl<T, R> Clone for CommandHandler<T, R> {
+    fn clone(&self) -> Self {
+        match self {
+            CommandHandler::Inline(handler) => CommandHandler::Inline(Arc::clone(handler)),
+            CommandHandler::Blocking(handler) => CommandHandler::Blocking(Arc::clone(handler)),
+            CommandHandler::Async(handler) => CommandHandler::Async(Arc::clone(handler)),
         }
+    }
+}
 
-        Ok(())
+/// The token clients must present, if the check is enabled
+fn auth_token(config: &SocketConfig) -> Option<&str> {
+    config.auth_token.as_deref().filter(|token| !token.is_empty())
+}
+
+/// Wait out a connection's `idle_timeout`, or forever without one
+async fn idle(limit: Option<std::time::Duration>) {
+    match limit {
+        Some(limit) => tokio::time::sleep(limit).await,
+        None => std::future::pending().await,
     }
 }
 
-/// Unix socket client for sending requests
-pub struct SocketClient {
+/// The message a panic was raised with, if it carried one
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// How a response is delimited on its connection
+#[derive(Debug, Clone, Copy)]
+enum ResponseMode {
+    /// The only response on the connection, read until EOF, so it may be
+    /// compressed. `dictionary` is set when the handshake agreed on the
+    /// compression dictionary.
+    Single {
+        #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+        dictionary: bool,
+    },
+    /// One of several responses on the connection. Only compressed with
+    /// length-prefixed framing; otherwise a compressed body couldn't be told
+    /// apart from the next response.
+    Streamed,
+}
+
+/// State shared between a server, its connection tasks and its handles
+struct ServerState<T, R> {
     config: SocketConfig,
+    handlers: RwLock<std::collections::HashMap<String, CommandHandler<T, R>>>,
+    middleware: RwLock<Vec<Middleware<T, R>>>,
+    post_hooks: RwLock<Vec<PostHook<R>>>,
+    authorizer: RwLock<Option<Authorizer>>,
+    connect_hooks: RwLock<Vec<ConnectionHook>>,
+    disconnect_hooks: RwLock<Vec<ConnectionHook>>,
+    raw_filters: RwLock<Vec<RawFilter>>,
+    upgrade_handlers: RwLock<std::collections::HashMap<String, UpgradeHandler<T, R>>>,
+    download_handlers: RwLock<std::collections::HashMap<String, DownloadHandler<T, R>>>,
+    streaming_handlers: RwLock<std::collections::HashMap<String, StreamingHandler<T, R>>>,
+    connections: ConnectionRegistry,
+    inflight: InflightRegistry,
+    started: std::sync::OnceLock<std::time::Instant>,
+    readiness: Readiness,
+    listeners: ListenerControl,
+    accept_gate: AcceptGate,
+    tasks: ConnectionTasks,
+    rate_limiter: Option<RateLimiter>,
+    events: EventBus,
+    log_throttle: LogThrottle,
+    metrics: RwLock<Option<Arc<dyn MetricsSink>>>,
+    dedup: RwLock<Arc<dyn DedupStore>>,
+    stats: Arc<prometheus::ServerStats>,
+    #[cfg(feature = "signing")]
+    replay_guard: signing::ReplayGuard,
 }
 
-impl SocketClient {
-    /// Create a new socket client
-    pub fn new(config: SocketConfig) -> Self {
-        Self { config }
+/// Unix socket server for handling incoming requests
+pub struct SocketServer<T, R> {
+    state: Arc<ServerState<T, R>>,
+}
+
+/// Tasks aborted when dropped, keyed by the endpoint they accept on
+#[derive(Default)]
+struct AbortOnDrop(std::collections::HashMap<String, tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in self.0.values() {
+            task.abort();
+        }
     }
+}
 
-    /// Send a request and wait for response
-    pub async fn send_request<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<SocketResponse<R>>
-    where
-        T: serde::Serialize,
-        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
-    {
-        let mut stream = tokio::time::timeout(
-            std::time::Duration::from_secs(self.config.timeout),
-            UnixStream::connect(&self.config.socket_path),
-        )
-        .await
-        .map_err(|_| SocketError::ConnectionTimeout)??;
+/// Cheap, cloneable handle for inspecting a server after `run` has taken ownership of it
+#[derive(Clone)]
+pub struct ServerHandle {
+    connections: ConnectionRegistry,
+    inflight: InflightRegistry,
+    readiness: Readiness,
+    listeners: ListenerControl,
+    accept_gate: AcceptGate,
+    tasks: ConnectionTasks,
+    events: EventBus,
+    stats: Arc<prometheus::ServerStats>,
+}
 
-        let request_json = serde_json::to_string(&payload)?;
-        stream.write_all(request_json.as_bytes()).await?;
-        stream.shutdown().await?;
+impl ServerHandle {
+    /// End the warm-up period, e.g. once caches are loaded
+    pub fn mark_ready(&self) {
+        self.readiness.mark_ready();
+    }
 
-        // Read response
-        let mut buffer = vec![0u8; 8192];
-        let n = tokio::time::timeout(
-            std::time::Duration::from_secs(self.config.timeout),
-            stream.read(&mut buffer),
-        )
-        .await
-        .map_err(|_| SocketError::ConnectionTimeout)??;
+    /// Connections currently open on the server
+    pub fn active_connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.list()
+    }
 
-        if n == 0 {
-            return Err(SocketError::InvalidRequest);
-        }
+    /// Requests whose handlers are currently executing, longest-running first
+    pub fn inflight_requests(&self) -> Vec<InflightRequest> {
+        self.inflight.list()
+    }
 
-        let response_str = String::from_utf8_lossy(&buffer[..n]);
-        let response: SocketResponse<R> = serde_json::from_str(&response_str)?;
-        debug!("Received response: {:?}", response);
+    /// Accepted connections waiting for a worker, with a
+    /// [`worker_pool`](SocketConfig::worker_pool) configured
+    pub fn queued_connections(&self) -> usize {
+        self.tasks.queued()
+    }
 
-        Ok(response)
+    /// Also accept connections at the endpoint `config` describes, e.g. a
+    /// new socket path clients are migrating to. Only the addressing fields
+    /// of `config` are used; everything else follows the server's config.
+    /// Returns the endpoint's name for [`ServerHandle::remove_listener`].
+    pub async fn add_listener(&self, config: SocketConfig) -> SocketResult<String> {
+        self.listeners.add(&config).await
     }
 
-    /// Send a request without waiting for response (fire and forget)
-    pub async fn send_request_no_response<T>(&self, payload: SocketPayload<T, ()>) -> SocketResult<()>
-    where
-        T: serde::Serialize,
-    {
-        let mut stream = tokio::time::timeout(
-            std::time::Duration::from_secs(self.config.timeout),
-            UnixStream::connect(&self.config.socket_path),
-        )
-        .await
-        .map_err(|_| SocketError::ConnectionTimeout)??;
+    /// Stop accepting connections at `endpoint`: a socket path, pipe name or
+    /// `tcp://` address, which may be the one the server started on.
+    /// Connections already accepted there are served to the end. Returns
+    /// whether the server was listening there.
+    pub async fn remove_listener(&self, endpoint: impl AsRef<Path>) -> bool {
+        self.listeners.remove(endpoint.as_ref().display().to_string()).await
+    }
 
-        let request_json = serde_json::to_string(&payload)?;
-        stream.write_all(request_json.as_bytes()).await?;
-        stream.shutdown().await?;
+    /// Stop accepting new connections on every listener, e.g. for a brief
+    /// maintenance window, while those already open are served as usual.
+    /// The listeners stay bound, so clients that connect meanwhile wait in
+    /// the listen backlog until [`resume`](Self::resume), or until their
+    /// own timeout.
+    pub fn pause(&self) {
+        self.accept_gate.pause();
+    }
 
-        Ok(())
+    /// Accept connections again after [`pause`](Self::pause), starting with
+    /// those waiting in the backlog
+    pub fn resume(&self) {
+        self.accept_gate.resume();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde::{Deserialize, Serialize};
-    use tokio::time::{sleep, Duration};
+    /// Whether the server is paused
+    pub fn is_paused(&self) -> bool {
+        self.accept_gate.is_paused()
+    }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    struct StartCommand {
-        pub process_id: String,
-        pub command: Vec<String>,
+    /// Stop accepting new connections, as with [`pause`](Self::pause), and
+    /// wait up to `timeout` for those already open to be served, e.g. before
+    /// the process exits for a new version to take over. Connections still
+    /// open after `timeout` are closed mid-request. Returns how many were.
+    pub async fn drain(&self, timeout: std::time::Duration) -> usize {
+        self.accept_gate.pause();
+        self.tasks.drain(timeout).await
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    struct StartResponse {
-        pub started: bool,
-        pub pid: u32,
+    /// Requests served per command, as from [`SocketServer::metrics`]
+    pub fn metrics(&self) -> SocketMetrics {
+        self.stats.snapshot()
+    }
+
+    /// Push `event` to every subscribed client, as with [`SocketServer::broadcast`]
+    pub fn broadcast<E: serde::Serialize>(&self, event: &SocketResponse<E>) -> SocketResult<usize> {
+        self.events.broadcast(event)
+    }
+
+    /// Push `data` to the clients subscribed to `topic`, as with [`SocketServer::publish`]
+    pub fn publish<E: serde::Serialize>(&self, topic: &str, data: E) -> SocketResult<usize> {
+        self.events.publish(topic, data)
+    }
+}
+
+impl<T, R> SocketServer<T, R>
+where
+    T: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    R: Send + Sync + 'static + serde::Serialize + for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+{
+    /// Configure a server and register its handlers in one chain, see
+    /// [`SocketServerBuilder`]
+    pub fn builder() -> SocketServerBuilder<T, R> {
+        SocketServerBuilder::new()
+    }
+
+    /// Create a new socket server
+    pub fn new(config: SocketConfig) -> Self {
+        Self {
+            state: Arc::new(ServerState {
+                log_throttle: LogThrottle::new(config.log_throttle_window),
+                metrics: RwLock::new(None),
+                dedup: RwLock::new(Arc::new(MemoryDedupStore::new())),
+                stats: Arc::default(),
+                readiness: Readiness::new(config.warm_up),
+                listeners: ListenerControl::new(),
+                accept_gate: AcceptGate::new(&config),
+                tasks: ConnectionTasks::new(config.worker_pool),
+                rate_limiter: config.rate_limit.map(RateLimiter::new),
+                events: EventBus::new(&config),
+                config,
+                handlers: RwLock::new(std::collections::HashMap::new()),
+                middleware: RwLock::new(Vec::new()),
+                post_hooks: RwLock::new(Vec::new()),
+                authorizer: RwLock::new(None),
+                connect_hooks: RwLock::new(Vec::new()),
+                disconnect_hooks: RwLock::new(Vec::new()),
+                raw_filters: RwLock::new(Vec::new()),
+                upgrade_handlers: RwLock::new(std::collections::HashMap::new()),
+                download_handlers: RwLock::new(std::collections::HashMap::new()),
+                streaming_handlers: RwLock::new(std::collections::HashMap::new()),
+                connections: ConnectionRegistry::default(),
+                inflight: InflightRegistry::default(),
+                started: std::sync::OnceLock::new(),
+                #[cfg(feature = "signing")]
+                replay_guard: signing::ReplayGuard::default(),
+            }),
+        }
+    }
+
+    /// Register a handler for a specific command
+    pub async fn register_handler<F>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        self.register_context_handler(command, move |payload, _| handler(payload)).await;
+    }
+
+    /// Register a handler that receives `state` with every request.
+    ///
+    /// Saves cloning an `Arc` into each closure by hand. Several handlers can
+    /// share one state by registering clones of the same `Arc`; `S` provides
+    /// its own synchronization, such as a `Mutex` or atomics, for anything
+    /// handlers change.
+    pub async fn register_handler_with_state<S, F>(&self, command: impl Into<String>, state: Arc<S>, handler: F)
+    where
+        S: Send + Sync + 'static,
+        F: Fn(Arc<S>, SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        self.register_handler(command, move |payload| handler(Arc::clone(&state), payload)).await;
+    }
+
+    /// Register a handler that also receives the request's [`RequestContext`],
+    /// including any [`Extensions`] middleware attached
+    pub async fn register_context_handler<F>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>, RequestContext) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        let mut handlers = self.state.handlers.write().await;
+        handlers.insert(command.into(), CommandHandler::Inline(Arc::new(handler)));
+    }
+
+    /// Run `middleware` before every handler, after any added earlier.
+    ///
+    /// Middleware can inspect the payload and attach values to the context's
+    /// [`Extensions`] for later middleware and handlers. Returning `Err`
+    /// stops the chain and answers the request with an error response.
+    pub async fn add_context_middleware<F>(&self, middleware: F)
+    where
+        F: Fn(&SocketPayload<T, R>, &mut RequestContext) -> SocketResult<()> + Send + Sync + 'static,
+    {
+        self.state.middleware.write().await.push(Arc::new(middleware));
+    }
+
+    /// Run `middleware` before every handler, after any added earlier.
+    ///
+    /// For checks that only need the payload, such as validation or
+    /// authorization; returning `Err` answers the request with an error
+    /// response without running the handler. Use
+    /// [`add_context_middleware`](Self::add_context_middleware) to pass values
+    /// on to handlers.
+    pub async fn add_middleware<F>(&self, middleware: F)
+    where
+        F: Fn(&SocketPayload<T, R>) -> SocketResult<()> + Send + Sync + 'static,
+    {
+        self.add_context_middleware(move |payload, _| middleware(payload)).await;
+    }
+
+    /// Run `hook` after every request that passed the middleware, in the
+    /// order added, with the response about to be sent.
+    ///
+    /// Hooks see error responses too, including ones for commands without a
+    /// handler, which suits logging and metrics. The context's extensions
+    /// went to the handler, so hooks get an empty type map.
+    pub async fn add_post_hook<F>(&self, hook: F)
+    where
+        F: Fn(&RequestContext, &SocketResponse<R>) + Send + Sync + 'static,
+    {
+        self.state.post_hooks.write().await.push(Arc::new(hook));
+    }
+
+    /// Decide per request whether the client may run the command, replacing
+    /// any authorizer set before.
+    ///
+    /// `authorizer` gets the request's context, with its peer credentials
+    /// and whatever middleware attached, and the command name. It runs after
+    /// the middleware and before the handler, including upgrade and download
+    /// handlers; returning `false` answers with an `unauthorized` error and
+    /// skips the handler.
+    pub async fn set_authorizer<F>(&self, authorizer: F)
+    where
+        F: Fn(&RequestContext, &str) -> bool + Send + Sync + 'static,
+    {
+        *self.state.authorizer.write().await = Some(Arc::new(authorizer));
+    }
+
+    /// Call `callback` with each connection's details as it is accepted,
+    /// before anything is read from it. The client name is not known yet.
+    pub async fn on_connect<F>(&self, callback: F)
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.state.connect_hooks.write().await.push(Arc::new(callback));
+    }
+
+    /// Call `callback` with each connection's final details once it closes,
+    /// however it ended: the client hung up, a request failed, the handler
+    /// panicked or the server was stopped.
+    pub async fn on_disconnect<F>(&self, callback: F)
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.state.disconnect_hooks.write().await.push(Arc::new(callback));
+    }
+
+    /// Register a handler that runs on tokio's blocking thread pool.
+    ///
+    /// Handlers registered with [`register_handler`](Self::register_handler)
+    /// run on the connection's task, so synchronous filesystem access,
+    /// hashing or other CPU-heavy work there stalls the accept loop and every
+    /// other connection sharing the worker. Register such handlers here
+    /// instead; each request is moved to `spawn_blocking` and the client sees
+    /// no difference. Quick, non-blocking handlers are cheaper inline.
+    pub async fn register_blocking_handler<F>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        let mut handlers = self.state.handlers.write().await;
+        handlers.insert(command.into(), CommandHandler::Blocking(Arc::new(move |payload, _| handler(payload))));
+    }
+
+    /// Register a handler that runs on tokio's blocking thread pool and is
+    /// abandoned if it hasn't returned within `timeout`.
+    ///
+    /// The request is then answered with a `handler_timeout` error, so a hung
+    /// handler can't stall its connection. The thread can't be interrupted
+    /// and keeps running until the handler returns; its result is discarded.
+    pub async fn register_handler_with_timeout<F>(
+        &self,
+        command: impl Into<String>,
+        timeout: std::time::Duration,
+        handler: F,
+    ) where
+        F: Fn(SocketPayload<T, R>) -> SocketResult<SocketResponse<R>> + Send + Sync + 'static,
+    {
+        let command = command.into();
+        let handler = Arc::new(handler);
+        let name = command.clone();
+        let timed: AsyncHandler<T, R> = Arc::new(move |payload, _| {
+            let handler = Arc::clone(&handler);
+            let command = name.clone();
+            Box::pin(async move {
+                let request_id = payload.request_id.clone();
+                // Carry the request's span over to the blocking thread
+                let span = tracing::Span::current();
+                let task = tokio::task::spawn_blocking(move || span.in_scope(|| handler(payload)));
+                match tokio::time::timeout(timeout, task).await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Ok(Err(e)) => Err(SocketError::Io(std::io::Error::other(e))),
+                    Err(_) => {
+                        warn!("Handler for command {} did not finish within {:?}", command, timeout);
+                        Ok(SocketResponse::error_with_code(
+                            request_id,
+                            "handler_timeout",
+                            format!("handler_timeout: handler for {} did not finish within {:?}", command, timeout),
+                        ))
+                    }
+                }
+            })
+        });
+        self.state.handlers.write().await.insert(command, CommandHandler::Async(timed));
+    }
+
+    /// Register a handler that returns a future, for work that awaits other
+    /// I/O such as spawning a child process, querying a database or calling
+    /// another socket. The future is awaited on the connection's task, so
+    /// other connections keep being served while it waits.
+    pub async fn register_async_handler<F, Fut>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = SocketResult<SocketResponse<R>>> + Send + 'static,
+    {
+        let handler: AsyncHandler<T, R> = Arc::new(move |payload, _| Box::pin(handler(payload)));
+        let mut handlers = self.state.handlers.write().await;
+        handlers.insert(command.into(), CommandHandler::Async(handler));
+    }
+
+    /// Register a [`Handler`] implementation for `command`. It is awaited on
+    /// the connection's task, like a handler from [`register_async_handler`](Self::register_async_handler).
+    pub async fn register_handler_trait(&self, command: impl Into<String>, handler: impl Handler<T, R>) {
+        let handler = Arc::new(handler);
+        self.register_async_handler(command, move |payload| {
+            let handler = Arc::clone(&handler);
+            async move { handler.handle(payload).await }
+        })
+        .await;
+    }
+
+    /// Register a handler that upgrades the connection to a raw byte pipe.
+    ///
+    /// The server answers the request with [`SocketResponse::upgrade`] and
+    /// then hands the connection to `handler`, which owns it from then on;
+    /// no further requests are read from it. Clients use
+    /// [`SocketClient::upgrade`] to get their end of the pipe.
+    ///
+    /// The handler is responsible for the rest of the connection: no
+    /// timeouts apply to it, and the connection stays open, counting towards
+    /// `max_connections`, until the handler returns or drops the stream.
+    pub async fn register_upgrade_handler<F, Fut>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>, UpgradedStream) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = SocketResult<()>> + Send + 'static,
+    {
+        let handler: UpgradeHandler<T, R> = Arc::new(move |payload, stream| Box::pin(handler(payload, stream)));
+        let mut handlers = self.state.upgrade_handlers.write().await;
+        handlers.insert(command.into(), handler);
+    }
+
+    /// Register a handler that sends the client a stream of binary data.
+    ///
+    /// The server answers the request with [`SocketResponse::download`] and
+    /// forwards everything `handler` writes to its [`DownloadSink`] as raw
+    /// chunks, with no JSON encoding of the data. When the handler returns,
+    /// the client is told whether it succeeded. Clients receive the data with
+    /// [`SocketClient::download`].
+    pub async fn register_download_handler<F, Fut>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>, DownloadSink) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = SocketResult<()>> + Send + 'static,
+    {
+        let handler: DownloadHandler<T, R> = Arc::new(move |payload, sink| Box::pin(handler(payload, sink)));
+        let mut handlers = self.state.download_handlers.write().await;
+        handlers.insert(command.into(), handler);
+    }
+
+    /// Register a handler that answers in parts, such as lines of a log it
+    /// tails or a build's output as it is produced.
+    ///
+    /// The server answers the request with [`SocketResponse::stream`], then
+    /// writes everything `handler` sends to its [`ResponseSink`] as a success
+    /// response of its own. When the handler returns, the stream ends with
+    /// [`SocketResponse::stream_end`], or the error if it failed. Clients
+    /// receive the parts with [`SocketClient::send_request_streaming`].
+    pub async fn register_streaming_handler<F, Fut>(&self, command: impl Into<String>, handler: F)
+    where
+        F: Fn(SocketPayload<T, R>, ResponseSink<R>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = SocketResult<()>> + Send + 'static,
+    {
+        let handler: StreamingHandler<T, R> = Arc::new(move |payload, sink| Box::pin(handler(payload, sink)));
+        let mut handlers = self.state.streaming_handlers.write().await;
+        handlers.insert(command.into(), handler);
+    }
+
+    /// Inspect every request's raw bytes before it is deserialized.
+    ///
+    /// Filters run in the order added, on the request body as received, in
+    /// the codec the client wrote it in (after signature verification). Returning `Err` rejects the request
+    /// with an error response without paying for full deserialization, so
+    /// cheap checks such as a size or prefix test can turn away obviously
+    /// bad requests early. Requests that pass go through normal dispatch.
+    pub async fn add_raw_filter<F>(&self, filter: F)
+    where
+        F: Fn(&[u8]) -> SocketResult<()> + Send + Sync + 'static,
+    {
+        self.state.raw_filters.write().await.push(Arc::new(filter));
+    }
+
+    /// Keep idempotency keys in `store` instead of in memory, e.g. to
+    /// remember them across restarts
+    pub async fn set_dedup_store<S>(&self, store: S)
+    where
+        S: DedupStore + 'static,
+    {
+        *self.state.dedup.write().await = Arc::new(store);
+    }
+
+    /// Report request and response sizes to `sink`, replacing any previous sink
+    pub async fn set_metrics_sink<M>(&self, sink: M)
+    where
+        M: MetricsSink + 'static,
+    {
+        *self.state.metrics.write().await = Some(Arc::new(sink));
+    }
+
+    /// Dry-run every registered handler with a default-valued payload.
+    ///
+    /// See [`self_test_with`](Self::self_test_with).
+    pub async fn self_test(&self) -> SelfTestReport
+    where
+        T: Default,
+    {
+        self.self_test_with(|_| T::default()).await
+    }
+
+    /// Dry-run every registered handler once, catching errors and panics.
+    ///
+    /// Each handler receives a synthetic payload with `dry_run` set and the
+    /// data `sample` builds for its command, so obviously broken handlers
+    /// show up at boot instead of on the first real request. Failures are
+    /// logged and collected in the returned report. Upgrade handlers need a
+    /// live connection and are not exercised.
+    pub async fn self_test_with<F>(&self, sample: F) -> SelfTestReport
+    where
+        F: Fn(&str) -> T,
+    {
+        let handlers = self.state.handlers.read().await;
+        let mut commands: Vec<&String> = handlers.keys().collect();
+        commands.sort();
+
+        let mut report = SelfTestReport::default();
+        for command in commands {
+            let mut payload = SocketPayload::new(command.as_str(), sample(command));
+            payload.dry_run = true;
+            let context = RequestContext {
+                request_id: payload.request_id.clone(),
+                command: command.clone(),
+                connection_id: 0,
+                client_name: None,
+                peer_credentials: None,
+                cancellation: CancellationToken::new(),
+                extensions: Extensions::new(),
+            };
+
+            let result = match &handlers[command] {
+                CommandHandler::Inline(handler) | CommandHandler::Blocking(handler) => {
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler(payload, context)))
+                        .map_err(|panic| panic_message(&*panic))
+                }
+                // Spawned so a panic surfaces as a join error instead of unwinding here
+                CommandHandler::Async(handler) => tokio::spawn(handler(payload, context)).await.map_err(|e| {
+                    match e.try_into_panic() {
+                        Ok(panic) => panic_message(&*panic),
+                        Err(e) => e.to_string(),
+                    }
+                }),
+            };
+            let outcome = match result {
+                Ok(Ok(_)) => CheckOutcome::Passed,
+                Ok(Err(e)) => CheckOutcome::Failed(e.to_string()),
+                Err(panic) => CheckOutcome::Panicked(panic),
+            };
+            if outcome != CheckOutcome::Passed {
+                warn!("Self-test of command {} failed: {:?}", command, outcome);
+            }
+            report.checks.push(CommandCheck {
+                command: command.clone(),
+                outcome,
+            });
+        }
+        report
+    }
+
+    /// Get a handle that stays usable while the server is running
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            connections: self.state.connections.clone(),
+            inflight: self.state.inflight.clone(),
+            readiness: self.state.readiness.clone(),
+            listeners: self.state.listeners.clone(),
+            accept_gate: self.state.accept_gate.clone(),
+            tasks: self.state.tasks.clone(),
+            events: self.state.events.clone(),
+            stats: Arc::clone(&self.state.stats),
+        }
+    }
+
+    /// Accept connections at another endpoint as well as the configured one;
+    /// see [`ServerHandle::add_listener`]. Listeners added before `run`
+    /// start accepting when it does.
+    pub async fn add_listener(&self, config: SocketConfig) -> SocketResult<String> {
+        self.state.listeners.add(&config).await
+    }
+
+    /// End the warm-up period set by [`SocketConfig::warm_up`] early
+    pub fn mark_ready(&self) {
+        self.state.readiness.mark_ready();
+    }
+
+    /// Push `event` to every client subscribed with [`SocketClient::subscribe`],
+    /// returning how many there are. Clients subscribing later don't see it.
+    /// Use [`ServerHandle::broadcast`] once `run` owns the server.
+    pub fn broadcast(&self, event: &SocketResponse<R>) -> SocketResult<usize> {
+        self.state.events.broadcast(event)
+    }
+
+    /// Push `data` to the clients subscribed to `topic` with
+    /// [`Subscription::subscribe`], as a success response whose `request_id`
+    /// is the topic. Returns how many there are.
+    pub fn publish(&self, topic: &str, data: R) -> SocketResult<usize> {
+        self.state.events.publish(topic, data)
+    }
+
+    /// Connections currently open on the server
+    pub fn active_connections(&self) -> Vec<ConnectionInfo> {
+        self.state.connections.list()
+    }
+
+    /// Names of the commands with a handler registered, of any kind, sorted
+    pub async fn list_commands(&self) -> Vec<String> {
+        Self::commands_state(&self.state).await
+    }
+
+    async fn commands_state(state: &ServerState<T, R>) -> Vec<String> {
+        let mut commands: Vec<String> = state.handlers.read().await.keys().cloned().collect();
+        commands.extend(state.upgrade_handlers.read().await.keys().cloned());
+        commands.extend(state.download_handlers.read().await.keys().cloned());
+        commands.extend(state.streaming_handlers.read().await.keys().cloned());
+        commands.sort();
+        commands
+    }
+
+    /// Remove the handler for `command`, of whichever kind, returning whether
+    /// there was one. Requests already being handled finish normally; later
+    /// ones get the usual error for an unknown command.
+    pub async fn unregister_handler(&self, command: &str) -> bool {
+        let removed = self.state.handlers.write().await.remove(command).is_some();
+        let upgrade = self.state.upgrade_handlers.write().await.remove(command).is_some();
+        let download = self.state.download_handlers.write().await.remove(command).is_some();
+        let streaming = self.state.streaming_handlers.write().await.remove(command).is_some();
+        removed || upgrade || download || streaming
+    }
+
+    /// Capture the server's operational state as one serializable value
+    pub async fn snapshot(&self) -> ServerSnapshot {
+        Self::snapshot_state(&self.state).await
+    }
+
+    async fn snapshot_state(state: &ServerState<T, R>) -> ServerSnapshot {
+        ServerSnapshot {
+            config: state.config.clone(),
+            commands: Self::commands_state(state).await,
+            uptime_ms: state.started.get().map_or(0, |started| started.elapsed().as_millis() as u64),
+            connections_accepted: state.connections.total_accepted(),
+            active_connections: state.connections.list(),
+            inflight_requests: state.inflight.list(),
+        }
+    }
+
+    /// Requests served per command, with success and error counts and
+    /// handler latencies, as of now
+    pub fn metrics(&self) -> SocketMetrics {
+        self.state.stats.snapshot()
+    }
+
+    /// The server's statistics in the Prometheus text exposition format, as
+    /// returned by the `__metrics` admin command
+    pub fn metrics_text(&self) -> String {
+        Self::metrics_text_state(&self.state)
+    }
+
+    fn metrics_text_state(state: &ServerState<T, R>) -> String {
+        state.stats.render(&prometheus::ServerGauges {
+            uptime: state.started.get().map_or(std::time::Duration::ZERO, |started| started.elapsed()),
+            connections_accepted: state.connections.total_accepted(),
+            active_connections: state.connections.list().len(),
+            inflight_requests: state.inflight.list().len(),
+            queued_connections: state.tasks.queued(),
+        })
+    }
+
+    /// Start the socket server. The socket file is removed again when the
+    /// server stops, including when this future is dropped or panics.
+    ///
+    /// Endpoints added with [`ServerHandle::add_listener`] are served
+    /// alongside the configured one. `run` returns once every endpoint,
+    /// including the configured one, has been removed.
+    pub async fn run(self) -> SocketResult<()> {
+        let listener = transport::Listener::bind(&self.state.config).await?;
+        let endpoint = listener.endpoint(&self.state.config);
+        info!("Socket server listening on: {}", endpoint);
+        let mut listener = Some(listener);
+        self.state.started.get_or_init(std::time::Instant::now);
+
+        let mut changes = self.state.listeners.take_changes().expect("run takes the listener changes once");
+        // Accept loops of added listeners, aborted when `run` stops
+        let mut added = AbortOnDrop::default();
+        let _workers = self.state.tasks.start_workers();
+        loop {
+            let accept = async {
+                match listener.as_mut() {
+                    Some(listener) => self.state.accept_gate.accept(listener).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                accepted = accept => match accepted {
+                    Ok((stream, admission)) => Self::spawn_connection(&self.state, stream, admission).await,
+                    Err(e) => self.state.log_throttle.error(format!("Error accepting connection: {}", e)),
+                },
+                Some(change) = changes.recv() => match change {
+                    ListenerChange::Add { endpoint, listener } => {
+                        info!("Socket server also listening on: {}", endpoint);
+                        let task = tokio::spawn(Self::accept_loop(Arc::clone(&self.state), listener));
+                        added.0.insert(endpoint, task.abort_handle());
+                    }
+                    ListenerChange::Remove { endpoint: removing, removed } => {
+                        let found = if removing == endpoint && listener.is_some() {
+                            listener = None;
+                            true
+                        } else {
+                            added.0.remove(&removing).inspect(|task| task.abort()).is_some()
+                        };
+                        if found {
+                            info!("Stopped listening on: {}", removing);
+                        }
+                        let _ = removed.send(found);
+                        if listener.is_none() && added.0.is_empty() {
+                            return Ok(());
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Serve every connection `listener` accepts, until aborted
+    async fn accept_loop(state: Arc<ServerState<T, R>>, mut listener: transport::Listener) {
+        loop {
+            match state.accept_gate.accept(&mut listener).await {
+                Ok((stream, admission)) => Self::spawn_connection(&state, stream, admission).await,
+                Err(e) => state.log_throttle.error(format!("Error accepting connection: {}", e)),
+            }
+        }
+    }
+
+    /// Serve an accepted connection on its own task, or queue it for a
+    /// worker, waiting while the queue is full
+    async fn spawn_connection(state: &Arc<ServerState<T, R>>, stream: TransportStream, admission: Admission) {
+        let tasks = state.tasks.clone();
+        let state = Arc::clone(state);
+        let Admission::Admitted(slot) = admission else {
+            tasks.spawn(async move {
+                if let Err(e) = Self::refuse_connection(stream, &state).await {
+                    debug!("Error refusing connection: {}", e);
+                }
+            });
+            return;
+        };
+        let mut connection = state.connections.register(stream.peer_credentials());
+        let span = info_span!(
+            "connection",
+            id = connection.id(),
+            client_name = tracing::field::Empty
+        );
+        let task = async move {
+            // Frees the connection's slot once it is served
+            let _slot = slot;
+            connection.set_disconnect_hooks(state.disconnect_hooks.read().await.clone());
+            if let Some(info) = connection.info() {
+                for hook in state.connect_hooks.read().await.iter() {
+                    hook(&info);
+                }
+            }
+            if let Err(e) = Self::handle_connection(stream, Arc::clone(&state), connection).await {
+                state.log_throttle.error(format!("Error handling connection: {}", e));
+            }
+        }
+        .instrument(span);
+        tasks.serve(task).await;
+    }
+
+    /// Answer the first message on a connection beyond `max_connections`
+    /// with a `too_many_connections` error and close it. The message is read
+    /// first so the client isn't reset mid-write.
+    async fn refuse_connection(stream: TransportStream, state: &ServerState<T, R>) -> SocketResult<()> {
+        let mut stream = Self::secure(state, stream).await?;
+        let limit = std::time::Duration::from_secs(state.config.timeout);
+        let first = tokio::time::timeout(limit, FrameReader::for_requests(&state.config).next_frame(&mut stream)).await;
+        let request_id = match first {
+            Ok(Ok(Some(frame))) => RequestHeader::decode(&frame, state.config.max_command_len)
+                .map(|h| h.request_id)
+                .ok(),
+            _ => None,
+        };
+        state.log_throttle.warn(format!(
+            "Refusing connection: max_connections ({}) reached",
+            state.config.max_connections.unwrap_or_default()
+        ));
+        let refusal = SocketResponse::<R>::error(
+            request_id.unwrap_or_default(),
+            "too_many_connections: the server is at its connection limit, try again later",
+        );
+        stream.write_all(&Self::encode_message(state, &refusal)?).await?;
+        Ok(())
+    }
+
+    /// Finish setting up an accepted connection: with TLS configured, run
+    /// the handshake on TCP connections, allowing `timeout` for it
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    async fn secure(state: &ServerState<T, R>, stream: TransportStream) -> SocketResult<TransportStream> {
+        #[cfg(feature = "tls")]
+        if state.config.tls.is_some() {
+            let limit = std::time::Duration::from_secs(state.config.timeout);
+            return tokio::time::timeout(limit, tls::accept(&state.config, stream))
+                .await
+                .map_err(|_| SocketError::ConnectionTimeout)?
+                .map_err(SocketError::from);
+        }
+        Ok(stream)
+    }
+
+    async fn handle_connection(
+        stream: TransportStream,
+        state: Arc<ServerState<T, R>>,
+        mut connection: ConnectionGuard,
+    ) -> SocketResult<()> {
+        let mut stream = Self::secure(&state, stream).await?;
+        let mut reader = FrameReader::for_requests(&state.config);
+        let hangup = Hangup::new(&stream);
+
+        // Read the request, answering an optional handshake first. Each message is
+        // parsed as soon as it is complete, so clients needn't half-close first.
+        let first_frame = reader.next_frame(&mut stream);
+        let first_frame = match state.config.handshake_timeout {
+            Some(limit) => match tokio::time::timeout(limit, first_frame).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    state.log_throttle.warn(format!(
+                        "Dropping connection: handshake_timeout, nothing received within {:?}",
+                        limit
+                    ));
+                    return Ok(());
+                }
+            },
+            None => first_frame.await,
+        };
+        let mut frame = match first_frame {
+            // Bytes that don't even form a JSON document
+            Err(SocketError::Serialization(e)) => {
+                stream.write_all(&Self::encode_message(&state, &Self::invalid_request("", e))?).await?;
+                return Ok(());
+            }
+            Err(e @ SocketError::MessageTooLarge { .. }) => {
+                Self::refuse_oversized(&state, &mut stream, e).await;
+                return Ok(());
+            }
+            // A compressed request this server doesn't accept
+            Err(e @ SocketError::InvalidRequest(_)) => {
+                let refusal = SocketResponse::<R>::error("", e.to_string());
+                stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                return Ok(());
+            }
+            frame => frame?,
+        };
+        let mut dictionary = false;
+        if let Some(handshake) = frame.as_deref().and_then(HandshakeFrame::parse) {
+            if let Some(name) = &handshake.client_name {
+                tracing::Span::current().record("client_name", name.as_str());
+            }
+            handshake::check_peer_version("Client", handshake.crate_version.as_deref());
+            connection.set_client_name(handshake.client_name);
+            if let Some(token) = auth_token(&state.config) {
+                if handshake::token_matches(token, handshake.auth_token.as_deref()) {
+                    connection.set_authenticated();
+                }
+            }
+
+            let server_dictionary = compression::dictionary_id(&state.config);
+            if let Some(client_dictionary) = &handshake.dictionary_id {
+                dictionary = Some(client_dictionary.as_str()) == server_dictionary;
+                if !dictionary {
+                    state.log_throttle.warn(format!(
+                        "Client uses compression dictionary {}, which is not the configured one",
+                        client_dictionary
+                    ));
+                }
+            }
+
+            let type_mismatch = match (&handshake.type_fingerprint, &state.config.type_fingerprint) {
+                (Some(client), Some(server)) => client != server,
+                _ => false,
+            };
+            let protocol_version = handshake.protocol_version.map(|client| (client, handshake::negotiate_version(client)));
+            let flow_control =
+                (handshake.multiplex && handshake.flow_control).then_some(state.config.initial_window_size);
+            let reply = HandshakeFrame {
+                handshake: ServerInfo {
+                    connection_id: connection.id(),
+                    crate_version: Some(handshake::CRATE_VERSION.to_string()),
+                    dictionary_id: server_dictionary.map(String::from),
+                    initial_window_size: flow_control,
+                    type_fingerprint: state.config.type_fingerprint.clone(),
+                    protocol_version: protocol_version
+                        .map(|(_, negotiated)| negotiated.unwrap_or(handshake::PROTOCOL_VERSION)),
+                },
+            };
+            stream.write_all(&Self::encode_message(&state, &reply)?).await?;
+            if let Some((client, None)) = protocol_version {
+                // Clients of this crate report the mismatch from our reply; say why for any other
+                let error = format!(
+                    "version_mismatch: client speaks protocol version {}, server supports {} to {}",
+                    client,
+                    handshake::MIN_PROTOCOL_VERSION,
+                    handshake::PROTOCOL_VERSION
+                );
+                state.log_throttle.warn(format!("Dropping connection: {}", error));
+                let refusal = SocketResponse::<R>::error_with_code("", "version_mismatch", error);
+                stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                return Ok(());
+            }
+            if type_mismatch {
+                // The client reports the mismatch from our reply
+                state.log_throttle.warn(format!(
+                    "Dropping connection: client type fingerprint {} does not match {}",
+                    handshake.type_fingerprint.unwrap_or_default(),
+                    state.config.type_fingerprint.as_deref().unwrap_or_default()
+                ));
+                return Ok(());
+            }
+            if handshake.multiplex {
+                return Self::serve_multiplexed(stream, reader, state, connection, flow_control).await;
+            }
+            frame = reader.next_frame(&mut stream).await?;
+        }
+
+        let Some(mut pending) = frame else {
+            state.log_throttle.warn("Empty connection received".to_string());
+            return Ok(());
+        };
+        loop {
+            let frame = std::mem::take(&mut pending);
+
+            let frame = match Self::open_frame(&state, frame) {
+                Ok(frame) => frame,
+                Err(refusal) => {
+                    stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                    return Ok(());
+                }
+            };
+
+            if let Some(refusal) = Self::filter_raw(&state, &frame).await {
+                stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                return Ok(());
+            }
+
+            // An array is a batch, answered with one response per entry as each completes
+            if codec::is_array(&frame) {
+                let payloads: Vec<SocketPayload<T, R>> = match codec::decode(&frame) {
+                    Ok(payloads) => payloads,
+                    Err(e) => {
+                        stream.write_all(&Self::encode_message(&state, &Self::invalid_request("", e))?).await?;
+                        return Ok(());
+                    }
+                };
+                debug!("Received batch of {} requests", payloads.len());
+                if let Some(refusal) = Self::check_trailing(&state, &mut reader, &mut stream).await {
+                    for payload in payloads {
+                        let refusal = SocketResponse::<R>::error(&payload.request_id, &refusal);
+                        stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                    }
+                    return Ok(());
+                }
+                // Entries run concurrently, and each response is written as soon as it completes
+                let mut running = FuturesUnordered::new();
+                for payload in payloads {
+                    let refusal = Self::check_auth(&state, &connection, &payload.request_id)
+                        .or_else(|| {
+                            let too_long = (payload.command.len() > state.config.max_command_len)
+                                .then_some(payload.command.len());
+                            Self::check_command_len(&state, &payload.request_id, too_long)
+                        })
+                        .or_else(|| Self::check_rate(&state, &connection, &payload.request_id));
+                    if let Some(refusal) = refusal {
+                        stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                        continue;
+                    }
+                    let command = payload.command.clone();
+                    let codec = Codec::negotiate(payload.accept_codec.as_deref(), state.config.codec);
+                    let (state, connection) = (&state, &connection);
+                    running.push(async move {
+                        // Checked as the entry starts, so entries starting once the budget is spent are all refused
+                        let response = match Self::check_budget(state, connection, &payload.request_id) {
+                            Some(refusal) => return (refusal, command, codec, true),
+                            None => Self::dispatch_timed(state, connection, payload).await,
+                        };
+                        (response, command, codec, false)
+                    });
+                }
+                let mut out_of_budget = false;
+                while let Some((response, command, codec, refused)) = running.next().await {
+                    out_of_budget |= refused;
+                    Self::write_response(&mut stream, &response, &state, &command, codec, ResponseMode::Streamed).await?;
+                }
+                if out_of_budget {
+                    return Ok(());
+                }
+                match Self::next_request(&state, &mut reader, &mut stream).await? {
+                    Some(next) => pending = next,
+                    None => return Ok(()),
+                }
+                continue;
+            }
+
+            let header = match RequestHeader::decode(&frame, state.config.max_command_len) {
+                Ok(header) => header,
+                Err(e) => {
+                    stream.write_all(&Self::encode_message(&state, &Self::invalid_request("", e))?).await?;
+                    return Ok(());
+                }
+            };
+            command_log!(state.config, &header.command, "Received request: {}", String::from_utf8_lossy(&frame));
+            let refusal = Self::refuse(&state, &connection, &header)
+                .or_else(|| Self::check_rate(&state, &connection, &header.request_id));
+            if let Some(refusal) = refusal {
+                stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                return Ok(());
+            }
+            if let Some(refusal) = Self::check_command_len(&state, &header.request_id, header.command_too_long) {
+                let codec = Codec::negotiate(header.accept_codec.as_deref(), state.config.codec);
+                let mode = ResponseMode::Single { dictionary };
+                Self::write_response(&mut stream, &refusal, &state, &header.command, codec, mode).await?;
+                return Ok(());
+            }
+            state.stats.record_request(&header.command, frame.len());
+            if let Some(metrics) = state.metrics.read().await.as_ref() {
+                metrics.on_request_size(&header.command, frame.len());
+            }
+
+            let subscribe = header.command == admin::SUBSCRIBE_COMMAND;
+            let upgrade_handler = state.upgrade_handlers.read().await.get(&header.command).cloned();
+            let download_handler = state.download_handlers.read().await.get(&header.command).cloned();
+            let streaming_handler = state.streaming_handlers.read().await.get(&header.command).cloned();
+            let takes_over = upgrade_handler.is_some() || download_handler.is_some() || streaming_handler.is_some();
+            if subscribe || takes_over || header.ack {
+                let refusal = match Self::check_ready(&state, &header.request_id) {
+                    Some(refusal) => Some(refusal),
+                    None => {
+                        let context = Self::request_context(&connection, &header.request_id, &header.command);
+                        Self::authorize(&state, &context).await
+                    }
+                };
+                if let Some(refusal) = refusal {
+                    stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                    return Ok(());
+                }
+            }
+
+            if subscribe {
+                return Self::serve_subscriber(stream, reader, &state, &connection, &frame).await;
+            }
+
+            if let Some(handler) = upgrade_handler {
+                let payload: SocketPayload<T, R> = match codec::decode(&frame) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        let response = Self::invalid_request(&header.request_id, e);
+                        stream.write_all(&Self::encode_message(&state, &response)?).await?;
+                        return Ok(());
+                    }
+                };
+                let response = SocketResponse::<R>::upgrade(&payload.request_id);
+                stream.write_all(&Self::encode_message(&state, &response)?).await?;
+                command_log!(state.config, &header.command, "Upgraded connection for request ID: {}", payload.request_id);
+                let span = info_span!("request", request_id = %payload.request_id, command = %header.command);
+                return handler(payload, UpgradedStream::new(stream, reader.into_buffered())).instrument(span).await;
+            }
+
+            if let Some(handler) = download_handler {
+                let payload: SocketPayload<T, R> = match codec::decode(&frame) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        let response = Self::invalid_request(&header.request_id, e);
+                        stream.write_all(&Self::encode_message(&state, &response)?).await?;
+                        return Ok(());
+                    }
+                };
+                let request_id = payload.request_id.clone();
+                let response = SocketResponse::<R>::download(&request_id);
+                stream.write_all(&Self::encode_message(&state, &response)?).await?;
+                let span = info_span!("request", request_id = %request_id, command = %header.command);
+                download::serve(&mut stream, &request_id, |sink| handler(payload, sink)).instrument(span).await?;
+                command_log!(state.config, &header.command, "Finished download for request ID: {}", request_id);
+                return Ok(());
+            }
+
+            if let Some(handler) = streaming_handler {
+                let payload: SocketPayload<T, R> = match codec::decode(&frame) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        let response = Self::invalid_request(&header.request_id, e);
+                        stream.write_all(&Self::encode_message(&state, &response)?).await?;
+                        return Ok(());
+                    }
+                };
+                let request_id = payload.request_id.clone();
+                let codec = Codec::negotiate(header.accept_codec.as_deref(), state.config.codec);
+                let span = info_span!("request", request_id = %request_id, command = %header.command);
+                Self::serve_streaming(&mut stream, &state, &header.command, codec, payload, handler)
+                    .instrument(span)
+                    .await?;
+                command_log!(state.config, &header.command, "Finished streaming response for request ID: {}", request_id);
+                return Ok(());
+            }
+
+            if let Some(refusal) = Self::check_trailing(&state, &mut reader, &mut stream).await {
+                let refusal = SocketResponse::<R>::error(&header.request_id, refusal);
+                stream.write_all(&Self::encode_message(&state, &refusal)?).await?;
+                return Ok(());
+            }
+            if header.ack {
+                return Self::serve_acknowledged(stream, &state, &connection, &header, &frame).await;
+            }
+            let mode = ResponseMode::Single { dictionary };
+            {
+                let respond = Self::respond(&mut stream, &state, &connection, &header, &frame, mode);
+                tokio::pin!(respond);
+                tokio::select! {
+                    responded = &mut respond => responded?,
+                    _ = hangup.closed() => {
+                        debug!("Client hung up, cancelling request ID: {}", header.request_id);
+                        connection.cancellation().cancel();
+                        // Let the handler wind down; nobody is left to read the response
+                        let _ = respond.await;
+                        return Ok(());
+                    }
+                }
+            }
+            command_log!(state.config, &header.command, "Sent response for request ID: {}", header.request_id);
+
+            match Self::next_request(&state, &mut reader, &mut stream).await? {
+                Some(next) => pending = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Acknowledge a fire-and-forget request once it parses and close the
+    /// connection, then run its handler to completion. Nobody waits for the
+    /// response, so the handler isn't cancelled when the client leaves.
+    async fn serve_acknowledged(
+        mut stream: TransportStream,
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        header: &RequestHeader,
+        frame: &[u8],
+    ) -> SocketResult<()> {
+        let payload: SocketPayload<T, R> = match codec::decode(frame) {
+            Ok(payload) => payload,
+            Err(e) => {
+                let response = Self::invalid_request(&header.request_id, e);
+                stream.write_all(&Self::encode_message(state, &response)?).await?;
+                return Ok(());
+            }
+        };
+        let ack = SocketResponse::<R>::ack(&header.request_id);
+        stream.write_all(&Self::encode_message(state, &ack)?).await?;
+        stream.shutdown().await?;
+        drop(stream);
+        command_log!(state.config, &header.command, "Acknowledged request ID: {}", header.request_id);
+
+        let response = Self::dispatch_timed(state, connection, payload).await;
+        if let Some(error) = &response.error {
+            warn!("Acknowledged request {} failed: {}", header.request_id, error);
+        }
+        Ok(())
+    }
+
+    /// Run a streaming handler, writing each chunk it sends as a response of
+    /// its own between the announcing response and the one ending the stream
+    async fn serve_streaming(
+        stream: &mut TransportStream,
+        state: &ServerState<T, R>,
+        command: &str,
+        codec: Codec,
+        payload: SocketPayload<T, R>,
+        handler: StreamingHandler<T, R>,
+    ) -> SocketResult<()> {
+        let request_id = payload.request_id.clone();
+        let started = SocketResponse::<R>::stream(&request_id);
+        Self::write_response(stream, &started, state, command, codec, ResponseMode::Streamed).await?;
+
+        let (sink, chunks) = streaming::channel(&request_id);
+        let handler = handler(payload, sink);
+        let forward = async {
+            // Owned here, so a handler still sending after the client is gone fails instead of waiting
+            let mut chunks = chunks;
+            while let Some(chunk) = chunks.recv().await {
+                Self::write_response(&mut *stream, &chunk, state, command, codec, ResponseMode::Streamed).await?;
+            }
+            Ok::<_, SocketError>(())
+        };
+
+        // The sink is dropped when the handler finishes, which ends forwarding
+        let (result, forwarded) = tokio::join!(handler, forward);
+        forwarded?;
+
+        let end: SocketResponse<R> = match result {
+            Ok(()) => SocketResponse::stream_end(&request_id),
+            Err(e) => {
+                warn!("Streaming handler for request {} failed: {}", request_id, e);
+                SocketResponse::error(&request_id, e.to_string())
+            }
+        };
+        Self::write_response(stream, &end, state, command, codec, ResponseMode::Streamed).await
+    }
+
+    /// Write events to a subscribed connection, and apply the topic changes
+    /// it asks for, until it disconnects or falls too far behind
+    async fn serve_subscriber(
+        mut stream: TransportStream,
+        mut reader: FrameReader,
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        first: &[u8],
+    ) -> SocketResult<()> {
+        // Subscribe before confirming, so every event sent once the client hears back reaches it
+        let mut subscriber = Subscriber::new(&state.events, connection.id());
+        stream.write_all(&subscriber.handle_request(first)?).await?;
+        debug!("Connection {} subscribed to events", connection.id());
+
+        let write_timeout = state.config.write_timeout;
+        loop {
+            tokio::select! {
+                event = subscriber.next_event(&state.log_throttle) => match event {
+                    Some(bytes) => framing::write_all_within(&mut stream, &bytes, write_timeout).await?,
+                    None => return Ok(()),
+                },
+                frame = reader.next_frame(&mut stream) => {
+                    let Some(frame) = frame? else {
+                        return Ok(());
+                    };
+                    let reply = match Self::open_frame(state, frame) {
+                        Ok(frame) => subscriber.handle_request(&frame)?,
+                        Err(refusal) => Self::encode_message(state, &refusal)?,
+                    };
+                    framing::write_all_within(&mut stream, &reply, write_timeout).await?;
+                }
+            }
+        }
+    }
+
+    /// With [`TrailingBytes::Reject`], wait for the client to finish sending
+    /// and return the error for a request followed by anything but whitespace
+    async fn check_trailing(
+        state: &ServerState<T, R>,
+        reader: &mut FrameReader,
+        stream: &mut TransportStream,
+    ) -> Option<String> {
+        if state.config.trailing_bytes != TrailingBytes::Reject {
+            return None;
+        }
+        match reader.next_frame(stream).await {
+            Ok(None) => None,
+            _ => {
+                state.log_throttle.warn("Refusing request followed by trailing bytes".to_string());
+                Some("trailing_bytes: the request is followed by more data".to_string())
+            }
+        }
+    }
+
+    /// With [`TrailingBytes::NextFrame`], read the request following the one
+    /// just answered. `None` once the client is done or in other modes.
+    async fn next_request(
+        state: &ServerState<T, R>,
+        reader: &mut FrameReader,
+        stream: &mut TransportStream,
+    ) -> SocketResult<Option<Vec<u8>>> {
+        if state.config.trailing_bytes != TrailingBytes::NextFrame {
+            return Ok(None);
+        }
+        let next = tokio::select! {
+            next = reader.next_frame(stream) => next,
+            _ = idle(state.config.idle_timeout) => {
+                debug!("Closing connection idle for {:?}", state.config.idle_timeout.unwrap_or_default());
+                return Ok(None);
+            }
+        };
+        match next {
+            Err(SocketError::Serialization(e)) => {
+                stream.write_all(&Self::encode_message(state, &Self::invalid_request("", e))?).await?;
+                Ok(None)
+            }
+            Err(e @ SocketError::MessageTooLarge { .. }) => {
+                Self::refuse_oversized(state, stream, e).await;
+                Ok(None)
+            }
+            frame => frame,
+        }
+    }
+
+    /// Answer a message over `max_message_size` with a `message_too_large`
+    /// error before the connection is closed. The client may still be
+    /// writing the rest of it, so the answer is best effort.
+    async fn refuse_oversized(state: &ServerState<T, R>, stream: &mut TransportStream, e: SocketError) {
+        state.log_throttle.warn(format!("Dropping connection: {}", e));
+        let refusal = SocketResponse::<R>::error("", format!("message_too_large: {}", e));
+        if let Ok(bytes) = Self::encode_message(state, &refusal) {
+            let _ = stream.write_all(&bytes).await;
+        }
+    }
+
+    /// Serve a connection whose client asked to multiplex requests.
+    ///
+    /// Every frame is a request whose handler runs on its own task, so a slow
+    /// handler doesn't hold up the others. Responses are written by a single
+    /// writer task in the order they complete and are matched to requests by
+    /// `request_id`. The connection closes once the client half-closes and
+    /// all outstanding responses are written, or after a refused request or
+    /// one marked `close_after`.
+    /// With `flow_control`, the initial window size, responses are sent in
+    /// data frames within per-request windows (see [`flow_control`]).
+    async fn serve_multiplexed(
+        stream: TransportStream,
+        mut reader: FrameReader,
+        state: Arc<ServerState<T, R>>,
+        connection: ConnectionGuard,
+        flow_control: Option<u32>,
+    ) -> SocketResult<()> {
+        let hangup = Hangup::new(&stream);
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        let (responses, outgoing) = tokio::sync::mpsc::channel::<WriterEvent>(64);
+        let write_timeout = state.config.write_timeout;
+        let mut writer = tokio::spawn(async move {
+            flow_control::write_responses(&mut write_half, outgoing, flow_control, write_timeout).await
+        });
+        let refusal = |request_id: &str, response: &SocketResponse<R>| -> SocketResult<WriterEvent> {
+            let bytes = Self::encode_message(&state, response)?;
+            Ok(WriterEvent::Response { request_id: request_id.to_string(), bytes })
+        };
+
+        let connection = Arc::new(connection);
+        loop {
+            // Stop reading if the writer gave up, e.g. after a write timeout
+            let frame = tokio::select! {
+                frame = reader.next_frame(&mut read_half) => frame?,
+                _ = responses.closed() => break,
+                _ = idle(state.config.idle_timeout) => {
+                    debug!("Closing connection idle for {:?}", state.config.idle_timeout.unwrap_or_default());
+                    break;
+                }
+            };
+            let Some(frame) = frame else {
+                let _ = responses.send(WriterEvent::ClientClosed).await;
+                break;
+            };
+            if flow_control.is_some() {
+                if let Some(update) = WindowUpdateFrame::parse(&frame) {
+                    let _ = responses.send(WriterEvent::WindowUpdate(update)).await;
+                    continue;
+                }
+            }
+            let frame = match Self::open_frame(&state, frame) {
+                Ok(frame) => frame,
+                Err(response) => {
+                    let _ = responses.send(refusal(&response.request_id, &response)?).await;
+                    break;
+                }
+            };
+            if let Some(response) = Self::filter_raw(&state, &frame).await {
+                let _ = responses.send(refusal(&response.request_id, &response)?).await;
+                continue;
+            }
+            let header = match RequestHeader::decode(&frame, state.config.max_command_len) {
+                Ok(header) => header,
+                Err(e) => {
+                    let _ = responses.send(refusal("", &Self::invalid_request("", e))?).await;
+                    break;
+                }
+            };
+            if let Some(response) = Self::refuse(&state, &connection, &header) {
+                let _ = responses.send(refusal(&header.request_id, &response)?).await;
+                break;
+            }
+            if let Some(response) = Self::check_command_len(&state, &header.request_id, header.command_too_long) {
+                let codec = Codec::negotiate(header.accept_codec.as_deref(), state.config.codec);
+                let mut bytes = Vec::new();
+                Self::write_response(&mut bytes, &response, &state, &header.command, codec, ResponseMode::Streamed).await?;
+                let _ = responses.send(WriterEvent::Response { request_id: header.request_id, bytes }).await;
+                break;
+            }
+            // Only this request is refused; the client may slow down and carry on
+            if let Some(response) = Self::check_rate(&state, &connection, &header.request_id) {
+                let _ = responses.send(refusal(&header.request_id, &response)?).await;
+                continue;
+            }
+            state.stats.record_request(&header.command, frame.len());
+            if let Some(metrics) = state.metrics.read().await.as_ref() {
+                metrics.on_request_size(&header.command, frame.len());
+            }
+
+            let close_after = header.close_after.then(|| header.request_id.clone());
+            let state = Arc::clone(&state);
+            let connection = Arc::clone(&connection);
+            let responses = responses.clone();
+            tokio::spawn(
+                async move {
+                    let mut response = Vec::new();
+                    if let Err(e) = Self::respond(&mut response, &state, &connection, &header, &frame, ResponseMode::Streamed).await {
+                        warn!("Error handling request {}: {}", header.request_id, e);
+                        let error_response = SocketResponse::<R>::error(&header.request_id, e.to_string());
+                        response = Self::encode_message(&state, &error_response).unwrap_or_default();
+                    }
+                    let event = WriterEvent::Response { request_id: header.request_id, bytes: response };
+                    let _ = responses.send(event).await;
+                }
+                .in_current_span(),
+            );
+            if let Some(request_id) = close_after {
+                debug!("Closing connection after request ID: {}", request_id);
+                break;
+            }
+        }
+
+        // The writer finishes once every request task has sent its response
+        drop(responses);
+        let written = tokio::select! {
+            written = &mut writer => written,
+            _ = hangup.closed() => {
+                debug!("Client hung up, cancelling its outstanding requests");
+                connection.cancellation().cancel();
+                writer.await
+            }
+        };
+        written.map_err(std::io::Error::other)??;
+        Ok(())
+    }
+
+    /// Serialize a message for the wire in the configured framing
+    fn encode_message<Q: serde::Serialize>(state: &ServerState<T, R>, message: &Q) -> SocketResult<Vec<u8>> {
+        framing::encode(state.config.framing, state.config.codec.encode(message)?)
+    }
+
+    /// Error response for a request that failed to parse, saying where and why
+    fn invalid_request(request_id: &str, e: impl std::fmt::Display) -> SocketResponse<R> {
+        SocketResponse::error(request_id, SocketError::InvalidRequest(e.to_string()).to_string())
+    }
+
+    /// Unwrap a request frame, verifying its signature when signing is configured
+    #[cfg_attr(not(feature = "signing"), allow(unused_variables))]
+    fn open_frame(state: &ServerState<T, R>, frame: Vec<u8>) -> Result<Vec<u8>, SocketResponse<R>> {
+        #[cfg(feature = "signing")]
+        if let Some(signing) = &state.config.signing {
+            return state.replay_guard.verify(signing, &frame).map_err(|reason| {
+                state
+                    .log_throttle
+                    .warn(format!("Rejected request with invalid signature: {}", reason));
+                SocketResponse::error("", format!("signature_invalid: {}", reason))
+            });
+        }
+        Ok(frame)
+    }
+
+    /// Run the raw filters over a request, returning the error response if one rejects it
+    async fn filter_raw(state: &ServerState<T, R>, frame: &[u8]) -> Option<SocketResponse<R>> {
+        let filters = state.raw_filters.read().await;
+        let error = filters.iter().find_map(|filter| filter(frame).err())?;
+
+        // Echo the request ID when the request is well-formed enough to have one
+        let request_id = RequestHeader::decode(frame, state.config.max_command_len)
+            .map(|header| header.request_id)
+            .unwrap_or_default();
+        debug!("Raw filter rejected request {:?}: {}", request_id, error);
+        Some(SocketResponse::error(request_id, error.to_string()))
+    }
+
+    /// Refuse a request before it is parsed further, if its connection isn't
+    /// authenticated or has spent its handler time budget
+    fn refuse(
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        header: &RequestHeader,
+    ) -> Option<SocketResponse<R>> {
+        Self::check_auth(state, connection, &header.request_id)
+            .or_else(|| Self::check_budget(state, connection, &header.request_id))
+    }
+
+    /// Refuse a request if its client has used up its rate limit
+    fn check_rate(
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        request_id: &str,
+    ) -> Option<SocketResponse<R>> {
+        let limiter = state.rate_limiter.as_ref()?;
+        let retry_after = limiter.check(connection.peer_credentials(), connection.id()).err()?;
+        state
+            .log_throttle
+            .warn(format!("Rate limited a client on connection {}", connection.id()));
+        Some(SocketResponse::error(
+            request_id,
+            format!("rate_limited: too many requests, retry in {}ms", retry_after.as_millis().max(1)),
+        ))
+    }
+
+    /// Refuse requests on a connection that didn't present the configured auth token
+    fn check_auth(
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        request_id: &str,
+    ) -> Option<SocketResponse<R>> {
+        auth_token(&state.config)?;
+        if connection.is_authenticated() {
+            return None;
+        }
+        state
+            .log_throttle
+            .warn("Rejected request from a connection without a valid auth token".to_string());
+        Some(SocketResponse::error(request_id, "unauthorized: missing or invalid auth token"))
+    }
+
+    /// Answer one request: an admin command, or the handler for its command
+    async fn respond<W>(
+        out: &mut W,
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        header: &RequestHeader,
+        frame: &[u8],
+        mode: ResponseMode,
+    ) -> SocketResult<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        let codec = Codec::negotiate(header.accept_codec.as_deref(), state.config.codec);
+        if let Some(response) = Self::admin_response(state, header).await {
+            return Self::write_response(out, &response, state, &header.command, codec, mode).await;
+        }
+
+        let decode_started = std::time::Instant::now();
+        let payload: SocketPayload<T, R> = match codec::decode(frame) {
+            Ok(payload) => payload,
+            Err(e) => {
+                let response = Self::invalid_request(&header.request_id, e);
+                return Self::write_response(out, &response, state, &header.command, codec, mode).await;
+            }
+        };
+        if let Some(metrics) = state.metrics.read().await.as_ref() {
+            metrics.on_decode_duration(&header.command, decode_started.elapsed());
+        }
+        let response = Self::dispatch_timed(state, connection, payload).await;
+        Self::write_response(out, &response, state, &header.command, codec, mode).await
+    }
+
+    /// Answer a built-in admin command, if enabled and `header` names one
+    async fn admin_response(
+        state: &ServerState<T, R>,
+        header: &RequestHeader,
+    ) -> Option<SocketResponse<serde_json::Value>> {
+        let data = match header.command.as_str() {
+            admin::PING_COMMAND => Ok(serde_json::json!("pong")),
+            admin::READY_COMMAND => Ok(serde_json::json!({ "ready": Self::is_ready(state) })),
+            admin::HEALTH_COMMAND if state.config.health_command => Ok(serde_json::json!({
+                "uptime_secs": state.started.get().map_or(0, |started| started.elapsed().as_secs()),
+                "commands": Self::commands_state(state).await.len(),
+            })),
+            _ if !state.config.admin_commands => return None,
+            admin::INFLIGHT_COMMAND => serde_json::to_value(state.inflight.list()),
+            admin::SNAPSHOT_COMMAND => serde_json::to_value(Self::snapshot_state(state).await),
+            admin::METRICS_COMMAND => Ok(serde_json::Value::String(Self::metrics_text_state(state))),
+            _ => return None,
+        };
+        command_log!(state.config, &header.command, "Answering admin command: {}", header.command);
+        Some(match data {
+            Ok(data) => SocketResponse::success(&header.request_id, data),
+            Err(e) => SocketResponse::error(&header.request_id, e.to_string()),
+        })
+    }
+
+    /// Whether the warm-up period is over
+    fn is_ready(state: &ServerState<T, R>) -> bool {
+        state.readiness.is_ready(state.started.get().copied())
+    }
+
+    /// Refuse a request while the server is still warming up
+    fn check_ready(state: &ServerState<T, R>, request_id: &str) -> Option<SocketResponse<R>> {
+        if Self::is_ready(state) {
+            return None;
+        }
+        Some(SocketResponse::error(request_id, "warming_up: server is not ready yet"))
+    }
+
+    /// Refuse a request if its connection has used up its handler time budget
+    fn check_budget(
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        request_id: &str,
+    ) -> Option<SocketResponse<R>> {
+        let budget = state.config.handler_time_budget?;
+        let used = connection.handler_time();
+        if used < budget {
+            return None;
+        }
+
+        warn!(
+            "Connection {} used {:?} of handler time (budget {:?}), closing",
+            connection.id(),
+            used,
+            budget
+        );
+        Some(SocketResponse::error(
+            request_id,
+            format!("cpu_budget_exceeded: used {:?} of {:?}", used, budget),
+        ))
+    }
+
+    /// Refuse a request whose command was `too_long`, i.e. longer than `max_command_len`
+    fn check_command_len(state: &ServerState<T, R>, request_id: &str, too_long: Option<usize>) -> Option<SocketResponse<R>> {
+        let length = too_long?;
+        let limit = state.config.max_command_len;
+        state
+            .log_throttle
+            .warn(format!("Rejected request with a command longer than {} bytes", limit));
+        Some(SocketResponse::error(
+            request_id,
+            format!("command_too_long: {} bytes exceeds limit of {}", length, limit),
+        ))
+    }
+
+    /// Dispatch a payload, charging the handler's run time to the connection
+    async fn dispatch_timed(
+        state: &ServerState<T, R>,
+        connection: &ConnectionGuard,
+        payload: SocketPayload<T, R>,
+    ) -> SocketResponse<R> {
+        let _inflight = state
+            .inflight
+            .start(&payload.command, &payload.request_id, connection.id());
+        let context = Self::request_context(connection, &payload.request_id, &payload.command);
+        let command = payload.command.clone();
+        let span = info_span!("request", request_id = %payload.request_id, command = %command);
+        let started = std::time::Instant::now();
+        let response = Self::dispatch(state, payload, context).instrument(span).await;
+        let elapsed = started.elapsed();
+        connection.add_handler_time(elapsed);
+        state.stats.record_handler(&command, elapsed, response.success);
+        response
+    }
+
+    fn request_context(connection: &ConnectionGuard, request_id: &str, command: &str) -> RequestContext {
+        RequestContext {
+            request_id: request_id.to_string(),
+            command: command.to_string(),
+            connection_id: connection.id(),
+            client_name: connection.client_name(),
+            peer_credentials: connection.peer_credentials(),
+            cancellation: connection.cancellation().clone(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Ask the authorizer, if one is set, whether the request in `context`
+    /// may run its command, returning the error to answer with if not
+    async fn authorize(state: &ServerState<T, R>, context: &RequestContext) -> Option<SocketResponse<R>> {
+        let authorizer = state.authorizer.read().await.clone()?;
+        if authorizer(context, &context.command) {
+            return None;
+        }
+        state.log_throttle.warn(format!(
+            "Denied command {} on connection {}",
+            context.command, context.connection_id
+        ));
+        Some(SocketResponse::error(
+            &context.request_id,
+            format!("unauthorized: not permitted to run {}", context.command),
+        ))
+    }
+
+    /// Run the middleware chain, the handler registered for a payload's
+    /// command and the post hooks, turning failures into error responses
+    async fn dispatch(
+        state: &ServerState<T, R>,
+        payload: SocketPayload<T, R>,
+        mut context: RequestContext,
+    ) -> SocketResponse<R> {
+        let request_id = payload.request_id.clone();
+        if let Some(refusal) = Self::check_ready(state, &request_id) {
+            return refusal;
+        }
+
+        let middleware = state.middleware.read().await.clone();
+        for middleware in middleware {
+            if let Err(e) = middleware(&payload, &mut context) {
+                command_log!(state.config, &payload.command, "Middleware rejected request {}: {}", request_id, e);
+                return SocketResponse::error(&request_id, e.to_string());
+            }
+        }
+        if let Some(refusal) = Self::authorize(state, &context).await {
+            return refusal;
+        }
+
+        let post_hooks = state.post_hooks.read().await.clone();
+        if post_hooks.is_empty() {
+            return Self::run_handler(state, payload, context).await;
+        }
+        let hook_context = context.without_extensions();
+        let response = Self::run_handler(state, payload, context).await;
+        for hook in post_hooks {
+            hook(&hook_context, &response);
+        }
+        response
+    }
+
+    /// Run the handler registered for a payload's command, turning failures
+    /// into error responses
+    async fn run_handler(
+        state: &ServerState<T, R>,
+        payload: SocketPayload<T, R>,
+        context: RequestContext,
+    ) -> SocketResponse<R> {
+        // Store request_id before moving payload
+        let request_id = payload.request_id.clone();
+        let command = payload.command.clone();
+
+        // Find and execute the handler
+        let Some(handler) = state.handlers.read().await.get(&payload.command).cloned() else {
+            #[cfg(feature = "http-fallback")]
+            if let Some(response) = Self::forward_to_http(state, &payload).await {
+                return response;
+            }
+            return SocketResponse::error(&request_id, format!("No handler for command: {}", command));
+        };
+
+        let dedup = match &payload.idempotency_key {
+            Some(key) => {
+                let store = state.dedup.read().await.clone();
+                if let Some(response) = Self::claim_key(store.as_ref(), key, &request_id) {
+                    return response;
+                }
+                Some((store, key.clone()))
+            }
+            None => None,
+        };
+        let result = match handler {
+            CommandHandler::Inline(handler) if !state.config.blocking_handlers => handler(payload, context),
+            CommandHandler::Inline(handler) | CommandHandler::Blocking(handler) => {
+                // Carry the request's span over to the blocking thread
+                let span = tracing::Span::current();
+                match tokio::task::spawn_blocking(move || span.in_scope(|| handler(payload, context))).await {
+                    Ok(result) => result,
+                    Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Err(e) => Err(SocketError::Io(std::io::Error::other(e))),
+                }
+            }
+            CommandHandler::Async(handler) => {
+                let cancellation = context.cancellation.clone();
+                tokio::select! {
+                    result = handler(payload, context) => result,
+                    _ = cancellation.cancelled() => {
+                        command_log!(state.config, &command, "Cancelled request {}: client disconnected", request_id);
+                        let error = "cancelled: the client disconnected";
+                        Ok(SocketResponse::error_with_code(&request_id, "cancelled", error))
+                    }
+                }
+            }
+        };
+
+        let response = match result {
+            Ok(response) if state.config.strict_responses && response.is_success_without_data() => {
+                warn!("Handler for command {} returned success without data", command);
+                SocketResponse::error(
+                    &request_id,
+                    format!("protocol_error: handler for {} returned success without data", command),
+                )
+            }
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Error handling request: {}", e);
+                SocketResponse::error(&request_id, e.to_string())
+            }
+        };
+
+        if let Some((store, key)) = dedup {
+            // A handler that didn't finish leaves nothing to replay, so a retry runs it again
+            let stored = if matches!(response.code.as_deref(), Some("cancelled" | "handler_timeout")) {
+                store.release(&key)
+            } else {
+                serde_json::to_value(&response)
+                    .map_err(SocketError::from)
+                    .and_then(|stored| store.complete(&key, stored))
+            };
+            if let Err(e) = stored {
+                warn!("Failed to store the response for idempotency key {}: {}", key, e);
+            }
+        }
+        response
+    }
+
+    /// Claim `key` for running the handler, or return what to answer instead:
+    /// the stored response of an earlier request with the key, or an error
+    fn claim_key(store: &dyn DedupStore, key: &str, request_id: &str) -> Option<SocketResponse<R>> {
+        match store.claim(key) {
+            Ok(DedupEntry::Claimed) => None,
+            Ok(DedupEntry::InProgress) => Some(SocketResponse::error(
+                request_id,
+                format!("duplicate_in_progress: a request with idempotency key {} is still running", key),
+            )),
+            Ok(DedupEntry::Completed(stored)) => {
+                debug!("Replaying the stored response for idempotency key {}", key);
+                Some(match serde_json::from_value::<SocketResponse<R>>(stored) {
+                    Ok(response) => SocketResponse { request_id: request_id.to_string(), ..response },
+                    Err(e) => {
+                        SocketResponse::error(request_id, format!("dedup_unavailable: stored response is invalid: {}", e))
+                    }
+                })
+            }
+            Err(e) => {
+                warn!("Failed to claim idempotency key {}: {}", key, e);
+                Some(SocketResponse::error(request_id, format!("dedup_unavailable: {}", e)))
+            }
+        }
+    }
+
+    /// Answer a command without a handler from the HTTP fallback, if one is
+    /// configured and maps the command to a URL
+    #[cfg(feature = "http-fallback")]
+    async fn forward_to_http(state: &ServerState<T, R>, payload: &SocketPayload<T, R>) -> Option<SocketResponse<R>> {
+        let fallback = state.config.http_fallback.as_ref()?;
+        let url = fallback.url_for(&payload.command)?;
+        command_log!(state.config, &payload.command, "Forwarding request {} to {}", payload.request_id, url);
+        let timeout = std::time::Duration::from_secs(state.config.timeout);
+        let response = fallback
+            .forward(&url, &payload.request_id, &payload.data, timeout)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("HTTP fallback for command {} failed: {}", payload.command, e);
+                SocketResponse::error(&payload.request_id, format!("http_fallback: {}", e))
+            });
+        Some(response)
+    }
+
+    /// Serialize a response and write it, applying the large response policy.
+    /// What is finally written is one message in the configured framing.
+    ///
+    /// Single responses on a connection that agreed on a compression
+    /// dictionary are zstd-compressed against it unless the policy streams or
+    /// rejects them. Otherwise `SocketConfig::compression` applies wherever
+    /// the framing delimits a compressed body.
+    async fn write_response<W, Q>(
+        stream: &mut W,
+        response: &SocketResponse<Q>,
+        state: &ServerState<T, R>,
+        command: &str,
+        codec: Codec,
+        mode: ResponseMode,
+    ) -> SocketResult<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+        Q: serde::Serialize,
+    {
+        let config = &state.config;
+        let encode_started = std::time::Instant::now();
+        let response_json = codec.encode(response)?;
+        state.stats.record_response(command, response_json.len());
+        if let Some(metrics) = state.metrics.read().await.as_ref() {
+            metrics.on_encode_duration(command, encode_started.elapsed());
+            metrics.on_response_size(command, response_json.len());
+        }
+        let threshold = config.large_response_threshold;
+        let timeout = config.write_timeout;
+        let oversize = response_json.len() > threshold;
+
+        #[cfg(feature = "zstd")]
+        if let (ResponseMode::Single { dictionary: true }, Some(dictionary)) = (mode, &config.compression_dictionary) {
+            let policy = config.large_response_policy;
+            let allowed = !oversize || matches!(policy, LargeResponsePolicy::Allow | LargeResponsePolicy::Compress);
+            if allowed && config.framing.delimits_compressed(true) {
+                let compressed = compression::zstd_compress(&response_json, dictionary)?;
+                framing::write_message(stream, config.framing, &compressed, timeout).await?;
+                return Ok(());
+            }
+        }
+
+        let delimited = config.framing.delimits_compressed(matches!(mode, ResponseMode::Single { .. }));
+        let policy_allows = matches!(config.large_response_policy, LargeResponsePolicy::Allow | LargeResponsePolicy::Compress);
+        if delimited && (!oversize || policy_allows) {
+            if let Some(compressed) = config.compression.compress(&response_json)? {
+                framing::write_message(stream, config.framing, &compressed, timeout).await?;
+                return Ok(());
+            }
+        }
+
+        if !oversize {
+            framing::write_message(stream, config.framing, &response_json, timeout).await?;
+            return Ok(());
+        }
+
+        match config.large_response_policy {
+            LargeResponsePolicy::Allow => {
+                framing::write_message(stream, config.framing, &response_json, timeout).await?
+            }
+            LargeResponsePolicy::Compress if !delimited => {
+                warn!(
+                    "Sending response for request ID {} uncompressed: {} bytes is over the {} byte limit, \
+                     but {:?} framing can't delimit a compressed body here",
+                    response.request_id,
+                    response_json.len(),
+                    threshold,
+                    config.framing
+                );
+                framing::write_message(stream, config.framing, &response_json, timeout).await?
+            }
+            LargeResponsePolicy::Stream => {
+                framing::write_prefix(stream, config.framing, response_json.len(), timeout).await?;
+                for chunk in response_json.chunks(threshold.max(1)) {
+                    framing::write_all_within(stream, chunk, timeout).await?;
+                    stream.flush().await?;
+                }
+                framing::write_terminator(stream, config.framing, timeout).await?;
+            }
+            LargeResponsePolicy::Compress => {
+                let compressed = compression::gzip(&response_json)?;
+                debug!(
+                    "Compressed response for request ID {} from {} to {} bytes",
+                    response.request_id,
+                    response_json.len(),
+                    compressed.len()
+                );
+                framing::write_message(stream, config.framing, &compressed, timeout).await?;
+            }
+            LargeResponsePolicy::Error => {
+                warn!(
+                    "Response for request ID {} is {} bytes, over the {} byte limit",
+                    response.request_id,
+                    response_json.len(),
+                    threshold
+                );
+                let error_response = SocketResponse::<Q>::error(
+                    &response.request_id,
+                    format!(
+                        "response_too_large: {} bytes exceeds limit of {}",
+                        response_json.len(),
+                        threshold
+                    ),
+                );
+                framing::write_message(stream, config.framing, &codec.encode(&error_response)?, timeout).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SocketServer<serde_json::Value, serde_json::Value> {
+    /// Register a handler for a typed [`Command`].
+    ///
+    /// The request data is deserialized as `C::Request` and the handler's
+    /// `C::Response` is returned as a success response, so one server can
+    /// serve commands with different types while the wire format stays the
+    /// same as with typed payloads.
+    pub async fn register_command<C, F>(&self, handler: F)
+    where
+        C: Command,
+        F: Fn(C::Request) -> SocketResult<C::Response> + Send + Sync + 'static,
+    {
+        self.register_handler(C::NAME, move |payload| {
+            let request: C::Request = serde_json::from_value(payload.data)?;
+            let response = serde_json::to_value(handler(request)?)?;
+            Ok(SocketResponse::success(payload.request_id, response))
+        })
+        .await;
+    }
+}
+
+/// Unix socket client for sending requests
+#[derive(Clone)]
+pub struct SocketClient {
+    config: SocketConfig,
+    client_name: Option<String>,
+    /// Connection opened ahead of the next request, when connecting eagerly
+    warm: Option<Arc<tokio::sync::Mutex<Option<TransportStream>>>>,
+    /// How many redirect responses `send_request` follows
+    max_redirects: usize,
+    /// Response validators, keyed by the response data type they check
+    validators: std::collections::HashMap<std::any::TypeId, Arc<dyn std::any::Any + Send + Sync>>,
+    /// How `send_request` retries requests that fail to reach the server
+    retry: Option<RetryConfig>,
+    /// Request IDs for the payloads this client creates, random UUIDs if unset
+    request_ids: Option<RequestIdGenerator>,
+}
+
+impl SocketClient {
+    /// Create a new socket client
+    pub fn new(config: SocketConfig) -> Self {
+        Self {
+            config,
+            client_name: None,
+            warm: None,
+            max_redirects: 0,
+            validators: std::collections::HashMap::new(),
+            retry: None,
+            request_ids: None,
+        }
+    }
+
+    /// Take the request IDs of payloads this client creates from `generator`
+    /// instead of random UUIDs, e.g. a counter for reproducible tests.
+    ///
+    /// This covers [`payload`](Self::payload) and the requests the client
+    /// builds itself, such as [`call`](Self::call); payloads built with
+    /// [`SocketPayload::new`] keep their random IDs.
+    pub fn with_request_id_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.request_ids = Some(Arc::new(generator));
+        self
+    }
+
+    /// Create a payload for `command`, with a request ID from the client's
+    /// generator if it has one
+    pub fn payload<T, R>(&self, command: impl Into<String>, data: T) -> SocketPayload<T, R> {
+        match &self.request_ids {
+            Some(generator) => SocketPayload::with_request_id(command, generator(), data),
+            None => SocketPayload::new(command, data),
+        }
+    }
+
+    /// Check every response carrying `R` data before returning it.
+    ///
+    /// The validator runs after deserialization on responses from
+    /// `send_request` and `send_batch_streaming`; returning `Err` turns the
+    /// response into [`SocketError::InvalidResponse`] with that message.
+    /// Registering another validator for the same `R` replaces the first.
+    ///
+    /// ```ignore
+    /// let client = SocketClient::new(config).with_response_validator(|response: &SocketResponse<Status>| {
+    ///     match (response.success, &response.data) {
+    ///         (true, None) => Err("success without data".to_string()),
+    ///         _ => Ok(()),
+    ///     }
+    /// });
+    /// ```
+    pub fn with_response_validator<R, F>(mut self, validator: F) -> Self
+    where
+        R: 'static,
+        F: Fn(&SocketResponse<R>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        let validator: ResponseValidator<R> = Arc::new(validator);
+        self.validators.insert(std::any::TypeId::of::<R>(), Arc::new(validator));
+        self
+    }
+
+    /// The validator registered for responses carrying `R`, if any
+    fn validator<R: 'static>(&self) -> Option<ResponseValidator<R>> {
+        self.validators
+            .get(&std::any::TypeId::of::<R>())?
+            .downcast_ref::<ResponseValidator<R>>()
+            .cloned()
+    }
+
+    /// Follow up to `max_hops` redirect responses by re-sending the request
+    /// to the socket each one names.
+    ///
+    /// With the default of zero, redirects are returned to the caller like
+    /// any other response. A chain longer than `max_hops` is an error.
+    pub fn with_max_redirects(mut self, max_hops: usize) -> Self {
+        self.max_redirects = max_hops;
+        self
+    }
+
+    /// Retry `send_request` with exponential backoff when it fails to reach
+    /// the server, for instance while the daemon restarts.
+    ///
+    /// Only failures to connect, handshake or write the request are retried;
+    /// once the request is written, a timeout or broken connection is
+    /// returned as it is, since the handler may already be running. A
+    /// handler's error response is returned as it is too.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Identify this client to the server by name.
+    ///
+    /// The name is sent in a handshake at the start of every connection and
+    /// shows up in the server's logs and `active_connections()`.
+    pub fn with_client_name(mut self, name: impl Into<String>) -> Self {
+        self.client_name = Some(name.into());
+        self
+    }
+
+    /// The config's timeout for connecting and for each response
+    fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.timeout)
+    }
+
+    /// When a request started now runs out of the config's timeout
+    fn deadline(&self) -> tokio::time::Instant {
+        tokio::time::Instant::now() + self.timeout()
+    }
+
+    /// Serialize a request for the wire, signing and compressing it if
+    /// configured. `last` says nothing follows the request before the client
+    /// half-closes, which lets it be compressed without length prefixes.
+    fn encode_request<P: serde::Serialize>(&self, request: &P, last: bool) -> SocketResult<Vec<u8>> {
+        let mut body = self.serialize_request(request)?;
+        if self.config.framing.delimits_compressed(last) {
+            if let Some(compressed) = self.config.compression.compress(&body)? {
+                body = compressed;
+            }
+        }
+        framing::encode(self.config.framing, body)
+    }
+
+    /// Serialize a request in the configured codec, signing it if configured
+    fn serialize_request<P: serde::Serialize>(&self, request: &P) -> SocketResult<Vec<u8>> {
+        #[cfg(feature = "signing")]
+        if let Some(signing) = &self.config.signing {
+            // The signature covers the request as JSON, whatever the codec
+            let signed = signing::sign(signing, &serde_json::to_vec(request)?)?;
+            return self.config.codec.reencode_json(signed);
+        }
+        self.config.codec.encode(request)
+    }
+
+    /// Open a connection right away and keep one ready for the next request.
+    ///
+    /// Fails immediately if the server isn't reachable, which suits
+    /// interactive tools that should report a missing daemon up front. Each
+    /// request then takes the waiting connection, so it pays no connect or
+    /// handshake latency, and a replacement is opened in the background.
+    pub async fn connect_eager(mut self) -> SocketResult<Self> {
+        let stream = Self::dial(&self.config, self.client_name.as_deref(), false, self.deadline()).await?;
+        self.warm = Some(Arc::new(tokio::sync::Mutex::new(Some(stream))));
+        Ok(self)
+    }
+
+    /// Get a connection for one request: the warm one if available, otherwise
+    /// a new one, which must be open by `deadline`
+    async fn open_stream(&self, deadline: tokio::time::Instant) -> SocketResult<TransportStream> {
+        let Some(warm) = &self.warm else {
+            return Self::dial(&self.config, self.client_name.as_deref(), false, deadline).await;
+        };
+
+        let stream = warm.lock().await.take().filter(|stream| {
+            // A warm connection the server has since closed reads as EOF
+            let mut probe = [0u8; 1];
+            matches!(stream.try_read(&mut probe), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+        });
+
+        let warm = Arc::clone(warm);
+        let config = self.config.clone();
+        let client_name = self.client_name.clone();
+        tokio::spawn(async move {
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(config.timeout);
+            match Self::dial(&config, client_name.as_deref(), false, deadline).await {
+                Ok(stream) => *warm.lock().await = Some(stream),
+                Err(e) => debug!("Could not open warm connection: {}", e),
+            }
+        });
+
+        match stream {
+            Some(stream) => Ok(stream),
+            None => Self::dial(&self.config, self.client_name.as_deref(), false, deadline).await,
+        }
+    }
+
+    /// Open a connection to the server, performing the handshake if there is
+    /// anything to announce: a client name, a dictionary, a type fingerprint
+    /// or multiplexing. Both must be done by `deadline`.
+    async fn dial(
+        config: &SocketConfig,
+        client_name: Option<&str>,
+        multiplex: bool,
+        deadline: tokio::time::Instant,
+    ) -> SocketResult<TransportStream> {
+        let mut stream = tokio::time::timeout_at(deadline, transport::connect(config))
+        .await
+        .map_err(|_| SocketError::ConnectionTimeout)??;
+
+        // A handshake is only needed to announce something
+        let dictionary = compression::dictionary_id(config);
+        let token = auth_token(config);
+        let announce = client_name.is_some() || dictionary.is_some() || config.type_fingerprint.is_some() || token.is_some();
+        if announce || multiplex {
+            let hello = HandshakeFrame {
+                handshake: Handshake {
+                    client_name: client_name.map(String::from),
+                    multiplex,
+                    crate_version: Some(handshake::CRATE_VERSION.to_string()),
+                    dictionary_id: dictionary.map(String::from),
+                    type_fingerprint: config.type_fingerprint.clone(),
+                    auth_token: token.map(String::from),
+                    protocol_version: Some(handshake::PROTOCOL_VERSION),
+                    ..Default::default()
+                },
+            };
+            let hello = framing::encode(config.framing, config.codec.encode(&hello)?)?;
+            let reader = async {
+                stream.write_all(&hello).await?;
+                FrameReader::new(config).next_frame(&mut stream).await
+            };
+            let reply = tokio::time::timeout_at(deadline, reader)
+                .await
+                .map_err(|_| SocketError::ConnectionTimeout)??
+                .ok_or_else(|| {
+                    SocketError::InvalidRequest("the server closed the connection during the handshake".into())
+                })?;
+            let reply: HandshakeFrame<ServerInfo> = codec::decode(&reply)?;
+            debug!("Handshake complete, connection ID: {}", reply.handshake.connection_id);
+            handshake::check_peer_version("Server", reply.handshake.crate_version.as_deref());
+
+            // Servers that predate versioning speak version 1
+            if let Some(server) = reply.handshake.protocol_version {
+                if handshake::negotiate_version(server) != Some(server) {
+                    return Err(SocketError::VersionMismatch {
+                        client: handshake::PROTOCOL_VERSION,
+                        server,
+                    });
+                }
+            }
+
+            if let Some(dictionary) = dictionary {
+                if reply.handshake.dictionary_id.as_deref() != Some(dictionary) {
+                    return Err(SocketError::InvalidResponse(format!(
+                        "compression dictionary mismatch: client uses {}, server uses {}",
+                        dictionary,
+                        reply.handshake.dictionary_id.as_deref().unwrap_or("none")
+                    )));
+                }
+            }
+            if let (Some(client), Some(server)) = (&config.type_fingerprint, reply.handshake.type_fingerprint) {
+                if *client != server {
+                    return Err(SocketError::TypeMismatch {
+                        client: client.clone(),
+                        server,
+                    });
+                }
+            }
+        }
+
+        Ok(stream)
+    }
+
+    /// Open a connection that carries any number of requests, sent with
+    /// [`Connection::send`] and answered concurrently by the server
+    pub async fn connect(&self) -> SocketResult<Connection> {
+        let stream = Self::dial(&self.config, self.client_name.as_deref(), true, self.deadline()).await?;
+        Ok(Connection::new(self.clone(), stream))
+    }
+
+    /// Send a request and wait for response
+    pub async fn send_request<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<SocketResponse<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        self.send_request_with_timeout(payload, self.timeout()).await
+    }
+
+    /// Send a request and wait for its response, allowing `timeout` instead
+    /// of the config's. It bounds the whole call, from connecting through
+    /// retries and redirects to reading the response. Suits commands that
+    /// legitimately run for minutes, or ones that should fail fast.
+    pub async fn send_request_with_timeout<T, R>(
+        &self,
+        payload: SocketPayload<T, R>,
+        timeout: std::time::Duration,
+    ) -> SocketResult<SocketResponse<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        let span = info_span!("send_request", request_id = %payload.request_id, command = %payload.command);
+        self.send_request_in_span(&payload, timeout).instrument(span).await
+    }
+
+    async fn send_request_in_span<T, R>(
+        &self,
+        payload: &SocketPayload<T, R>,
+        timeout: std::time::Duration,
+    ) -> SocketResult<SocketResponse<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        // One deadline for every connection, handshake and exchange, retries and redirects included
+        let deadline = tokio::time::Instant::now() + timeout;
+        let request_json = self.encode_request(payload, true)?;
+        let mut response: SocketResponse<R> = self.exchange_with_retry(&request_json, deadline).await?;
+        Self::check_request_id(payload, &response)?;
+
+        let mut hops = 0;
+        while let Some(ResponseKind::Redirect { target }) = &response.kind {
+            if hops == self.max_redirects {
+                if hops == 0 {
+                    break;
+                }
+                return Err(SocketError::InvalidResponse(format!(
+                    "too many redirects (max {})",
+                    self.max_redirects
+                )));
+            }
+            hops += 1;
+            debug!("Following redirect for request ID {} to {:?}", payload.request_id, target);
+
+            let config = target.apply(&self.config);
+            let stream = Self::dial(&config, self.client_name.as_deref(), false, deadline).await?;
+            response = self.exchange(stream, &request_json, deadline).await?;
+            Self::check_request_id(payload, &response)?;
+        }
+
+        if let Some(validator) = self.validator::<R>() {
+            validator(&response).map_err(SocketError::InvalidResponse)?;
+        }
+        Ok(response)
+    }
+
+    /// Fail with [`SocketError::ResponseMismatch`] if `response` answers
+    /// another request than `payload`. Errors the server couldn't tie to a
+    /// request, such as `message_too_large`, carry no ID and pass.
+    fn check_request_id<T, R>(payload: &SocketPayload<T, R>, response: &SocketResponse<R>) -> SocketResult<()> {
+        if response.request_id == payload.request_id || (!response.success && response.request_id.is_empty()) {
+            return Ok(());
+        }
+        Err(SocketError::ResponseMismatch {
+            expected: payload.request_id.clone(),
+            got: response.request_id.clone(),
+        })
+    }
+
+    /// Send a typed [`Command`] and return its response data.
+    ///
+    /// An error response becomes [`SocketError::ServerError`].
+    pub async fn call<C: Command>(&self, request: C::Request) -> SocketResult<C::Response> {
+        let payload = self.payload::<C::Request, C::Response>(C::NAME, request);
+        self.send_request(payload).await?.into_result()
+    }
+
+    /// Exchange an encoded request on a new connection, retrying failures to
+    /// reach the server as configured with `with_retry` until `deadline`
+    async fn exchange_with_retry<R>(
+        &self,
+        request_json: &[u8],
+        deadline: tokio::time::Instant,
+    ) -> SocketResult<SocketResponse<R>>
+    where
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+    {
+        let max_attempts = self.retry.as_ref().map_or(1, |retry| retry.max_attempts.max(1));
+        let mut attempt = 1;
+        loop {
+            let result = match self.open_stream(deadline).await {
+                Ok(stream) => self.exchange(stream, request_json, deadline).await,
+                Err(error) => Err(Failed { phase: Phase::Connect, error }),
+            };
+            match (result, &self.retry) {
+                (Err(failed), Some(retry)) if attempt < max_attempts && failed.is_retryable() => {
+                    let backoff = retry.backoff(attempt);
+                    if tokio::time::Instant::now() + backoff >= deadline {
+                        return Err(failed.into());
+                    }
+                    debug!(
+                        "Attempt {} of {} failed ({}), retrying in {:?}",
+                        attempt, max_attempts, failed.error, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                (result, _) => return result.map_err(SocketError::from),
+            }
+        }
+    }
+
+    /// Write an encoded request on a fresh connection and read back its
+    /// response by `deadline`, tagging a failure with how far it got
+    async fn exchange<R>(
+        &self,
+        mut stream: TransportStream,
+        request_json: &[u8],
+        deadline: tokio::time::Instant,
+    ) -> Result<SocketResponse<R>, Failed>
+    where
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+    {
+        // One deadline for the whole exchange, however many reads it takes, so
+        // a peer dribbling out its response a byte at a time can't stall us
+        let mut phase = Phase::Send;
+        let exchange = async {
+            stream.write_all(request_json).await?;
+            phase = Phase::Receive;
+            stream.shutdown().await?;
+            let limit = self.config.max_message_size;
+            if self.config.framing == Framing::LengthPrefixed {
+                return read_framed_with_limit(&mut stream, limit).await;
+            }
+
+            // The server closes the connection after responding, so read until EOF
+            let mut buffer = Vec::new();
+            match (&mut stream).take(limit as u64 + 1).read_to_end(&mut buffer).await {
+                Ok(_) => {}
+                // A TLS server closes without close_notify; parsing the response catches any truncation
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !buffer.is_empty() => {}
+                Err(e) => return Err(e.into()),
+            }
+            framing::check_size(buffer.len(), limit)?;
+            if buffer.is_empty() {
+                return Err(SocketError::InvalidRequest("the server closed the connection without responding".into()));
+            }
+            Ok(buffer)
+        };
+        let body = match tokio::time::timeout_at(deadline, exchange).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(error)) => return Err(Failed { phase, error }),
+            Err(_) => return Err(Failed { phase, error: SocketError::ConnectionTimeout }),
+        };
+
+        let received = |error: SocketError| Failed { phase: Phase::Receive, error };
+        let body = compression::Decompressor::new(&self.config).decompress(&body).map_err(received)?;
+        let response: SocketResponse<R> = codec::decode(&body).map_err(|e| received(e.into()))?;
+        debug!("Received response: {:?}", response);
+
+        Ok(response)
+    }
+
+    /// Write an encoded request by `deadline`, half-closing the connection
+    /// after it when it is the `last` thing sent
+    async fn send_within(
+        stream: &mut TransportStream,
+        request_json: &[u8],
+        last: bool,
+        deadline: tokio::time::Instant,
+    ) -> SocketResult<()> {
+        let send = async {
+            stream.write_all(request_json).await?;
+            if last {
+                stream.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        tokio::time::timeout_at(deadline, send)
+            .await
+            .map_err(|_| SocketError::ConnectionTimeout)?
+            .map_err(SocketError::from)
+    }
+
+    /// Read the first response on a connection by `deadline`
+    async fn first_frame(
+        reader: &mut FrameReader,
+        stream: &mut TransportStream,
+        deadline: tokio::time::Instant,
+    ) -> SocketResult<Vec<u8>> {
+        tokio::time::timeout_at(deadline, reader.next_frame(stream))
+            .await
+            .map_err(|_| SocketError::ConnectionTimeout)??
+            .ok_or_else(|| SocketError::InvalidRequest("the server closed the connection without responding".into()))
+    }
+
+    /// Send several requests in one batch and receive the responses as a
+    /// stream, each as soon as the server has produced it.
+    ///
+    /// Responses are matched to requests by `request_id`; a failing entry
+    /// yields an error response without stopping the rest of the batch.
+    pub async fn send_batch_streaming<T, R>(
+        &self,
+        payloads: Vec<SocketPayload<T, R>>,
+    ) -> SocketResult<ResponseStream<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let request_json = self.encode_request(&payloads, true)?;
+        Self::send_within(&mut stream, &request_json, true, deadline).await?;
+
+        Ok(ResponseStream::new(
+            stream,
+            FrameReader::new(&self.config),
+            self.timeout(),
+            self.validator::<R>(),
+        ))
+    }
+
+    /// Send a request to a streaming handler and receive the parts of its
+    /// response as the handler sends them.
+    ///
+    /// Fails with [`SocketError::ServerError`] if the server answers with an
+    /// error instead of starting the stream. A handler failing part-way ends
+    /// the stream with its error response.
+    pub async fn send_request_streaming<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<ResponseStream<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let request_json = self.encode_request(&payload, true)?;
+        Self::send_within(&mut stream, &request_json, true, deadline).await?;
+
+        let mut reader = FrameReader::new(&self.config);
+        let frame = Self::first_frame(&mut reader, &mut stream, deadline).await?;
+
+        let response: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received response: {:?}", response);
+        if response.kind == Some(ResponseKind::Stream) {
+            let responses = ResponseStream::new(stream, reader, self.timeout(), self.validator::<R>());
+            return Ok(responses.delimited());
+        }
+        match response.into_result() {
+            Ok(_) => Err(SocketError::InvalidResponse(
+                "server did not start a streaming response".to_string(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send several requests over one connection and wait for all of their
+    /// responses, returned in the order of `payloads`.
+    ///
+    /// The server answers every entry even when some fail, so error
+    /// responses sit alongside successful ones. Use `send_batch_streaming`
+    /// to act on each response as soon as it completes.
+    pub async fn send_batch<T, R>(&self, payloads: Vec<SocketPayload<T, R>>) -> SocketResult<Vec<SocketResponse<R>>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        let ids: Vec<String> = payloads.iter().map(|payload| payload.request_id.clone()).collect();
+        let mut responses: std::collections::HashMap<String, SocketResponse<R>> = self
+            .send_batch_streaming(payloads)
+            .await?
+            .collect()
+            .await?
+            .into_iter()
+            .map(|response| (response.request_id.clone(), response))
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                responses.remove(&id).ok_or_else(|| {
+                    SocketError::InvalidResponse(format!("batch ended without a response for request {}", id))
+                })
+            })
+            .collect()
+    }
+
+    /// Send a request that upgrades the connection to a raw byte pipe.
+    ///
+    /// Succeeds only if the server answers with an upgrade response; an error
+    /// response becomes [`SocketError::ServerError`]. The returned stream is
+    /// then free for any bidirectional protocol the two sides agree on.
+    pub async fn upgrade<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<UpgradedStream>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let request_json = self.encode_request(&payload, false)?;
+        Self::send_within(&mut stream, &request_json, false, deadline).await?;
+
+        let mut reader = FrameReader::new(&self.config);
+        let frame = Self::first_frame(&mut reader, &mut stream, deadline).await?;
+
+        let response: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received response: {:?}", response);
+        if response.kind == Some(ResponseKind::Upgrade) {
+            return Ok(UpgradedStream::new(stream, reader.into_buffered()));
+        }
+        match response.into_result() {
+            Ok(_) => Err(SocketError::InvalidResponse(
+                "server did not upgrade the connection".to_string(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Subscribe to the events the server broadcasts, on a connection of
+    /// their own. Use [`Subscription::subscribe`] to also receive what is
+    /// published to a topic.
+    ///
+    /// Returns once the server has confirmed the subscription, so every
+    /// event broadcast after that is delivered.
+    pub async fn subscribe<R>(&self) -> SocketResult<Subscription<R>>
+    where
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let payload = self.payload::<(), R>(admin::SUBSCRIBE_COMMAND, ());
+        let request_json = self.encode_request(&payload, false)?;
+        Self::send_within(&mut stream, &request_json, false, deadline).await?;
+
+        let mut reader = FrameReader::new(&self.config);
+        let frame = Self::first_frame(&mut reader, &mut stream, deadline).await?;
+
+        let response: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received response: {:?}", response);
+        if response.kind == Some(ResponseKind::Subscribed) {
+            return Ok(Subscription::new(self.clone(), stream, reader));
+        }
+        match response.into_result() {
+            Ok(_) => Err(SocketError::InvalidResponse("server did not confirm the subscription".to_string())),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a request to a download handler and copy the data it sends into
+    /// `writer`, returning the number of bytes received.
+    ///
+    /// Fails with [`SocketError::ServerError`] if the server answers with an
+    /// error or the handler fails part-way, in which case `writer` may
+    /// already hold some of the data.
+    pub async fn download<T, R, W>(&self, payload: SocketPayload<T, R>, writer: &mut W) -> SocketResult<u64>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let request_json = self.encode_request(&payload, false)?;
+        Self::send_within(&mut stream, &request_json, false, deadline).await?;
+
+        let mut reader = FrameReader::new(&self.config);
+        let frame = Self::first_frame(&mut reader, &mut stream, deadline).await?;
+
+        let response: SocketResponse<R> = codec::decode(&frame)?;
+        debug!("Received response: {:?}", response);
+        if response.kind == Some(ResponseKind::Download) {
+            let mut chunks = UpgradedStream::new(stream, reader.into_buffered());
+            return download::receive(&mut chunks, writer).await;
+        }
+        match response.into_result() {
+            Ok(_) => Err(SocketError::InvalidResponse(
+                "server did not start a download".to_string(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a request and wait only for the server to acknowledge it, not for
+    /// the handler to finish. The server acknowledges once the request
+    /// parses, so an `Ok` means it was delivered; whether the handler
+    /// succeeds is only logged on the server. Error responses sent before
+    /// the acknowledgement, such as an unauthorized token, become
+    /// [`SocketError::ServerError`].
+    pub async fn send_request_with_ack<T>(&self, mut payload: SocketPayload<T, ()>) -> SocketResult<()>
+    where
+        T: serde::Serialize,
+    {
+        payload.ack = true;
+        let request_json = self.encode_request(&payload, true)?;
+        let response: SocketResponse<()> = self.exchange_with_retry(&request_json, self.deadline()).await?;
+        Self::check_request_id(&payload, &response)?;
+        match response.kind {
+            Some(ResponseKind::Ack) => Ok(()),
+            _ if !response.success => Err(SocketError::ServerError(
+                response.error.unwrap_or_else(|| "unknown error".to_string()),
+            )),
+            _ => Err(SocketError::InvalidResponse("expected an acknowledgement".to_string())),
+        }
+    }
+
+    /// Send a request without waiting for response (fire and forget)
+    pub async fn send_request_no_response<T>(&self, payload: SocketPayload<T, ()>) -> SocketResult<()>
+    where
+        T: serde::Serialize,
+    {
+        let deadline = self.deadline();
+        let mut stream = self.open_stream(deadline).await?;
+
+        let request_json = self.encode_request(&payload, true)?;
+        Self::send_within(&mut stream, &request_json, true, deadline).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use tokio::time::{sleep, Duration};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct StartCommand {
+        pub process_id: String,
+        pub command: Vec<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct StartResponse {
+        pub started: bool,
+        pub pid: u32,
+    }
+
+    #[test]
+    fn test_into_result() {
+        let ok = SocketResponse::success("1", 7u32);
+        assert_eq!(ok.into_result().unwrap(), 7);
+
+        let err = SocketResponse::<u32>::error("2", "boom");
+        assert!(matches!(err.into_result(), Err(SocketError::ServerError(msg)) if msg == "boom"));
+
+        let empty = SocketResponse::<u32> {
+            request_id: "3".to_string(),
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+            kind: None,
+        };
+        assert!(empty.is_success_without_data());
+        assert!(matches!(empty.into_result(), Err(SocketError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn test_from_result() {
+        let ok = SocketResponse::from_result("1", Ok::<_, std::io::Error>(7u32));
+        assert!(ok.success);
+        assert_eq!(ok.request_id, "1");
+        assert_eq!(ok.data, Some(7));
+
+        let parsed = "seven".parse::<u32>();
+        let err = SocketResponse::from_result("2", parsed);
+        assert!(!err.success);
+        assert_eq!(err.request_id, "2");
+        assert!(err.data.is_none());
+        assert_eq!(err.error.as_deref(), Some("invalid digit found in string"));
+    }
+
+    /// Non-Rust clients and snapshots rely on this exact field order and on
+    /// the optional fields being left out rather than sent as defaults
+    #[test]
+    fn test_wire_field_order() {
+        let mut payload = SocketPayload::<_, ()>::new("start", serde_json::json!({"name": "web"}));
+        payload.request_id = "1".to_string();
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"request_id":"1","command":"start","data":{"name":"web"}}"#
+        );
+        payload.dry_run = true;
+        payload.close_after = true;
+        payload.accept_codec = Some("json".to_string());
+        payload.idempotency_key = Some("deploy-7".to_string());
+        payload.ack = true;
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            concat!(
+                r#"{"request_id":"1","command":"start","data":{"name":"web"},"#,
+                r#""dry_run":true,"close_after":true,"accept_codec":"json","idempotency_key":"deploy-7","ack":true}"#
+            )
+        );
+
+        let cases = [
+            (SocketResponse::success("1", 7u32), r#"{"request_id":"1","success":true,"data":7,"error":null}"#),
+            (SocketResponse::error("2", "boom"), r#"{"request_id":"2","success":false,"data":null,"error":"boom"}"#),
+            (
+                SocketResponse::error_with_code("4", "not_found", "no such service"),
+                r#"{"request_id":"4","success":false,"data":null,"error":"no such service","code":"not_found"}"#,
+            ),
+            (
+                SocketResponse::redirect("3", "/tmp/b.sock"),
+                concat!(
+                    r#"{"request_id":"3","success":false,"data":null,"error":"redirect: send the request to /tmp/b.sock","#,
+                    r#""code":"redirect","kind":{"type":"redirect","target":{"path":"/tmp/b.sock"}}}"#
+                ),
+            ),
+        ];
+        for (response, expected) in cases {
+            assert_eq!(serde_json::to_string(&response).unwrap(), expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_in_runtime_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = std::env::temp_dir().join(format!("circle_runtime_{}", std::process::id())).join("run");
+
+        let config = SocketConfig::in_dir(&base, Path::new("deployer")).unwrap();
+        assert_eq!(config.socket_path, base.join("deployer.sock"));
+        let mode = std::fs::metadata(&base).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let config = SocketConfig::in_dir(&base, Path::new("monitor.socket")).unwrap();
+        assert_eq!(config.socket_path, base.join("monitor.socket"));
+
+        std::fs::remove_dir_all(base.parent().unwrap()).ok();
     }
 
-    
     #[tokio::test]
     async fn test_socket_communication() {
-        let socket_path = "/tmp/test_circle_socket.sock";
+        let socket_path = if cfg!(windows) { r"\\.\pipe\test_circle_socket" } else { "/tmp/test_circle_socket.sock" };
         let config = SocketConfig::from(socket_path);
 
         // Start server in background
@@ -0,0 +1,67 @@
+//! Endpoints added to and removed from a running server.
+//!
+//! `run` owns every listener. Adding one binds it right away, so errors
+//! reach the caller, then hands it to `run` to accept on; removing one has
+//! `run` drop it, which stops new connections there while the ones already
+//! accepted carry on.
+
+use crate::transport::Listener;
+use crate::{SocketConfig, SocketResult};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// A change `run` applies to the set of listeners
+pub(crate) enum ListenerChange {
+    Add {
+        endpoint: String,
+        listener: Listener,
+    },
+    Remove {
+        endpoint: String,
+        removed: oneshot::Sender<bool>,
+    },
+}
+
+/// Sends listener changes to the server's `run` loop
+#[derive(Clone)]
+pub(crate) struct ListenerControl {
+    changes: mpsc::UnboundedSender<ListenerChange>,
+    receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<ListenerChange>>>>,
+}
+
+impl ListenerControl {
+    pub(crate) fn new() -> Self {
+        let (changes, receiver) = mpsc::unbounded_channel();
+        Self {
+            changes,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+        }
+    }
+
+    /// Bind the endpoint `config` describes and have `run` accept on it,
+    /// returning the endpoint's name
+    pub(crate) async fn add(&self, config: &SocketConfig) -> SocketResult<String> {
+        let listener = Listener::bind(config).await?;
+        let endpoint = listener.endpoint(config);
+        // If `run` has already stopped, the listener is dropped straight away
+        let _ = self.changes.send(ListenerChange::Add {
+            endpoint: endpoint.clone(),
+            listener,
+        });
+        Ok(endpoint)
+    }
+
+    /// Have `run` stop accepting on `endpoint`. Returns whether it was listening there.
+    pub(crate) async fn remove(&self, endpoint: String) -> bool {
+        let (removed, reply) = oneshot::channel();
+        if self.changes.send(ListenerChange::Remove { endpoint, removed }).is_err() {
+            return false;
+        }
+        reply.await.unwrap_or(false)
+    }
+
+    /// The receiving end, taken once by `run`
+    pub(crate) fn take_changes(&self) -> Option<mpsc::UnboundedReceiver<ListenerChange>> {
+        self.receiver.lock().unwrap().take()
+    }
+}
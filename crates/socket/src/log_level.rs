@@ -0,0 +1,44 @@
+//! Per-command verbosity for the server's request logging.
+
+/// Level at which the server logs its per-request lines for a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, serde::Serialize)]
+pub enum LogLevel {
+    Trace,
+    #[default]
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Log a per-request line at the level configured for `$command`
+macro_rules! command_log {
+    ($config:expr, $command:expr, $($arg:tt)+) => {
+        match $config.command_log_level($command) {
+            $crate::LogLevel::Trace => tracing::trace!($($arg)+),
+            $crate::LogLevel::Debug => tracing::debug!($($arg)+),
+            $crate::LogLevel::Info => tracing::info!($($arg)+),
+            $crate::LogLevel::Warn => tracing::warn!($($arg)+),
+            $crate::LogLevel::Error => tracing::error!($($arg)+),
+        }
+    };
+}
+
+pub(crate) use command_log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SocketConfig;
+
+    #[test]
+    fn test_command_log_level() {
+        let mut config = SocketConfig::default();
+        config.command_log_levels.insert("status".to_string(), LogLevel::Trace);
+        config.command_log_levels.insert("deploy".to_string(), LogLevel::Info);
+
+        assert_eq!(config.command_log_level("status"), LogLevel::Trace);
+        assert_eq!(config.command_log_level("deploy"), LogLevel::Info);
+        assert_eq!(config.command_log_level("other"), LogLevel::Debug);
+    }
+}
@@ -0,0 +1,123 @@
+//! Deduplication of repeated log lines on noisy error paths.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// Distinct messages tracked at most. Stale entries are pruned once there
+/// are this many, and the oldest is dropped if none are stale.
+const MAX_TRACKED: usize = 1024;
+
+struct Entry {
+    window_start: Instant,
+    suppressed: u64,
+}
+
+/// Logs each distinct message at most once per window, summarizing the
+/// suppressed repeats the next time the message is let through
+pub(crate) struct LogThrottle {
+    window: Option<Duration>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl LogThrottle {
+    /// Throttle to one line per message per `window`; `None` logs everything
+    pub(crate) fn new(window: Option<Duration>) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn warn(&self, message: String) {
+        if let Some(line) = self.admit(message) {
+            warn!("{}", line);
+        }
+    }
+
+    pub(crate) fn error(&self, message: String) {
+        if let Some(line) = self.admit(message) {
+            error!("{}", line);
+        }
+    }
+
+    /// The line to log for `message`, or `None` if it should be suppressed
+    fn admit(&self, message: String) -> Option<String> {
+        let Some(window) = self.window else {
+            return Some(message);
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get_mut(&message) {
+            let elapsed = now.duration_since(entry.window_start);
+            if elapsed < window {
+                entry.suppressed += 1;
+                return None;
+            }
+            let suppressed = std::mem::take(&mut entry.suppressed);
+            entry.window_start = now;
+            return Some(if suppressed == 0 {
+                message
+            } else {
+                format!("{} (repeated {}x in last {}s)", message, suppressed, elapsed.as_secs())
+            });
+        }
+
+        if entries.len() >= MAX_TRACKED {
+            entries.retain(|_, entry| now.duration_since(entry.window_start) < window);
+        }
+        if entries.len() >= MAX_TRACKED {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.window_start)
+                .map(|(message, _)| message.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(message.clone(), Entry {
+            window_start: now,
+            suppressed: 0,
+        });
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_suppressed_and_summarized() {
+        let throttle = LogThrottle::new(Some(Duration::from_millis(50)));
+
+        assert_eq!(throttle.admit("bad request".into()).as_deref(), Some("bad request"));
+        for _ in 0..500 {
+            assert_eq!(throttle.admit("bad request".into()), None);
+        }
+        // Other messages are tracked independently
+        assert_eq!(throttle.admit("other".into()).as_deref(), Some("other"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        let line = throttle.admit("bad request".into()).unwrap();
+        assert!(line.starts_with("bad request (repeated 500x in last"), "{}", line);
+
+        let unthrottled = LogThrottle::new(None);
+        assert!(unthrottled.admit("x".into()).is_some());
+        assert!(unthrottled.admit("x".into()).is_some());
+    }
+
+    #[test]
+    fn test_tracked_messages_are_capped() {
+        let throttle = LogThrottle::new(Some(Duration::from_secs(60)));
+        for peer in 0..MAX_TRACKED * 3 {
+            assert!(throttle.admit(format!("bad request from {}", peer)).is_some());
+        }
+        assert_eq!(throttle.entries.lock().unwrap().len(), MAX_TRACKED);
+
+        // The most recent messages are still throttled
+        assert_eq!(throttle.admit(format!("bad request from {}", MAX_TRACKED * 3 - 1)), None);
+    }
+}
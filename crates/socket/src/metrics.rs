@@ -0,0 +1,24 @@
+//! Hooks for exporting server measurements to a metrics pipeline.
+
+use std::time::Duration;
+
+/// Receives measurements taken while the server handles requests.
+///
+/// Every method has a no-op default, so implementations only override what
+/// they record. Methods are called inline on the connection's task and
+/// should return quickly.
+pub trait MetricsSink: Send + Sync {
+    /// A request for `command` arrived, `bytes` long as read off the socket
+    fn on_request_size(&self, _command: &str, _bytes: usize) {}
+
+    /// A response for `command` was serialized to `bytes`, measured before
+    /// any compression or chunking
+    fn on_response_size(&self, _command: &str, _bytes: usize) {}
+
+    /// Deserializing a request for `command` took `elapsed`
+    fn on_decode_duration(&self, _command: &str, _elapsed: Duration) {}
+
+    /// Serializing a response for `command` took `elapsed`, not counting
+    /// compression or writing it out
+    fn on_encode_duration(&self, _command: &str, _elapsed: Duration) {}
+}
@@ -0,0 +1,86 @@
+//! A bounded set of reusable client connections.
+
+use crate::{Connection, SocketClient, SocketPayload, SocketResponse, SocketResult};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+/// Hands out up to `SocketConfig::pool_size` connections from
+/// [`SocketClient::connect`] and keeps them open between requests.
+///
+/// Connections the server has closed are dropped and replaced by a fresh
+/// one on the next [`acquire`](Self::acquire).
+pub struct SocketClientPool {
+    client: SocketClient,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl SocketClientPool {
+    /// Create an empty pool; connections are opened as they are needed
+    pub fn new(client: SocketClient) -> Self {
+        let size = client.config.pool_size.max(1);
+        Self {
+            client,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            permits: Arc::new(Semaphore::new(size)),
+        }
+    }
+
+    /// Take a connection, waiting while all of them are in use. It goes
+    /// back to the pool when dropped.
+    pub async fn acquire(&self) -> SocketResult<PooledConnection> {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("the pool never closes its semaphore");
+
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) if !connection.is_closed() => connection,
+            stale => {
+                if stale.is_some() {
+                    debug!("Replacing pooled connection closed by the server");
+                }
+                self.client.connect().await?
+            }
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            idle: Arc::clone(&self.idle),
+            _permit: permit,
+        })
+    }
+
+    /// Send a request on a pooled connection and wait for its response
+    pub async fn send_request<T, R>(&self, payload: SocketPayload<T, R>) -> SocketResult<SocketResponse<R>>
+    where
+        T: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de> + std::fmt::Debug + 'static,
+    {
+        self.acquire().await?.send(payload).await
+    }
+}
+
+/// A connection borrowed from a [`SocketClientPool`]
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl std::ops::Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().expect("present until dropped")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take().filter(|c| !c.is_closed()) {
+            self.idle.lock().unwrap().push(connection);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Server statistics in the Prometheus text exposition format.
+//!
+//! The server keeps per-command counters and histograms regardless of any
+//! [`MetricsSink`](crate::MetricsSink), and renders them together with its
+//! connection counts for the `__metrics` admin command, so a small bridge
+//! can scrape the socket and serve the text over HTTP. In-process, the same
+//! counters are available as a [`SocketMetrics`] snapshot.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the request and response size buckets, in bytes
+const SIZE_BUCKETS: &[f64] = &[64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+
+/// Upper bounds of the handler duration buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Observations counted into cumulative buckets
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Bucket bounds with the number of observations up to each
+    fn cumulative(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.bounds.iter().copied().zip(self.counts.iter().copied())
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Picks one of a command's histograms
+type SelectHistogram = fn(&CommandStats) -> &Histogram;
+
+/// Everything recorded for one command
+struct CommandStats {
+    requests: u64,
+    successes: u64,
+    errors: u64,
+    request_bytes: Histogram,
+    response_bytes: Histogram,
+    handler_seconds: Histogram,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            successes: 0,
+            errors: 0,
+            request_bytes: Histogram::new(SIZE_BUCKETS),
+            response_bytes: Histogram::new(SIZE_BUCKETS),
+            handler_seconds: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+}
+
+/// Gauges and counters the server tracks elsewhere, sampled when rendering
+pub(crate) struct ServerGauges {
+    pub(crate) uptime: Duration,
+    pub(crate) connections_accepted: u64,
+    pub(crate) active_connections: usize,
+    pub(crate) inflight_requests: usize,
+    pub(crate) queued_connections: usize,
+}
+
+/// Per-command statistics, by command name
+#[derive(Default)]
+pub(crate) struct ServerStats {
+    commands: Mutex<HashMap<String, CommandStats>>,
+}
+
+impl ServerStats {
+    /// A request for `command` arrived, `bytes` long
+    pub(crate) fn record_request(&self, command: &str, bytes: usize) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command.to_string()).or_default();
+        stats.requests += 1;
+        stats.request_bytes.observe(bytes as f64);
+    }
+
+    /// A response for `command` was serialized to `bytes`
+    pub(crate) fn record_response(&self, command: &str, bytes: usize) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.entry(command.to_string()).or_default().response_bytes.observe(bytes as f64);
+    }
+
+    /// The handler for `command` finished after `elapsed`
+    pub(crate) fn record_handler(&self, command: &str, elapsed: Duration, success: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command.to_string()).or_default();
+        stats.handler_seconds.observe(elapsed.as_secs_f64());
+        if success {
+            stats.successes += 1;
+        } else {
+            stats.errors += 1;
+        }
+    }
+
+    /// A copy of the request counters and latencies as they are now
+    pub(crate) fn snapshot(&self) -> SocketMetrics {
+        let commands = self.commands.lock().unwrap();
+        let commands: BTreeMap<String, CommandMetrics> = commands
+            .iter()
+            .map(|(command, stats)| {
+                let latency = LatencyHistogram {
+                    buckets: stats
+                        .handler_seconds
+                        .cumulative()
+                        .map(|(bound, count)| (Duration::from_secs_f64(bound), count))
+                        .collect(),
+                    count: stats.handler_seconds.count,
+                    total: Duration::from_secs_f64(stats.handler_seconds.sum),
+                };
+                let metrics = CommandMetrics {
+                    requests: stats.requests,
+                    successes: stats.successes,
+                    errors: stats.errors,
+                    latency,
+                };
+                (command.clone(), metrics)
+            })
+            .collect();
+        SocketMetrics {
+            total_requests: commands.values().map(|command| command.requests).sum(),
+            commands,
+        }
+    }
+
+    /// All statistics in the Prometheus text exposition format
+    pub(crate) fn render(&self, gauges: &ServerGauges) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+        };
+        let uptime = gauges.uptime.as_secs_f64().to_string();
+        metric("circle_uptime_seconds", "gauge", "Time since the server started.", uptime);
+        metric(
+            "circle_connections_accepted_total",
+            "counter",
+            "Connections accepted since the server was created.",
+            gauges.connections_accepted.to_string(),
+        );
+        let active = gauges.active_connections.to_string();
+        metric("circle_connections_active", "gauge", "Connections currently open.", active);
+        metric(
+            "circle_requests_inflight",
+            "gauge",
+            "Requests whose handlers are currently executing.",
+            gauges.inflight_requests.to_string(),
+        );
+        metric(
+            "circle_connections_queued",
+            "gauge",
+            "Accepted connections waiting for a worker.",
+            gauges.queued_connections.to_string(),
+        );
+
+        let commands = self.commands.lock().unwrap();
+        let mut names: Vec<&String> = commands.keys().collect();
+        names.sort();
+        let rows = |out: &mut String, name: &str, kind: &str, help: &str, value: &dyn Fn(&CommandStats) -> u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for command in &names {
+                let _ = writeln!(out, "{name}{{command=\"{}\"}} {}", escape(command), value(&commands[*command]));
+            }
+        };
+        rows(&mut out, "circle_requests_total", "counter", "Requests received.", &|s| s.requests);
+        rows(&mut out, "circle_request_errors_total", "counter", "Requests answered with an error.", &|s| s.errors);
+
+        let histograms: [(&str, &str, SelectHistogram); 3] = [
+            ("circle_request_bytes", "Size of requests as read off the socket.", |s| &s.request_bytes),
+            ("circle_response_bytes", "Size of serialized responses.", |s| &s.response_bytes),
+            ("circle_handler_duration_seconds", "Time spent in handlers.", |s| &s.handler_seconds),
+        ];
+        for (name, help, histogram) in histograms {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+            for command in &names {
+                let histogram = histogram(&commands[*command]);
+                let label = escape(command);
+                for (bound, count) in histogram.cumulative() {
+                    let _ = writeln!(out, "{name}_bucket{{command=\"{label}\",le=\"{bound}\"}} {count}");
+                }
+                let _ = writeln!(out, "{name}_bucket{{command=\"{label}\",le=\"+Inf\"}} {}", histogram.count);
+                let _ = writeln!(out, "{name}_sum{{command=\"{label}\"}} {}", histogram.sum);
+                let _ = writeln!(out, "{name}_count{{command=\"{label}\"}} {}", histogram.count);
+            }
+        }
+        out
+    }
+}
+
+/// Request counts and handler latencies by command, from
+/// `SocketServer::metrics()`
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct SocketMetrics {
+    /// Requests received for any command
+    pub total_requests: u64,
+    /// Statistics for each command that has received a request
+    pub commands: BTreeMap<String, CommandMetrics>,
+}
+
+/// Statistics for one command
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct CommandMetrics {
+    /// Requests received
+    pub requests: u64,
+    /// Requests answered with a success response
+    pub successes: u64,
+    /// Requests answered with an error response
+    pub errors: u64,
+    /// Time spent producing the responses
+    pub latency: LatencyHistogram,
+}
+
+/// Distribution of handler times
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LatencyHistogram {
+    /// Upper bounds with the number of requests that took at most that long
+    pub buckets: Vec<(Duration, u64)>,
+    /// Requests measured
+    pub count: u64,
+    /// Time all of them took together
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Average time per request, if any were measured
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|count| *count > 0)?;
+        Some(self.total / count)
+    }
+}
+
+/// Escape a label value as the exposition format requires
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let stats = ServerStats::default();
+        stats.record_request("status", 100);
+        stats.record_response("status", 3000);
+        stats.record_handler("status", Duration::from_millis(2), true);
+        stats.record_request("say \"hi\"", 10);
+        stats.record_handler("say \"hi\"", Duration::from_millis(20), false);
+
+        let text = stats.render(&ServerGauges {
+            uptime: Duration::from_millis(1500),
+            connections_accepted: 3,
+            active_connections: 1,
+            inflight_requests: 0,
+            queued_connections: 2,
+        });
+        assert!(text.contains("# TYPE circle_uptime_seconds gauge\ncircle_uptime_seconds 1.5\n"));
+        assert!(text.contains("circle_connections_accepted_total 3\n"));
+        assert!(text.contains("circle_connections_queued 2\n"));
+        assert!(text.contains("circle_requests_total{command=\"status\"} 1\n"));
+        assert!(text.contains("circle_request_errors_total{command=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("circle_request_bytes_bucket{command=\"status\",le=\"64\"} 0\n"));
+        assert!(text.contains("circle_request_bytes_bucket{command=\"status\",le=\"256\"} 1\n"));
+        assert!(text.contains("circle_response_bytes_bucket{command=\"status\",le=\"4096\"} 1\n"));
+        assert!(text.contains("circle_handler_duration_seconds_bucket{command=\"status\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("circle_handler_duration_seconds_count{command=\"status\"} 1\n"));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let stats = ServerStats::default();
+        for (elapsed, success) in [(2, true), (20, true), (200, false)] {
+            stats.record_request("status", 10);
+            stats.record_handler("status", Duration::from_millis(elapsed), success);
+        }
+        stats.record_request("stop", 10);
+
+        let metrics = stats.snapshot();
+        assert_eq!(metrics.total_requests, 4);
+        let status = &metrics.commands["status"];
+        assert_eq!((status.requests, status.successes, status.errors), (3, 2, 1));
+        assert_eq!(status.latency.count, 3);
+        assert_eq!(status.latency.mean(), Some(Duration::from_millis(74)));
+        assert_eq!(status.latency.buckets[1], (Duration::from_millis(5), 1));
+        assert_eq!(status.latency.buckets[4], (Duration::from_millis(100), 2));
+        assert_eq!(metrics.commands["stop"].latency.mean(), None);
+    }
+}
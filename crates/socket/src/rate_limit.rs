@@ -0,0 +1,128 @@
+//! Limiting how fast each client may send requests.
+//!
+//! Every client has a token bucket holding up to [`RateLimit::burst`]
+//! requests, refilled at [`RateLimit::per_second`]. A request takes a token;
+//! one arriving at an empty bucket is refused. Clients on Unix domain
+//! sockets are told apart by the uid and pid the kernel reports, so a
+//! client reconnecting starts on the same bucket. Connections without peer
+//! credentials, over TCP or named pipes, each get a bucket of their own.
+
+use crate::PeerCredentials;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients tracked before the buckets of idle ones are pruned
+const MAX_TRACKED: usize = 1024;
+
+/// How many requests a client may send, set with
+/// [`SocketConfig::rate_limit`](crate::SocketConfig::rate_limit)
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct RateLimit {
+    /// Requests a client may send back to back after being idle
+    pub burst: u32,
+    /// Requests a client may send per second on average
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// Allow `per_second` requests a second on average, in bursts of up to `burst`
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+
+    /// How long an empty bucket takes to hold `tokens` again
+    fn refill_time(&self, tokens: f64) -> Duration {
+        Duration::try_from_secs_f64(tokens / self.per_second).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Who a bucket belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    Peer { uid: u32, pid: Option<i32> },
+    Connection(u64),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of every client seen recently
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for a request from the client with `peer_credentials`,
+    /// or on connection `connection_id` if there are none. Fails with how
+    /// long until a token is free if the bucket is empty.
+    pub(crate) fn check(&self, peer_credentials: Option<PeerCredentials>, connection_id: u64) -> Result<(), Duration> {
+        let client = match peer_credentials {
+            Some(peer) => Client::Peer {
+                uid: peer.uid,
+                pid: peer.pid,
+            },
+            None => Client::Connection(connection_id),
+        };
+        let burst = f64::from(self.limit.burst);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED {
+            // A bucket that has refilled completely is no different from a new one
+            let full = self.limit.refill_time(burst);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < full);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.limit.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(self.limit.refill_time(1.0 - bucket.tokens));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_per_client() {
+        let limiter = RateLimiter::new(RateLimit::new(2, 1.0));
+        let peer = |pid| {
+            Some(PeerCredentials {
+                uid: 1000,
+                gid: 1000,
+                pid: Some(pid),
+            })
+        };
+
+        assert!(limiter.check(peer(1), 1).is_ok());
+        // The same process on another connection shares its bucket
+        assert!(limiter.check(peer(1), 2).is_ok());
+        let wait = limiter.check(peer(1), 3).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        assert!(limiter.check(peer(2), 4).is_ok());
+        assert!(limiter.check(None, 5).is_ok());
+        assert!(limiter.check(None, 5).is_ok());
+        assert!(limiter.check(None, 5).is_err());
+        assert!(limiter.check(None, 6).is_ok());
+    }
+}
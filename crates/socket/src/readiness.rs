@@ -0,0 +1,63 @@
+//! Whether the server has finished warming up and serves requests.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Tracks the end of the warm-up period, shared with [`ServerHandle`](crate::ServerHandle)s
+#[derive(Clone)]
+pub(crate) struct Readiness {
+    ready: Arc<AtomicBool>,
+    warm_up: Option<Duration>,
+}
+
+impl Readiness {
+    /// Ready right away unless a `warm_up` period is configured
+    pub(crate) fn new(warm_up: Option<Duration>) -> Self {
+        Self {
+            ready: Arc::new(AtomicBool::new(warm_up.is_none())),
+            warm_up,
+        }
+    }
+
+    /// End the warm-up period now
+    pub(crate) fn mark_ready(&self) {
+        if !self.ready.swap(true, Ordering::Relaxed) {
+            info!("Server marked ready");
+        }
+    }
+
+    /// Whether the server is ready, given when it started listening. The
+    /// warm-up also ends once it has lasted its configured duration.
+    pub(crate) fn is_ready(&self, started: Option<Instant>) -> bool {
+        if self.ready.load(Ordering::Relaxed) {
+            return true;
+        }
+        let elapsed = self.warm_up.zip(started).is_some_and(|(warm_up, started)| started.elapsed() >= warm_up);
+        if elapsed && !self.ready.swap(true, Ordering::Relaxed) {
+            info!("Warm-up period over, serving requests");
+        }
+        elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warm_up_ends_on_timer_or_mark_ready() {
+        assert!(Readiness::new(None).is_ready(None));
+
+        let started = Some(Instant::now() - Duration::from_secs(2));
+        let readiness = Readiness::new(Some(Duration::from_secs(1)));
+        assert!(!readiness.is_ready(None));
+        assert!(readiness.is_ready(started));
+
+        let readiness = Readiness::new(Some(Duration::MAX));
+        assert!(!readiness.is_ready(started));
+        readiness.clone().mark_ready();
+        assert!(readiness.is_ready(started));
+    }
+}
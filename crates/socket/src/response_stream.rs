@@ -0,0 +1,99 @@
+//! Reading several responses off one connection as they arrive.
+
+use crate::framing::FrameReader;
+use crate::{codec, ResponseKind, ResponseValidator, SocketError, SocketResponse, SocketResult, TransportStream};
+use std::time::Duration;
+use tracing::debug;
+
+/// Responses delivered one by one over a single connection, in the order
+/// the server finished them: the responses to a batch, or the parts of a
+/// streaming response.
+///
+/// Each response carries the `request_id` of the request it answers.
+pub struct ResponseStream<R> {
+    stream: TransportStream,
+    reader: FrameReader,
+    timeout: Duration,
+    validator: Option<ResponseValidator<R>>,
+    /// Whether the server ends the stream with a response of its own, as
+    /// streaming responses do, so the connection closing first is an error
+    delimited: bool,
+    finished: bool,
+}
+
+impl<R> ResponseStream<R>
+where
+    R: for<'de> serde::Deserialize<'de> + std::fmt::Debug,
+{
+    pub(crate) fn new(
+        stream: TransportStream,
+        reader: FrameReader,
+        timeout: Duration,
+        validator: Option<ResponseValidator<R>>,
+    ) -> Self {
+        Self {
+            stream,
+            reader,
+            timeout,
+            validator,
+            delimited: false,
+            finished: false,
+        }
+    }
+
+    /// Expect the parts of a streaming response, which end with
+    /// [`ResponseKind::StreamEnd`] or an error response
+    pub(crate) fn delimited(mut self) -> Self {
+        self.delimited = true;
+        self
+    }
+
+    /// Wait for the next response. Returns `None` once the server has sent
+    /// everything.
+    pub async fn next(&mut self) -> Option<SocketResult<SocketResponse<R>>> {
+        if self.finished {
+            return None;
+        }
+        let frame = tokio::time::timeout(self.timeout, self.reader.next_frame(&mut self.stream))
+            .await
+            .map_err(|_| SocketError::ConnectionTimeout);
+
+        match frame {
+            Ok(Ok(Some(frame))) => {
+                let response = codec::decode::<SocketResponse<R>>(&frame).map_err(SocketError::from);
+                if let Ok(response) = &response {
+                    if self.delimited && response.kind == Some(ResponseKind::StreamEnd) {
+                        self.finished = true;
+                        return None;
+                    }
+                    // A failed handler's error is the last part of its response
+                    self.finished = self.delimited && !response.success;
+                    debug!("Received streamed response: {:?}", response);
+                    if let Some(validator) = &self.validator {
+                        if let Err(reason) = validator(response) {
+                            return Some(Err(SocketError::InvalidResponse(reason)));
+                        }
+                    }
+                }
+                Some(response)
+            }
+            Ok(Ok(None)) if self.delimited => {
+                self.finished = true;
+                Some(Err(SocketError::InvalidResponse(
+                    "streaming response ended before it was complete".to_string(),
+                )))
+            }
+            Ok(Ok(None)) => None,
+            Ok(Err(e)) | Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Drain the remaining responses into a `Vec`, stopping at the first error
+    pub async fn collect(mut self) -> SocketResult<Vec<SocketResponse<R>>> {
+        let mut responses = Vec::new();
+        while let Some(response) = self.next().await {
+            responses.push(response?);
+        }
+        Ok(responses)
+    }
+}
@@ -0,0 +1,127 @@
+//! Retrying requests that fail to reach the server.
+
+use crate::SocketError;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How [`SocketClient::with_retry`](crate::SocketClient::with_retry) retries
+/// requests that fail at the connection level, for instance while the
+/// daemon restarts.
+///
+/// Only I/O errors and timeouts while connecting, handshaking or writing the
+/// request are retried: until the request is written in full, the server
+/// can't have started handling it. Failures while waiting for the response,
+/// including a handler slower than the timeout, and error responses from a
+/// handler are returned as they are.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Attempts in total, including the first
+    pub max_attempts: u32,
+    /// Wait before the first retry
+    pub initial_backoff: Duration,
+    /// Longest wait between attempts
+    pub max_backoff: Duration,
+    /// Factor the wait grows by after each failed attempt
+    pub multiplier: f64,
+    /// Wait a random time between half and all of the backoff, so clients
+    /// that failed together don't all retry at once
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Retry up to `max_attempts` attempts in total with the default backoff
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait after `attempt` (counting from 1) failed
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let backoff = Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()));
+        if !self.jitter {
+            return backoff;
+        }
+        // A randomly seeded hasher is random enough to spread retries out
+        let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+    }
+}
+
+/// How far an attempt at sending a request got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Connecting and handshaking, before any of the request is sent
+    Connect,
+    /// Writing the request, which the server can't act on until it has all of it
+    Send,
+    /// Waiting for the response to a request the server has in full
+    Receive,
+}
+
+/// A failed attempt, tagged with the phase it failed in
+#[derive(Debug)]
+pub(crate) struct Failed {
+    pub(crate) phase: Phase,
+    pub(crate) error: SocketError,
+}
+
+impl Failed {
+    /// Whether the request can't have reached a handler, so sending it again is safe
+    pub(crate) fn is_retryable(&self) -> bool {
+        self.phase != Phase::Receive && matches!(self.error, SocketError::Io(_) | SocketError::ConnectionTimeout)
+    }
+}
+
+impl From<Failed> for SocketError {
+    fn from(failed: Failed) -> Self {
+        failed.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_to_limit() {
+        let config = RetryConfig {
+            jitter: false,
+            ..RetryConfig::new(10)
+        };
+        assert_eq!(config.backoff(1), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(200));
+        assert_eq!(config.backoff(4), Duration::from_millis(800));
+        assert_eq!(config.backoff(9), Duration::from_secs(5));
+
+        let jittered = RetryConfig::new(10);
+        for _ in 0..100 {
+            let backoff = jittered.backoff(3);
+            assert!(backoff >= Duration::from_millis(200) && backoff <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn test_only_failures_before_the_request_is_sent_retry() {
+        let failed = |phase, error| Failed { phase, error };
+        assert!(failed(Phase::Connect, SocketError::ConnectionTimeout).is_retryable());
+        assert!(failed(Phase::Send, std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()).is_retryable());
+        assert!(!failed(Phase::Receive, SocketError::ConnectionTimeout).is_retryable());
+        assert!(!failed(Phase::Connect, SocketError::InvalidResponse("mismatch".into())).is_retryable());
+    }
+}
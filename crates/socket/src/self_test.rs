@@ -0,0 +1,40 @@
+//! Startup self-test of registered handlers.
+
+/// How a handler reacted to its synthetic dry-run request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// The handler produced a response (successful or not)
+    Passed,
+    /// The handler returned an error
+    Failed(String),
+    /// The handler panicked
+    Panicked(String),
+}
+
+/// Result of dry-running one command
+#[derive(Debug, Clone)]
+pub struct CommandCheck {
+    /// The command that was exercised
+    pub command: String,
+    /// What happened
+    pub outcome: CheckOutcome,
+}
+
+/// Report produced by `SocketServer::self_test`
+#[derive(Debug, Clone, Default)]
+pub struct SelfTestReport {
+    /// One entry per registered command, sorted by command name
+    pub checks: Vec<CommandCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether every handler passed
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Checks that did not pass
+    pub fn failures(&self) -> impl Iterator<Item = &CommandCheck> {
+        self.checks.iter().filter(|check| check.outcome != CheckOutcome::Passed)
+    }
+}
@@ -0,0 +1,23 @@
+//! A single serializable view of a server's operational state.
+
+use crate::{ConnectionInfo, InflightRequest, SocketConfig};
+use serde::Serialize;
+
+/// Everything the server can report about itself, for support bundles and
+/// diagnostics. Returned by `SocketServer::snapshot` and the `__snapshot`
+/// admin command.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerSnapshot {
+    /// The server's configuration, minus secrets
+    pub config: SocketConfig,
+    /// Registered command names, sorted
+    pub commands: Vec<String>,
+    /// Time since `run` started, in milliseconds
+    pub uptime_ms: u64,
+    /// Connections accepted since the server was created
+    pub connections_accepted: u64,
+    /// Connections currently open
+    pub active_connections: Vec<ConnectionInfo>,
+    /// Requests whose handlers are currently executing
+    pub inflight_requests: Vec<InflightRequest>,
+}
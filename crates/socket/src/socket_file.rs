@@ -0,0 +1,63 @@
+//! Removing the socket file when the server stops.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Unlinks the socket file a server bound once dropped, which happens when
+/// `run` returns, is aborted or panics. A file another server has since put
+/// at the path is left alone.
+pub(crate) struct SocketFileGuard {
+    path: PathBuf,
+    identity: Option<(u64, u64)>,
+}
+
+impl SocketFileGuard {
+    /// Guard the file just bound at `path`
+    pub(crate) fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            identity: file_identity(path),
+        }
+    }
+}
+
+impl Drop for SocketFileGuard {
+    fn drop(&mut self) {
+        if self.identity.is_none() || file_identity(&self.path) != self.identity {
+            return;
+        }
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => debug!("Removed socket file: {:?}", self.path),
+            Err(e) => debug!("Failed to remove socket file {:?}: {}", self.path, e),
+        }
+    }
+}
+
+/// Device and inode of the file at `path`, telling it apart from a replacement
+fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaves_replaced_file_alone() {
+        let path = std::env::temp_dir().join(format!("circle_socket_file_{}", std::process::id()));
+        let replacement = path.with_extension("new");
+        std::fs::write(&path, b"").unwrap();
+        let guard = SocketFileGuard::new(&path);
+
+        // Another server took over the path
+        std::fs::write(&replacement, b"").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        drop(guard);
+        assert!(path.exists());
+
+        drop(SocketFileGuard::new(&path));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,48 @@
+//! Responses a handler sends in parts, as it produces them.
+//!
+//! A streaming response starts with a [`ResponseKind::Stream`](crate::ResponseKind::Stream)
+//! response, followed by a success response for every chunk the handler
+//! sends through its [`ResponseSink`], each framed like any other message.
+//! A [`ResponseKind::StreamEnd`](crate::ResponseKind::StreamEnd) response
+//! ends it once the handler returns, or an error response if it failed.
+
+use crate::{SocketError, SocketResponse, SocketResult};
+use tokio::sync::mpsc;
+
+/// Chunks a handler may queue ahead of the connection before it has to wait
+const QUEUED_CHUNKS: usize = 8;
+
+/// Where a streaming handler sends the parts of its response
+pub struct ResponseSink<R> {
+    request_id: String,
+    chunks: mpsc::Sender<SocketResponse<R>>,
+}
+
+impl<R> ResponseSink<R> {
+    /// Send `chunk` to the client as the next part of the response, waiting
+    /// if the client is reading slower than the handler produces them.
+    /// Fails once the client has gone away.
+    pub async fn send(&mut self, chunk: R) -> SocketResult<()> {
+        let response = SocketResponse::success(&self.request_id, chunk);
+        self.chunks.send(response).await.map_err(|_| closed())
+    }
+
+    /// ID of the request being answered
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+}
+
+/// A sink for the handler answering `request_id`, and the chunks it sends
+pub(crate) fn channel<R>(request_id: &str) -> (ResponseSink<R>, mpsc::Receiver<SocketResponse<R>>) {
+    let (chunks, outgoing) = mpsc::channel(QUEUED_CHUNKS);
+    let sink = ResponseSink {
+        request_id: request_id.to_string(),
+        chunks,
+    };
+    (sink, outgoing)
+}
+
+fn closed() -> SocketError {
+    SocketError::Io(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+}
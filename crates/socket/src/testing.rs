@@ -0,0 +1,29 @@
+//! Helpers for exercising a running server below the typed client.
+//!
+//! These are meant for robustness tests: they let a test write arbitrary
+//! bytes (truncated frames, garbage, bogus length prefixes) straight onto the
+//! socket and inspect exactly what the server wrote back.
+
+use crate::{transport, SocketConfig, SocketError, SocketResult};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Connect to the server at `config.socket_path`, write `bytes` verbatim,
+/// half-close the write side and return every byte the server sent back
+/// before closing the connection.
+///
+/// The whole exchange is bounded by `config.timeout`.
+pub async fn send_raw(config: &SocketConfig, bytes: &[u8]) -> SocketResult<Vec<u8>> {
+    let exchange = async {
+        let mut stream = transport::connect(config).await?;
+        stream.write_all(bytes).await?;
+        stream.shutdown().await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    };
+
+    tokio::time::timeout(std::time::Duration::from_secs(config.timeout), exchange)
+        .await
+        .map_err(|_| SocketError::ConnectionTimeout)?
+}
@@ -0,0 +1,111 @@
+//! TLS for the TCP transport.
+//!
+//! With [`SocketConfig::tls`](crate::SocketConfig::tls) set, TCP connections
+//! are wrapped in TLS right after they are established, before the first
+//! byte of framing, so handshakes, requests and responses are all
+//! encrypted. Unix sockets and named pipes never leave the machine and are
+//! used as they are.
+
+use crate::{SocketConfig, TransportStream};
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// One side's TLS settings: a certificate to present for a server, or the
+/// certificate authority to verify the server against for a client
+#[derive(Clone)]
+pub struct TlsConfig {
+    server: Option<Arc<ServerConfig>>,
+    client: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+}
+
+impl TlsConfig {
+    /// Present the certificate chain in `cert_chain_pem`, leaf first, with
+    /// the private key in `private_key_pem`
+    pub fn server(cert_chain_pem: &[u8], private_key_pem: &[u8]) -> io::Result<Self> {
+        let chain = CertificateDer::pem_slice_iter(cert_chain_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_input)?;
+        let key = PrivateKeyDer::from_pem_slice(private_key_pem).map_err(invalid_input)?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(invalid_input)?;
+        Ok(Self {
+            server: Some(Arc::new(config)),
+            client: None,
+        })
+    }
+
+    /// Trust servers whose certificate is issued by a certificate authority
+    /// in `ca_pem`, a self-signed certificate included, and valid for
+    /// `server_name`, the DNS name or IP address the certificate names
+    pub fn client(ca_pem: &[u8], server_name: &str) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(ca_pem) {
+            roots.add(cert.map_err(invalid_input)?).map_err(invalid_input)?;
+        }
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid_input)?;
+        Ok(Self {
+            server: None,
+            client: Some((Arc::new(config), server_name)),
+        })
+    }
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("server", &self.server.is_some())
+            .field("server_name", &self.client.as_ref().map(|(_, name)| name))
+            .finish()
+    }
+}
+
+/// Ring, rather than whichever provider the process happens to install
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn invalid_input(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// Run the server side of the TLS handshake on an accepted TCP connection,
+/// if `config` asks for TLS. Other connections are returned as they are.
+pub(crate) async fn accept(config: &SocketConfig, stream: TransportStream) -> io::Result<TransportStream> {
+    let (tls, stream) = match (&config.tls, stream) {
+        (Some(tls), TransportStream::Tcp(stream)) => (tls, stream),
+        (_, stream) => return Ok(stream),
+    };
+    let Some(server) = &tls.server else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TLS is configured without a server certificate",
+        ));
+    };
+    let accepted = TlsAcceptor::from(Arc::clone(server)).accept(stream).await?;
+    Ok(TransportStream::TlsServer(Box::new(accepted)))
+}
+
+/// Run the client side of the TLS handshake on a new TCP connection
+pub(crate) async fn connect(tls: &TlsConfig, stream: tokio::net::TcpStream) -> io::Result<TransportStream> {
+    let Some((client, server_name)) = &tls.client else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TLS is configured without a certificate authority to verify the server against",
+        ));
+    };
+    let connected = TlsConnector::from(Arc::clone(client)).connect(server_name.clone(), stream).await?;
+    Ok(TransportStream::TlsClient(Box::new(connected)))
+}
@@ -0,0 +1,403 @@
+//! The local IPC mechanism connections run over.
+//!
+//! Unix domain sockets are used on Unix and named pipes on Windows, and TCP
+//! reaches daemons on other hosts or in containers. All carry the same byte
+//! stream, so framing, handshakes and everything above them are shared;
+//! only binding, accepting and dialing differ.
+
+use crate::{PeerCredentials, SocketConfig, SocketResult};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(unix)]
+use crate::{socket_file::SocketFileGuard, ExistingSocketPolicy, SocketError};
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use tokio::io::Interest;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tracing::{debug, info};
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+
+/// How servers listen and clients connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum Transport {
+    /// A Unix domain socket file at `SocketConfig::socket_path`. On Linux,
+    /// a path starting with `@`, such as `@circle`, names a socket in the
+    /// abstract namespace instead, which has no file to clean up or protect.
+    #[cfg(unix)]
+    Unix,
+    /// A named pipe, such as `\\.\pipe\circle`, named by `SocketConfig::socket_path`
+    #[cfg(windows)]
+    NamedPipe,
+    /// TCP at this address; `socket_path` is not used
+    Tcp(SocketAddr),
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        #[cfg(unix)]
+        return Transport::Unix;
+        #[cfg(windows)]
+        return Transport::NamedPipe;
+    }
+}
+
+/// A connection over any [`Transport`]
+#[derive(Debug)]
+pub enum TransportStream {
+    /// A Unix domain socket
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// The client end of a named pipe
+    #[cfg(windows)]
+    PipeClient(NamedPipeClient),
+    /// The server end of a named pipe
+    #[cfg(windows)]
+    PipeServer(NamedPipeServer),
+    /// A TCP connection
+    Tcp(TcpStream),
+    /// The server end of a TCP connection wrapped in TLS
+    #[cfg(feature = "tls")]
+    TlsServer(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    /// The client end of a TCP connection wrapped in TLS
+    #[cfg(feature = "tls")]
+    TlsClient(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+/// Run `$body` with `$stream` bound to whichever stream `$value` holds
+macro_rules! with_stream {
+    ($value:expr, $stream:ident => $body:expr) => {
+        match $value {
+            #[cfg(unix)]
+            TransportStream::Unix($stream) => $body,
+            #[cfg(windows)]
+            TransportStream::PipeClient($stream) => $body,
+            #[cfg(windows)]
+            TransportStream::PipeServer($stream) => $body,
+            TransportStream::Tcp($stream) => $body,
+            #[cfg(feature = "tls")]
+            TransportStream::TlsServer($stream) => $body,
+            #[cfg(feature = "tls")]
+            TransportStream::TlsClient($stream) => $body,
+        }
+    };
+}
+
+impl TransportStream {
+    /// Read without waiting, failing with `WouldBlock` if nothing is available
+    pub(crate) fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_read(buf),
+            #[cfg(windows)]
+            Self::PipeClient(stream) => stream.try_read(buf),
+            #[cfg(windows)]
+            Self::PipeServer(stream) => stream.try_read(buf),
+            Self::Tcp(stream) => stream.try_read(buf),
+            // Reading past TLS would consume its records, so assume there is nothing to read
+            #[cfg(feature = "tls")]
+            Self::TlsServer(_) | Self::TlsClient(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// The process on the other end, for Unix domain sockets
+    pub(crate) fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => match stream.peer_cred() {
+                Ok(cred) => Some(PeerCredentials {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                }),
+                Err(e) => {
+                    debug!("Failed to read peer credentials: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Notices the client closing a connection outright.
+///
+/// Clients half-close after sending a request, so reaching the end of the
+/// stream doesn't mean they stopped listening. A Unix domain socket tells
+/// the two apart by hanging up once the peer has closed both directions;
+/// other transports can't, and never report a hang-up. The watched socket
+/// is a duplicate, so the connection's own stream stays free for the
+/// response.
+pub(crate) struct Hangup {
+    #[cfg(unix)]
+    watched: Option<UnixStream>,
+}
+
+impl Hangup {
+    pub(crate) fn new(stream: &TransportStream) -> Self {
+        #[cfg(unix)]
+        if let TransportStream::Unix(stream) = stream {
+            let watched = duplicate(stream).inspect_err(|e| debug!("Failed to watch for hang-ups: {}", e));
+            return Self { watched: watched.ok() };
+        }
+        #[cfg(not(unix))]
+        let _ = stream;
+        Self {
+            #[cfg(unix)]
+            watched: None,
+        }
+    }
+
+    /// Wait until the client hangs up, which may be never
+    pub(crate) async fn closed(&self) {
+        #[cfg(unix)]
+        if let Some(stream) = &self.watched {
+            loop {
+                match stream.ready(Interest::WRITABLE).await {
+                    Ok(ready) if ready.is_write_closed() => return,
+                    // Clear the readiness, so the next wait lasts until the socket's state changes
+                    Ok(_) => {
+                        let _ = stream.try_io(Interest::WRITABLE, || Err::<(), _>(io::ErrorKind::WouldBlock.into()));
+                    }
+                    Err(_) => return,
+                }
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(unix)]
+fn duplicate(stream: &UnixStream) -> io::Result<UnixStream> {
+    use std::os::fd::AsFd;
+    let std = std::os::unix::net::UnixStream::from(stream.as_fd().try_clone_to_owned()?);
+    std.set_nonblocking(true)?;
+    UnixStream::from_std(std)
+}
+
+impl AsyncRead for TransportStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        with_stream!(self.get_mut(), stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for TransportStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        with_stream!(self.get_mut(), stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        with_stream!(self.get_mut(), stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        with_stream!(self.get_mut(), stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+/// Connect to the server `config` points at
+pub(crate) async fn connect(config: &SocketConfig) -> io::Result<TransportStream> {
+    match config.transport {
+        #[cfg(unix)]
+        Transport::Unix => Ok(TransportStream::Unix(UnixStream::connect(unix_address(&config.socket_path)).await?)),
+        #[cfg(windows)]
+        Transport::NamedPipe => {
+            // All instances of the pipe are busy until the server creates the next one
+            const ERROR_PIPE_BUSY: i32 = 231;
+            loop {
+                match ClientOptions::new().open(&config.socket_path) {
+                    Ok(client) => return Ok(TransportStream::PipeClient(client)),
+                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+                    Err(e) => return Err(e),
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        }
+        Transport::Tcp(addr) => {
+            let stream = TcpStream::connect(addr).await?;
+            // Requests and responses are small and written whole
+            stream.set_nodelay(true)?;
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &config.tls {
+                return crate::tls::connect(tls, stream).await;
+            }
+            Ok(TransportStream::Tcp(stream))
+        }
+    }
+}
+
+/// Where a server accepts connections
+pub(crate) enum Listener {
+    /// Removes the socket file again when dropped
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        _socket_file: Option<SocketFileGuard>,
+    },
+    /// The pipe instance the next client will connect to
+    #[cfg(windows)]
+    NamedPipe {
+        name: std::path::PathBuf,
+        next: NamedPipeServer,
+    },
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Start listening where `config` says, dealing with an existing socket
+    /// file as configured
+    pub(crate) async fn bind(config: &SocketConfig) -> SocketResult<Self> {
+        let path = &config.socket_path;
+        match config.transport {
+            #[cfg(target_os = "linux")]
+            Transport::Unix if is_abstract(path) => {
+                // Nothing to clear, guard or chmod; a live server holds the name until it exits
+                let listener = UnixListener::bind(unix_address(path)).map_err(|e| match e.kind() {
+                    io::ErrorKind::AddrInUse => SocketError::AlreadyExists(path.clone()),
+                    _ => e.into(),
+                })?;
+                Ok(Self::Unix {
+                    listener,
+                    _socket_file: None,
+                })
+            }
+            #[cfg(unix)]
+            Transport::Unix => {
+                clear_socket_path(path, config.socket_policy()).await?;
+                let listener = UnixListener::bind(path).map_err(|e| bind_error(path, e))?;
+                let socket_file = SocketFileGuard::new(path);
+                if let Some(mode) = config.socket_mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+                Ok(Self::Unix {
+                    listener,
+                    _socket_file: Some(socket_file),
+                })
+            }
+            #[cfg(windows)]
+            Transport::NamedPipe => {
+                // Creating the first instance fails while another server owns the name
+                let next = ServerOptions::new().first_pipe_instance(true).create(path).map_err(|e| {
+                    match e.kind() {
+                        io::ErrorKind::PermissionDenied => crate::SocketError::AlreadyExists(path.clone()),
+                        _ => e.into(),
+                    }
+                })?;
+                Ok(Self::NamedPipe { name: path.clone(), next })
+            }
+            Transport::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Where clients reach this listener, for logging
+    pub(crate) fn endpoint(&self, config: &SocketConfig) -> String {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("tcp://{}", addr),
+                Err(_) => "tcp".to_string(),
+            },
+            _ => config.socket_path.display().to_string(),
+        }
+    }
+
+    /// Wait for the next client
+    pub(crate) async fn accept(&mut self) -> io::Result<TransportStream> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => Ok(TransportStream::Unix(listener.accept().await?.0)),
+            #[cfg(windows)]
+            Self::NamedPipe { name, next } => {
+                next.connect().await?;
+                let connected = std::mem::replace(next, ServerOptions::new().create(&*name)?);
+                Ok(TransportStream::PipeServer(connected))
+            }
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok(TransportStream::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// Whether `path` names a socket in Linux's abstract namespace, written `@name`
+#[cfg(target_os = "linux")]
+fn is_abstract(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().starts_with(b"@")
+}
+
+/// `path` as tokio expects it, which marks an abstract name with a leading
+/// NUL byte rather than `@`
+#[cfg(unix)]
+fn unix_address(path: &Path) -> std::borrow::Cow<'_, Path> {
+    #[cfg(target_os = "linux")]
+    if is_abstract(path) {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let mut name = path.as_os_str().as_bytes().to_vec();
+        name[0] = 0;
+        return std::borrow::Cow::Owned(std::ffi::OsString::from_vec(name).into());
+    }
+    std::borrow::Cow::Borrowed(path)
+}
+
+/// Name the full filesystem when binding fails for lack of space, which
+/// otherwise surfaces as a bare `ENOSPC`. Abstract sockets have no file and
+/// never fail this way.
+#[cfg(unix)]
+fn bind_error(socket_path: &Path, e: io::Error) -> SocketError {
+    match e.kind() {
+        io::ErrorKind::StorageFull => SocketError::DiskFull(socket_path.to_path_buf()),
+        _ => e.into(),
+    }
+}
+
+/// Make way for binding at `socket_path` according to `policy`
+#[cfg(unix)]
+async fn clear_socket_path(socket_path: &Path, policy: ExistingSocketPolicy) -> SocketResult<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(metadata) = std::fs::symlink_metadata(socket_path) else {
+        return Ok(());
+    };
+    match policy {
+        ExistingSocketPolicy::Overwrite => {}
+        ExistingSocketPolicy::FailIfExists => return Err(SocketError::AlreadyExists(socket_path.to_path_buf())),
+        ExistingSocketPolicy::ReplaceIfStale => {
+            // Only a socket nobody answers on is safe to remove
+            if !metadata.file_type().is_socket() || UnixStream::connect(socket_path).await.is_ok() {
+                return Err(SocketError::AlreadyExists(socket_path.to_path_buf()));
+            }
+            info!("Removing stale socket file: {:?}", socket_path);
+        }
+    }
+    std::fs::remove_file(socket_path)?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_error_names_full_filesystem() {
+        let path = Path::new("/run/circle.sock");
+        const ENOSPC: i32 = 28;
+        let error = bind_error(path, io::Error::from_raw_os_error(ENOSPC));
+        assert!(matches!(&error, SocketError::DiskFull(p) if p == path));
+        assert!(error.to_string().contains("/run/circle.sock"));
+
+        let error = bind_error(path, io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(error, SocketError::Io(_)));
+    }
+}
@@ -0,0 +1,67 @@
+//! Connections upgraded from request/response to a raw byte pipe.
+
+use crate::TransportStream;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A connection that has left the request/response protocol.
+///
+/// After an upgrade both peers read and write raw bytes; no framing or
+/// serialization is applied. Bytes the peer sent right behind the upgrade
+/// request or response are delivered first, so nothing is lost in the switch.
+pub struct UpgradedStream {
+    buffered: Vec<u8>,
+    position: usize,
+    stream: TransportStream,
+}
+
+impl UpgradedStream {
+    pub(crate) fn new(stream: TransportStream, buffered: Vec<u8>) -> Self {
+        Self {
+            buffered,
+            position: 0,
+            stream,
+        }
+    }
+
+    /// Recover the underlying stream. Any bytes still buffered from before
+    /// the upgrade are returned alongside it.
+    pub fn into_inner(self) -> (TransportStream, Vec<u8>) {
+        let remaining = self.buffered[self.position..].to_vec();
+        (self.stream, remaining)
+    }
+}
+
+impl AsyncRead for UpgradedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position < this.buffered.len() {
+            let remaining = &this.buffered[this.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UpgradedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,97 @@
+//! Serving connections on a fixed pool of worker tasks.
+//!
+//! With a [`WorkerPool`] configured, accepted connections wait in a bounded
+//! queue until one of the workers is free to serve them. While the queue is
+//! full the server stops accepting, leaving new clients in the listen
+//! backlog instead of taking on more work than the workers can get through.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+/// How many connections are served at once, set with
+/// [`SocketConfig::worker_pool`](crate::SocketConfig::worker_pool)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct WorkerPool {
+    /// Connections served at once
+    pub workers: usize,
+    /// Accepted connections that may wait for a worker before the server
+    /// stops accepting
+    pub queue: usize,
+}
+
+impl WorkerPool {
+    /// Serve `workers` connections at once, with up to `queue` more waiting
+    pub fn new(workers: usize, queue: usize) -> Self {
+        Self { workers, queue }
+    }
+}
+
+/// A connection waiting to be served
+pub(crate) type QueuedConnection = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where accepted connections wait for a worker
+#[derive(Clone)]
+pub(crate) struct ConnectionQueue {
+    workers: usize,
+    sender: mpsc::Sender<QueuedConnection>,
+    receiver: Arc<Mutex<mpsc::Receiver<QueuedConnection>>>,
+}
+
+impl ConnectionQueue {
+    pub(crate) fn new(pool: WorkerPool) -> Self {
+        let (sender, receiver) = mpsc::channel(pool.queue.max(1));
+        Self {
+            workers: pool.workers.max(1),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Workers to start
+    pub(crate) fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Queue `connection`, waiting while the queue is full
+    pub(crate) async fn push(&self, connection: QueuedConnection) {
+        // The queue holds the receiver, so it is never closed while sending
+        let _ = self.sender.send(connection).await;
+    }
+
+    /// The next connection for a worker to serve
+    pub(crate) async fn next(&self) -> Option<QueuedConnection> {
+        self.receiver.lock().await.recv().await
+    }
+
+    /// Connections waiting for a worker
+    pub(crate) fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Close every connection still waiting for a worker, returning how many there were
+    pub(crate) fn clear(&self) -> usize {
+        // A worker waiting on the receiver means there is nothing queued
+        let Ok(mut receiver) = self.receiver.try_lock() else {
+            return 0;
+        };
+        let mut cleared = 0;
+        while receiver.try_recv().is_ok() {
+            cleared += 1;
+        }
+        cleared
+    }
+}
+
+/// The running workers, stopped when dropped
+pub(crate) struct Workers(pub(crate) Vec<JoinHandle<()>>);
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        for worker in &self.0 {
+            worker.abort();
+        }
+    }
+}
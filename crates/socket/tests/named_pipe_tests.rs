@@ -0,0 +1,50 @@
+#![cfg(windows)]
+
+use circle_socket::{SocketClient, SocketConfig, SocketPayload, SocketResponse, SocketServer};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TestData {
+    value: String,
+    number: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TestResponse {
+    result: String,
+    doubled: i32,
+}
+
+#[tokio::test]
+async fn test_named_pipe_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let config = SocketConfig::from(r"\\.\pipe\test_circle_named_pipe");
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+        value: "piped".to_string(),
+        number: 21,
+    });
+    let response = client.send_request(payload).await?.into_result()?;
+    assert_eq!(response.result, "piped");
+    assert_eq!(response.doubled, 42);
+
+    server_handle.abort();
+
+    Ok(())
+}
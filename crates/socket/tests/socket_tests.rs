@@ -1,4 +1,6 @@
-use circle_socket::{SocketClient, SocketConfig, SocketPayload, SocketResponse, SocketServer};
+#![cfg(unix)]
+
+use circle_socket::{testing, LargeResponsePolicy, SocketClient, SocketConfig, SocketPayload, SocketResponse, SocketServer};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use tokio::time::{sleep, Duration};
@@ -99,4 +101,5103 @@ async fn test_start_stop_pattern() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_raw_bytes_do_not_break_server() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_raw.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("start", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // Garbage and truncated JSON are answered with an error
+    for raw in [&b"\x00\xff not json at all"[..], br#"{"request_id":"1","command":"st"#] {
+        let response = testing::send_raw(&config, raw).await?;
+        let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+        assert!(response.error.unwrap().starts_with("Invalid request format"));
+    }
+
+    // A well-formed request written by hand still gets a normal reply
+    let raw = br#"{"request_id":"raw-1","command":"start","data":{"value":"raw","number":4}}"#;
+    let response = testing::send_raw(&config, raw).await?;
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+    assert_eq!(response.request_id, "raw-1");
+    assert_eq!(response.data.unwrap().doubled, 8);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_response_policy() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Framing;
+
+    // Newline-delimited lines can't carry a compressed body, so Compress sends it as it is
+    for (name, policy, framing) in [
+        ("compress", LargeResponsePolicy::Compress, Framing::Json),
+        ("compress_ndjson", LargeResponsePolicy::Compress, Framing::NdJson),
+        ("error", LargeResponsePolicy::Error, Framing::Json),
+    ] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_large_{}.sock", name));
+        let config = SocketConfig {
+            large_response_policy: policy,
+            large_response_threshold: 256,
+            framing,
+            ..SocketConfig::from(&socket_path)
+        };
+
+        let server_config = config.clone();
+        let server_handle = tokio::spawn(async move {
+            let server = SocketServer::<TestData, TestResponse>::new(server_config);
+            server
+                .register_handler("big", |payload| {
+                    Ok(SocketResponse::success(payload.request_id, TestResponse {
+                        result: "x".repeat(4000),
+                        doubled: payload.data.number * 2,
+                    }))
+                })
+                .await;
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let client = SocketClient::new(config);
+        let payload = SocketPayload::new("big", TestData { value: String::new(), number: 1 });
+        let response = client.send_request::<TestData, TestResponse>(payload).await?;
+
+        match policy {
+            LargeResponsePolicy::Compress => {
+                assert!(response.success);
+                assert_eq!(response.data.unwrap().result.len(), 4000);
+            }
+            _ => {
+                assert!(!response.success);
+                assert!(response.error.unwrap().starts_with("response_too_large"));
+            }
+        }
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_name_in_active_connections() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_client_name.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let handle = server.handle();
+    let seen = handle.clone();
+    server
+        .register_handler("whoami", move |payload| {
+            let names: Vec<String> = seen
+                .active_connections()
+                .into_iter()
+                .filter_map(|c| c.client_name)
+                .collect();
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: names.join(","),
+                doubled: 0,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_client_name("deployer");
+    let payload = SocketPayload::new("whoami", TestData { value: String::new(), number: 0 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    assert_eq!(response.data.unwrap().result, "deployer");
+
+    // The connection is deregistered once it closes
+    sleep(Duration::from_millis(50)).await;
+    assert!(handle.active_connections().is_empty());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_streaming() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_batch_stream.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("double", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payloads = vec![
+        SocketPayload::new("double", TestData { value: "a".to_string(), number: 1 }),
+        SocketPayload::new("missing", TestData { value: "b".to_string(), number: 2 }),
+        SocketPayload::new("double", TestData { value: "c".to_string(), number: 3 }),
+    ];
+    let ids: Vec<String> = payloads.iter().map(|p| p.request_id.clone()).collect();
+
+    let mut stream = client.send_batch_streaming::<TestData, TestResponse>(payloads).await?;
+    let mut received = Vec::new();
+    while let Some(response) = stream.next().await {
+        received.push(response?);
+    }
+
+    assert_eq!(received.len(), 3);
+    assert_eq!(received.iter().map(|r| r.request_id.clone()).collect::<Vec<_>>(), ids);
+    assert_eq!(received[0].data.as_ref().unwrap().doubled, 2);
+    assert!(!received[1].success);
+    assert_eq!(received[2].data.as_ref().unwrap().doubled, 6);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_responses_in_completion_order() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_batch_completion.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_async_handler("nap", |payload| async move {
+            sleep(Duration::from_millis(payload.data.number as u64)).await;
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payloads = vec![
+        SocketPayload::new("nap", TestData { value: "slow".to_string(), number: 500 }),
+        SocketPayload::new("nap", TestData { value: "fast".to_string(), number: 10 }),
+    ];
+
+    let started = std::time::Instant::now();
+    let mut stream = client.send_batch_streaming::<TestData, TestResponse>(payloads).await?;
+    let first = stream.next().await.unwrap()?;
+    assert_eq!(first.data.unwrap().result, "fast");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    let second = stream.next().await.unwrap()?;
+    assert_eq!(second.data.unwrap().result, "slow");
+    assert!(stream.next().await.is_none());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_batch() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_send_batch.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payloads = vec![
+        SocketPayload::new("double", TestData { value: "a".to_string(), number: 1 }),
+        SocketPayload::new("missing", TestData { value: "b".to_string(), number: 2 }),
+        SocketPayload::new("double", TestData { value: "c".to_string(), number: 3 }),
+    ];
+    let ids: Vec<String> = payloads.iter().map(|p| p.request_id.clone()).collect();
+
+    let responses = client.send_batch::<TestData, TestResponse>(payloads).await?;
+    assert_eq!(responses.iter().map(|r| r.request_id.clone()).collect::<Vec<_>>(), ids);
+    assert_eq!(responses[0].data.as_ref().unwrap().doubled, 2);
+    assert!(responses[1].error.as_deref().unwrap().contains("missing"));
+    assert_eq!(responses[2].data.as_ref().unwrap().doubled, 6);
+
+    // A batch of one is still a batch, not a handshake
+    let single = SocketPayload::new("double", TestData { value: "d".to_string(), number: 4 });
+    let responses = client.send_batch::<TestData, TestResponse>(vec![single]).await?;
+    assert_eq!(responses[0].data.as_ref().unwrap().doubled, 8);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_responses_reject_success_without_data() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_strict.sock");
+    let config = SocketConfig {
+        strict_responses: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("empty", |payload| {
+                Ok(SocketResponse {
+                    request_id: payload.request_id,
+                    success: true,
+                    data: None,
+                    error: None,
+                    code: None,
+                    kind: None,
+                })
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::new("empty", TestData { value: String::new(), number: 0 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    assert!(!response.success);
+    assert!(response.error.unwrap().starts_with("protocol_error"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_time_budget_closes_connection() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_budget.sock");
+    let config = SocketConfig {
+        handler_time_budget: Some(Duration::from_millis(100)),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("slow", |payload| {
+                std::thread::sleep(std::time::Duration::from_millis(60));
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: 0,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payloads = (0..4)
+        .map(|i| SocketPayload::new("slow", TestData { value: i.to_string(), number: i }))
+        .collect();
+    let responses = client
+        .send_batch_streaming::<TestData, TestResponse>(payloads)
+        .await?
+        .collect()
+        .await?;
+
+    // Two handlers fit in the budget, the rest of the batch is refused and the connection closes
+    assert_eq!(responses.len(), 4);
+    assert!(responses[0].success && responses[1].success);
+    assert!(responses[2].error.as_deref().unwrap().starts_with("cpu_budget_exceeded"));
+    assert!(responses[3].error.as_deref().unwrap().starts_with("cpu_budget_exceeded"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_json_envelope() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::JsonEnvelope;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_envelope.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<JsonEnvelope<TestData>, JsonEnvelope<TestResponse>>::new(server_config);
+        server
+            .register_envelope_handler("double", |data: TestData| {
+                Ok(TestResponse { result: data.value, doubled: data.number * 2 })
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let data = TestData { value: "env".to_string(), number: 21 };
+    let response: TestResponse = client.send_envelope("double", data).await?;
+    assert_eq!(response.doubled, 42);
+
+    // Peers that nest JSON in plain strings by hand see the same wire format
+    let nested = serde_json::to_string(&TestData { value: "str".to_string(), number: 2 })?;
+    let payload = SocketPayload::new("double", nested);
+    let response = client.send_request::<String, String>(payload).await?.into_result()?;
+    assert_eq!(serde_json::from_str::<TestResponse>(&response)?.doubled, 4);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_length_prefixed_framing() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Framing;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_length_prefixed.sock");
+    let config = SocketConfig {
+        framing: Framing::LengthPrefixed,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("echo", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // Requests and responses well past a single read round-trip intact
+    let client = SocketClient::new(config.clone()).with_client_name("framed");
+    let payload = SocketPayload::new("echo", TestData { value: "y".repeat(100_000), number: 3 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?.into_result()?;
+    assert_eq!(response.result.len(), 100_000);
+    assert_eq!(response.doubled, 6);
+
+    // On the wire, the response is a 4-byte big-endian length and the JSON body
+    let payload = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "raw".to_string(),
+        number: 1,
+    });
+    let body = serde_json::to_vec(&payload)?;
+    let mut request = (body.len() as u32).to_be_bytes().to_vec();
+    request.extend_from_slice(&body);
+    let reply = testing::send_raw(&config, &request).await?;
+    let len = u32::from_be_bytes(reply[..4].try_into()?) as usize;
+    assert_eq!(len, reply.len() - 4);
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&reply[4..])?;
+    assert_eq!(response.into_result()?.result, "raw");
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_response_round_trips() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_large_response.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("big", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: "z".repeat(50_000),
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::new("big", TestData { value: String::new(), number: 5 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?.into_result()?;
+    assert_eq!(response.result, "z".repeat(50_000));
+    assert_eq!(response.doubled, 10);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_download.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_download_handler("export", |payload, mut sink| async move {
+                let data: Vec<u8> = (0..payload.data.number as u32).map(|i| (i % 251) as u8).collect();
+                sink.write_all(&data).await?;
+                if payload.data.value == "fail" {
+                    return Err(SocketError::ServerError("export failed".to_string()));
+                }
+                Ok(())
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::new("export", TestData { value: String::new(), number: 1_000_000 });
+    let mut received = Vec::new();
+    let n = client.download::<TestData, TestResponse, _>(payload, &mut received).await?;
+    assert_eq!(n, 1_000_000);
+    assert_eq!(received.len(), 1_000_000);
+    assert!(received.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+    // A handler failing after sending data fails the download
+    let payload = SocketPayload::new("export", TestData { value: "fail".to_string(), number: 10 });
+    let result = client.download::<TestData, TestResponse, _>(payload, &mut Vec::new()).await;
+    assert!(matches!(result, Err(SocketError::ServerError(msg)) if msg.contains("export failed")));
+
+    // Commands without a download handler don't start a download
+    let payload = SocketPayload::new("missing", TestData { value: String::new(), number: 0 });
+    assert!(client.download::<TestData, TestResponse, _>(payload, &mut Vec::new()).await.is_err());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_response() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_streaming.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_streaming_handler("tail", |payload, mut sink| async move {
+                for line in 1..=payload.data.number {
+                    sink.send(TestResponse {
+                        result: format!("{} line {}", payload.data.value, line),
+                        doubled: line * 2,
+                    })
+                    .await?;
+                }
+                if payload.data.value == "fail" {
+                    return Err(SocketError::ServerError("log rotated".to_string()));
+                }
+                Ok(())
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let tail = |value: &str, number: i32| {
+        SocketPayload::<TestData, TestResponse>::new("tail", TestData { value: value.to_string(), number })
+    };
+    let chunks = client.send_request_streaming(tail("app", 3)).await?.collect().await?;
+    let lines: Vec<_> = chunks.iter().map(|chunk| chunk.data.as_ref().unwrap().result.as_str()).collect();
+    assert_eq!(lines, ["app line 1", "app line 2", "app line 3"]);
+    assert_eq!(chunks[2].data.as_ref().unwrap().doubled, 6);
+
+    // A handler failing part-way ends the stream with its error
+    let mut chunks = client.send_request_streaming(tail("fail", 1)).await?;
+    assert!(chunks.next().await.unwrap()?.success);
+    let failed = chunks.next().await.unwrap()?;
+    assert!(failed.error.unwrap().contains("log rotated"));
+    assert!(chunks.next().await.is_none());
+
+    // Commands without a streaming handler don't start a stream
+    let payload = SocketPayload::<TestData, TestResponse>::new("missing", TestData { value: String::new(), number: 0 });
+    assert!(client.send_request_streaming(payload).await.is_err());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgrade_to_raw_stream() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_upgrade.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_upgrade_handler("shell", |payload, mut stream| async move {
+                // Greet with the request's value, then echo everything back uppercased
+                stream.write_all(payload.data.value.as_bytes()).await?;
+                let mut buf = [0u8; 64];
+                loop {
+                    let n = stream.read(&mut buf).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    stream.write_all(&buf[..n].to_ascii_uppercase()).await?;
+                }
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::new("shell", TestData { value: "hi>".to_string(), number: 0 });
+    let mut stream = client.upgrade::<TestData, TestResponse>(payload).await?;
+
+    let mut greeting = [0u8; 3];
+    stream.read_exact(&mut greeting).await?;
+    assert_eq!(&greeting, b"hi>");
+
+    stream.write_all(b"echo").await?;
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).await?;
+    assert_eq!(&echoed, b"ECHO");
+
+    // Commands without an upgrade handler don't upgrade
+    let payload = SocketPayload::new("missing", TestData { value: String::new(), number: 0 });
+    assert!(client.upgrade::<TestData, TestResponse>(payload).await.is_err());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_without_half_close_gets_response() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_no_shutdown.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("start", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // Write a complete request and then just wait, keeping the write half open
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    let payload = SocketPayload::<TestData, TestResponse>::new("start", TestData { value: "waiting".to_string(), number: 5 });
+    stream.write_all(&serde_json::to_vec(&payload)?).await?;
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await??;
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+    assert_eq!(response.request_id, payload.request_id);
+    assert_eq!(response.data.unwrap().doubled, 10);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "signing")]
+#[tokio::test]
+async fn test_signed_requests() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SigningConfig;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_signed.sock");
+    let config = SocketConfig {
+        signing: Some(SigningConfig::new("shared-secret")),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("start", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // Correctly signed
+    let client = SocketClient::new(config.clone());
+    let payload = SocketPayload::new("start", TestData { value: "signed".to_string(), number: 2 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    assert!(response.success);
+
+    // Wrong key
+    let bad_client = SocketClient::new(SocketConfig {
+        signing: Some(SigningConfig::new("wrong-secret")),
+        ..config.clone()
+    });
+    let payload = SocketPayload::new("start", TestData { value: "forged".to_string(), number: 2 });
+    let response = bad_client.send_request::<TestData, TestResponse>(payload).await?;
+    assert!(response.error.unwrap().starts_with("signature_invalid"));
+
+    // Unsigned
+    let raw = br#"{"request_id":"raw","command":"start","data":{"value":"x","number":1}}"#;
+    let response = testing::send_raw(&config, raw).await?;
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+    assert!(response.error.unwrap().starts_with("signature_invalid"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_inflight_admin_command() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{admin, InflightRequest};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_inflight.sock");
+    let config = SocketConfig {
+        admin_commands: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("stuck", |payload| {
+                // Tell the runtime this worker blocks so other connections keep being served
+                tokio::task::block_in_place(|| std::thread::sleep(std::time::Duration::from_millis(800)));
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: String::new(),
+                    doubled: 0,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let stuck_client = SocketClient::new(config.clone());
+    let stuck_payload = SocketPayload::new("stuck", TestData { value: String::new(), number: 0 });
+    let stuck_id = stuck_payload.request_id.clone();
+    let stuck = tokio::spawn(async move {
+        stuck_client.send_request::<TestData, TestResponse>(stuck_payload).await
+    });
+
+    // Poll until the stuck handler shows up
+    let client = SocketClient::new(config);
+    let mut inflight = Vec::new();
+    for _ in 0..20 {
+        sleep(Duration::from_millis(25)).await;
+        let payload = SocketPayload::new(admin::INFLIGHT_COMMAND, ());
+        inflight = client
+            .send_request::<(), Vec<InflightRequest>>(payload)
+            .await?
+            .into_result()?;
+        if !inflight.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(inflight.len(), 1);
+    assert_eq!(inflight[0].command, "stuck");
+    assert_eq!(inflight[0].request_id, stuck_id);
+    assert!(inflight[0].age_ms > 0);
+
+    assert!(stuck.await??.success);
+
+    server_handle.abort();
+    let _ = server_handle.await;
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_admin_command() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::admin;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_snapshot.sock");
+    let config = SocketConfig {
+        admin_commands: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    for command in ["stop", "start"] {
+        server
+            .register_handler(command, |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: String::new(),
+                    doubled: 0,
+                }))
+            })
+            .await;
+    }
+    let snapshot = server.snapshot().await;
+    assert_eq!(snapshot.commands, vec!["start", "stop"]);
+    assert_eq!(snapshot.uptime_ms, 0);
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_client_name("support");
+    let payload = SocketPayload::new(admin::SNAPSHOT_COMMAND, ());
+    let snapshot = client
+        .send_request::<(), serde_json::Value>(payload)
+        .await?
+        .into_result()?;
+    assert_eq!(snapshot["commands"], serde_json::json!(["start", "stop"]));
+    assert_eq!(snapshot["config"]["socket_path"], "/tmp/test_circle_snapshot.sock");
+    assert_eq!(snapshot["active_connections"][0]["client_name"], "support");
+    assert!(snapshot["uptime_ms"].as_u64().unwrap() > 0);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_eager_client_connection() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_eager.sock");
+    let config = SocketConfig::from(&socket_path);
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    // No daemon: eager connection fails up front
+    assert!(SocketClient::new(config.clone()).connect_eager().await.is_err());
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let handle = server.handle();
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_client_name("eager").connect_eager().await?;
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(handle.active_connections().len(), 1);
+
+    for number in 1..=3 {
+        let payload = SocketPayload::new("start", TestData { value: "eager".to_string(), number });
+        let response = client.send_request::<TestData, TestResponse>(payload).await?;
+        assert_eq!(response.data.unwrap().doubled, number * 2);
+    }
+
+    // A replacement connection is kept warm for the next request
+    sleep(Duration::from_millis(50)).await;
+    let connections = handle.active_connections();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].client_name.as_deref(), Some("eager"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_follow_redirects() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{RedirectTarget, ResponseKind, SocketError};
+
+    let front_path = PathBuf::from("/tmp/test_circle_redirect_front.sock");
+    let shard_path = PathBuf::from("/tmp/test_circle_redirect_shard.sock");
+
+    let front = SocketServer::<TestData, TestResponse>::new(SocketConfig::from(&front_path));
+    let target = shard_path.clone();
+    front
+        .register_handler("start", move |payload| Ok(SocketResponse::redirect(payload.request_id, &target)))
+        .await;
+    let shard = SocketServer::<TestData, TestResponse>::new(SocketConfig::from(&shard_path));
+    shard
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "from shard".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let front_handle = tokio::spawn(async move { tokio::time::timeout(Duration::from_secs(5), front.run()).await });
+    let shard_handle = tokio::spawn(async move { tokio::time::timeout(Duration::from_secs(5), shard.run()).await });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // By default the redirect is returned as-is
+    let client = SocketClient::new(SocketConfig::from(&front_path));
+    let payload = SocketPayload::new("start", TestData { value: String::new(), number: 4 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    assert_eq!(response.kind, Some(ResponseKind::Redirect { target: RedirectTarget::Path(shard_path.clone()) }));
+    assert_eq!(response.code.as_deref(), Some("redirect"));
+    let expected = format!("redirect: send the request to {}", shard_path.display());
+    assert!(matches!(response.into_result(), Err(SocketError::ServerError(e)) if e == expected));
+
+    // Following redirects reaches the shard
+    let client = SocketClient::new(SocketConfig::from(&front_path)).with_max_redirects(2);
+    let payload = SocketPayload::new("start", TestData { value: String::new(), number: 4 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    let data = response.data.unwrap();
+    assert_eq!(data.result, "from shard");
+    assert_eq!(data.doubled, 8);
+
+    front_handle.abort();
+    shard_handle.abort();
+    for path in [front_path, shard_path] {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_follow_redirects_between_transports() -> Result<(), Box<dyn std::error::Error>> {
+    let front_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let shard_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let local_path = PathBuf::from("/tmp/test_circle_redirect_local.sock");
+
+    let front = SocketServer::<TestData, TestResponse>::new(SocketConfig::tcp(front_addr));
+    front
+        .register_handler("start", move |payload| Ok(SocketResponse::redirect(payload.request_id, shard_addr)))
+        .await;
+    let shard = SocketServer::<TestData, TestResponse>::new(SocketConfig::tcp(shard_addr));
+    shard
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "from shard".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    // A daemon on a Unix socket sending its clients to a TCP peer
+    let local = SocketServer::<TestData, TestResponse>::new(SocketConfig::from(&local_path));
+    local
+        .register_handler("start", move |payload| Ok(SocketResponse::redirect(payload.request_id, front_addr)))
+        .await;
+    let handles = [
+        tokio::spawn(async move { tokio::time::timeout(Duration::from_secs(5), front.run()).await }),
+        tokio::spawn(async move { tokio::time::timeout(Duration::from_secs(5), shard.run()).await }),
+        tokio::spawn(async move { tokio::time::timeout(Duration::from_secs(5), local.run()).await }),
+    ];
+
+    sleep(Duration::from_millis(100)).await;
+
+    for (config, hops) in [(SocketConfig::tcp(front_addr), 1), (SocketConfig::from(&local_path), 2)] {
+        let client = SocketClient::new(config).with_max_redirects(hops);
+        let payload = SocketPayload::new("start", TestData { value: String::new(), number: 5 });
+        let data = client.send_request::<TestData, TestResponse>(payload).await?.into_result()?;
+        assert_eq!(data.result, "from shard");
+        assert_eq!(data.doubled, 10);
+    }
+
+    for handle in handles {
+        handle.abort();
+    }
+    if local_path.exists() {
+        std::fs::remove_file(&local_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_self_test_reports_broken_handlers() {
+    use circle_socket::{CheckOutcome, SocketError};
+
+    let server = SocketServer::<TestData, TestResponse>::new(SocketConfig::from("/tmp/test_circle_self_test.sock"));
+    server
+        .register_handler("ok", |payload| {
+            // Dry runs must not have side effects
+            assert!(payload.dry_run);
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: String::new(),
+                doubled: 0,
+            }))
+        })
+        .await;
+    server
+        .register_handler("fails", |payload| Err(SocketError::HandlerNotFound(payload.command)))
+        .await;
+    server
+        .register_handler("panics", |payload| {
+            let value: i32 = payload.data.value.parse().expect("value must be a number");
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: String::new(),
+                doubled: value * 2,
+            }))
+        })
+        .await;
+
+    let report = server
+        .self_test_with(|_| TestData { value: String::new(), number: 0 })
+        .await;
+
+    assert!(!report.passed());
+    let outcomes: Vec<_> = report.checks.iter().map(|c| (c.command.as_str(), &c.outcome)).collect();
+    assert_eq!(outcomes[0].0, "fails");
+    assert!(matches!(outcomes[0].1, CheckOutcome::Failed(_)));
+    assert_eq!(outcomes[1], ("ok", &CheckOutcome::Passed));
+    assert_eq!(outcomes[2].0, "panics");
+    assert!(matches!(outcomes[2].1, CheckOutcome::Panicked(msg) if msg.contains("value must be a number")));
+    assert_eq!(report.failures().count(), 2);
+}
+
+#[tokio::test]
+async fn test_metrics_sink_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::MetricsSink;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Sizes {
+        requests: Mutex<Vec<(String, usize)>>,
+        responses: Mutex<Vec<(String, usize)>>,
+        timed: Mutex<Vec<(&'static str, String)>>,
+    }
+
+    struct Recorder(Arc<Sizes>);
+
+    impl MetricsSink for Recorder {
+        fn on_request_size(&self, command: &str, bytes: usize) {
+            self.0.requests.lock().unwrap().push((command.to_string(), bytes));
+        }
+
+        fn on_response_size(&self, command: &str, bytes: usize) {
+            self.0.responses.lock().unwrap().push((command.to_string(), bytes));
+        }
+
+        fn on_decode_duration(&self, command: &str, _elapsed: Duration) {
+            self.0.timed.lock().unwrap().push(("decode", command.to_string()));
+        }
+
+        fn on_encode_duration(&self, command: &str, _elapsed: Duration) {
+            self.0.timed.lock().unwrap().push(("encode", command.to_string()));
+        }
+    }
+
+    let socket_path = PathBuf::from("/tmp/test_circle_metrics_sizes.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let sizes = Arc::new(Sizes::default());
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server.set_metrics_sink(Recorder(Arc::clone(&sizes))).await;
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let payload = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "x".repeat(1000),
+        number: 1,
+    });
+    let request_bytes = serde_json::to_vec(&payload)?.len();
+    let response = SocketClient::new(config).send_request(payload).await?;
+    let response_bytes = serde_json::to_vec(&response)?.len();
+
+    assert_eq!(*sizes.requests.lock().unwrap(), vec![("echo".to_string(), request_bytes)]);
+    assert_eq!(*sizes.responses.lock().unwrap(), vec![("echo".to_string(), response_bytes)]);
+    assert_eq!(
+        *sizes.timed.lock().unwrap(),
+        vec![("decode", "echo".to_string()), ("encode", "echo".to_string())]
+    );
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_command_too_long() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_command_too_long.sock");
+    let config = SocketConfig {
+        max_command_len: 16,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let payload = SocketPayload::<TestData, TestResponse>::new("x".repeat(1024 * 1024), TestData {
+        value: String::new(),
+        number: 0,
+    });
+    let request_id = payload.request_id.clone();
+    let response = SocketClient::new(config).send_request(payload).await?;
+    assert_eq!(response.request_id, request_id);
+    assert!(!response.success);
+    assert_eq!(
+        response.error.as_deref(),
+        Some("command_too_long: 1048576 bytes exceeds limit of 16")
+    );
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_warm_up() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_warm_up.sock");
+    let config = SocketConfig {
+        warm_up: Some(Duration::MAX),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let echo = || {
+        SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+            value: "hi".to_string(),
+            number: 1,
+        })
+    };
+    let ready = || SocketPayload::<(), serde_json::Value>::new("__ready", ());
+
+    let response = client.send_request(echo()).await?;
+    assert!(response.error.unwrap().starts_with("warming_up"));
+    let pong = client.send_request(SocketPayload::<(), String>::new("__ping", ())).await?;
+    assert_eq!(pong.into_result()?, "pong");
+    assert_eq!(client.send_request(ready()).await?.into_result()?["ready"], false);
+
+    handle.mark_ready();
+    assert_eq!(client.send_request(ready()).await?.into_result()?["ready"], true);
+    assert_eq!(client.send_request(echo()).await?.into_result()?.result, "hi");
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_command() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_health_command.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config.clone());
+    let health = || SocketPayload::<(), serde_json::Value>::new("__health", ());
+    let response = client.send_request(health()).await?;
+    assert!(response.success);
+    let data = response.into_result()?;
+    assert_eq!(data["commands"], 0);
+    assert!(data["uptime_secs"].is_u64());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    // Strict setups can turn it off, leaving the name unanswered
+    let config = SocketConfig {
+        health_command: false,
+        ..config
+    };
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let response = SocketClient::new(config).send_request(health()).await?;
+    assert!(!response.success);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_handler() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_async_handler.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_async_handler("wait", |payload| async move {
+            sleep(Duration::from_millis(payload.data.number as u64)).await;
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    server
+        .register_async_handler("fail", |_payload| async move {
+            Err(circle_socket::SocketError::ServerError("async failure".to_string()))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // While one handler is waiting, other requests are served
+    let slow_client = SocketClient::new(config.clone());
+    let slow = tokio::spawn(async move {
+        slow_client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("wait", TestData {
+                value: "slow".to_string(),
+                number: 600,
+            }))
+            .await
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let client = SocketClient::new(config);
+    let started = std::time::Instant::now();
+    let fast = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("wait", TestData {
+            value: "fast".to_string(),
+            number: 1,
+        }))
+        .await?
+        .into_result()?;
+    assert_eq!(fast.result, "fast");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(slow.await??.into_result()?.doubled, 1200);
+
+    let payload = SocketPayload::<TestData, TestResponse>::new("fail", TestData {
+        value: String::new(),
+        number: 0,
+    });
+    let response = client.send_request(payload).await?;
+    assert!(response.error.unwrap().contains("async failure"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blocking_handler_does_not_stall_runtime() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_blocking.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_blocking_handler("slow", |payload| {
+            std::thread::sleep(std::time::Duration::from_millis(800));
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "slow".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    server
+        .register_handler("fast", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "fast".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let slow_client = SocketClient::new(config.clone());
+    let slow = tokio::spawn(async move {
+        slow_client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("slow", TestData {
+                value: String::new(),
+                number: 2,
+            }))
+            .await
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // The single-threaded test runtime stays free while the slow handler blocks
+    let started = std::time::Instant::now();
+    let fast = SocketClient::new(config)
+        .send_request(SocketPayload::<TestData, TestResponse>::new("fast", TestData {
+            value: String::new(),
+            number: 1,
+        }))
+        .await?
+        .into_result()?;
+    assert_eq!(fast.result, "fast");
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    let slow = slow.await??.into_result()?;
+    assert_eq!(slow.result, "slow");
+    assert_eq!(slow.doubled, 4);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_response_validator() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_validator.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_response_validator(|response: &SocketResponse<TestResponse>| {
+        match &response.data {
+            Some(data) if data.doubled < 0 => Err(format!("negative result {}", data.doubled)),
+            _ => Ok(()),
+        }
+    });
+    let request = |number| {
+        SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: String::new(),
+            number,
+        })
+    };
+
+    let response = client.send_request(request(2)).await?;
+    assert_eq!(response.into_result()?.doubled, 4);
+
+    let rejected = client.send_request(request(-2)).await;
+    assert!(matches!(rejected, Err(SocketError::InvalidResponse(msg)) if msg == "negative result -4"));
+
+    let mut stream = client.send_batch_streaming(vec![request(-1)]).await?;
+    let next = stream.next().await;
+    assert!(matches!(next, Some(Err(SocketError::InvalidResponse(_)))), "{:?}", next);
+
+    // Validators only apply to the response type they were registered for
+    let untyped = client
+        .send_request(SocketPayload::<TestData, serde_json::Value>::new("double", TestData {
+            value: String::new(),
+            number: -3,
+        }))
+        .await?;
+    assert_eq!(untyped.data.unwrap()["doubled"], -6);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiplexed_connection() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_multiplexed.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_blocking_handler("slow", |payload| {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "slow".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    server
+        .register_handler("fast", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "fast".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    stream
+        .write_all(br#"{"handshake":{"client_name":"mux","multiplex":true}}"#)
+        .await?;
+    let mut reply = vec![0u8; 256];
+    let n = stream.read(&mut reply).await?;
+    let reply: serde_json::Value = serde_json::from_slice(&reply[..n])?;
+    assert!(reply["handshake"]["connection_id"].is_u64());
+
+    let slow = SocketPayload::<TestData, TestResponse>::new("slow", TestData {
+        value: String::new(),
+        number: 1,
+    });
+    let fast = SocketPayload::<TestData, TestResponse>::new("fast", TestData {
+        value: String::new(),
+        number: 2,
+    });
+    stream.write_all(&serde_json::to_vec(&slow)?).await?;
+    stream.write_all(&serde_json::to_vec(&fast)?).await?;
+    stream.shutdown().await?;
+
+    let mut body = Vec::new();
+    stream.read_to_end(&mut body).await?;
+    let responses: Vec<SocketResponse<TestResponse>> = serde_json::Deserializer::from_slice(&body)
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    // Responses arrive as handlers finish, not in request order
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].request_id, fast.request_id);
+    assert_eq!(responses[0].data.as_ref().unwrap().doubled, 4);
+    assert_eq!(responses[1].request_id, slow.request_id);
+    assert_eq!(responses[1].data.as_ref().unwrap().result, "slow");
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_after() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_close_after.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    stream.write_all(br#"{"handshake":{"multiplex":true}}"#).await?;
+    let mut reply = vec![0u8; 256];
+    let n = stream.read(&mut reply).await?;
+    assert!(serde_json::from_slice::<serde_json::Value>(&reply[..n])?["handshake"].is_object());
+
+    let first = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "first".to_string(),
+        number: 1,
+    });
+    let mut last = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "last".to_string(),
+        number: 2,
+    });
+    last.close_after = true;
+    stream.write_all(&serde_json::to_vec(&first)?).await?;
+    stream.write_all(&serde_json::to_vec(&last)?).await?;
+
+    // The server closes the connection without the client half-closing
+    let mut body = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut body)).await??;
+    let responses: Vec<SocketResponse<TestResponse>> = serde_json::Deserializer::from_slice(&body)
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    assert_eq!(responses.len(), 2);
+    assert!(responses.iter().any(|r| r.request_id == last.request_id));
+    sleep(Duration::from_millis(100)).await;
+    assert!(handle.active_connections().is_empty());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiplexed_flow_control() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{DataHeader, WindowUpdate};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Read one data frame: a `{"data": ...}` header, then its raw bytes
+    async fn read_data_frame(
+        stream: &mut tokio::net::UnixStream,
+        buf: &mut Vec<u8>,
+    ) -> Result<(DataHeader, Vec<u8>), Box<dyn std::error::Error>> {
+        loop {
+            let mut frames = serde_json::Deserializer::from_slice(buf).into_iter::<serde_json::Value>();
+            if let Some(Ok(frame)) = frames.next() {
+                let start = frames.byte_offset();
+                let header: DataHeader = serde_json::from_value(frame["data"].clone())?;
+                if buf.len() >= start + header.len {
+                    let piece = buf[start..start + header.len].to_vec();
+                    buf.drain(..start + header.len);
+                    return Ok((header, piece));
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            let n = stream.read(&mut chunk).await?;
+            assert!(n > 0, "connection closed mid-frame");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    let socket_path = PathBuf::from("/tmp/test_circle_flow_control.sock");
+    let config = SocketConfig {
+        initial_window_size: 1024,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    stream
+        .write_all(br#"{"handshake":{"multiplex":true,"flow_control":true}}"#)
+        .await?;
+    let mut reply = vec![0u8; 256];
+    let n = stream.read(&mut reply).await?;
+    let reply: serde_json::Value = serde_json::from_slice(&reply[..n])?;
+    assert_eq!(reply["handshake"]["initial_window_size"], 1024);
+
+    let big = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "x".repeat(50_000),
+        number: 1,
+    });
+    stream.write_all(&serde_json::to_vec(&big)?).await?;
+    sleep(Duration::from_millis(100)).await;
+    let small = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "small".to_string(),
+        number: 2,
+    });
+    stream.write_all(&serde_json::to_vec(&small)?).await?;
+
+    // The big response stalls at its window while the small one gets through
+    let mut buf = Vec::new();
+    let mut big_body = Vec::new();
+    let small_body = loop {
+        let (header, piece) = read_data_frame(&mut stream, &mut buf).await?;
+        if header.request_id == small.request_id {
+            assert!(header.end);
+            break piece;
+        }
+        big_body.extend_from_slice(&piece);
+    };
+    assert_eq!(big_body.len(), 1024);
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&small_body)?;
+    assert_eq!(response.into_result()?.doubled, 4);
+
+    // Granting credit lets the rest of the big response through
+    let update = serde_json::json!({
+        "window_update": WindowUpdate { request_id: big.request_id.clone(), increment: 1 << 20 }
+    });
+    stream.write_all(&serde_json::to_vec(&update)?).await?;
+    loop {
+        let (header, piece) = read_data_frame(&mut stream, &mut buf).await?;
+        assert_eq!(header.request_id, big.request_id);
+        big_body.extend_from_slice(&piece);
+        if header.end {
+            break;
+        }
+    }
+    let response: SocketResponse<TestResponse> = serde_json::from_slice(&big_body)?;
+    assert_eq!(response.into_result()?.result.len(), 50_000);
+
+    stream.shutdown().await?;
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_existing_socket_policy() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{ExistingSocketPolicy, SocketError};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_existing_policy.sock");
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let config = SocketConfig::from(&socket_path);
+
+    // A socket left behind by a server that is gone
+    drop(std::os::unix::net::UnixListener::bind(&socket_path)?);
+    assert!(socket_path.exists());
+
+    let strict = SocketConfig {
+        existing_socket_policy: ExistingSocketPolicy::FailIfExists,
+        ..config.clone()
+    };
+    let result = SocketServer::<TestData, TestResponse>::new(strict).run().await;
+    assert!(matches!(result, Err(SocketError::AlreadyExists(path)) if path == socket_path));
+
+    // The default replaces the stale socket
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // ...but refuses to take over from a live server
+    let result = SocketServer::<TestData, TestResponse>::new(config.clone()).run().await;
+    assert!(matches!(result, Err(SocketError::AlreadyExists(_))));
+    assert!(!server_handle.is_finished());
+
+    // Unless told to overwrite it
+    let overwrite = SocketConfig {
+        overwrite_existing: true,
+        ..config.clone()
+    };
+    let server = SocketServer::<TestData, TestResponse>::new(overwrite);
+    let replacement_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert!(!replacement_handle.is_finished());
+
+    // The replaced server leaves the new socket file in place when it stops
+    server_handle.abort();
+    let _ = server_handle.await;
+    assert!(socket_path.exists());
+
+    replacement_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_middleware_extensions() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    struct UserId(String);
+
+    let socket_path = PathBuf::from("/tmp/test_circle_extensions.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .add_context_middleware(|payload, context| {
+            // Treat the value as a token naming the user
+            match payload.data.value.strip_prefix("token:") {
+                Some(user) => {
+                    context.extensions.insert(UserId(user.to_string()));
+                    Ok(())
+                }
+                None => Err(SocketError::ServerError("unauthenticated".to_string())),
+            }
+        })
+        .await;
+    server
+        .register_context_handler("whoami", |payload, context| {
+            let user = context.extensions.get::<UserId>().expect("set by middleware");
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: format!("{} via {}", user.0, context.client_name.unwrap_or_default()),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_client_name("cli");
+    let request = |value: &str| {
+        SocketPayload::<TestData, TestResponse>::new("whoami", TestData {
+            value: value.to_string(),
+            number: 1,
+        })
+    };
+
+    let response = client.send_request(request("token:alice")).await?.into_result()?;
+    assert_eq!(response.result, "alice via cli");
+
+    let rejected = client.send_request(request("anonymous")).await?;
+    assert!(!rejected.success);
+    assert_eq!(rejected.error.as_deref(), Some("Server returned an error: unauthenticated"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_crate_version() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::ServerInfo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_crate_version.sock");
+    let server = SocketServer::<TestData, TestResponse>::new(SocketConfig::from(&socket_path));
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    stream.write_all(br#"{"handshake":{"crate_version":"0.0.1"}}"#).await?;
+    stream.shutdown().await?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+
+    let reply: serde_json::Value = serde_json::from_slice(&reply)?;
+    let info: ServerInfo = serde_json::from_value(reply["handshake"].clone())?;
+    assert_eq!(info.crate_version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn test_compression_dictionary() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{CompressionDictionary, SocketError};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_dictionary.sock");
+    let dictionary =
+        CompressionDictionary::new(br#"{"request_id":"","success":true,"data":{"result":"","doubled":0},"error":null}"#.to_vec());
+    let config = SocketConfig {
+        compression_dictionary: Some(dictionary.clone()),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let request = || {
+        SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+            value: "hello".to_string(),
+            number: 21,
+        })
+    };
+
+    // The response after the handshake reply is a zstd frame
+    let mut raw = format!(r#"{{"handshake":{{"dictionary_id":"{}"}}}}"#, dictionary.id()).into_bytes();
+    raw.extend(serde_json::to_vec(&request())?);
+    let reply = testing::send_raw(&config, &raw).await?;
+    assert!(reply.windows(4).any(|bytes| bytes == [0x28, 0xb5, 0x2f, 0xfd]));
+
+    let response = SocketClient::new(config.clone()).send_request(request()).await?.into_result()?;
+    assert_eq!(response.doubled, 42);
+
+    // Clients without the dictionary still get plain responses
+    let plain = SocketClient::new(SocketConfig::from(&socket_path)).send_request(request()).await?;
+    assert_eq!(plain.into_result()?.result, "hello");
+
+    let mismatched = SocketConfig {
+        compression_dictionary: Some(CompressionDictionary::new(b"another dictionary".to_vec())),
+        ..config
+    };
+    let result = SocketClient::new(mismatched).send_request(request()).await;
+    assert!(matches!(result, Err(SocketError::InvalidResponse(msg)) if msg.starts_with("compression dictionary mismatch")));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::AsyncReadExt;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_handshake_timeout.sock");
+    let config = SocketConfig {
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // A client that connects but never sends anything is dropped
+    let mut silent = tokio::net::UnixStream::connect(&socket_path).await?;
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(1), silent.read_to_end(&mut received)).await??;
+    assert!(received.is_empty());
+    assert!(handle.active_connections().is_empty());
+
+    // Clients that send their request promptly are unaffected
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::new("double", TestData { value: "ok".to_string(), number: 4 });
+    let response = client.send_request::<TestData, TestResponse>(payload).await?;
+    assert_eq!(response.into_result()?.doubled, 8);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_timeout_closes_connection() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_write_timeout.sock");
+    let config = SocketConfig {
+        write_timeout: Some(Duration::from_millis(200)),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("huge", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "x".repeat(16 * 1024 * 1024),
+                doubled: 0,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // A client that stops reading once the socket buffer is full
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+    let payload = SocketPayload::<TestData, TestResponse>::new("huge", TestData {
+        value: String::new(),
+        number: 0,
+    });
+    stream.write_all(&serde_json::to_vec(&payload)?).await?;
+    stream.shutdown().await?;
+    sleep(Duration::from_millis(800)).await;
+    for _ in 0..40 {
+        if handle.active_connections().is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(handle.active_connections().is_empty());
+
+    // What was sent before the timeout is followed by EOF, never more data
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await?;
+    assert!(received.len() < 16 * 1024 * 1024);
+    assert!(serde_json::from_slice::<SocketResponse<TestResponse>>(&received).is_err());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_raw_filter() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_raw_filter.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let handled = Arc::new(AtomicUsize::new(0));
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .add_raw_filter(|body| {
+            if body.len() > 512 {
+                return Err(SocketError::ServerError(format!("request of {} bytes rejected", body.len())));
+            }
+            Ok(())
+        })
+        .await;
+    let counter = Arc::clone(&handled);
+    server
+        .register_handler("echo", move |payload| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |value: String| {
+        SocketPayload::<TestData, TestResponse>::new("echo", TestData { value, number: 1 })
+    };
+
+    let response = client.send_request(request("small".to_string())).await?;
+    assert_eq!(response.into_result()?.result, "small");
+
+    let big = request("x".repeat(1000));
+    let request_id = big.request_id.clone();
+    let rejected = client.send_request(big).await?;
+    assert_eq!(rejected.request_id, request_id);
+    assert!(rejected.error.unwrap().contains("rejected"));
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "macros")]
+mod commands {
+    use super::*;
+    use circle_socket::{define_command, Command, Commands, SocketError};
+
+    define_command! {
+        Double = "double": TestData => TestResponse;
+        Count = "count": () => usize;
+    }
+
+    #[tokio::test]
+    async fn test_define_command() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(Double::NAME, "double");
+
+        let socket_path = PathBuf::from("/tmp/test_circle_define_command.sock");
+        let config = SocketConfig::from(&socket_path);
+
+        let server = SocketServer::new(config.clone());
+        server
+            .register_command::<Double, _>(|data| {
+                Ok(TestResponse {
+                    result: data.value,
+                    doubled: data.number * 2,
+                })
+            })
+            .await;
+        server.register_command::<Count, _>(|()| Ok(2)).await;
+
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let client = SocketClient::new(config.clone());
+        let doubled = client
+            .call::<Double>(TestData {
+                value: "typed".to_string(),
+                number: 4,
+            })
+            .await?;
+        assert_eq!(doubled.result, "typed");
+        assert_eq!(doubled.doubled, 8);
+        assert_eq!(client.call::<Count>(()).await?, 2);
+
+        // The wire format is unchanged: plain payloads reach typed commands
+        let response = client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("double", TestData {
+                value: String::new(),
+                number: 1,
+            }))
+            .await?;
+        assert_eq!(response.into_result()?.doubled, 2);
+
+        // Mismatched request data is reported, not panicked on
+        let mismatched = client.send_request(SocketPayload::<&str, TestResponse>::new("double", "oops")).await?;
+        assert!(matches!(mismatched.into_result(), Err(SocketError::ServerError(_))));
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+
+        Ok(())
+    }
+
+    #[derive(Commands)]
+    enum Arithmetic {
+        /// Double a number
+        #[command(response = TestResponse)]
+        Double(TestData),
+        #[command(name = "negate_all", response = Vec<i32>)]
+        Negate(Vec<i32>),
+    }
+
+    struct Calculator;
+
+    impl ArithmeticHandler for Calculator {
+        fn double(&self, data: TestData) -> circle_socket::SocketResult<TestResponse> {
+            Ok(TestResponse {
+                result: data.value,
+                doubled: data.number * 2,
+            })
+        }
+
+        fn negate(&self, numbers: Vec<i32>) -> circle_socket::SocketResult<Vec<i32>> {
+            Ok(numbers.into_iter().map(|n| -n).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_derive_commands() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(Arithmetic::COMMAND_NAMES, ["double", "negate_all"]);
+        assert_eq!(Arithmetic::Negate(vec![]).command_name(), "negate_all");
+
+        let socket_path = PathBuf::from("/tmp/test_circle_derive_commands.sock");
+        let config = SocketConfig::from(&socket_path);
+
+        let server = SocketServer::new(config.clone());
+        Arithmetic::register(&server, Calculator).await;
+        assert_eq!(server.list_commands().await, ["double", "negate_all"]);
+
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let client = SocketClient::new(config);
+        let doubled = Arithmetic::Double(TestData {
+            value: "derived".to_string(),
+            number: 21,
+        })
+        .send(&client)
+        .await?;
+        assert!(matches!(doubled, ArithmeticResponse::Double(TestResponse { doubled: 42, .. })));
+
+        let negated = Arithmetic::Negate(vec![1, -2, 3]).send(&client).await?;
+        assert!(matches!(negated, ArithmeticResponse::Negate(numbers) if numbers == [-1, 2, -3]));
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "http-fallback")]
+#[tokio::test]
+async fn test_http_fallback() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A minimal HTTP backend: /rpc/double doubles the posted number, anything else fails
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let backend = format!("http://{}/rpc", listener.local_addr()?);
+    let http_handle = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            let (head, body) = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .to_ascii_lowercase()
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length:")?.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        break (head.to_string(), body.to_string());
+                    }
+                }
+            };
+            let (status, reply) = if head.starts_with("POST /rpc/double ") {
+                let data: TestData = serde_json::from_str(&body).unwrap();
+                let reply = TestResponse { result: data.value, doubled: data.number * 2 };
+                ("200 OK", serde_json::to_string(&reply).unwrap())
+            } else {
+                ("500 Internal Server Error", "backend exploded".to_string())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                reply.len(),
+                reply
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let socket_path = PathBuf::from("/tmp/test_circle_http_fallback.sock");
+    let config = SocketConfig {
+        http_fallback: Some(circle_socket::HttpFallback::new(backend)),
+        ..SocketConfig::from(&socket_path)
+    };
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = |command| {
+        SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: "forwarded".to_string(),
+            number: 21,
+        })
+    };
+
+    let response = client.send_request(payload("double")).await?.into_result()?;
+    assert_eq!(response.result, "forwarded");
+    assert_eq!(response.doubled, 42);
+
+    let error = client.send_request(payload("explode")).await?.error.unwrap();
+    assert!(error.contains("500"), "{error}");
+    assert!(error.contains("backend exploded"), "{error}");
+
+    server_handle.abort();
+    http_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_file_removed_on_drop() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_socket_file_drop.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config);
+    let server_handle = tokio::spawn(server.run());
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(socket_path.exists());
+
+    // Aborting drops the running server
+    server_handle.abort();
+    assert!(server_handle.await.unwrap_err().is_cancelled());
+    assert!(!socket_path.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_transport() -> Result<(), Box<dyn std::error::Error>> {
+    // Find a free port for the server
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = SocketConfig::tcp(addr);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config).with_client_name("remote");
+    for number in [1, 2] {
+        let payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: "over tcp".to_string(),
+            number,
+        });
+        let response = client.send_request(payload).await?.into_result()?;
+        assert_eq!(response.result, "over tcp");
+        assert_eq!(response.doubled, number * 2);
+    }
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_over_tcp() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::TlsConfig;
+
+    // Self-signed for `localhost`, valid until the year 4096
+    let cert_pem = include_str!("fixtures/localhost.crt");
+    let key_pem = include_str!("fixtures/localhost.key");
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server_config = SocketConfig {
+        tls: Some(TlsConfig::server(cert_pem.as_bytes(), key_pem.as_bytes())?),
+        ..SocketConfig::tcp(addr)
+    };
+    let client_config = SocketConfig {
+        tls: Some(TlsConfig::client(cert_pem.as_bytes(), "localhost")?),
+        ..SocketConfig::tcp(addr)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(server_config);
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let payload = || {
+        SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: "over tls".to_string(),
+            number: 21,
+        })
+    };
+    let response = SocketClient::new(client_config).send_request(payload()).await?.into_result()?;
+    assert_eq!(response.result, "over tls");
+    assert_eq!(response.doubled, 42);
+
+    // The server only speaks TLS
+    let plaintext = SocketClient::new(SocketConfig {
+        timeout: 1,
+        ..SocketConfig::tcp(addr)
+    });
+    assert!(plaintext.send_request(payload()).await.is_err());
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accept_codec_falls_back_to_default() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_accept_codec.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config.clone());
+    for codec in ["json", "bincode"] {
+        let mut payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: codec.to_string(),
+            number: 4,
+        });
+        payload.accept_codec = Some(codec.to_string());
+        let response = client.send_request(payload).await?.into_result()?;
+        assert_eq!(response.result, codec);
+        assert_eq!(response.doubled, 8);
+    }
+
+    // The unsupported codec is answered in plain JSON
+    let raw = testing::send_raw(
+        &config,
+        br#"{"request_id":"1","command":"double","data":{"value":"x","number":1},"accept_codec":"bincode"}"#,
+    )
+    .await?;
+    let response: serde_json::Value = serde_json::from_slice(&raw)?;
+    assert_eq!(response["data"]["doubled"], 2);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_listener() -> Result<(), Box<dyn std::error::Error>> {
+    let old_path = PathBuf::from("/tmp/test_circle_migrate_old.sock");
+    let new_path = PathBuf::from("/tmp/test_circle_migrate_new.sock");
+    let old_config = SocketConfig::from(&old_path);
+    let new_config = SocketConfig::from(&new_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(old_config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let echo = |value: &str| {
+        SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+            value: value.to_string(),
+            number: 1,
+        })
+    };
+    let endpoint = handle.add_listener(new_config.clone()).await?;
+    assert_eq!(endpoint, new_path.display().to_string());
+
+    // Both paths are served during the migration
+    let old_client = SocketClient::new(old_config);
+    let new_client = SocketClient::new(new_config);
+    assert_eq!(old_client.send_request(echo("old")).await?.into_result()?.result, "old");
+    assert_eq!(new_client.send_request(echo("new")).await?.into_result()?.result, "new");
+
+    // Retiring the old path leaves the new one working
+    assert!(handle.remove_listener(&old_path).await);
+    assert!(!handle.remove_listener(&old_path).await);
+    assert!(!old_path.exists());
+    assert!(old_client.send_request(echo("old")).await.is_err());
+    assert_eq!(new_client.send_request(echo("new")).await?.into_result()?.result, "new");
+
+    // Once the last endpoint is removed, run returns
+    assert!(handle.remove_listener(&new_path).await);
+    assert!(server_handle.await?.is_ok_and(|result| result.is_ok()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_reuses_socket() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_connection.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let connection = client.connect().await?;
+    let double = |number: i32| {
+        SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: number.to_string(),
+            number,
+        })
+    };
+    let (one, two, three) = tokio::join!(
+        connection.send(double(1)),
+        connection.send(double(2)),
+        connection.send(double(3))
+    );
+    for (response, number) in [(one?, 1), (two?, 2), (three?, 3)] {
+        let response = response.into_result()?;
+        assert_eq!(response.result, number.to_string());
+        assert_eq!(response.doubled, number * 2);
+    }
+
+    // All three went over the one connection
+    assert_eq!(handle.active_connections().len(), 1);
+    connection.close().await?;
+    sleep(Duration::from_millis(50)).await;
+    assert!(handle.active_connections().is_empty());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_pool() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketClientPool;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_pool.sock");
+    let config = SocketConfig {
+        pool_size: 4,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let pool = std::sync::Arc::new(SocketClientPool::new(SocketClient::new(config)));
+    let mut requests = tokio::task::JoinSet::new();
+    for number in 0..100 {
+        let pool = std::sync::Arc::clone(&pool);
+        requests.spawn(async move {
+            let payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+                value: number.to_string(),
+                number,
+            });
+            let response = pool.send_request(payload).await?.into_result()?;
+            assert_eq!(response.doubled, number * 2);
+            Ok::<_, circle_socket::SocketError>(())
+        });
+    }
+    while let Some(result) = requests.join_next().await {
+        result??;
+    }
+
+    // The pool never opened more than its size
+    let open = handle.active_connections().len();
+    assert!((1..=4).contains(&open), "{open} connections open");
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_type_fingerprint_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_type_fingerprint.sock");
+    let config = SocketConfig {
+        type_fingerprint: Some("0123456789abcdef".to_string()),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let payload = || SocketPayload::<TestData, TestResponse>::new("double", TestData {
+        value: "fingerprint".to_string(),
+        number: 4,
+    });
+
+    let response = SocketClient::new(config.clone()).send_request(payload()).await?;
+    assert_eq!(response.into_result()?.doubled, 8);
+
+    // A client built against other types is refused when connecting
+    let stale = SocketClient::new(SocketConfig {
+        type_fingerprint: Some("fedcba9876543210".to_string()),
+        ..config.clone()
+    });
+    match stale.send_request(payload()).await {
+        Err(SocketError::TypeMismatch { client, server }) => {
+            assert_eq!(client, "fedcba9876543210");
+            assert_eq!(server, "0123456789abcdef");
+        }
+        other => panic!("expected a type mismatch, got {:?}", other),
+    }
+
+    // Without a fingerprint nothing is checked
+    let unchecked = SocketClient::new(SocketConfig {
+        type_fingerprint: None,
+        ..config
+    });
+    assert_eq!(unchecked.send_request(payload()).await?.into_result()?.doubled, 8);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_admin_command() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::admin;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_metrics.sock");
+    let config = SocketConfig {
+        admin_commands: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            if payload.data.number < 0 {
+                return Err(circle_socket::SocketError::ServerError("negative".to_string()));
+            }
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    for number in [1, 2, -1] {
+        let payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: "metrics".to_string(),
+            number,
+        });
+        client.send_request(payload).await?;
+    }
+
+    let metrics = client
+        .send_request::<(), String>(SocketPayload::new(admin::METRICS_COMMAND, ()))
+        .await?
+        .into_result()?;
+    assert!(metrics.contains("# TYPE circle_requests_total counter\n"));
+    assert!(metrics.contains("circle_requests_total{command=\"double\"} 3\n"));
+    assert!(metrics.contains("circle_request_errors_total{command=\"double\"} 1\n"));
+    assert!(metrics.contains("circle_handler_duration_seconds_count{command=\"double\"} 3\n"));
+    assert!(metrics.contains("circle_connections_accepted_total 4\n"));
+    assert!(metrics.contains("circle_connections_active 1\n"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_until_server_starts() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::RetryConfig;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_retry.sock");
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let config = SocketConfig::from(&socket_path);
+
+    // The daemon comes up 200ms after the first attempt
+    let server_config = config.clone();
+    let server_handle = tokio::spawn(async move {
+        sleep(Duration::from_millis(200)).await;
+        let server = SocketServer::<TestData, TestResponse>::new(server_config);
+        server
+            .register_handler("double", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    let payload = || SocketPayload::<TestData, TestResponse>::new("double", TestData {
+        value: "retry".to_string(),
+        number: 21,
+    });
+
+    // Without retries the first attempt fails outright
+    assert!(SocketClient::new(config.clone()).send_request(payload()).await.is_err());
+
+    let client = SocketClient::new(config).with_retry(RetryConfig {
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+        ..RetryConfig::new(20)
+    });
+    let response = client.send_request(payload()).await?.into_result()?;
+    assert_eq!(response.doubled, 42);
+
+    // Error responses reached the server and are not retried
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("missing", TestData {
+            value: String::new(),
+            number: 0,
+        }))
+        .await?;
+    assert!(!response.success);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_request_with_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{RetryConfig, SocketError};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_request_timeout.sock");
+    let config = SocketConfig {
+        timeout: 1,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let builds = Arc::new(AtomicUsize::new(0));
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let counter = Arc::clone(&builds);
+    server
+        .register_async_handler("build", move |payload| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                sleep(Duration::from_millis(payload.data.number as u64)).await;
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            }
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(10), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let build = |millis: i32| SocketPayload::<TestData, TestResponse>::new("build", TestData {
+        value: "build".to_string(),
+        number: millis,
+    });
+    let client = SocketClient::new(config.clone());
+
+    // Longer than the config's one second
+    let result = client.send_request(build(1500)).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)));
+    let response = client.send_request_with_timeout(build(1500), Duration::from_secs(3)).await?;
+    assert_eq!(response.into_result()?.doubled, 3000);
+
+    // Shorter than the config's
+    let result = client.send_request_with_timeout(build(300), Duration::from_millis(100)).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)));
+
+    // A request that timed out after reaching the server is not sent again
+    sleep(Duration::from_millis(300)).await;
+    builds.store(0, Ordering::SeqCst);
+    let retrying = SocketClient::new(config).with_retry(RetryConfig {
+        initial_backoff: Duration::from_millis(20),
+        ..RetryConfig::new(3)
+    });
+    let result = retrying.send_request_with_timeout(build(500), Duration::from_millis(100)).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)));
+    assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+    // The timeout also bounds retrying a server that isn't there
+    let missing = SocketClient::new(SocketConfig::from(PathBuf::from("/tmp/test_circle_request_timeout_missing.sock")))
+        .with_retry(RetryConfig {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+            ..RetryConfig::new(100)
+        });
+    let started = std::time::Instant::now();
+    assert!(missing.send_request_with_timeout(build(0), Duration::from_millis(300)).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trailing_bytes() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::TrailingBytes;
+
+    let request = |id: &str, number: i32| {
+        format!(r#"{{"request_id":"{id}","command":"start","data":{{"value":"raw","number":{number}}}}}"#)
+    };
+    let responses = |bytes: &[u8]| -> Vec<SocketResponse<TestResponse>> {
+        serde_json::Deserializer::from_slice(bytes)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+
+    for (name, trailing_bytes) in [
+        ("ignore", TrailingBytes::Ignore),
+        ("reject", TrailingBytes::Reject),
+        ("next_frame", TrailingBytes::NextFrame),
+    ] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_trailing_{}.sock", name));
+        let config = SocketConfig {
+            trailing_bytes,
+            ..SocketConfig::from(&socket_path)
+        };
+        let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+        server
+            .register_handler("start", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        // A valid frame followed by garbage, and by a second request
+        let garbage = testing::send_raw(&config, format!("{} \x00garbage", request("a", 1)).as_bytes()).await?;
+        let second = testing::send_raw(&config, format!("{}\n{}", request("a", 1), request("b", 2)).as_bytes()).await?;
+        let padded = testing::send_raw(&config, format!("{}\n\n", request("a", 1)).as_bytes()).await?;
+        let (garbage, second, padded) = (responses(&garbage), responses(&second), responses(&padded));
+
+        match trailing_bytes {
+            TrailingBytes::Ignore => {
+                assert!(garbage.len() == 1 && garbage[0].success);
+                assert!(second.len() == 1 && second[0].request_id == "a");
+            }
+            TrailingBytes::Reject => {
+                assert_eq!(garbage.len(), 1);
+                assert!(garbage[0].error.as_deref().unwrap().starts_with("trailing_bytes"));
+                assert!(second.len() == 1 && !second[0].success);
+            }
+            TrailingBytes::NextFrame => {
+                // The garbage is answered with an error once the first request is, then closes the connection
+                assert!(garbage.len() == 2 && garbage[0].success);
+                assert!(garbage[1].error.as_deref().unwrap().starts_with("Invalid request format"));
+                let ids: Vec<_> = second.iter().map(|r| r.request_id.as_str()).collect();
+                assert_eq!(ids, ["a", "b"]);
+                assert_eq!(second[1].data.as_ref().unwrap().doubled, 4);
+            }
+        }
+        // Trailing whitespace is never more data
+        assert!(padded.len() == 1 && padded[0].success);
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ndjson_framing() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{Framing, TrailingBytes};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_ndjson.sock");
+    let config = SocketConfig {
+        framing: Framing::NdJson,
+        trailing_bytes: TrailingBytes::NextFrame,
+        ..SocketConfig::from(&socket_path)
+    };
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // As piped in from a shell: one request per line
+    let requests = concat!(
+        r#"{"request_id":"a","command":"start","data":{"value":"one","number":1}}"#,
+        "\n",
+        r#"{"request_id":"b","command":"start","data":{"value":"two","number":2}}"#,
+        "\n",
+    );
+    let output = testing::send_raw(&config, requests.as_bytes()).await?;
+    let output = String::from_utf8(output)?;
+    assert!(output.ends_with('\n'));
+    let responses = output
+        .lines()
+        .map(serde_json::from_str::<SocketResponse<TestResponse>>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let ids: Vec<_> = responses.iter().map(|r| r.request_id.as_str()).collect();
+    assert_eq!(ids, ["a", "b"]);
+    assert_eq!(responses[1].data.as_ref().unwrap().doubled, 4);
+
+    // The client speaks it too
+    let payload = SocketPayload::<TestData, TestResponse>::new("start", TestData {
+        value: "typed".to_string(),
+        number: 3,
+    });
+    assert_eq!(SocketClient::new(config).send_request(payload).await?.into_result()?.doubled, 6);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::RateLimit;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_rate_limit.sock");
+    let config = SocketConfig {
+        rate_limit: Some(RateLimit::new(2, 5.0)),
+        ..SocketConfig::from(&socket_path)
+    };
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let double = || {
+        SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: "limited".to_string(),
+            number: 2,
+        })
+    };
+
+    // The burst goes through, each request on a connection of its own
+    for _ in 0..2 {
+        assert_eq!(client.send_request(double()).await?.into_result()?.doubled, 4);
+    }
+    let limited = client.send_request(double()).await?;
+    assert!(limited.error.unwrap().starts_with("rate_limited"));
+
+    // A token is back after a fifth of a second
+    sleep(Duration::from_millis(250)).await;
+    assert_eq!(client.send_request(double()).await?.into_result()?.doubled, 4);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pause_and_resume_accepting() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_pause.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let request = |number: i32| {
+        let client = SocketClient::new(config.clone());
+        async move {
+            let payload = SocketPayload::<TestData, TestResponse>::new("double", TestData {
+                value: "paused".to_string(),
+                number,
+            });
+            client.send_request(payload).await?.into_result()
+        }
+    };
+
+    handle.pause();
+    assert!(handle.is_paused());
+    let queued = tokio::spawn(request(5));
+
+    // The client waits in the backlog without being accepted
+    sleep(Duration::from_millis(300)).await;
+    assert!(!queued.is_finished());
+    assert!(socket_path.exists());
+    assert!(handle.active_connections().is_empty());
+
+    handle.resume();
+    assert_eq!(queued.await??.doubled, 10);
+    assert_eq!(request(6).await?.doubled, 12);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drain_finishes_inflight_requests() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_drain.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_async_handler("slow", |payload| async move {
+            sleep(Duration::from_millis(300)).await;
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let slow = tokio::spawn(async move {
+        let payload = SocketPayload::<TestData, TestResponse>::new("slow", TestData {
+            value: "draining".to_string(),
+            number: 4,
+        });
+        client.send_request(payload).await?.into_result()
+    });
+    // A connection that never sends anything is still open when draining ends
+    let _idle = tokio::net::UnixStream::connect(&socket_path).await?;
+    sleep(Duration::from_millis(100)).await;
+
+    let cancelled = handle.drain(Duration::from_secs(1)).await;
+    assert_eq!(cancelled, 1);
+    assert!(handle.is_paused());
+    assert_eq!(slow.await??.doubled, 8);
+    assert!(handle.active_connections().is_empty());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_timeout_covers_whole_response() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_dribble.sock");
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let config = SocketConfig {
+        timeout: 1,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    // A server that sends its response one byte every 50ms, so every read
+    // returns well within the timeout but the whole response takes seconds
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    let server_handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await?;
+        let response = br#"{"request_id":"slow","success":true,"data":{"result":"slow","doubled":2},"error":null}"#;
+        for byte in response {
+            stream.write_all(&[*byte]).await?;
+            sleep(Duration::from_millis(50)).await;
+        }
+        Ok::<_, std::io::Error>(())
+    });
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::<TestData, TestResponse>::new("slow", TestData {
+        value: "slow".to_string(),
+        number: 1,
+    });
+    let started = std::time::Instant::now();
+    let result = client.send_request(payload).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)), "{:?}", result);
+    assert!(started.elapsed() < Duration::from_millis(1500), "gave up after {:?}", started.elapsed());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_send_is_bounded_by_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_stalled_reader.sock");
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+    let config = SocketConfig {
+        timeout: 1,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    // A server that accepts connections but never reads from them
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    let server_handle = tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+
+    // Far more than the socket buffers take before the server reads
+    let payload = || SocketPayload::<TestData, TestResponse>::new("double", TestData {
+        value: "x".repeat(8 * 1024 * 1024),
+        number: 1,
+    });
+    let client = SocketClient::new(config);
+
+    let started = std::time::Instant::now();
+    let result = client.send_batch_streaming(vec![payload()]).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)), "{:?}", result.err());
+    assert!(started.elapsed() < Duration::from_millis(1500), "gave up after {:?}", started.elapsed());
+
+    let started = std::time::Instant::now();
+    let result = client.send_request_streaming(payload()).await;
+    assert!(matches!(result, Err(SocketError::ConnectionTimeout)), "{:?}", result.err());
+    assert!(started.elapsed() < Duration::from_millis(1500), "gave up after {:?}", started.elapsed());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn test_codec_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Codec;
+
+    for codec in [Codec::Json, Codec::MessagePack] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_codec_{}.sock", codec.name()));
+        let config = SocketConfig {
+            codec,
+            ..SocketConfig::from(&socket_path)
+        };
+
+        let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+        server
+            .register_handler("double", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let payload = |number: i32| SocketPayload::<TestData, TestResponse>::new("double", TestData {
+            value: format!("{} \u{e9}", codec.name()),
+            number,
+        });
+        let client = SocketClient::new(config.clone()).with_client_name("codec");
+
+        let response = client.send_request(payload(3)).await?.into_result()?;
+        assert_eq!(response.result, format!("{} \u{e9}", codec.name()));
+        assert_eq!(response.doubled, 6);
+
+        let batch = client.send_batch_streaming(vec![payload(1), payload(2)]).await?.collect().await?;
+        assert_eq!(batch.len(), 2);
+
+        let connection = client.connect().await?;
+        assert_eq!(connection.send(payload(4)).await?.into_result()?.doubled, 8);
+        connection.close().await?;
+
+        // The server answers in its configured codec
+        let raw = br#"{"request_id":"raw","command":"double","data":{"value":"raw","number":1}}"#;
+        let response = testing::send_raw(&config, raw).await?;
+        match codec {
+            Codec::Json => assert_eq!(response[0], b'{'),
+            Codec::MessagePack => assert!((0x80..=0x8f).contains(&response[0])),
+        }
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn test_codec_non_string_map_keys() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Codec;
+    use std::collections::HashMap;
+
+    type Names = HashMap<u32, String>;
+    for codec in [Codec::Json, Codec::MessagePack] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_codec_keys_{}.sock", codec.name()));
+        let config = SocketConfig {
+            codec,
+            ..SocketConfig::from(&socket_path)
+        };
+
+        let server = SocketServer::<Names, Names>::new(config.clone());
+        server
+            .register_handler("echo", |payload| Ok(SocketResponse::success(payload.request_id, payload.data)))
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let names: Names = [(1, "init".to_string()), (42, "web".to_string())].into();
+        let client = SocketClient::new(config);
+        let response = client.send_request(SocketPayload::<Names, Names>::new("echo", names.clone())).await?;
+        assert_eq!(response.into_result()?, names, "{}", codec.name());
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotency_key_runs_handler_once() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_idempotency.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let handled = Arc::new(AtomicUsize::new(0));
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let counter = Arc::clone(&handled);
+    server
+        .register_async_handler("charge", move |payload| {
+            let number = counter.fetch_add(1, Ordering::SeqCst) as i32;
+            async move {
+                sleep(Duration::from_millis(200)).await;
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: number,
+                }))
+            }
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |key: &str| {
+        SocketPayload::<TestData, TestResponse>::new("charge", TestData {
+            value: "order-1".to_string(),
+            number: 10,
+        })
+        .with_idempotency_key(key)
+    };
+
+    // A duplicate arriving while the first request runs is refused
+    let (first, duplicate) = tokio::join!(client.send_request(request("order-1")), async {
+        sleep(Duration::from_millis(50)).await;
+        client.send_request(request("order-1")).await
+    });
+    assert_eq!(first?.into_result()?.doubled, 0);
+    assert!(duplicate?.error.unwrap().starts_with("duplicate_in_progress"));
+
+    // Retries replay the first response under their own request IDs
+    for _ in 0..2 {
+        let retry = request("order-1");
+        let request_id = retry.request_id.clone();
+        let response = client.send_request(retry).await?;
+        assert_eq!(response.request_id, request_id);
+        assert_eq!(response.into_result()?.doubled, 0);
+    }
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+
+    // Another key runs the handler again
+    let response = client.send_request(request("order-2")).await?.into_result()?;
+    assert_eq!(response.doubled, 1);
+    assert_eq!(handled.load(Ordering::SeqCst), 2);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compression_shrinks_large_response() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Compression;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // About 100KB of the kind of process listing a daemon sends back
+    let listing: String = (0..5000).map(|i| format!("proc-{:05} running\n", i)).collect();
+    let request = || {
+        SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+            value: listing.clone(),
+            number: 1,
+        })
+    };
+
+    let mut wire_sizes = Vec::new();
+    for (name, compression) in [("plain", Compression::None), ("gzip", Compression::Gzip)] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_compression_{}.sock", name));
+        let config = SocketConfig {
+            compression,
+            ..SocketConfig::from(&socket_path)
+        };
+
+        let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+        server
+            .register_handler("echo", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        // What crosses the socket for an uncompressed request
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+        stream.write_all(&serde_json::to_vec(&request())?).await?;
+        stream.shutdown().await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        wire_sizes.push(response.len());
+
+        // The client compresses its request the same way and reads either kind of response
+        let response = SocketClient::new(config).send_request(request()).await?.into_result()?;
+        assert_eq!(response.result, listing);
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    assert!(wire_sizes[0] > 100_000);
+    assert!(wire_sizes[1] < wire_sizes[0] / 4, "compressed {} of {} bytes", wire_sizes[1], wire_sizes[0]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_request_limits() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::Compression;
+    use std::io::{Read, Write};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let gzip = |body: &[u8]| -> std::io::Result<Vec<u8>> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    };
+    let request = serde_json::to_vec(&SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "hello".to_string(),
+        number: 1,
+    }))?;
+    // Inflates to 64 MiB, far past the 1 MiB limit
+    let bomb = gzip(&vec![b' '; 64 * 1024 * 1024])?;
+
+    for (name, compression) in [("plain", Compression::None), ("gzip", Compression::Gzip)] {
+        let socket_path = PathBuf::from(format!("/tmp/test_circle_compressed_request_{}.sock", name));
+        let server = SocketServer::<TestData, TestResponse>::new(SocketConfig {
+            compression,
+            max_message_size: 1024 * 1024,
+            ..SocketConfig::from(&socket_path)
+        });
+        server
+            .register_handler("echo", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        let exchange = |body: Vec<u8>| {
+            let socket_path = socket_path.clone();
+            async move {
+                let mut stream = tokio::net::UnixStream::connect(&socket_path).await?;
+                stream.write_all(&body).await?;
+                stream.shutdown().await?;
+                let mut response = Vec::new();
+                stream.read_to_end(&mut response).await?;
+                // The gzip server compresses its responses too
+                if response.starts_with(&[0x1f, 0x8b]) {
+                    let mut decoded = Vec::new();
+                    flate2::read::GzDecoder::new(response.as_slice()).read_to_end(&mut decoded)?;
+                    response = decoded;
+                }
+                Ok::<_, std::io::Error>(response)
+            }
+        };
+
+        // Only a server with compression configured accepts a compressed request
+        let response = exchange(gzip(&request)?).await?;
+        let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+        assert_eq!(response.success, compression != Compression::None, "{:?}", response);
+
+        let response = exchange(bomb.clone()).await?;
+        let response: SocketResponse<TestResponse> = serde_json::from_slice(&response)?;
+        assert!(!response.success);
+        if compression == Compression::None {
+            assert!(response.error.unwrap().contains("compression is not enabled"));
+        } else {
+            assert!(response.error.unwrap().starts_with("message_too_large"));
+        }
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_middleware_and_post_hooks() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+    use std::sync::{Arc, Mutex};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_middleware_hooks.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .add_middleware(|payload| {
+            if payload.data.value.is_empty() {
+                return Err(SocketError::ServerError("missing field: value".to_string()));
+            }
+            Ok(())
+        })
+        .await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&seen);
+    server
+        .add_post_hook(move |context, response| {
+            log.lock().unwrap().push((context.command.clone(), response.success));
+        })
+        .await;
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |command: &str, value: &str| {
+        SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: value.to_string(),
+            number: 4,
+        })
+    };
+
+    let response = client.send_request(request("echo", "present")).await?.into_result()?;
+    assert_eq!(response.doubled, 8);
+
+    let rejected = client.send_request(request("echo", "")).await?;
+    assert!(rejected.error.unwrap().contains("missing field: value"));
+
+    let missing = client.send_request(request("unknown", "present")).await?;
+    assert!(!missing.success);
+
+    // The rejected request never reached the handler or the hooks
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![("echo".to_string(), true), ("unknown".to_string(), false)]
+    );
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_metrics_count_requests() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_server_metrics.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("double", |payload| {
+            if payload.data.number < 0 {
+                return Err(SocketError::ServerError("negative number".to_string()));
+            }
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let handle = server.handle();
+    assert_eq!(server.metrics().total_requests, 0);
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    for number in [1, 2, 3, -1, -2] {
+        client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("double", TestData {
+                value: "n".to_string(),
+                number,
+            }))
+            .await?;
+    }
+    client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("missing", TestData {
+            value: String::new(),
+            number: 0,
+        }))
+        .await?;
+
+    let metrics = handle.metrics();
+    assert_eq!(metrics.total_requests, 6);
+    let double = &metrics.commands["double"];
+    assert_eq!((double.requests, double.successes, double.errors), (5, 3, 2));
+    assert_eq!(double.latency.count, 5);
+    assert_eq!(double.latency.buckets.last().unwrap().1, 5);
+    assert_eq!(metrics.commands["missing"].errors, 1);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_spans() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::{Arc, Mutex};
+
+    /// Collects formatted log lines
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The test runtime is single-threaded, so server tasks log here too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let socket_path = PathBuf::from("/tmp/test_circle_request_spans.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            tracing::info!("handling echo");
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let payload = SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+        value: "traced".to_string(),
+        number: 1,
+    });
+    let request_id = payload.request_id.clone();
+    SocketClient::new(config).send_request(payload).await?;
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone())?;
+    let server_span = format!("request{{request_id={} command=echo}}: ", request_id);
+    assert!(
+        logs.lines().any(|line| line.contains(&server_span) && line.ends_with("handling echo")),
+        "{}",
+        logs
+    );
+    let client_span = format!("send_request{{request_id={} command=echo}}: ", request_id);
+    assert!(logs.lines().any(|line| line.contains(&client_span) && line.contains("Received response")));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_mode() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_socket_mode.sock");
+    for mode in [0o600, 0o660] {
+        let config = SocketConfig {
+            socket_mode: Some(mode),
+            ..SocketConfig::from(&socket_path)
+        };
+        let server = SocketServer::<TestData, TestResponse>::new(config);
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(std::fs::metadata(&socket_path)?.permissions().mode() & 0o777, mode);
+
+        server_handle.abort();
+        let _ = server_handle.await;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_builder() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_server_builder.sock");
+    let server = SocketServer::<TestData, TestResponse>::builder()
+        .socket_path(&socket_path)
+        .timeout(5)
+        .socket_mode(0o600)
+        .middleware(|payload| match payload.data.number {
+            n if n < 0 => Err(SocketError::ServerError("negative number".to_string())),
+            _ => Ok(()),
+        })
+        .handler("double", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .async_handler("triple", |payload| async move {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 3,
+            }))
+        })
+        .build();
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(std::fs::metadata(&socket_path)?.permissions().mode() & 0o777, 0o600);
+    let client = SocketClient::new(SocketConfig::from(&socket_path));
+    let request = |command: &str, number: i32| {
+        SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: "built".to_string(),
+            number,
+        })
+    };
+    assert_eq!(client.send_request(request("double", 7)).await?.into_result()?.doubled, 14);
+    assert_eq!(client.send_request(request("triple", 7)).await?.into_result()?.doubled, 21);
+    let rejected = client.send_request(request("double", -1)).await?;
+    assert!(rejected.error.unwrap().contains("negative number"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unregister_handler() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_unregister.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    for command in ["plugin_a", "plugin_b"] {
+        server
+            .register_handler(command, |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+    }
+    assert_eq!(server.list_commands().await, vec!["plugin_a", "plugin_b"]);
+
+    assert!(server.unregister_handler("plugin_a").await);
+    assert!(!server.unregister_handler("plugin_a").await);
+    assert_eq!(server.list_commands().await, vec!["plugin_b"]);
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |command: &str| {
+        SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: "plugin".to_string(),
+            number: 1,
+        })
+    };
+    let removed = client.send_request(request("plugin_a")).await?;
+    assert!(removed.error.unwrap().contains("No handler for command: plugin_a"));
+    assert!(client.send_request(request("plugin_b")).await?.success);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_with_state() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Counter {
+        total: AtomicI32,
+    }
+
+    let socket_path = PathBuf::from("/tmp/test_circle_handler_state.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let counter = Arc::new(Counter::default());
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler_with_state("add", Arc::clone(&counter), |counter, payload| {
+            let total = counter.total.fetch_add(payload.data.number, Ordering::SeqCst) + payload.data.number;
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: total,
+            }))
+        })
+        .await;
+
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    for (number, total) in [(1, 1), (2, 3), (3, 6)] {
+        let response = client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("add", TestData {
+                value: "count".to_string(),
+                number,
+            }))
+            .await?
+            .into_result()?;
+        assert_eq!(response.doubled, total);
+    }
+    assert_eq!(counter.total.load(Ordering::SeqCst), 6);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_request_explains_parse_error() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_invalid_request.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let send_raw = |raw: &'static str| {
+        let config = config.clone();
+        async move {
+            let response = testing::send_raw(&config, raw.as_bytes()).await?;
+            Ok::<_, Box<dyn std::error::Error>>(serde_json::from_slice::<SocketResponse<TestResponse>>(&response)?)
+        }
+    };
+
+    // A well-formed header whose data doesn't match the handler's type
+    let response = send_raw(r#"{"request_id":"bad-data","command":"start","data":{"value":"x","number":"two"}}"#).await?;
+    assert!(!response.success);
+    assert_eq!(response.request_id, "bad-data");
+    let error = response.error.unwrap();
+    assert!(error.contains("Invalid request format"), "{}", error);
+    assert!(error.contains("invalid type"), "{}", error);
+
+    // JSON that ends part way through
+    let response = send_raw(r#"{"request_id":"cut-off","command":"start","data":{"#).await?;
+    assert!(!response.success);
+    assert!(response.error.unwrap().contains("EOF while parsing"));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_codes() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_error_codes.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("lookup", |payload| {
+            if payload.data.number < 0 {
+                return Ok(SocketResponse::error(payload.request_id, "negative numbers are not allowed"));
+            }
+            Ok(SocketResponse::error_with_code(
+                payload.request_id,
+                "not_found",
+                format!("no entry named {}", payload.data.value),
+            ))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |number| {
+        SocketPayload::<TestData, TestResponse>::new("lookup", TestData {
+            value: "web".to_string(),
+            number,
+        })
+    };
+    let response = client.send_request(request(1)).await?;
+    assert_eq!(response.code.as_deref(), Some("not_found"));
+    assert_eq!(response.error.as_deref(), Some("no entry named web"));
+
+    let response = client.send_request(request(-1)).await?;
+    assert!(response.code.is_none());
+    assert!(response.error.is_some());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_abstract_socket() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let name = format!("@test_circle_abstract_{}", std::process::id());
+    let config = SocketConfig::from(name.as_str());
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // No file appears for an abstract name
+    assert!(!PathBuf::from(&name).exists());
+
+    let client = SocketClient::new(config.clone());
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("start", TestData {
+            value: "abstract".to_string(),
+            number: 4,
+        }))
+        .await?;
+    assert_eq!(response.data.unwrap().doubled, 8);
+
+    // The name stays taken while the server runs
+    let second = SocketServer::<TestData, TestResponse>::new(config);
+    let result = tokio::time::timeout(Duration::from_secs(1), second.run()).await?;
+    assert!(matches!(result, Err(SocketError::AlreadyExists(_))));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_response_for_another_request() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_response_mismatch.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("confused", |payload| {
+            Ok(SocketResponse::success("someone-else", TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let payload = SocketPayload::<TestData, TestResponse>::with_request_id("confused", "mine", TestData {
+        value: "lost".to_string(),
+        number: 1,
+    });
+    match client.send_request(payload).await {
+        Err(SocketError::ResponseMismatch { expected, got }) => {
+            assert_eq!(expected, "mine");
+            assert_eq!(got, "someone-else");
+        }
+        other => panic!("expected a response mismatch, got {:?}", other),
+    }
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ack_before_slow_handler() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_ack.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, ()>::new(config.clone());
+    let finished = Arc::new(AtomicBool::new(false));
+    let done = Arc::clone(&finished);
+    server
+        .register_async_handler("slow", move |payload| {
+            let done = Arc::clone(&done);
+            async move {
+                sleep(Duration::from_millis(500)).await;
+                done.store(true, Ordering::SeqCst);
+                Ok(SocketResponse::success(payload.request_id, ()))
+            }
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let sent = Instant::now();
+    client
+        .send_request_with_ack(SocketPayload::new("slow", TestData {
+            value: "queued".to_string(),
+            number: 1,
+        }))
+        .await?;
+    assert!(sent.elapsed() < Duration::from_millis(400));
+    assert!(!finished.load(Ordering::SeqCst));
+
+    // The handler keeps running after the client has left
+    sleep(Duration::from_millis(700)).await;
+    assert!(finished.load(Ordering::SeqCst));
+
+    // Requests that don't parse are refused instead of acknowledged
+    let unparsable = client.send_request_with_ack(SocketPayload::new("slow", "not test data")).await;
+    assert!(matches!(unparsable, Err(circle_socket::SocketError::ServerError(_))));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_trait() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{async_trait, Handler, SocketResult};
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// Adds every request's number to a running total
+    struct Accumulator {
+        label: String,
+        total: AtomicI32,
+    }
+
+    #[async_trait]
+    impl Handler<TestData, TestResponse> for Accumulator {
+        async fn handle(
+            &self,
+            payload: SocketPayload<TestData, TestResponse>,
+        ) -> SocketResult<SocketResponse<TestResponse>> {
+            let total = self.total.fetch_add(payload.data.number, Ordering::SeqCst) + payload.data.number;
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: format!("{} {}", self.label, payload.data.value),
+                doubled: total,
+            }))
+        }
+    }
+
+    let accumulator = Accumulator {
+        label: "sum".to_string(),
+        total: AtomicI32::new(0),
+    };
+    // Called directly, without a server
+    let direct = accumulator
+        .handle(SocketPayload::new("add", TestData {
+            value: "direct".to_string(),
+            number: 5,
+        }))
+        .await?;
+    assert_eq!(direct.into_result()?.doubled, 5);
+
+    let socket_path = PathBuf::from("/tmp/test_circle_handler_trait.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server.register_handler_trait("add", accumulator).await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("add", TestData {
+            value: "served".to_string(),
+            number: 3,
+        }))
+        .await?
+        .into_result()?;
+    assert_eq!(response.result, "sum served");
+    assert_eq!(response.doubled, 8);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_request_ids() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_request_ids.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config.clone());
+    let payload = SocketPayload::<TestData, TestResponse>::with_request_id("echo", "trace-4bf92f35", TestData {
+        value: "traced".to_string(),
+        number: 1,
+    });
+    let response = client.send_request(payload).await?;
+    assert_eq!(response.request_id, "trace-4bf92f35");
+    assert!(response.success);
+
+    let counter = AtomicU64::new(0);
+    let client = SocketClient::new(config)
+        .with_request_id_generator(move || format!("req-{}", counter.fetch_add(1, Ordering::SeqCst)));
+    for expected in ["req-0", "req-1"] {
+        let payload = client.payload::<TestData, TestResponse>("echo", TestData {
+            value: "counted".to_string(),
+            number: 2,
+        });
+        assert_eq!(payload.request_id, expected);
+        assert_eq!(client.send_request(payload).await?.request_id, expected);
+    }
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_worker_pool_bounds_concurrency() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::WorkerPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_worker_pool.sock");
+    let config = SocketConfig {
+        worker_pool: Some(WorkerPool::new(2, 2)),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let running = Arc::new(AtomicUsize::new(0));
+    let most_running = Arc::new(AtomicUsize::new(0));
+    {
+        let (running, most_running) = (Arc::clone(&running), Arc::clone(&most_running));
+        server
+            .register_async_handler("work", move |payload| {
+                let (running, most_running) = (Arc::clone(&running), Arc::clone(&most_running));
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    sleep(Duration::from_millis(200)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(SocketResponse::success(payload.request_id, TestResponse {
+                        result: payload.data.value,
+                        doubled: payload.data.number * 2,
+                    }))
+                }
+            })
+            .await;
+    }
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let burst: Vec<_> = (0..8)
+        .map(|number| {
+            let client = SocketClient::new(config.clone());
+            tokio::spawn(async move {
+                let payload = SocketPayload::<TestData, TestResponse>::new("work", TestData {
+                    value: "burst".to_string(),
+                    number,
+                });
+                client.send_request(payload).await?.into_result()
+            })
+        })
+        .collect();
+    sleep(Duration::from_millis(100)).await;
+    // Two connections are being served and two more wait for a worker
+    assert_eq!(handle.queued_connections(), 2);
+
+    for (number, request) in burst.into_iter().enumerate() {
+        assert_eq!(request.await??.doubled, number as i32 * 2);
+    }
+    assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    assert_eq!(handle.queued_connections(), 0);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_connections() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::ConnectionLimitPolicy;
+
+    for policy in [ConnectionLimitPolicy::Wait, ConnectionLimitPolicy::Reject] {
+        let socket_path = PathBuf::from("/tmp/test_circle_max_connections.sock");
+        let config = SocketConfig {
+            max_connections: Some(2),
+            connection_limit_policy: policy,
+            ..SocketConfig::from(&socket_path)
+        };
+
+        let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+        server
+            .register_handler("start", |payload| {
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+        let server_handle = tokio::spawn(async move {
+            tokio::time::timeout(Duration::from_secs(5), server.run()).await
+        });
+
+        sleep(Duration::from_millis(100)).await;
+
+        // Two idle connections take both slots
+        let first = tokio::net::UnixStream::connect(&socket_path).await?;
+        let _second = tokio::net::UnixStream::connect(&socket_path).await?;
+        sleep(Duration::from_millis(100)).await;
+
+        let client = SocketClient::new(config);
+        let mut third = tokio::spawn(async move {
+            client
+                .send_request(SocketPayload::<TestData, TestResponse>::new("start", TestData {
+                    value: "third".to_string(),
+                    number: 3,
+                }))
+                .await
+        });
+
+        match policy {
+            ConnectionLimitPolicy::Wait => {
+                assert!(tokio::time::timeout(Duration::from_millis(300), &mut third).await.is_err());
+                // Closing a connection frees its slot for the waiting one
+                drop(first);
+                let response = tokio::time::timeout(Duration::from_secs(2), third).await???;
+                assert_eq!(response.data.unwrap().doubled, 6);
+            }
+            ConnectionLimitPolicy::Reject => {
+                let response = tokio::time::timeout(Duration::from_secs(2), third).await???;
+                assert!(response.error.unwrap().starts_with("too_many_connections"));
+            }
+        }
+
+        server_handle.abort();
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_handler_timeout.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler_with_timeout("sleepy", Duration::from_millis(200), |payload| {
+            std::thread::sleep(Duration::from_millis(payload.data.number as u64));
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |millis| {
+        SocketPayload::<TestData, TestResponse>::new("sleepy", TestData {
+            value: "nap".to_string(),
+            number: millis,
+        })
+    };
+
+    let started = std::time::Instant::now();
+    let response = client.send_request(request(2000)).await?;
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert!(response.error.unwrap().starts_with("handler_timeout"));
+
+    let response = client.send_request(request(10)).await?;
+    assert_eq!(response.data.unwrap().doubled, 20);
+
+    // A timed-out request leaves its idempotency key free for the retry
+    let response = client.send_request(request(2000).with_idempotency_key("nap-1")).await?;
+    assert_eq!(response.code.as_deref(), Some("handler_timeout"));
+    let response = client.send_request(request(10).with_idempotency_key("nap-1")).await?;
+    assert_eq!(response.data.unwrap().doubled, 20);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_disconnect_cancels_handler() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_cancellation.sock");
+    let config = SocketConfig {
+        blocking_handlers: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let observed = Arc::new(AtomicBool::new(false));
+    let handler_observed = Arc::clone(&observed);
+    server
+        .register_context_handler("expensive", move |payload, context| {
+            let started = std::time::Instant::now();
+            while !context.cancellation.is_cancelled() && started.elapsed() < Duration::from_secs(3) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            handler_observed.store(context.cancellation.is_cancelled(), Ordering::SeqCst);
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+
+    // Dropped with the future, once the client disconnects
+    struct Finished(Arc<AtomicBool>);
+    impl Drop for Finished {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+    let dropped = Arc::new(AtomicBool::new(false));
+    let handler_dropped = Arc::clone(&dropped);
+    server
+        .register_async_handler("expensive_async", move |payload| {
+            let finished = Finished(Arc::clone(&handler_dropped));
+            async move {
+                sleep(Duration::from_secs(3)).await;
+                drop(finished);
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            }
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    for (command, flag) in [("expensive", &observed), ("expensive_async", &dropped)] {
+        let payload = SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: "abandoned".to_string(),
+            number: 1,
+        });
+        // Like a client interrupted with Ctrl-C, giving up without waiting for the response
+        let abandoned = tokio::time::timeout(Duration::from_millis(200), client.send_request(payload)).await;
+        assert!(abandoned.is_err());
+
+        let started = std::time::Instant::now();
+        while !flag.load(Ordering::SeqCst) && started.elapsed() < Duration::from_secs(1) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(flag.load(Ordering::SeqCst), "{} was not cancelled", command);
+    }
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blocking_handlers_run_concurrently() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_blocking_handlers.sock");
+    let config = SocketConfig {
+        blocking_handlers: true,
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    for command in ["slow_a", "slow_b"] {
+        server
+            .register_handler(command, |payload| {
+                // Would block this test's single-threaded runtime if run inline
+                std::thread::sleep(Duration::from_millis(400));
+                Ok(SocketResponse::success(payload.request_id, TestResponse {
+                    result: payload.data.value,
+                    doubled: payload.data.number * 2,
+                }))
+            })
+            .await;
+    }
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let request = |command: &str| {
+        SocketPayload::<TestData, TestResponse>::new(command, TestData {
+            value: command.to_string(),
+            number: 1,
+        })
+    };
+    let started = std::time::Instant::now();
+    let (a, b) = tokio::join!(client.send_request(request("slow_a")), client.send_request(request("slow_b")));
+    assert!(a?.success && b?.success);
+    assert!(started.elapsed() < Duration::from_millis(750), "handlers ran one after the other");
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_credentials() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let socket_path = PathBuf::from("/tmp/test_circle_peer_credentials.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_context_handler("whoami", |payload, context| {
+            let peer = context.peer_credentials.expect("Unix sockets report their peer");
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: format!("{} {:?}", peer.uid, peer.pid),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // The socket file belongs to this process's user, which is also the client
+    let uid = std::fs::metadata(&socket_path)?.uid();
+    let client = SocketClient::new(config);
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("whoami", TestData {
+            value: String::new(),
+            number: 1,
+        }))
+        .await?
+        .into_result()?;
+    assert!(response.result.starts_with(&format!("{} ", uid)));
+    if cfg!(target_os = "linux") {
+        assert!(response.result.ends_with(&format!("Some({})", std::process::id())));
+    }
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_callbacks() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::SocketError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_connection_callbacks.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            if payload.data.number < 0 {
+                return Err(SocketError::ServerError("negative".to_string()));
+            }
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let connected = Arc::new(AtomicUsize::new(0));
+    let disconnected = Arc::new(Mutex::new(Vec::new()));
+    let counter = Arc::clone(&connected);
+    server
+        .on_connect(move |info| {
+            assert!(info.client_name.is_none());
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .await;
+    let ids = Arc::clone(&disconnected);
+    server.on_disconnect(move |info| ids.lock().unwrap().push(info.id)).await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config.clone());
+    for number in [1, -1, 2] {
+        client
+            .send_request(SocketPayload::<TestData, TestResponse>::new("start", TestData {
+                value: "conn".to_string(),
+                number,
+            }))
+            .await?;
+    }
+    // A peer that hangs up without sending anything
+    drop(tokio::net::UnixStream::connect(&socket_path).await?);
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(connected.load(Ordering::SeqCst), 4);
+    let mut ids = disconnected.lock().unwrap().clone();
+    ids.sort();
+    assert_eq!(ids, [1, 2, 3, 4]);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idle_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_idle_timeout.sock");
+    let config = SocketConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let idle = client.connect().await?;
+    let active = client.connect().await?;
+    for number in 0..6 {
+        sleep(Duration::from_millis(100)).await;
+        let payload = SocketPayload::<TestData, TestResponse>::new("start", TestData {
+            value: "active".to_string(),
+            number,
+        });
+        assert_eq!(active.send(payload).await?.data.unwrap().doubled, number * 2);
+    }
+
+    assert!(idle.is_closed());
+    assert!(!active.is_closed());
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auth_token() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_auth_token.sock");
+    let config = SocketConfig {
+        auth_token: Some("s3cret".to_string()),
+        ..SocketConfig::from(&socket_path)
+    };
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value,
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let request = || {
+        SocketPayload::<TestData, TestResponse>::new("start", TestData {
+            value: "auth".to_string(),
+            number: 2,
+        })
+    };
+
+    let response = SocketClient::new(config.clone()).send_request(request()).await?;
+    assert_eq!(response.data.unwrap().doubled, 4);
+    let connection = SocketClient::new(config.clone()).connect().await?;
+    assert!(connection.send(request()).await?.success);
+
+    for token in [Some("wrong".to_string()), None] {
+        let client = SocketClient::new(SocketConfig {
+            auth_token: token,
+            ..config.clone()
+        });
+        let response = client.send_request(request()).await?;
+        assert!(response.data.is_none());
+        assert!(response.error.unwrap().starts_with("unauthorized"));
+    }
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_authorizer() -> Result<(), Box<dyn std::error::Error>> {
+    #[derive(Clone)]
+    struct Uid(u32);
+
+    let socket_path = PathBuf::from("/tmp/test_circle_authorizer.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    // Stand in for the peer's uid with one the request carries
+    server
+        .add_context_middleware(|payload, context| {
+            context.extensions.insert(Uid(payload.data.number as u32));
+            Ok(())
+        })
+        .await;
+    server
+        .set_authorizer(|context, command| {
+            let uid = context.extensions.get::<Uid>().map(|uid| uid.0);
+            command != "stop" || uid == Some(0)
+        })
+        .await;
+    server
+        .register_handler("stop", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "stopped".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let stop_as = |uid: i32| {
+        SocketPayload::<TestData, TestResponse>::new("stop", TestData {
+            value: String::new(),
+            number: uid,
+        })
+    };
+
+    let allowed = client.send_request(stop_as(0)).await?.into_result()?;
+    assert_eq!(allowed.result, "stopped");
+
+    let denied = client.send_request(stop_as(1000)).await?;
+    assert!(!denied.success);
+    assert!(denied.data.is_none());
+    assert!(denied.error.unwrap().starts_with("unauthorized: "));
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multibyte_utf8_round_trips() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_multibyte.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("echo", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: payload.data.value.chars().rev().collect(),
+                doubled: payload.data.value.chars().count() as i32,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    // Two-, three- and four-byte sequences, parsed from bytes on both sides
+    let value = "héllo, 日本語 🦀".to_string();
+    let client = SocketClient::new(config);
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("echo", TestData {
+            value: value.clone(),
+            number: 0,
+        }))
+        .await?
+        .into_result()?;
+    assert_eq!(response.result, value.chars().rev().collect::<String>());
+    assert_eq!(response.doubled, 12);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_broadcast_to_subscriber() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_broadcast.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let exited = |request_id: &str, pid: i32| {
+        SocketResponse::success(request_id, TestResponse {
+            result: "exited".to_string(),
+            doubled: pid,
+        })
+    };
+    // Nobody is listening yet, so this one is lost
+    assert_eq!(handle.broadcast(&exited("event-1", 41))?, 0);
+
+    let client = SocketClient::new(config);
+    let mut subscription = client.subscribe::<TestResponse>().await?;
+    assert_eq!(handle.broadcast(&exited("event-2", 42))?, 1);
+
+    let event = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await?
+        .expect("the subscription is open")?;
+    assert_eq!(event.request_id, "event-2");
+    assert_eq!(event.into_result()?.doubled, 42);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_publish_to_topics() -> Result<(), Box<dyn std::error::Error>> {
+    let socket_path = PathBuf::from("/tmp/test_circle_topics.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    let handle = server.handle();
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let client = SocketClient::new(config);
+    let mut builds = client.subscribe::<TestResponse>().await?;
+    builds.subscribe("builds").await?;
+    let mut deploys = client.subscribe::<TestResponse>().await?;
+    deploys.subscribe("deploys").await?;
+
+    let event = |result: &str| TestResponse {
+        result: result.to_string(),
+        doubled: 0,
+    };
+    assert_eq!(handle.publish("builds", event("build finished"))?, 1);
+    assert_eq!(handle.publish("deploys", event("deploy finished"))?, 1);
+    assert_eq!(handle.publish("alerts", event("nobody listens"))?, 0);
+
+    // Each sees its own topic's event first, so the other one never reached it
+    let build = tokio::time::timeout(Duration::from_secs(1), builds.next()).await?.expect("subscribed")?;
+    assert_eq!(build.request_id, "builds");
+    assert_eq!(build.into_result()?.result, "build finished");
+    let deploy = tokio::time::timeout(Duration::from_secs(1), deploys.next()).await?.expect("subscribed")?;
+    assert_eq!(deploy.request_id, "deploys");
+    assert_eq!(deploy.into_result()?.result, "deploy finished");
+
+    deploys.unsubscribe("deploys").await?;
+    assert_eq!(handle.publish("deploys", event("deploy rolled back"))?, 0);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unsupported_protocol_version_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    use circle_socket::{ServerInfo, PROTOCOL_VERSION};
+
+    let socket_path = PathBuf::from("/tmp/test_circle_protocol_version.sock");
+    let config = SocketConfig::from(&socket_path);
+
+    let server = SocketServer::<TestData, TestResponse>::new(config.clone());
+    server
+        .register_handler("start", |payload| {
+            Ok(SocketResponse::success(payload.request_id, TestResponse {
+                result: "started".to_string(),
+                doubled: payload.data.number * 2,
+            }))
+        })
+        .await;
+    let server_handle = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_secs(5), server.run()).await
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let request = r#"{"request_id":"1","command":"start","data":{"value":"","number":2}}"#;
+    let exchange = |version: u32| {
+        let config = config.clone();
+        async move {
+            let handshake = format!(r#"{{"handshake":{{"protocol_version":{}}}}}"#, version);
+            let reply = testing::send_raw(&config, format!("{}{}", handshake, request).as_bytes()).await?;
+            let mut messages = serde_json::Deserializer::from_slice(&reply).into_iter::<serde_json::Value>();
+            let mut reply = messages.next().expect("a handshake reply")?;
+            let info: ServerInfo = serde_json::from_value(reply["handshake"].take())?;
+            let response: SocketResponse<TestResponse> = serde_json::from_value(messages.next().expect("a response")?)?;
+            Ok::<_, Box<dyn std::error::Error>>((info, response))
+        }
+    };
+
+    // A client from before version 1 is turned away without the handler running
+    let (info, response) = exchange(0).await?;
+    assert_eq!(info.protocol_version, Some(PROTOCOL_VERSION));
+    assert!(!response.success);
+    assert_eq!(response.code.as_deref(), Some("version_mismatch"));
+    assert!(response.error.unwrap().contains("protocol version 0"));
+
+    // A newer client is downgraded to the server's version and served
+    let (info, response) = exchange(PROTOCOL_VERSION + 1).await?;
+    assert_eq!(info.protocol_version, Some(PROTOCOL_VERSION));
+    assert_eq!(response.into_result()?.doubled, 4);
+
+    // Clients of this crate agree on the current version
+    let client = SocketClient::new(config.clone()).with_client_name("cli");
+    let response = client
+        .send_request(SocketPayload::<TestData, TestResponse>::new("start", TestData {
+            value: String::new(),
+            number: 3,
+        }))
+        .await?;
+    assert_eq!(response.into_result()?.doubled, 6);
+
+    server_handle.abort();
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path)?;
+    }
+
+    Ok(())
+}